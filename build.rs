@@ -0,0 +1,28 @@
+//! Records the git commit the builder is compiled from, for build
+//! provenance. Set STAGE3_GIT_COMMIT to override it (e.g. in a tarball
+//! build without .git).
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=STAGE3_GIT_COMMIT");
+    if std::env::var_os("STAGE3_GIT_COMMIT").is_some() {
+        return;
+    }
+    for path in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(commit) = commit.filter(|commit| !commit.is_empty()) {
+        println!("cargo:rustc-env=STAGE3_GIT_COMMIT={}", commit);
+    }
+}
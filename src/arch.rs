@@ -0,0 +1,139 @@
+//! Target architectures and where their libraries live.
+//!
+//! Rocky and Fedora keep 64-bit libraries in /usr/lib64 on every
+//! architecture. Debian-style donors keep them in a multiarch directory
+//! named after the GNU triplet instead (/usr/lib/aarch64-linux-gnu). The
+//! build reads the layout off the donor, from where its C library is, and
+//! steps copying library directories (PAM modules, gconv, systemd's
+//! private libraries) [relocate](LibraryLayout::relocate) the lib64 paths
+//! they name into it. On riscv64 the loader looks in an ABI subdirectory
+//! of lib64 (/usr/lib64/lp64d) rather than lib64 itself; libraries are
+//! staged in lib64 all the same, with `lp64d` linking back to it.
+//!
+//! Some boards of an architecture lack what every other target has (a
+//! virtual console on many RISC-V boards), so [`is_optional`] lets their
+//! essential units and helpers be missing from the donor.
+//!
+//! Donor binaries are never run to find out what they need, so a donor of
+//! another architecture than the build host's builds the same way; only
+//! what would run on the host (`--ldd`, host fallback libraries, config
+//! checkers in a chroot) is held to the host's architecture.
+
+use std::path::{Path, PathBuf};
+
+use crate::donor::PackageSource;
+
+/// Directory the lib64 layout keeps libraries in.
+const LIB64_DIR: &str = "usr/lib64";
+
+/// Where a rootfs keeps its libraries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LibraryLayout {
+    /// /usr/lib64, as on Rocky and Fedora
+    #[default]
+    Lib64,
+    /// /usr/lib/TRIPLET, as on Debian
+    Multiarch(&'static str),
+}
+
+impl LibraryLayout {
+    /// Layout of the donor for `arch`: multiarch if its C library is in
+    /// the triplet's directory, lib64 otherwise.
+    pub fn detect(source: &dyn PackageSource, arch: &str) -> Self {
+        let Some(triplet) = multiarch_triplet(arch) else {
+            return LibraryLayout::Lib64;
+        };
+        let has_libc = |dir: &Path| source.find_file(&dir.join("libc.so.6")).is_some();
+        if !has_libc(Path::new(LIB64_DIR)) && has_libc(&Path::new("usr/lib").join(triplet)) {
+            LibraryLayout::Multiarch(triplet)
+        } else {
+            LibraryLayout::Lib64
+        }
+    }
+
+    /// Rootfs-relative library directory (`usr/lib64`).
+    pub fn lib_dir(&self) -> PathBuf {
+        match self {
+            LibraryLayout::Lib64 => PathBuf::from(LIB64_DIR),
+            LibraryLayout::Multiarch(triplet) => Path::new("usr/lib").join(triplet),
+        }
+    }
+
+    /// Rootfs path `path`, written for the lib64 layout, in this one:
+    /// `usr/lib64/security` is `usr/lib/aarch64-linux-gnu/security` on a
+    /// multiarch aarch64 donor. Other paths are left alone.
+    pub fn relocate(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        let absolute = path.has_root();
+        let rel = path.strip_prefix("/").unwrap_or(path);
+        let relocated = match rel.strip_prefix(LIB64_DIR) {
+            Ok(rest) if *self != LibraryLayout::Lib64 => self.lib_dir().join(rest),
+            _ => rel.to_path_buf(),
+        };
+        match absolute {
+            true => Path::new("/").join(relocated),
+            false => relocated,
+        }
+    }
+}
+
+/// Architectures a build can target.
+pub const SUPPORTED: &[&str] = &[
+    "x86_64", "i686", "aarch64", "armv7", "riscv64", "ppc64le", "s390x",
+];
+
+/// GNU triplet naming multiarch library directories for `arch`.
+pub fn multiarch_triplet(arch: &str) -> Option<&'static str> {
+    match arch {
+        "x86_64" => Some("x86_64-linux-gnu"),
+        "i686" => Some("i386-linux-gnu"),
+        "aarch64" => Some("aarch64-linux-gnu"),
+        "armv7" => Some("arm-linux-gnueabihf"),
+        "riscv64" => Some("riscv64-linux-gnu"),
+        "ppc64le" => Some("powerpc64le-linux-gnu"),
+        "s390x" => Some("s390x-linux-gnu"),
+        _ => None,
+    }
+}
+
+/// Subdirectory of lib64 the loader of `arch` searches instead of lib64
+/// itself (`lp64d` on riscv64).
+pub fn abi_subdir(arch: &str) -> Option<&'static str> {
+    match arch {
+        "riscv64" => Some("lp64d"),
+        _ => None,
+    }
+}
+
+/// Multiarch and ABI library directories the loader searches for `arch`,
+/// rootfs-relative, before the plain ones.
+pub fn arch_lib_dirs(arch: &str) -> Vec<String> {
+    let multiarch = multiarch_triplet(arch)
+        .into_iter()
+        .flat_map(|triplet| [format!("lib/{}", triplet), format!("usr/lib/{}", triplet)]);
+    let abi = abi_subdir(arch)
+        .into_iter()
+        .flat_map(|abi| [format!("lib64/{}", abi), format!("usr/lib64/{}", abi)]);
+    multiarch.chain(abi).collect()
+}
+
+/// Essential units and systemd helpers some boards of an architecture do
+/// without, by architecture.
+const OPTIONAL: &[(&str, &[&str])] = &[(
+    "riscv64",
+    &["systemd-vconsole-setup.service", "systemd-vconsole-setup"],
+)];
+
+/// Whether essential unit or systemd helper `name` may be missing from a
+/// donor for `arch`.
+pub fn is_optional(arch: &str, name: &str) -> bool {
+    OPTIONAL
+        .iter()
+        .any(|(optional_arch, names)| *optional_arch == arch && names.contains(&name))
+}
+
+/// Whether `arch` is the build host's architecture, so its binaries can
+/// be run here.
+pub fn is_host(arch: &str) -> bool {
+    arch == std::env::consts::ARCH
+}
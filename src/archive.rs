@@ -0,0 +1,1122 @@
+//! Streaming access to stage3 tarballs.
+//!
+//! Archives are decoded in-process (xz, zstd, gzip or plain tar, detected
+//! from the magic bytes) so callers can walk entries as they are
+//! decompressed instead of buffering the output of `tar -t`.
+//! [`Stage3Archive`] is the reader every command uses, and the API for
+//! consumers such as the installer.
+//!
+//! Writing is in-process as well, which lets the builder emit entries that
+//! have no backing file in staging (device nodes) and control ownership
+//! without touching the filesystem. Files with several names in staging
+//! are written once, and later names as hard links to the first; with
+//! `dedup`, so are files with the same contents. Sparse files are written
+//! as GNU sparse entries, holding only their data, and recorded extended
+//! attributes as PAX records.
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+use crate::cancel::CancelToken;
+use crate::checksum::{sha256_file, sha256_reader};
+use crate::clock::BuildClock;
+use crate::elf;
+use crate::fakeroot::{MetadataLayer, Xattrs};
+use crate::manifest::{EntryKind, ManifestEntry};
+use crate::sandbox;
+use crate::sparse::{self, ExtentReader};
+use crate::xattrs;
+
+/// Compression of a tarball.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Plain tar, for fast local iteration and piping into other tools
+    None,
+    /// gzip, for extraction environments without xz
+    Gzip,
+    #[default]
+    Xz,
+    Zstd,
+}
+
+/// Offset of the `ustar` magic in a tar header.
+const USTAR_MAGIC_OFFSET: usize = 257;
+
+/// Largest ELF file [`Stage3Entry::metadata`] reads into memory for its
+/// `DT_NEEDED`; bigger ones are only hashed.
+const ELF_READ_LIMIT: u64 = 256 << 20;
+
+impl Compression {
+    /// Detect the compression format of a file from its magic bytes.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut magic = Vec::with_capacity(USTAR_MAGIC_OFFSET + 5);
+        File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?
+            .take(USTAR_MAGIC_OFFSET as u64 + 5)
+            .read_to_end(&mut magic)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Ok(Self::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Ok(Self::Zstd)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Ok(Self::Gzip)
+        } else if magic.get(USTAR_MAGIC_OFFSET..) == Some(b"ustar") {
+            Ok(Self::None)
+        } else {
+            anyhow::bail!("Unsupported archive compression: {}", path.display())
+        }
+    }
+
+    /// Filename extension of a tarball with this compression.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::None => ".tar",
+            Self::Gzip => ".tar.gz",
+            Self::Xz => ".tar.xz",
+            Self::Zstd => ".tar.zst",
+        }
+    }
+
+    /// The compression a filename's extension implies, if it has a known one.
+    pub fn from_filename(name: &str) -> Option<Self> {
+        // Longest first, so `.tar.gz` doesn't match as `.tar`
+        [Self::Gzip, Self::Xz, Self::Zstd, Self::None]
+            .into_iter()
+            .find(|c| name.ends_with(c.extension()))
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "xz" => Ok(Self::Xz),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(format!(
+                "unknown compression {:?} (expected none, gzip, xz or zstd)",
+                other
+            )),
+        }
+    }
+}
+
+/// A compressing writer; [`Encoder::finish`] must be called to complete
+/// the stream.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Start a compressed stream over `writer`.
+    pub fn new(writer: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(writer),
+            Compression::Gzip => Self::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            Compression::Xz => Self::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            Compression::Zstd => Self::Zstd(
+                zstd::stream::write::Encoder::new(writer, 19)
+                    .context("Failed to initialize zstd encoder")?,
+            ),
+        })
+    }
+
+    /// Write the end of the compressed stream and return the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(writer) => Ok(writer),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writer that adds up the bytes written and the time spent writing them.
+struct TimedWriter<W> {
+    inner: W,
+    bytes: u64,
+    spent: Duration,
+}
+
+impl<W> TimedWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            spent: Duration::ZERO,
+        }
+    }
+}
+
+impl<W: Write> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let written = self.inner.write(buf)?;
+        self.spent += started.elapsed();
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        self.inner.flush()?;
+        self.spent += started.elapsed();
+        Ok(())
+    }
+}
+
+/// Reader that counts the compressed bytes consumed.
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// An open stage3 tarball, read in a single streaming pass.
+///
+/// This is the one reader behind list, verify, inspect, audit and
+/// extraction, and the API the installer uses to consume artifacts:
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use stage3::archive::Stage3Archive;
+///
+/// let mut archive = Stage3Archive::open("levitateos-stage3-1.0-x86_64.tar.xz".as_ref())?;
+/// for entry in archive.entries()? {
+///     let entry = entry?;
+///     println!("{} ({:?}, {} bytes)", entry.path(), entry.kind(), entry.size()?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Entries come in archive order and can only be walked once; open the
+/// tarball again for another pass.
+pub struct Stage3Archive {
+    path: PathBuf,
+    compression: Compression,
+    archive: tar::Archive<Box<dyn Read>>,
+    progress: ScanProgress,
+}
+
+impl Stage3Archive {
+    /// Open a tarball, detecting its compression from the magic bytes.
+    pub fn open(path: &Path) -> Result<Self> {
+        let compression = Compression::detect(path)?;
+        let (decoder, progress) = decoder(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            compression,
+            archive: tar::Archive::new(decoder),
+            progress,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Number of entries read so far.
+    pub fn entries_read(&self) -> u64 {
+        self.progress.entries()
+    }
+
+    /// Iterate over the entries, decompressing as they are read.
+    ///
+    /// Progress is shown on stderr while iterating, when it is a terminal.
+    pub fn entries(&mut self) -> Result<Entries<'_>> {
+        Ok(Entries {
+            inner: self.archive.entries()?,
+            progress: &mut self.progress,
+        })
+    }
+
+    /// Read a single member into memory.
+    ///
+    /// Returns `None` if the member is not present.
+    pub fn read_member(mut self, member: &str) -> Result<Option<Vec<u8>>> {
+        let wanted = normalize_path(Path::new(member));
+        for entry in self.entries()? {
+            let mut entry = entry?;
+            if entry.path() == wanted {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                return Ok(Some(contents));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Extract into `dest`, returning the number of entries extracted.
+    pub fn extract(mut self, dest: &Path, options: &ExtractOptions) -> Result<u64> {
+        fs::create_dir_all(dest)?;
+        let matcher = compile_globs(&options.include)?;
+        self.archive.set_preserve_permissions(options.preserve);
+        self.archive.set_preserve_ownerships(options.preserve);
+        self.archive.set_unpack_xattrs(options.preserve);
+
+        let mut extracted = BTreeSet::new();
+        let mut directories = Vec::new();
+        for entry in self.entries()? {
+            let mut entry = entry?;
+            let path = entry.path().to_string();
+            if !options.include.is_empty() && !matcher.is_match(&path) {
+                continue;
+            }
+
+            let kind = entry.kind();
+            let node = matches!(kind, EntryKind::Char | EntryKind::Block | EntryKind::Fifo);
+            if node && !options.preserve {
+                continue;
+            }
+            if kind == EntryKind::Hardlink {
+                // A hardlink can only be made to a file that was extracted
+                let target = entry.link_name()?.map(|t| normalize_path(&t));
+                if !target.is_some_and(|t| extracted.contains(&t)) {
+                    continue;
+                }
+            }
+            match kind {
+                // Modes and mtimes are applied once the contents are in
+                // place, deepest first, like tar does
+                EntryKind::Dir if options.preserve => directories.push(entry),
+                EntryKind::Dir => fs::create_dir_all(dest_path(dest, &path)?)?,
+                _ if node => make_node(&entry, &dest_path(dest, &path)?)?,
+                _ => {
+                    entry
+                        .entry
+                        .unpack_in(dest)
+                        .with_context(|| format!("Failed to extract /{}", path))?;
+                }
+            }
+            extracted.insert(path);
+        }
+
+        directories.sort_by(|a, b| b.path().cmp(a.path()));
+        for mut directory in directories {
+            directory
+                .entry
+                .unpack_in(dest)
+                .with_context(|| format!("Failed to extract /{}", directory.path()))?;
+        }
+        Ok(extracted.len() as u64)
+    }
+}
+
+/// Where an entry is extracted to, refusing paths that leave `dest`.
+fn dest_path(dest: &Path, rel: &str) -> Result<PathBuf> {
+    let rel = Path::new(rel);
+    // The archive's root entry
+    if rel.as_os_str().is_empty() {
+        return Ok(dest.to_path_buf());
+    }
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("Refusing to extract /{} outside the target", rel.display());
+    }
+    let path = dest.join(rel);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+        if !parent.canonicalize()?.starts_with(dest.canonicalize()?) {
+            anyhow::bail!("Refusing to extract /{} through a symlink", rel.display());
+        }
+    }
+    Ok(path)
+}
+
+/// Create a device node or FIFO with its mode and owner.
+///
+/// The tar crate would extract these as empty regular files.
+fn make_node(entry: &Stage3Entry, path: &Path) -> Result<()> {
+    let header = entry.header();
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(path)?;
+    }
+
+    let mut cmd = sandbox::command("mknod");
+    cmd.arg("-m")
+        .arg(format!("{:o}", header.mode()? & 0o7777))
+        .arg(path);
+    match entry.kind() {
+        EntryKind::Fifo => cmd.arg("p"),
+        kind => cmd
+            .arg(if kind == EntryKind::Block { "b" } else { "c" })
+            .arg(header.device_major()?.unwrap_or(0).to_string())
+            .arg(header.device_minor()?.unwrap_or(0).to_string()),
+    };
+    let status = cmd.status().context("Failed to run mknod")?;
+    if !status.success() {
+        anyhow::bail!("mknod failed for /{} ({})", entry.path(), status);
+    }
+
+    let (uid, gid) = (header.uid()? as u32, header.gid()? as u32);
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+        .with_context(|| format!("Failed to set the owner of /{}", entry.path()))
+}
+
+/// What [`Stage3Archive::extract`] extracts, and how faithfully.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Only extract entries matching one of these globs (everything if
+    /// empty); a leading `/` is allowed and `*` crosses `/`
+    pub include: Vec<String>,
+    /// Restore ownership, modes, device nodes and extended attributes for a
+    /// bootable rootfs; this needs root. Otherwise device nodes and FIFOs
+    /// are skipped and directories get default permissions, which works
+    /// unprivileged for tools that only read the tree.
+    pub preserve: bool,
+}
+
+impl ExtractOptions {
+    /// Everything, as a faithful rootfs.
+    pub fn rootfs() -> Self {
+        Self {
+            include: Vec::new(),
+            preserve: true,
+        }
+    }
+}
+
+/// Iterator over the entries of a [`Stage3Archive`].
+pub struct Entries<'a> {
+    inner: tar::Entries<'a, Box<dyn Read>>,
+    progress: &'a mut ScanProgress,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Stage3Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.inner.next()?;
+        self.progress.tick();
+        Some(
+            entry
+                .context("Failed to read tarball entry")
+                .and_then(Stage3Entry::new),
+        )
+    }
+}
+
+impl Drop for Entries<'_> {
+    fn drop(&mut self) {
+        self.progress.finish();
+    }
+}
+
+/// One entry of a [`Stage3Archive`]; reading it yields the file contents.
+pub struct Stage3Entry<'a> {
+    entry: tar::Entry<'a, Box<dyn Read>>,
+    path: String,
+}
+
+impl<'a> Stage3Entry<'a> {
+    fn new(entry: tar::Entry<'a, Box<dyn Read>>) -> Result<Self> {
+        let path = normalize_path(&entry.path()?);
+        Ok(Self { entry, path })
+    }
+
+    /// Path inside the rootfs (`usr/bin/bash`; empty for the root itself).
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Path exactly as stored in the archive (`./usr/bin/bash`, `./`).
+    pub fn archive_path(&self) -> Result<PathBuf> {
+        Ok(self.entry.path()?.into_owned())
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        EntryKind::from(self.entry.header().entry_type())
+    }
+
+    /// Size of the contents in bytes (0 for anything but regular files).
+    pub fn size(&self) -> Result<u64> {
+        // Of a sparse file, the size it expands to, not that of its data
+        Ok(self.entry.size())
+    }
+
+    /// The raw tar header, for ownership, mode and device numbers.
+    pub fn header(&self) -> &tar::Header {
+        self.entry.header()
+    }
+
+    /// Target of a symlink or hardlink, as stored in the archive.
+    pub fn link_name(&self) -> Result<Option<PathBuf>> {
+        Ok(self.entry.link_name()?.map(|target| target.into_owned()))
+    }
+
+    /// PAX records stored for the entry (extended attributes, ACLs, long
+    /// names, ...), in archive order.
+    pub fn pax_records(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut records = Vec::new();
+        if let Some(extensions) = self.entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                records.push((
+                    extension.key()?.to_string(),
+                    extension.value_bytes().to_vec(),
+                ));
+            }
+        }
+        Ok(records)
+    }
+
+    /// The entry's metadata in manifest form, hashing the contents of
+    /// regular files (and reading their `DT_NEEDED`) if `hash` is set.
+    ///
+    /// Contents are hashed as they stream past; only ELF files up to
+    /// [`ELF_READ_LIMIT`] are held in memory, to be parsed.
+    pub fn metadata(&mut self, hash: bool) -> Result<ManifestEntry> {
+        let header = self.entry.header().clone();
+        let kind = self.kind();
+
+        let target = match kind {
+            EntryKind::Symlink | EntryKind::Hardlink => {
+                self.link_name()?.map(|t| t.to_string_lossy().into_owned())
+            }
+            _ => None,
+        };
+        let device = match kind {
+            EntryKind::Char | EntryKind::Block => Some(format!(
+                "{}:{}",
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0)
+            )),
+            _ => None,
+        };
+        let (sha256, needed) = match kind {
+            EntryKind::File if hash => {
+                let path = self.path.clone();
+                let size = self.entry.size();
+                let mut magic = Vec::with_capacity(4);
+                (&mut *self)
+                    .take(4)
+                    .read_to_end(&mut magic)
+                    .with_context(|| format!("Failed to read /{}", path))?;
+                if elf::is_elf(&magic) && size <= ELF_READ_LIMIT {
+                    let mut contents = magic;
+                    self.read_to_end(&mut contents)
+                        .with_context(|| format!("Failed to read /{}", path))?;
+                    let needed = elf::dynamic_info(&contents)
+                        .map(|info| info.needed)
+                        .filter(|needed| !needed.is_empty());
+                    (Some(sha256_reader(&contents[..])?), needed)
+                } else {
+                    let sha256 = sha256_reader(magic.chain(&mut *self))
+                        .with_context(|| format!("Failed to read /{}", path))?;
+                    (Some(sha256), None)
+                }
+            }
+            _ => (None, None),
+        };
+
+        Ok(ManifestEntry {
+            path: if self.path.is_empty() {
+                ".".to_string()
+            } else {
+                self.path.clone()
+            },
+            kind,
+            size: self.entry.size(),
+            mode: format!("{:04o}", header.mode()? & 0o7777),
+            uid: header.uid()?,
+            gid: header.gid()?,
+            sha256,
+            target,
+            device,
+            needed,
+        })
+    }
+}
+
+impl Read for Stage3Entry<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.entry.read(buf)
+    }
+}
+
+/// Open a decompressing reader over the raw tar stream of a tarball.
+pub fn decoder(path: &Path) -> Result<(Box<dyn Read>, ScanProgress)> {
+    let compression = Compression::detect(path)?;
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let total = file.metadata()?.len();
+
+    let count = Rc::new(Cell::new(0));
+    let counted = BufReader::new(CountingReader {
+        inner: file,
+        count: Rc::clone(&count),
+    });
+
+    let decoder: Box<dyn Read> = match compression {
+        Compression::None => Box::new(counted),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(counted)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(counted)),
+        Compression::Zstd => Box::new(
+            zstd::stream::read::Decoder::with_buffer(counted)
+                .context("Failed to initialize zstd decoder")?,
+        ),
+    };
+
+    Ok((decoder, ScanProgress::new(total, count)))
+}
+
+/// Compile path globs; a leading `/` is allowed and `*` crosses `/`.
+pub fn compile_globs(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_start_matches('/');
+        builder
+            .add(Glob::new(pattern).with_context(|| format!("Invalid path pattern: {}", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Normalize an archive member path (`./usr/bin/bash` -> `usr/bin/bash`).
+pub fn normalize_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    path.trim_start_matches("./")
+        .trim_start_matches('/')
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Progress reporting while scanning an archive.
+///
+/// Updates are written to stderr, and only when it is a terminal, so piped
+/// output stays clean.
+pub struct ScanProgress {
+    total: u64,
+    read: Rc<Cell<u64>>,
+    entries: u64,
+    last_update: Instant,
+    enabled: bool,
+}
+
+impl ScanProgress {
+    fn new(total: u64, read: Rc<Cell<u64>>) -> Self {
+        Self {
+            total,
+            read,
+            entries: 0,
+            last_update: Instant::now(),
+            // Status output, so hidden by --quiet and without a subscriber
+            enabled: io::stderr().is_terminal() && tracing::enabled!(tracing::Level::INFO),
+        }
+    }
+
+    /// Number of entries seen so far.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Percentage of the compressed input consumed.
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            (self.read.get() as f64 / self.total as f64 * 100.0).min(100.0)
+        }
+    }
+
+    /// Record one more entry, redrawing the progress line periodically.
+    pub fn tick(&mut self) {
+        self.entries += 1;
+        if self.enabled && self.last_update.elapsed() >= Duration::from_millis(200) {
+            self.last_update = Instant::now();
+            eprint!(
+                "\r  Scanned {} entries ({:.0}%)...",
+                self.entries,
+                self.percent()
+            );
+            io::stderr().flush().ok();
+        }
+    }
+
+    /// Clear the progress line.
+    pub fn finish(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[2K");
+            io::stderr().flush().ok();
+        }
+    }
+}
+
+/// Kind of device node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Char,
+    Block,
+}
+
+/// A device node emitted directly as an archive entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceNode {
+    /// Path inside the rootfs (e.g. `dev/null`)
+    pub path: Cow<'static, str>,
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: u32,
+    pub mode: u32,
+}
+
+impl DeviceNode {
+    /// A character device node.
+    pub const fn char(path: &'static str, major: u32, minor: u32, mode: u32) -> Self {
+        Self {
+            path: Cow::Borrowed(path),
+            kind: DeviceKind::Char,
+            major,
+            minor,
+            mode,
+        }
+    }
+}
+
+/// Options for [`write_tarball`].
+///
+/// Every entry is owned by 0:0 regardless of who ran the build unless the
+/// metadata layer records something else.
+pub struct WriteOptions<'a> {
+    /// Intended ownership, modes and device nodes recorded during the build
+    pub metadata: Option<&'a MetadataLayer>,
+    /// Clock every entry's mtime is clamped to
+    pub clock: BuildClock,
+    /// Compression of the written tarball
+    pub compression: Compression,
+    /// Stops writing when cancelled, leaving a partial file to remove
+    pub cancel: Option<&'a CancelToken>,
+    /// Also write files with the same contents and attributes as an
+    /// earlier one as hard links to it
+    pub dedup: bool,
+}
+
+/// What [`write_tarball`] wrote.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteStats {
+    /// Size of the tar stream
+    pub tar_bytes: u64,
+    /// Size of the compressed file
+    pub compressed_bytes: u64,
+    /// Time spent in the compressor, without writing its output
+    pub compression_time: Duration,
+    /// Files written as hard links to an earlier entry
+    pub hardlinks: u64,
+}
+
+/// Mode, owner, group, mtime and extended attributes; names of one file
+/// must agree on them.
+type LinkAttributes = (u32, u64, u64, u64, Xattrs);
+
+/// First names of the regular files written so far.
+#[derive(Default)]
+struct HardLinks {
+    /// By staging inode, for files with more than one name in staging
+    inodes: HashMap<(u64, u64), (PathBuf, LinkAttributes)>,
+    /// By size and SHA-256, with `dedup`
+    contents: Option<HashMap<(u64, String), (PathBuf, LinkAttributes)>>,
+}
+
+impl HardLinks {
+    /// Earlier entry the staged file at `path`, written as `name`, can be
+    /// a hard link to; otherwise remember it as the first of its kind.
+    fn earlier(
+        &mut self,
+        path: &Path,
+        name: &Path,
+        metadata: &fs::Metadata,
+        header: &tar::Header,
+        xattrs: &Xattrs,
+    ) -> Result<Option<PathBuf>> {
+        let attributes = (
+            header.mode()?,
+            header.uid()?,
+            header.gid()?,
+            header.mtime()?,
+            xattrs.clone(),
+        );
+        if metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(first) = first_with(&mut self.inodes, key, name, attributes.clone()) {
+                return Ok(Some(first));
+            }
+        }
+        if let Some(ref mut contents) = self.contents {
+            // Empty files are as big as a link
+            if metadata.len() > 0 {
+                let key = (metadata.len(), sha256_file(path)?);
+                return Ok(first_with(contents, key, name, attributes));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The first name under `key` if its attributes are `attributes`; `name`
+/// becomes the first if there is none.
+fn first_with<K: Eq + std::hash::Hash>(
+    firsts: &mut HashMap<K, (PathBuf, LinkAttributes)>,
+    key: K,
+    name: &Path,
+    attributes: LinkAttributes,
+) -> Option<PathBuf> {
+    match firsts.entry(key) {
+        Entry::Occupied(first) if first.get().1 == attributes => Some(first.get().0.clone()),
+        Entry::Occupied(_) => None,
+        Entry::Vacant(first) => {
+            first.insert((name.to_path_buf(), attributes));
+            None
+        }
+    }
+}
+
+/// Apply recorded ownership and permissions to a header.
+fn apply_attributes(header: &mut tar::Header, metadata: Option<&MetadataLayer>, path: &Path) {
+    let attributes = metadata.map(|m| m.attributes(path)).unwrap_or_default();
+    header.set_uid(attributes.uid.unwrap_or(0));
+    header.set_gid(attributes.gid.unwrap_or(0));
+    if let Some(mode) = attributes.mode {
+        header.set_mode(mode);
+    }
+}
+
+/// Write the PAX records for the extended attributes of the next entry.
+fn append_xattrs<W: Write>(builder: &mut tar::Builder<W>, xattrs: &Xattrs) -> io::Result<()> {
+    let records = xattrs::pax_records(xattrs);
+    builder.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+}
+
+/// Make `header` the GNU sparse entry of a file of `real_size` bytes with
+/// data `extents`, returning the extended sparse headers that go before
+/// the data.
+fn sparse_header(header: &mut tar::Header, real_size: u64, extents: &[(u64, u64)]) -> Vec<u8> {
+    // GNU tar ends the map with an empty extent at the end of the file
+    let map: Vec<(u64, u64)> = extents.iter().copied().chain([(real_size, 0)]).collect();
+    header.set_entry_type(tar::EntryType::GNUSparse);
+    header.set_size(extents.iter().map(|(_, len)| len).sum());
+    let gnu = header.as_gnu_mut().expect("stage3 headers are GNU headers");
+    gnu.set_real_size(real_size);
+    let (first, rest) = map.split_at(map.len().min(gnu.sparse.len()));
+    for (entry, &(offset, len)) in gnu.sparse.iter_mut().zip(first) {
+        entry.set_offset(offset);
+        entry.set_length(len);
+    }
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut extended = Vec::new();
+    let per_block = tar::GnuExtSparseHeader::new().sparse.len();
+    let mut blocks = rest.chunks(per_block).peekable();
+    while let Some(block) = blocks.next() {
+        let mut ext = tar::GnuExtSparseHeader::new();
+        for (entry, &(offset, len)) in ext.sparse.iter_mut().zip(block) {
+            entry.set_offset(offset);
+            entry.set_length(len);
+        }
+        ext.set_is_extended(blocks.peek().is_some());
+        extended.extend_from_slice(ext.as_bytes());
+    }
+    extended
+}
+
+/// Write the staging tree to a tarball.
+///
+/// Entries are written in sorted order with mtimes clamped to the build
+/// clock, so identical staging trees produce byte-identical archives.
+pub fn write_tarball(staging: &Path, output: &Path, options: &WriteOptions) -> Result<WriteStats> {
+    let file =
+        File::create(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    // Time in the encoder minus time writing its output is compression
+    let sink = TimedWriter::new(BufWriter::new(file));
+    let encoder = Encoder::new(sink, options.compression)?;
+    let mut builder = tar::Builder::new(TimedWriter::new(encoder));
+    builder.follow_symlinks(false);
+
+    let clamp = options.clock.timestamp();
+    let mut links = HardLinks {
+        contents: options.dedup.then(HashMap::new),
+        ..HardLinks::default()
+    };
+    let mut hardlinks = 0;
+
+    for entry in WalkDir::new(staging).sort_by_file_name() {
+        if let Some(cancel) = options.cancel {
+            cancel.check()?;
+        }
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(staging)?;
+        let name = if rel.as_os_str().is_empty() {
+            Path::new("./")
+        } else {
+            rel
+        };
+
+        let metadata = fs::symlink_metadata(entry.path())
+            .with_context(|| format!("Failed to read metadata: {}", entry.path().display()))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+        header.set_mtime(header.mtime()?.min(clamp));
+        if let Some(gnu) = header.as_gnu_mut() {
+            // GNU tar leaves these empty outside incremental mode
+            gnu.atime = [0; 12];
+            gnu.ctime = [0; 12];
+        }
+        apply_attributes(&mut header, options.metadata, rel);
+
+        let xattrs = options.metadata.map(|m| m.xattrs(rel)).unwrap_or_default();
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            builder.append_link(&mut header, name, &target)?;
+        } else if file_type.is_dir() {
+            append_xattrs(&mut builder, &xattrs)?;
+            builder.append_data(&mut header, name, io::empty())?;
+        } else if file_type.is_file() {
+            if let Some(first) = links.earlier(entry.path(), name, &metadata, &header, &xattrs)? {
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                builder.append_link(&mut header, name, &first)?;
+                hardlinks += 1;
+                continue;
+            }
+            let file = File::open(entry.path())
+                .with_context(|| format!("Failed to open {}", entry.path().display()))?;
+            append_xattrs(&mut builder, &xattrs)?;
+            match sparse::data_extents(&file)? {
+                Some(extents) => {
+                    let extended = sparse_header(&mut header, metadata.len(), &extents);
+                    let data = io::Cursor::new(extended).chain(ExtentReader::new(&file, &extents));
+                    builder.append_data(&mut header, name, data)?;
+                }
+                None => builder.append_data(&mut header, name, file)?,
+            }
+        } else {
+            tracing::warn!("  Warning: skipping special file /{}", rel.display());
+        }
+    }
+
+    let devices = options.metadata.map(|m| m.devices()).unwrap_or_default();
+    for node in &devices {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(match node.kind {
+            DeviceKind::Char => tar::EntryType::Char,
+            DeviceKind::Block => tar::EntryType::Block,
+        });
+        header.set_device_major(node.major)?;
+        header.set_device_minor(node.minor)?;
+        header.set_mode(node.mode);
+        header.set_size(0);
+        header.set_mtime(clamp);
+        apply_attributes(&mut header, options.metadata, Path::new(&*node.path));
+        builder.append_data(&mut header, &*node.path, io::empty())?;
+    }
+
+    let mut stream = builder.into_inner()?;
+    let started = Instant::now();
+    let mut sink = stream.inner.finish()?;
+    stream.spent += started.elapsed();
+    let compression_time = stream.spent.saturating_sub(sink.spent);
+    sink.flush()?;
+    Ok(WriteStats {
+        tar_bytes: stream.bytes,
+        compressed_bytes: sink.bytes,
+        compression_time,
+        hardlinks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::FileExt;
+
+    /// An entry of a written tarball, as read back.
+    struct Written {
+        kind: tar::EntryType,
+        size: u64,
+        contents: Vec<u8>,
+        link: Option<PathBuf>,
+        mode: u32,
+        owner: (u64, u64),
+        records: Vec<(String, Vec<u8>)>,
+    }
+
+    /// Write `staging` to a tarball and read every entry back.
+    fn write(staging: &Path, metadata: &MetadataLayer, dedup: bool) -> BTreeMap<String, Written> {
+        let output = staging.with_extension("tar");
+        let options = WriteOptions {
+            metadata: Some(metadata),
+            clock: BuildClock::fixed(1_700_000_000),
+            compression: Compression::None,
+            cancel: None,
+            dedup,
+        };
+        write_tarball(staging, &output, &options).unwrap();
+
+        let mut archive = Stage3Archive::open(&output).unwrap();
+        let mut written = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let records = entry.pax_records().unwrap();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            let header = entry.header();
+            let entry_written = Written {
+                kind: header.entry_type(),
+                size: entry.size().unwrap(),
+                contents,
+                link: entry.link_name().unwrap(),
+                mode: header.mode().unwrap(),
+                owner: (header.uid().unwrap(), header.gid().unwrap()),
+                records,
+            };
+            written.insert(entry.path().to_string(), entry_written);
+        }
+        written
+    }
+
+    fn staging() -> (tempfile::TempDir, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let staging = root.path().join("staging");
+        fs::create_dir(&staging).unwrap();
+        (root, staging)
+    }
+
+    #[test]
+    fn sparse_files_keep_their_contents_past_the_first_header() {
+        let (_root, staging) = staging();
+        // More extents than fit in the header, so extended headers follow
+        let file = File::create(staging.join("disk.img")).unwrap();
+        let extents = 30;
+        for i in 0..extents {
+            file.write_all_at(format!("extent {}", i).as_bytes(), i << 20)
+                .unwrap();
+        }
+        file.set_len(extents << 20).unwrap();
+        fs::write(staging.join("next"), "after\n").unwrap();
+
+        let written = write(&staging, &MetadataLayer::default(), false);
+        let disk = &written["disk.img"];
+        assert_eq!(disk.kind, tar::EntryType::GNUSparse);
+        assert_eq!(disk.size, extents << 20);
+        assert_eq!(disk.contents, fs::read(staging.join("disk.img")).unwrap());
+        assert_eq!(written["next"].contents, b"after\n");
+    }
+
+    #[test]
+    fn recorded_attributes_and_xattrs_are_written() {
+        let (_root, staging) = staging();
+        fs::create_dir_all(staging.join("usr/bin")).unwrap();
+        fs::write(staging.join("usr/bin/ping"), "ping\n").unwrap();
+        let metadata = MetadataLayer::default();
+        metadata.chown("usr/bin/ping", 0, 4);
+        metadata.chmod("usr/bin/ping", 0o4750);
+        let capability = vec![1, 0, 0, 2, 0, 32, 0, 0];
+        let xattrs = Xattrs::from([("security.capability".to_string(), capability.clone())]);
+        metadata.set_xattrs("usr/bin/ping", xattrs);
+        metadata.mknod(DeviceNode::char("dev/null", 1, 3, 0o666));
+
+        let written = write(&staging, &metadata, false);
+        let ping = &written["usr/bin/ping"];
+        assert_eq!((ping.mode, ping.owner), (0o4750, (0, 4)));
+        assert_eq!(
+            ping.records,
+            [("SCHILY.xattr.security.capability".to_string(), capability)]
+        );
+        assert_eq!(written["usr/bin"].owner, (0, 0));
+        assert!(written["usr/bin"].records.is_empty());
+        let null = &written["dev/null"];
+        assert_eq!((null.kind, null.mode), (tar::EntryType::Char, 0o666));
+    }
+
+    #[test]
+    fn names_of_one_file_are_hard_links() {
+        let (_root, staging) = staging();
+        fs::write(staging.join("a"), "shared\n").unwrap();
+        fs::hard_link(staging.join("a"), staging.join("b")).unwrap();
+        fs::write(staging.join("c"), "shared\n").unwrap();
+
+        let written = write(&staging, &MetadataLayer::default(), false);
+        assert_eq!(written["a"].kind, tar::EntryType::Regular);
+        assert_eq!(written["b"].kind, tar::EntryType::Link);
+        assert_eq!(written["b"].link.as_deref(), Some(Path::new("a")));
+        // Same contents under another inode only with dedup
+        assert_eq!(written["c"].kind, tar::EntryType::Regular);
+        assert_eq!(written["c"].contents, b"shared\n");
+    }
+
+    #[test]
+    fn dedup_links_only_files_with_the_same_attributes() {
+        let (_root, staging) = staging();
+        for name in ["a", "b", "c"] {
+            fs::write(staging.join(name), "shared\n").unwrap();
+        }
+        fs::write(staging.join("d"), "").unwrap();
+        fs::write(staging.join("e"), "").unwrap();
+        let metadata = MetadataLayer::default();
+        metadata.chmod("c", 0o600);
+
+        let written = write(&staging, &metadata, true);
+        assert_eq!(written["a"].kind, tar::EntryType::Regular);
+        assert_eq!(written["b"].kind, tar::EntryType::Link);
+        assert_eq!(written["b"].link.as_deref(), Some(Path::new("a")));
+        assert_eq!(written["c"].kind, tar::EntryType::Regular);
+        // Empty files are never linked
+        assert_eq!(written["e"].kind, tar::EntryType::Regular);
+    }
+}
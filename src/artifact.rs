@@ -0,0 +1,94 @@
+//! Artifact naming for stage3 tarballs.
+//!
+//! Output filenames are rendered from a template so nightly and release
+//! builds can carry distinct names instead of overwriting each other.
+
+use anyhow::{bail, Result};
+
+use crate::archive::Compression;
+use crate::clock::BuildClock;
+
+/// Default output filename template.
+pub const DEFAULT_OUTPUT_NAME: &str = "levitateos-stage3-{version}-{arch}{ext}";
+
+/// LevitateOS release version stamped into artifact names.
+pub const OS_VERSION: &str = "1.0";
+
+/// Default build profile name.
+pub const DEFAULT_PROFILE: &str = "base";
+
+/// Values substituted into an output filename template.
+pub struct ArtifactInfo {
+    /// LevitateOS version (`{version}`)
+    pub version: String,
+    /// Target architecture (`{arch}`)
+    pub arch: String,
+    /// Build date as YYYYMMDD (`{date}`)
+    pub date: String,
+    /// Build profile name (`{profile}`)
+    pub profile: String,
+    /// Tarball compression (`{ext}`: `.tar.xz`, `.tar.gz`, ...)
+    pub compression: Compression,
+}
+
+impl ArtifactInfo {
+    pub fn new(profile: &str, arch: &str, clock: &BuildClock) -> Self {
+        Self {
+            version: OS_VERSION.to_string(),
+            arch: arch.to_string(),
+            date: clock.date(),
+            profile: profile.to_string(),
+            compression: Compression::default(),
+        }
+    }
+}
+
+/// Render an output filename template.
+///
+/// Supported placeholders: `{version}`, `{arch}`, `{date}`, `{profile}`,
+/// `{ext}`. A name ending in a tarball extension must match the
+/// compression.
+pub fn render_output_name(template: &str, info: &ArtifactInfo) -> Result<String> {
+    let mut name = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => bail!("Unterminated placeholder in output name: {}", template),
+        };
+
+        match &after[..end] {
+            "version" => name.push_str(&info.version),
+            "arch" => name.push_str(&info.arch),
+            "date" => name.push_str(&info.date),
+            "profile" => name.push_str(&info.profile),
+            "ext" => name.push_str(info.compression.extension()),
+            other => bail!(
+                "Unknown placeholder {{{}}} in output name: {}",
+                other,
+                template
+            ),
+        }
+
+        rest = &after[end + 1..];
+    }
+    name.push_str(rest);
+
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("Invalid output name: {:?}", name);
+    }
+    if let Some(implied) = Compression::from_filename(&name) {
+        if implied != info.compression {
+            bail!(
+                "Output name {} does not match the compression (use {} or {{ext}})",
+                name,
+                info.compression.extension()
+            );
+        }
+    }
+
+    Ok(name)
+}
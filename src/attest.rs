@@ -0,0 +1,250 @@
+//! Tamper detection for deployed release tarballs.
+//!
+//! `stage3 attest` checks a tarball served by a mirror against the release
+//! manifest `stage3 release` wrote for it: the hashes are recomputed, the
+//! build metadata read from the tarball has to match the manifest, and the
+//! detached signature is checked when the release was signed. Each class
+//! of failure has its own exit code (see `failure`) so audits can be
+//! scripted.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::builder::{CheckResult, CheckStatus};
+use crate::checksum::sha256_file;
+use crate::failure::FailureKind;
+use crate::release::{content_hash, read_os_release, tarball_arch, ReleaseManifest};
+use crate::signing;
+
+/// Why a tarball failed attestation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttestFailure {
+    /// The tarball's bytes or contents differ from the release manifest
+    ModifiedContent,
+    /// The signature is missing, malformed or doesn't match
+    BadSignature,
+    /// The release manifest or the metadata it's checked against is missing
+    MissingMetadata,
+}
+
+impl From<AttestFailure> for FailureKind {
+    fn from(failure: AttestFailure) -> Self {
+        match failure {
+            AttestFailure::ModifiedContent => FailureKind::ModifiedContent,
+            AttestFailure::BadSignature => FailureKind::BadSignature,
+            AttestFailure::MissingMetadata => FailureKind::MissingMetadata,
+        }
+    }
+}
+
+impl std::fmt::Display for AttestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AttestFailure::ModifiedContent => "modified content",
+            AttestFailure::BadSignature => "bad signature",
+            AttestFailure::MissingMetadata => "missing metadata",
+        })
+    }
+}
+
+/// Results of [`attest_tarball`].
+#[derive(Debug, Serialize)]
+pub struct Attestation {
+    pub tarball: PathBuf,
+    pub manifest: PathBuf,
+    pub checks: Vec<CheckResult>,
+    /// Class of the first failed check, if any
+    pub failure: Option<AttestFailure>,
+}
+
+impl Attestation {
+    /// Print the results for a human.
+    pub fn print(&self) {
+        for check in &self.checks {
+            check.print();
+        }
+    }
+
+    /// Add a check, remembering `failure` if it's the first one to fail.
+    fn record(&mut self, check: CheckResult, failure: AttestFailure) {
+        if check.status == CheckStatus::Fail && self.failure.is_none() {
+            self.failure = Some(failure);
+        }
+        self.checks.push(check);
+    }
+}
+
+/// Path of the release manifest `stage3 release` writes for a tarball.
+pub fn release_manifest_path(tarball: &Path) -> PathBuf {
+    let mut name = tarball.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+/// Check a tarball against its release manifest and signature.
+///
+/// `manifest` defaults to the one next to the tarball. Without
+/// `public_key`, minisign's default key location is used. Errors are
+/// returned only when the tarball can't be read at all.
+pub fn attest_tarball(
+    tarball: &Path,
+    manifest: Option<&Path>,
+    public_key: Option<&Path>,
+) -> Result<Attestation> {
+    if !tarball.is_file() {
+        anyhow::bail!("Tarball does not exist: {}", tarball.display());
+    }
+    let manifest_path = manifest
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| release_manifest_path(tarball));
+    let mut attestation = Attestation {
+        tarball: tarball.to_path_buf(),
+        manifest: manifest_path.clone(),
+        checks: Vec::new(),
+        failure: None,
+    };
+
+    let release = match read_release_manifest(&manifest_path) {
+        Ok(release) => release,
+        Err(err) => {
+            attestation.record(
+                CheckResult::from_error("manifest", err),
+                AttestFailure::MissingMetadata,
+            );
+            return Ok(attestation);
+        }
+    };
+
+    let check = check_content(tarball, &release)?;
+    attestation.record(check, AttestFailure::ModifiedContent);
+    let (check, failure) = check_metadata(tarball, &release)?;
+    attestation.record(check, failure);
+    attestation.record(
+        check_signature(tarball, &release, public_key),
+        AttestFailure::BadSignature,
+    );
+
+    Ok(attestation)
+}
+
+fn read_release_manifest(path: &Path) -> Result<ReleaseManifest> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Release manifest not found: {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid release manifest: {}", path.display()))
+}
+
+/// Compare the size and both hashes with the manifest.
+fn check_content(tarball: &Path, release: &ReleaseManifest) -> Result<CheckResult> {
+    let mut details = Vec::new();
+    let size = fs::metadata(tarball)?.len();
+    if size != release.size {
+        details.push(format!("size is {}, manifest says {}", size, release.size));
+    }
+    let sha256 = sha256_file(tarball)?;
+    if !sha256.eq_ignore_ascii_case(&release.sha256) {
+        details.push(format!(
+            "sha256 is {}, manifest says {}",
+            sha256, release.sha256
+        ));
+    }
+    let content_sha256 = content_hash(tarball)?;
+    if !content_sha256.eq_ignore_ascii_case(&release.content_sha256) {
+        details.push(format!(
+            "content sha256 is {}, manifest says {}",
+            content_sha256, release.content_sha256
+        ));
+    } else if !details.is_empty() {
+        details.push("the tar stream is unchanged, so it was recompressed".to_string());
+    }
+
+    Ok(match details.is_empty() {
+        true => CheckResult::pass(
+            "content",
+            format!("Content matches the manifest ({})", content_sha256),
+        ),
+        false => CheckResult::fail("content", "Tarball was modified", details),
+    })
+}
+
+/// Compare the build metadata read from the tarball with the manifest.
+///
+/// A value that differs means modified content; one missing on either
+/// side means missing metadata.
+fn check_metadata(
+    tarball: &Path,
+    release: &ReleaseManifest,
+) -> Result<(CheckResult, AttestFailure)> {
+    let os_release = read_os_release(tarball)?;
+    let os_release = os_release.as_ref();
+    let arch = tarball_arch(tarball)?;
+    let fields = [
+        (
+            "NAME",
+            os_release.and_then(|r| r.get("NAME")).cloned(),
+            &release.build.os_name,
+        ),
+        (
+            "VERSION",
+            os_release.and_then(|r| r.get("VERSION")).cloned(),
+            &release.build.os_version,
+        ),
+        ("arch", arch, &release.build.arch),
+    ];
+
+    let mut details = Vec::new();
+    let mut failure = AttestFailure::MissingMetadata;
+    for (field, actual, recorded) in fields {
+        match (actual, recorded) {
+            (Some(actual), Some(recorded)) if actual != *recorded => {
+                details.push(format!(
+                    "{} differs: tarball has {:?}, manifest says {:?}",
+                    field, actual, recorded
+                ));
+                failure = AttestFailure::ModifiedContent;
+            }
+            (Some(_), Some(_)) => {}
+            (_, None) => details.push(format!("manifest does not record {}", field)),
+            (None, Some(_)) => details.push(format!("{} not found in the tarball", field)),
+        }
+    }
+
+    let check = match details.is_empty() {
+        true => CheckResult::pass(
+            "metadata",
+            format!(
+                "Build metadata matches ({} {}, {})",
+                release.build.os_name.as_deref().unwrap_or_default(),
+                release.build.os_version.as_deref().unwrap_or_default(),
+                release.build.arch.as_deref().unwrap_or_default()
+            ),
+        ),
+        false => CheckResult::fail("metadata", "Build metadata does not match", details),
+    };
+    Ok((check, failure))
+}
+
+/// Check the detached signature if the release was signed or one is present.
+///
+/// A signature next to the tarball is checked even when the manifest says
+/// the release is unsigned.
+fn check_signature(
+    tarball: &Path,
+    release: &ReleaseManifest,
+    public_key: Option<&Path>,
+) -> CheckResult {
+    let sig_path = signing::signature_path(tarball);
+    if release.signature.is_none() && !sig_path.exists() {
+        return CheckResult::skip("signature", "Release is unsigned");
+    }
+    match signing::verify_signature(tarball, public_key) {
+        Ok(()) => CheckResult::pass(
+            "signature",
+            format!("Signature valid ({})", sig_path.display()),
+        ),
+        Err(err) => CheckResult::from_error("signature", err),
+    }
+}
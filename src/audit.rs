@@ -0,0 +1,265 @@
+//! Audits of a finished stage3 tarball.
+//!
+//! These checks look only at what is inside the archive, the way the
+//! installed system will, so problems show up before first boot.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::arch::arch_lib_dirs;
+use crate::archive::Stage3Archive;
+use crate::binary::elf_arch_from_header;
+use crate::elf;
+use crate::resolve;
+use crate::status;
+use crate::validate::symlinks::{is_runtime_path, lexical_target};
+
+/// Library directories the dynamic loader always searches.
+const DEFAULT_LIB_DIRS: &[&str] = &["lib64", "usr/lib64", "lib", "usr/lib"];
+
+/// Kind of an entry in the archive index.
+enum Node {
+    File,
+    Dir,
+    Symlink(String),
+    Other,
+}
+
+/// In-memory index of every path in a tarball.
+pub struct ArchiveIndex {
+    nodes: BTreeMap<String, Node>,
+}
+
+impl ArchiveIndex {
+    fn insert(&mut self, path: String, entry_type: tar::EntryType, target: Option<String>) {
+        let node = match (entry_type, target) {
+            (tar::EntryType::Symlink, Some(target)) => Node::Symlink(target),
+            // Hardlinks and sparse files are full files as far as the
+            // loader is concerned
+            (
+                tar::EntryType::Regular
+                | tar::EntryType::Continuous
+                | tar::EntryType::GNUSparse
+                | tar::EntryType::Link,
+                _,
+            ) => Node::File,
+            (tar::EntryType::Directory, _) => Node::Dir,
+            _ => Node::Other,
+        };
+        self.nodes.insert(path, node);
+    }
+
+    /// Resolve a rootfs path, following symlinks inside the archive.
+    ///
+    /// Returns the normalized path of the final target, or `None` if it
+    /// doesn't exist in the archive.
+    pub fn resolve(&self, path: &str) -> Option<String> {
+        let resolved = resolve::resolve(Path::new(path), |rel| {
+            match rel.to_str().and_then(|rel| self.nodes.get(rel)) {
+                None => resolve::Node::Missing,
+                Some(Node::Symlink(target)) => resolve::Node::Symlink(PathBuf::from(target)),
+                Some(Node::File | Node::Dir | Node::Other) => resolve::Node::Present,
+            }
+        })?;
+        Some(resolved.to_string_lossy().into_owned())
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.resolve(path)
+            .is_some_and(|p| matches!(self.nodes.get(&p), Some(Node::File)))
+    }
+}
+
+/// Everything the audits need from one pass over the tarball.
+struct Scan {
+    index: ArchiveIndex,
+    /// Dynamic ELF objects with their requirements and architecture
+    binaries: Vec<(String, elf::DynamicInfo, Option<&'static str>)>,
+    /// Library directories from ld.so.conf(.d)
+    ld_conf_dirs: Vec<String>,
+}
+
+fn scan(path: &Path) -> Result<Scan> {
+    let mut index = ArchiveIndex {
+        nodes: BTreeMap::new(),
+    };
+    let mut binaries = Vec::new();
+    let mut ld_conf_dirs = Vec::new();
+    let mut archive = Stage3Archive::open(path)?;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path().to_string();
+        let entry_type = entry.header().entry_type();
+        let target = entry.link_name()?.map(|t| t.to_string_lossy().into_owned());
+        index.insert(entry_path.clone(), entry_type, target);
+
+        if !entry_type.is_file() && !entry_type.is_gnu_sparse() {
+            continue;
+        }
+
+        let is_ld_conf = entry_path == "etc/ld.so.conf"
+            || (entry_path.starts_with("etc/ld.so.conf.d/") && entry_path.ends_with(".conf"));
+        if is_ld_conf {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).ok();
+            ld_conf_dirs.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| l.starts_with('/'))
+                    .map(|l| l.trim_start_matches('/').to_string()),
+            );
+            continue;
+        }
+
+        let mut magic = [0u8; 4];
+        if entry.read_exact(&mut magic).is_err() || !elf::is_elf(&magic) {
+            continue;
+        }
+        let mut contents = magic.to_vec();
+        entry.read_to_end(&mut contents)?;
+        if let Some(info) = elf::dynamic_info(&contents) {
+            binaries.push((entry_path, info, elf_arch_from_header(&contents)));
+        }
+    }
+
+    Ok(Scan {
+        index,
+        binaries,
+        ld_conf_dirs,
+    })
+}
+
+/// ELF objects whose interpreter or libraries can't be found.
+///
+/// `DT_NEEDED` entries are resolved against `DT_RUNPATH`/`DT_RPATH`, the
+/// directories listed in the archive's ld.so.conf and the default library
+/// directories of the object's architecture, all inside the archive.
+fn unresolved_libraries(scan: &Scan) -> Vec<String> {
+    let index = &scan.index;
+    let mut problems = Vec::new();
+
+    for (binary, info, arch) in &scan.binaries {
+        if let Some(ref interpreter) = info.interpreter {
+            if !index.is_file(interpreter) {
+                problems.push(format!(
+                    "/{}: interpreter {} not found",
+                    binary, interpreter
+                ));
+            }
+        }
+
+        let origin = Path::new(binary)
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let search: Vec<String> = info
+            .runpath
+            .iter()
+            .map(|dir| {
+                dir.replace("$ORIGIN", &format!("/{}", origin))
+                    .replace("${ORIGIN}", &format!("/{}", origin))
+            })
+            .chain(scan.ld_conf_dirs.iter().cloned())
+            .chain(arch.map(arch_lib_dirs).unwrap_or_default())
+            .chain(DEFAULT_LIB_DIRS.iter().map(|d| d.to_string()))
+            .collect();
+
+        for needed in &info.needed {
+            // A slash means the loader uses the path as-is
+            let found = if needed.contains('/') {
+                index.is_file(needed)
+            } else {
+                search
+                    .iter()
+                    .any(|dir| index.is_file(&format!("{}/{}", dir, needed)))
+            };
+            if !found {
+                problems.push(format!("/{}: {} not found", binary, needed));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Symlinks whose targets don't exist inside the archive.
+fn dangling_symlinks(index: &ArchiveIndex) -> Vec<String> {
+    index
+        .nodes
+        .iter()
+        .filter_map(|(path, node)| match node {
+            Node::Symlink(target) => Some((path, target)),
+            _ => None,
+        })
+        .filter(|(path, target)| {
+            !is_runtime_path(&lexical_target(Path::new(path.as_str()), Path::new(target)))
+                && index.resolve(path).is_none()
+        })
+        .map(|(path, target)| format!("/{} -> {}", path, target))
+        .collect()
+}
+
+/// Audit a tarball for unloadable binaries and dangling symlinks.
+pub fn audit_tarball(path: &Path) -> Result<()> {
+    status!("Auditing {}...", path.display());
+
+    let scan = scan(path)?;
+    let mut failed = 0;
+
+    let libraries = unresolved_libraries(&scan);
+    if libraries.is_empty() {
+        status!(
+            "  All {} ELF objects resolve their libraries",
+            scan.binaries.len()
+        );
+    } else {
+        tracing::warn!("  Unresolved dependencies:");
+        for line in &libraries {
+            tracing::warn!("    - {}", line);
+        }
+        failed += libraries.len();
+    }
+
+    let symlinks = dangling_symlinks(&scan.index);
+    if symlinks.is_empty() {
+        status!("  No dangling symlinks");
+    } else {
+        tracing::warn!("  Dangling symlinks:");
+        for line in &symlinks {
+            tracing::warn!("    - {}", line);
+        }
+        failed += symlinks.len();
+    }
+
+    if failed > 0 {
+        anyhow::bail!("Audit failed: {} problems found", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_and_hardlinked_entries_are_files() {
+        let mut index = ArchiveIndex {
+            nodes: BTreeMap::new(),
+        };
+        index.insert("usr/lib64/big.so".into(), tar::EntryType::GNUSparse, None);
+        index.insert("usr/lib64/copy.so".into(), tar::EntryType::Link, None);
+        let target = Some("big.so".to_string());
+        index.insert("usr/lib64/link.so".into(), tar::EntryType::Symlink, target);
+        index.insert("usr/lib64".into(), tar::EntryType::Directory, None);
+        index.insert("usr".into(), tar::EntryType::Directory, None);
+
+        assert!(index.is_file("usr/lib64/big.so"));
+        assert!(index.is_file("usr/lib64/copy.so"));
+        assert!(index.is_file("/usr/lib64/link.so"));
+        assert!(!index.is_file("usr/lib64"));
+    }
+}
@@ -2,13 +2,24 @@
 //!
 //! Copied and adapted from leviso/src/initramfs/binary.rs
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
+use super::arch;
+use super::console::{self, Copied};
 use super::context::BuildContext;
+use super::detail;
+use super::donor::PackageSource;
+use super::dlopen;
+use super::error::{Result, Stage3Error};
+use super::linker;
+use super::report::Severity;
+use super::store::{self, link_donor};
+
+/// Report check name for libraries taken from the build host.
+pub const HOST_FALLBACK_CHECK: &str = "host-fallback";
 
 /// Parse ldd output to extract library paths.
 /// Handles "not found" libraries by logging warnings.
@@ -21,7 +32,7 @@ pub fn parse_ldd_output(output: &str) -> Result<Vec<String>> {
         // Handle "not found" case
         if line.contains("not found") {
             if let Some(lib_name) = line.split_whitespace().next() {
-                println!("  Warning: library {} not found", lib_name);
+                tracing::warn!("  Warning: library {} not found", lib_name);
             }
             continue;
         }
@@ -44,105 +55,240 @@ pub fn parse_ldd_output(output: &str) -> Result<Vec<String>> {
     Ok(libs)
 }
 
+/// Interpreter and libraries a donor binary needs, as absolute rootfs
+/// paths.
+///
+/// They are read from the binary's ELF headers and looked up in the donor
+/// (see [`linker`]), or with the build's `ldd` option taken from the host's
+/// `ldd`. Libraries the donor lacks are recorded in the build report.
+pub fn library_paths(ctx: &BuildContext, binary: &str, bin_path: &Path) -> Result<Vec<String>> {
+    if ctx.ldd {
+        let Ok(output) = ctx.sandbox.command("ldd").arg(bin_path).output() else {
+            return Ok(Vec::new());
+        };
+        if !output.status.success() {
+            return Ok(Vec::new());
+        }
+        return parse_ldd_output(&String::from_utf8_lossy(&output.stdout));
+    }
+
+    let deps = linker::library_dependencies(ctx.package_source.as_ref(), &ctx.libraries, bin_path)?;
+    for lib in &deps.missing {
+        detail!("  Warning: library {} not found", lib);
+        ctx.report.push(
+            ctx.missing_severity(Severity::Warning),
+            "libraries",
+            Some(lib),
+            format!("needed by {}, not in the source rootfs", binary),
+        );
+    }
+    Ok(deps.libraries)
+}
+
+/// Copy the libraries a donor binary needs, recording the ones that fail,
+/// and what the dlopen hints for it and them name.
+pub fn copy_libraries(ctx: &BuildContext, binary: &str, bin_path: &Path) -> Result<()> {
+    let libs = library_paths(ctx, binary, bin_path)?;
+    for lib in &libs {
+        if let Err(e) = copy_library(ctx, lib) {
+            detail!("  Warning: Failed to copy library {}: {}", lib, e);
+            ctx.report.push(
+                ctx.missing_severity(Severity::Warning),
+                "libraries",
+                Some(lib),
+                format!("failed to copy: {}", e),
+            );
+        }
+    }
+    Ok(dlopen::copy_hinted(ctx, binary, &libs)?)
+}
+
 /// Copy a library from rootfs to staging, handling symlinks.
-pub fn copy_library(rootfs: &Path, lib_path: &str, staging: &Path) -> Result<()> {
-    // Try to find the library in rootfs first, then fall back to host
-    let src_candidates = [
-        rootfs.join(lib_path.trim_start_matches('/')),
-        rootfs.join("usr").join(lib_path.trim_start_matches('/')),
-        PathBuf::from(lib_path), // Host system fallback
-    ];
+///
+/// Libraries missing from the donor rootfs are taken from the build host
+/// unless host fallback is disabled or the build is for another
+/// architecture; every such copy is recorded in the build report.
+pub fn copy_library(ctx: &BuildContext, lib_path: &str) -> Result<()> {
+    let dest_path = ctx.target(library_dest(lib_path)?);
+
+    // Already copied for an earlier binary, or being copied on another
+    // thread; either way only one copy reports trouble with it
+    if !ctx.libraries.claim(&dest_path) || dest_path.exists() {
+        return Ok(());
+    }
 
-    let src = src_candidates
-        .iter()
-        .find(|p| p.exists())
-        .with_context(|| format!("Could not find library: {}", lib_path))?;
-
-    // Determine destination path - preserve usr/lib64 structure for stage3
-    let dest_path = if lib_path.contains("lib64") {
-        staging.join("usr/lib64").join(
-            Path::new(lib_path)
-                .file_name()
-                .with_context(|| format!("Library path has no filename: {}", lib_path))?,
-        )
-    } else {
-        staging.join("usr/lib").join(
-            Path::new(lib_path)
-                .file_name()
-                .with_context(|| format!("Library path has no filename: {}", lib_path))?,
-        )
+    // Try to find the library in rootfs first, then fall back to host
+    let donor_src = ctx.package_source.find_library(lib_path);
+    let host_src = PathBuf::from(lib_path);
+    let src = match donor_src.as_ref() {
+        Some(src) => src,
+        None if !host_src.exists() => return Err(Stage3Error::MissingLibrary {
+            library: lib_path.to_string(),
+            host_fallback: true,
+        }),
+        // The host's copy is for the host's architecture
+        None if !arch::is_host(&ctx.options.arch) => {
+            ctx.report.push(
+                Severity::Error,
+                HOST_FALLBACK_CHECK,
+                Some(lib_path),
+                format!(
+                    "not in the donor rootfs, and the build host's copy isn't for {}",
+                    ctx.options.arch
+                ),
+            );
+            return Err(Stage3Error::MissingLibrary {
+                library: lib_path.to_string(),
+                host_fallback: false,
+            });
+        }
+        None if !ctx.host_fallback => {
+            ctx.report.push(
+                Severity::Error,
+                HOST_FALLBACK_CHECK,
+                Some(lib_path),
+                "not in the donor rootfs and host fallback is disabled",
+            );
+            return Err(Stage3Error::MissingLibrary {
+                library: lib_path.to_string(),
+                host_fallback: false,
+            });
+        }
+        None => {
+            detail!("  Warning: {} copied from the build host", lib_path);
+            ctx.report.warn(
+                HOST_FALLBACK_CHECK,
+                Some(lib_path),
+                "not in the donor rootfs, copied from the build host",
+            );
+            &host_src
+        }
     };
 
-    if !dest_path.exists() {
-        // Handle symlinks
-        if src.is_symlink() {
-            let link_target = fs::read_link(src)?;
-            // If it's a relative symlink, resolve it
-            let actual_src = if link_target.is_relative() {
-                src.parent()
-                    .with_context(|| format!("Library path has no parent: {}", src.display()))?
-                    .join(&link_target)
-            } else {
-                link_target.clone()
-            };
+    fs::create_dir_all(dest_path.parent().unwrap())?;
+
+    // Handle symlinks
+    if src.is_symlink() {
+        let link_target = fs::read_link(src)?;
+        // If it's a relative symlink, resolve it
+        let actual_src = if link_target.is_relative() {
+            src.parent()
+                .with_context(|| format!("Library path has no parent: {}", src.display()))?
+                .join(&link_target)
+        } else if donor_src.is_some() {
+            // Absolute links in the donor point inside the donor, not the host
+            let rel = link_target.strip_prefix("/").unwrap_or(&link_target);
+            ctx.package_source
+                .find_file(rel)
+                .unwrap_or_else(|| link_target.clone())
+        } else {
+            link_target.clone()
+        };
 
-            // Copy the actual file
-            if actual_src.exists() {
-                fs::copy(&actual_src, &dest_path)?;
+        // Copy the actual file
+        if actual_src.exists() {
+            link_donor(ctx, &actual_src, &dest_path)?;
+        } else {
+            // Try in rootfs
+            let rootfs_target = ctx.package_source.find_file(Path::new(
+                link_target
+                    .to_str()
+                    .with_context(|| {
+                        format!("Link target is not valid UTF-8: {}", link_target.display())
+                    })?
+                    .trim_start_matches('/'),
+            ));
+            if let Some(rootfs_target) = rootfs_target {
+                link_donor(ctx, &rootfs_target, &dest_path)?;
             } else {
-                // Try in rootfs
-                let rootfs_target = rootfs.join(
-                    link_target
-                        .to_str()
-                        .with_context(|| {
-                            format!("Link target is not valid UTF-8: {}", link_target.display())
-                        })?
-                        .trim_start_matches('/'),
-                );
-                if rootfs_target.exists() {
-                    fs::copy(&rootfs_target, &dest_path)?;
-                } else {
-                    fs::copy(src, &dest_path)?;
-                }
+                link_donor(ctx, src, &dest_path)?;
             }
-        } else {
-            fs::copy(src, &dest_path)?;
         }
+    } else {
+        link_donor(ctx, src, &dest_path)?;
     }
 
+    console::copied(Copied::Library);
     Ok(())
 }
 
-/// Find a binary in the rootfs.
-pub fn find_binary(rootfs: &Path, binary: &str) -> Option<PathBuf> {
-    let bin_candidates = [
-        rootfs.join("usr/bin").join(binary),
-        rootfs.join("bin").join(binary),
-        rootfs.join("usr/sbin").join(binary),
-        rootfs.join("sbin").join(binary),
-    ];
+/// Rootfs-relative destination of a library, before remapping.
+///
+/// Everything lands in usr/lib64 or usr/lib, preserving the lib64 split
+/// and multiarch directories (usr/lib/aarch64-linux-gnu).
+pub fn library_dest(lib_path: &str) -> Result<PathBuf> {
+    let path = Path::new(lib_path);
+    let multiarch = path
+        .parent()
+        .and_then(Path::file_name)
+        .filter(|dir| dir.to_string_lossy().contains("-linux-gnu"));
+    let lib_dir = match multiarch {
+        Some(dir) => Path::new("usr/lib").join(dir),
+        None if lib_path.contains("lib64") => PathBuf::from("usr/lib64"),
+        None => PathBuf::from("usr/lib"),
+    };
+    let name = path
+        .file_name()
+        .with_context(|| format!("Library path has no filename: {}", lib_path))?;
+    Ok(lib_dir.join(name))
+}
 
-    bin_candidates.into_iter().find(|p| p.exists())
+/// Determine the architecture of an ELF binary from its header.
+///
+/// Returns the canonical arch name (as used in artifact names), or `None`
+/// if the file is not an ELF binary for a known architecture.
+pub fn elf_arch(path: &Path) -> Option<&'static str> {
+    let mut header = [0u8; 20];
+    let mut file = fs::File::open(path).ok()?;
+    std::io::Read::read_exact(&mut file, &mut header).ok()?;
+    elf_arch_from_header(&header)
 }
 
-/// Find a binary in sbin directories.
-pub fn find_sbin_binary(rootfs: &Path, binary: &str) -> Option<PathBuf> {
-    let sbin_candidates = [
-        rootfs.join("usr/sbin").join(binary),
-        rootfs.join("sbin").join(binary),
-        rootfs.join("usr/bin").join(binary),
-        rootfs.join("bin").join(binary),
-    ];
+/// Determine the architecture from the first bytes of an ELF file.
+pub fn elf_arch_from_header(header: &[u8]) -> Option<&'static str> {
+    if header.len() < 20 || header[..4] != *b"\x7fELF" {
+        return None;
+    }
 
-    sbin_candidates.into_iter().find(|p| p.exists())
+    let is_64 = header[4] == 2;
+    let machine = match header[5] {
+        1 => u16::from_le_bytes([header[18], header[19]]),
+        2 => u16::from_be_bytes([header[18], header[19]]),
+        _ => return None,
+    };
+
+    match (machine, is_64) {
+        (0x3e, true) => Some("x86_64"),
+        (0x03, false) => Some("i686"),
+        (0xb7, true) => Some("aarch64"),
+        (0x28, false) => Some("armv7"),
+        (0xf3, true) => Some("riscv64"),
+        (0x15, true) => Some("ppc64le"),
+        (0x16, true) => Some("s390x"),
+        _ => None,
+    }
 }
 
-/// Make a file executable (chmod 755).
+/// Detect the target architecture of a rootfs by inspecting its shell.
+pub fn detect_rootfs_arch(rootfs: &dyn PackageSource) -> Option<&'static str> {
+    ["usr/bin/bash", "bin/bash", "usr/lib/systemd/systemd"]
+        .iter()
+        .filter_map(|p| rootfs.find_resolved(Path::new(p)))
+        .find_map(|p| elf_arch(&p))
+}
+
+/// Make a file executable (chmod 755), keeping any setuid/setgid bits.
 pub fn make_executable(path: &Path) -> Result<()> {
     let mut perms = fs::metadata(path)
         .with_context(|| format!("Failed to read metadata: {}", path.display()))?
         .permissions();
-    perms.set_mode(0o755);
+    let mode = 0o755 | (perms.mode() & 0o6000);
+    if perms.mode() & 0o7777 == mode {
+        return Ok(());
+    }
+    // A binary from the object store shares its mode with the object
+    store::unshare(path)?;
+    perms.set_mode(mode);
     fs::set_permissions(path, perms)
         .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
     Ok(())
@@ -150,107 +296,89 @@ pub fn make_executable(path: &Path) -> Result<()> {
 
 /// Copy a binary and its library dependencies to staging directory.
 pub fn copy_binary_with_libs(ctx: &BuildContext, binary: &str, dest_dir: &str) -> Result<bool> {
-    let bin_path = match find_binary(&ctx.source, binary) {
+    let bin_path = match ctx.package_source.find_binary(binary) {
         Some(p) => p,
         None => {
-            println!("  Warning: {} not found, skipping", binary);
+            detail!("  Warning: {} not found, skipping", binary);
+            ctx.report.push(
+                ctx.missing_severity(Severity::Skipped),
+                "binaries",
+                Some(binary),
+                "not found in source",
+            );
             return Ok(false);
         }
     };
 
     // Copy binary to appropriate destination
-    let dest = ctx.staging.join(dest_dir).join(binary);
-    if !dest.exists() {
+    let dest = ctx.target(Path::new(dest_dir).join(binary));
+    // Another step may be copying it at the same time
+    if ctx.libraries.claim(&dest) && !dest.exists() {
         fs::create_dir_all(dest.parent().unwrap())?;
-        fs::copy(&bin_path, &dest)?;
+        link_donor(ctx, &bin_path, &dest)?;
         make_executable(&dest)?;
+        console::copied(Copied::Binary);
     }
 
     // Get and copy its libraries
-    let ldd_output = Command::new("ldd").arg(&bin_path).output();
-
-    if let Ok(output) = ldd_output {
-        if output.status.success() {
-            let libs = parse_ldd_output(&String::from_utf8_lossy(&output.stdout))?;
-            for lib in &libs {
-                if let Err(e) = copy_library(&ctx.source, lib, &ctx.staging) {
-                    println!("  Warning: Failed to copy library {}: {}", lib, e);
-                }
-            }
-        }
-    }
+    copy_libraries(ctx, binary, &bin_path)?;
 
     Ok(true)
 }
 
 /// Copy a sbin binary and its library dependencies.
 pub fn copy_sbin_binary_with_libs(ctx: &BuildContext, binary: &str) -> Result<bool> {
-    let bin_path = match find_sbin_binary(&ctx.source, binary) {
+    let bin_path = match ctx.package_source.find_sbin_binary(binary) {
         Some(p) => p,
         None => {
-            println!("  Warning: {} not found, skipping", binary);
+            detail!("  Warning: {} not found, skipping", binary);
+            ctx.report.push(
+                ctx.missing_severity(Severity::Skipped),
+                "binaries",
+                Some(binary),
+                "not found in source",
+            );
             return Ok(false);
         }
     };
 
     // Copy binary to usr/sbin
-    let dest = ctx.staging.join("usr/sbin").join(binary);
-    if !dest.exists() {
+    let dest = ctx.target(Path::new("usr/sbin").join(binary));
+    if ctx.libraries.claim(&dest) && !dest.exists() {
         fs::create_dir_all(dest.parent().unwrap())?;
-        fs::copy(&bin_path, &dest)?;
+        link_donor(ctx, &bin_path, &dest)?;
         make_executable(&dest)?;
+        console::copied(Copied::Binary);
     }
 
     // Get and copy its libraries
-    let ldd_output = Command::new("ldd").arg(&bin_path).output();
-
-    if let Ok(output) = ldd_output {
-        if output.status.success() {
-            let libs = parse_ldd_output(&String::from_utf8_lossy(&output.stdout))?;
-            for lib in &libs {
-                if let Err(e) = copy_library(&ctx.source, lib, &ctx.staging) {
-                    println!("  Warning: Failed to copy library {}: {}", lib, e);
-                }
-            }
-        }
-    }
+    copy_libraries(ctx, binary, &bin_path)?;
 
     Ok(true)
 }
 
 /// Copy bash and its dependencies.
 pub fn copy_bash(ctx: &BuildContext) -> Result<()> {
-    let bash_candidates = [
-        ctx.source.join("usr/bin/bash"),
-        ctx.source.join("bin/bash"),
-    ];
-    let bash_path = bash_candidates
+    let bash_path = ["usr/bin/bash", "bin/bash"]
         .iter()
-        .find(|p| p.exists())
-        .context("Could not find bash in source rootfs")?;
-
-    println!("Found bash at: {}", bash_path.display());
-
-    // Copy bash
-    let bash_dest = ctx.staging.join("usr/bin/bash");
-    fs::create_dir_all(bash_dest.parent().unwrap())?;
-    fs::copy(bash_path, &bash_dest)?;
-    make_executable(&bash_dest)?;
-
-    // Get library dependencies using ldd
-    let ldd_output = Command::new("ldd")
-        .arg(bash_path)
-        .output()
-        .context("Failed to run ldd")?;
-
-    let libs = parse_ldd_output(&String::from_utf8_lossy(&ldd_output.stdout))?;
-
-    // Copy libraries
-    for lib in &libs {
-        if let Err(e) = copy_library(&ctx.source, lib, &ctx.staging) {
-            println!("  Warning: Failed to copy library {}: {}", lib, e);
-        }
+        .find_map(|p| ctx.package_source.find_file(Path::new(p)))
+        .ok_or_else(|| Stage3Error::RequiredBinary {
+            binary: "bash".to_string(),
+        })?;
+
+    detail!("Found bash at: {}", bash_path.display());
+
+    // Copy bash, unless another step has
+    let bash_dest = ctx.target("usr/bin/bash");
+    if ctx.libraries.claim(&bash_dest) {
+        fs::create_dir_all(bash_dest.parent().unwrap())?;
+        link_donor(ctx, &bash_path, &bash_dest)?;
+        make_executable(&bash_dest)?;
+        console::copied(Copied::Binary);
     }
 
+    // Copy its libraries
+    copy_libraries(ctx, "bash", &bash_path)?;
+
     Ok(())
 }
@@ -0,0 +1,387 @@
+//! Boot smoke tests of a finished stage3 tarball.
+//!
+//! The tarball is extracted to a throwaway directory and booted, and the
+//! console is watched for markers of a working system:
+//!
+//! - **nspawn** (default): `systemd-nspawn --boot` on the extracted tree.
+//!   Passing means multi-user.target was reached, so getty, logind, PAM
+//!   and networkd came up far enough for systemd to finish booting.
+//! - **QEMU**: the tree is turned into an ext4 disk image and booted with a
+//!   provided kernel, with the serial console on stdio. This also covers
+//!   udev, fsck and the serial getty, and waits for the login prompt. The
+//!   tree is finalized with the image's filesystem UUID first, like the
+//!   installer does.
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+use crate::archive::{ExtractOptions, Stage3Archive};
+use crate::binary::detect_rootfs_arch;
+use crate::donor::DonorTree;
+use crate::failure::{Failure, FailureKind};
+use crate::placeholders::{finalize, FinalizeValues};
+use crate::sandbox;
+use crate::status;
+
+/// Console text meaning multi-user.target was reached.
+///
+/// systemd prints the unit description or the unit name depending on
+/// `StatusUnitFormat=`.
+const MULTI_USER_MARKERS: &[&str] = &[
+    "Reached target Multi-User System",
+    "Reached target multi-user.target",
+];
+
+/// Console text of a getty waiting for a user.
+const LOGIN_MARKER: &str = "login:";
+
+/// Console lines worth surfacing when the boot goes wrong.
+const FAILURE_MARKERS: &[&str] = &["Failed to start", "Dependency failed", "FAILED"];
+
+/// Free space added to the disk image on top of the rootfs contents.
+const IMAGE_SLACK: u64 = 256 * 1024 * 1024;
+
+/// Options for [`boot_test`].
+pub struct BootTestOptions {
+    /// How long to wait for every expected marker
+    pub timeout: Duration,
+    /// Where to write the captured console log
+    pub log: PathBuf,
+    /// Boot under QEMU instead of systemd-nspawn
+    pub qemu: Option<QemuOptions>,
+    /// Additional console text that must appear
+    pub expect: Vec<String>,
+}
+
+/// Settings for booting under QEMU.
+pub struct QemuOptions {
+    /// Kernel to boot; it needs virtio-blk and ext4, built in or in `initrd`
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    /// Guest memory in MiB
+    pub memory: u32,
+}
+
+/// Text that must show up on the console; any one alternative will do.
+struct Marker {
+    alternatives: Vec<String>,
+}
+
+impl Marker {
+    fn new(alternatives: &[&str]) -> Self {
+        Self {
+            alternatives: alternatives.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        self.alternatives.iter().any(|a| text.contains(a.as_str()))
+    }
+}
+
+/// Boot a tarball and wait for the console to show a working system.
+pub fn boot_test(path: &Path, options: &BootTestOptions) -> Result<()> {
+    status!("Boot-testing {}...", path.display());
+
+    if fs::metadata("/proc/self")?.uid() != 0 {
+        bail!("boot-test needs root to extract the rootfs with its ownership");
+    }
+
+    let mut markers = vec![Marker::new(MULTI_USER_MARKERS)];
+    if options.qemu.is_some() {
+        markers.push(Marker::new(&[LOGIN_MARKER]));
+    }
+    markers.extend(options.expect.iter().map(|e| Marker::new(&[e.as_str()])));
+
+    let work = tempfile::Builder::new()
+        .prefix("stage3-boot-")
+        .tempdir()
+        .context("Failed to create a directory to boot in")?;
+    let root = work.path().join("rootfs");
+
+    // Dropping `work` removes the extracted rootfs and the qemu files
+    Stage3Archive::open(path)
+        .and_then(|archive| archive.extract(&root, &ExtractOptions::rootfs()))
+        .and_then(|_| match options.qemu {
+            Some(ref qemu) => boot_qemu(work.path(), &root, qemu, options, &markers),
+            None => boot_nspawn(&root, options, &markers),
+        })
+}
+
+fn boot_nspawn(root: &Path, options: &BootTestOptions, markers: &[Marker]) -> Result<()> {
+    if sandbox::find_program("systemd-nspawn").is_none() {
+        bail!("systemd-nspawn not found (install systemd-container)");
+    }
+
+    let mut child = sandbox::command("systemd-nspawn")
+        .arg(format!("--directory={}", root.display()))
+        .args([
+            "--boot",
+            "--quiet",
+            "--register=no",
+            "--private-network",
+            "--console=pipe",
+        ])
+        // Arguments after --boot go to the container's init
+        .arg("systemd.show_status=yes")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run systemd-nspawn")?;
+
+    let outcome = watch_console(&mut child, options, markers);
+    shutdown(&mut child);
+    outcome
+}
+
+fn boot_qemu(
+    work: &Path,
+    root: &Path,
+    qemu: &QemuOptions,
+    options: &BootTestOptions,
+    markers: &[Marker],
+) -> Result<()> {
+    let arch = detect_rootfs_arch(&DonorTree::new(root))
+        .context("Cannot detect the rootfs architecture")?;
+    let (program, machine, console) = match arch {
+        "x86_64" => ("qemu-system-x86_64", None, "ttyS0"),
+        "aarch64" => ("qemu-system-aarch64", Some("virt"), "ttyAMA0"),
+        "riscv64" => ("qemu-system-riscv64", Some("virt"), "ttyS0"),
+        other => bail!("boot-test --qemu doesn't support {} yet", other),
+    };
+    if sandbox::find_program(program).is_none() {
+        bail!("{} not found", program);
+    }
+
+    let uuid = image_uuid(work);
+    finalize(
+        root,
+        &FinalizeValues {
+            root_uuid: uuid.clone(),
+            root_fstype: "ext4".to_string(),
+            esp_uuid: None,
+            luks_uuid: None,
+        },
+    )?;
+    let image = work.join("rootfs.img");
+    build_disk_image(root, &image, &uuid)?;
+
+    let mut cmd = sandbox::command(program);
+    if let Some(machine) = machine {
+        cmd.args(["-machine", machine, "-cpu", "max"]);
+    }
+    if Path::new("/dev/kvm").exists() && arch == std::env::consts::ARCH {
+        cmd.arg("-enable-kvm");
+    }
+    cmd.args(["-m", &qemu.memory.to_string()])
+        .args(["-display", "none", "-serial", "stdio", "-no-reboot"])
+        .arg("-kernel")
+        .arg(&qemu.kernel);
+    if let Some(ref initrd) = qemu.initrd {
+        cmd.arg("-initrd").arg(initrd);
+    }
+    cmd.arg("-append")
+        .arg(format!(
+            "root=/dev/vda rw console={} systemd.show_status=yes panic=-1",
+            console
+        ))
+        .arg("-drive")
+        .arg(format!("file={},format=raw,if=virtio", image.display()))
+        // No NIC: the test must not depend on the network
+        .args(["-nic", "none"]);
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+
+    let outcome = watch_console(&mut child, options, markers);
+    shutdown(&mut child);
+    outcome
+}
+
+/// A random-enough filesystem UUID for the throwaway disk image.
+fn image_uuid(work: &Path) -> String {
+    let seed = format!("{}:{:?}", work.display(), std::time::SystemTime::now());
+    let hex = format!("{:x}", Sha256::digest(seed.as_bytes()));
+    format!(
+        "{}-{}-4{}-8{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[13..16],
+        &hex[17..20],
+        &hex[20..32]
+    )
+}
+
+/// Turn an extracted rootfs into a raw ext4 image with filesystem `uuid`.
+fn build_disk_image(root: &Path, image: &Path, uuid: &str) -> Result<()> {
+    if sandbox::find_program("mkfs.ext4").is_none() {
+        bail!("mkfs.ext4 not found (install e2fsprogs)");
+    }
+
+    let contents: u64 = WalkDir::new(root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    let size = contents + contents / 2 + IMAGE_SLACK;
+    File::create(image)?.set_len(size)?;
+
+    let status = sandbox::command("mkfs.ext4")
+        .args(["-q", "-F", "-L", "levitate-root", "-U", uuid, "-d"])
+        .arg(root)
+        .arg(image)
+        .status()
+        .context("Failed to run mkfs.ext4")?;
+    if !status.success() {
+        bail!("mkfs.ext4 failed to build the disk image");
+    }
+
+    status!("  Built {} MiB disk image", size / (1024 * 1024));
+    Ok(())
+}
+
+/// Follow the console until every marker showed up, the guest exits or the
+/// timeout expires.
+///
+/// Partial lines are matched too, since a getty prompt doesn't end in a
+/// newline.
+fn watch_console(child: &mut Child, options: &BootTestOptions, markers: &[Marker]) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_output(stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_output(stderr, tx.clone());
+    }
+    drop(tx);
+
+    let mut log = File::create(&options.log)
+        .with_context(|| format!("Failed to create {}", options.log.display()))?;
+    let deadline = Instant::now() + options.timeout;
+    let mut seen = vec![false; markers.len()];
+    let mut failures = Vec::new();
+    let mut partial = String::new();
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let chunk = match rx.recv_timeout(remaining) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                report_failures(&failures);
+                let missing: Vec<&str> = markers
+                    .iter()
+                    .zip(&seen)
+                    .filter(|(_, seen)| !**seen)
+                    .map(|(marker, _)| marker.alternatives[0].as_str())
+                    .collect();
+                let reason = match err {
+                    mpsc::RecvTimeoutError::Timeout => {
+                        format!("not seen within {}s", options.timeout.as_secs())
+                    }
+                    mpsc::RecvTimeoutError::Disconnected => "guest exited first".to_string(),
+                };
+                bail!(Failure::new(
+                    FailureKind::BootTestFailed,
+                    format!(
+                        "Boot test failed: {:?} {} (log: {})",
+                        missing,
+                        reason,
+                        options.log.display()
+                    )
+                ));
+            }
+        };
+        log.write_all(chunk.as_bytes())?;
+        partial.push_str(&chunk.replace('\r', ""));
+
+        while let Some(newline) = partial.find('\n') {
+            let line: String = partial.drain(..=newline).collect();
+            if FAILURE_MARKERS.iter().any(|m| line.contains(m)) {
+                failures.push(line.trim().to_string());
+            }
+            mark_seen(markers, &mut seen, &line);
+        }
+        mark_seen(markers, &mut seen, &partial);
+
+        if seen.iter().all(|s| *s) {
+            report_failures(&failures);
+            for marker in markers {
+                status!("  Saw {:?}", marker.alternatives[0]);
+            }
+            status!("  Boot test passed (log: {})", options.log.display());
+            return Ok(());
+        }
+    }
+}
+
+fn mark_seen(markers: &[Marker], seen: &mut [bool], text: &str) {
+    for (marker, seen) in markers.iter().zip(seen.iter_mut()) {
+        if !*seen && marker.matches(text) {
+            *seen = true;
+        }
+    }
+}
+
+/// Send everything read from `stream` to `tx` from a background thread.
+fn forward_output<R: Read + Send + 'static>(mut stream: R, tx: mpsc::Sender<String>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0
+                || tx
+                    .send(String::from_utf8_lossy(&buf[..n]).into_owned())
+                    .is_err()
+            {
+                break;
+            }
+        }
+    });
+}
+
+fn report_failures(failures: &[String]) {
+    if !failures.is_empty() {
+        tracing::warn!("  Failures during boot:");
+        for line in failures {
+            tracing::warn!("    - {}", line);
+        }
+    }
+}
+
+/// Stop the guest, cleanly if it reacts in time.
+///
+/// SIGTERM makes systemd-nspawn ask the container's init to power off and
+/// QEMU quit.
+fn shutdown(child: &mut Child) {
+    if matches!(child.try_wait(), Ok(Some(_))) {
+        return;
+    }
+    sandbox::command("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .ok();
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while Instant::now() < deadline {
+        if matches!(child.try_wait(), Ok(Some(_))) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    child.kill().ok();
+    child.wait().ok();
+}
@@ -2,22 +2,177 @@
 //!
 //! Builds a complete rootfs tarball for LevitateOS installation.
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::sync::Arc;
 
-use crate::context::BuildContext;
-use crate::rootfs::{binaries, etc, filesystem, pam, recipe, systemd};
+use crate::arch::{self, LibraryLayout};
+use crate::archive::{self, Compression, ExtractOptions, Stage3Archive, Stage3Entry};
+use crate::artifact::{render_output_name, ArtifactInfo, DEFAULT_OUTPUT_NAME, DEFAULT_PROFILE};
+use crate::binary::{detect_rootfs_arch, elf_arch, elf_arch_from_header, HOST_FALLBACK_CHECK};
+use crate::cache::BuildCache;
+use crate::cancel::CancelToken;
+use crate::checkpoint::{self, Checkpoint, Checkpoints, Job, StepRun};
+use crate::checksum;
+use crate::clock::BuildClock;
+use crate::config::{ConditionalServices, ConditionalSettings, Stage3Config};
+use crate::console::{self, Color};
+use crate::container;
+use crate::context::{BuildContext, BuildOptions};
+use crate::diff;
+use crate::dlopen;
+use crate::donor::{DonorTree, PackageSource};
+use crate::error::{Result, Stage3Error};
+use crate::failure::FailureKind;
+use crate::fragments::{self, FragmentCache};
+use crate::inspect::{self, ByteSize};
+use crate::linker::LibraryCache;
+use crate::lock::OutputLock;
+use crate::manifest::{self, Manifest};
+use crate::plan::{self, BuildPlan, PlanOptions};
+use crate::policy::{self, AdmissionPolicy};
+use crate::progress::{ProgressEvent, ProgressReporter};
+use crate::provenance::{self, Provenance, SourceIdentity};
+use crate::remap::{PathRemap, PathRemaps};
+use crate::report::{self, Severity};
+use crate::rootfs::binaries::BinaryOverrides;
+use crate::rootfs::recipe::UpgradeTimer;
+use crate::rootfs::systemd::{RandomSeedPolicy, UnitOverrides};
+use crate::rootfs::user_services::{self, UserService};
+use crate::rootfs::{
+    accessibility, binaries, etc, filesystem, healthcheck, help, lockdown, pam, recipe, rescue,
+    sanitize, systemd,
+};
+use crate::sandbox::Sandbox;
+use crate::secrets::{self, Secret};
+use crate::signing;
+use crate::steps::{self, BuildStep, PlannedStep, Step, StepKind, StepSelection};
+use crate::store::ObjectStore;
+use crate::templates::Templates;
+use crate::timings::CompressionTiming;
+use crate::validate;
+use crate::xattrs::{self, SelinuxLabels};
+use crate::{detail, status};
 
 /// Builder for stage3 tarballs.
+#[derive(Clone)]
 pub struct Stage3Builder {
     /// Source directory containing Rocky rootfs
     source_dir: PathBuf,
     /// Output directory for the tarball
     output_dir: PathBuf,
+    /// Architecture the source rootfs must be
+    arch: Option<String>,
     /// Optional path to recipe binary
     recipe_binary: Option<PathBuf>,
+    /// Output filename template (see `artifact::render_output_name`)
+    output_name: String,
+    /// Build profile name
+    profile: String,
+    /// Admission policies evaluated over the staging tree
+    policies: Vec<Arc<dyn AdmissionPolicy>>,
+    /// Rootfs steps added with `with_step`, in order
+    custom_steps: Vec<Arc<dyn Step>>,
+    /// Rootfs steps run at the same time
+    step_jobs: usize,
+    /// Restrict the build to operations that work unprivileged
+    container_safe: bool,
+    /// minisign secret key used to sign the tarball
+    sign_key: Option<PathBuf>,
+    /// Fixed build time (`SOURCE_DATE_EPOCH` if unset)
+    source_date_epoch: Option<u64>,
+    /// What to ship at /var/lib/systemd/random-seed
+    random_seed: RandomSeedPolicy,
+    /// Secrets injected into the rootfs, excluded from the manifest
+    secrets: Vec<Secret>,
+    /// Run `systemd-analyze verify` over the enabled units
+    verify_units: bool,
+    /// Globs of donor cruft removed from the rootfs
+    sanitize_patterns: Vec<String>,
+    /// Globs exempt from sanitization
+    sanitize_keep: Vec<String>,
+    /// Forbid network access for the build and every spawned helper
+    offline: bool,
+    /// Resolve library dependencies with the host's ldd
+    ldd: bool,
+    /// Allow copying libraries missing from the donor from the build host
+    host_fallback: bool,
+    /// Treat validation findings as errors instead of warnings
+    strict: bool,
+    /// Include braille and speech console support
+    accessibility: bool,
+    /// Unattended upgrade timer to install
+    upgrade_timer: Option<UpgradeTimer>,
+    /// Static busybox to ship for recovery
+    busybox_static: Option<PathBuf>,
+    /// Disable console and rescue logins, leaving key-only SSH
+    lockdown: bool,
+    /// Public keys installed for root under lockdown
+    authorized_keys: Option<PathBuf>,
+    /// Leave the staging directory in place after a successful build
+    keep_staging: bool,
+    /// Directory of templates overriding the built-in config files
+    template_dir: Option<PathBuf>,
+    /// Fail the build if the compressed tarball is larger than this
+    max_size: Option<ByteSize>,
+    /// How many of the largest staged files to print after archiving
+    largest_files: usize,
+    /// Also write the largest files as JSON next to the tarball
+    largest_files_json: bool,
+    /// Manifest of a previous build to report changes against
+    baseline: Option<PathBuf>,
+    /// Compression of the tarball
+    compression: Compression,
+    /// Where donor paths land in the rootfs
+    remaps: Vec<PathRemap>,
+    /// User units enabled for every user
+    user_services: Vec<UserService>,
+    /// Binaries added to or left out of the built-in lists
+    binaries: BinaryOverrides,
+    /// System units shipped and enabled on top of the essential ones
+    units: UnitOverrides,
+    /// Units and user units for the builds their condition matches
+    conditional_services: Vec<ConditionalServices>,
+    /// Components and templates for the builds their condition matches
+    conditional_settings: Vec<ConditionalSettings>,
+    /// Donor files binaries or libraries load with dlopen, by name
+    dlopen_hints: BTreeMap<String, Vec<String>>,
+    /// Ship the boot health check service
+    healthcheck: bool,
+    /// Ship the offline help bundle
+    offline_help: bool,
+    /// Markdown pages replacing or adding to the built-in help
+    help_pages: Option<PathBuf>,
+    /// Receives the build's progress events
+    progress: Option<Arc<dyn ProgressReporter>>,
+    /// Donor to copy from instead of the source directory tree
+    package_source: Option<Arc<dyn PackageSource>>,
+    /// Stops the build between steps once cancelled
+    cancel: CancelToken,
+    /// Continue from the staging directory's checkpoint
+    resume: bool,
+    /// Reuse the donor copies of the last build
+    incremental: bool,
+    /// Link staged binaries and libraries from this object store
+    object_store: Option<PathBuf>,
+    /// Keep and reuse component fragments in this directory
+    fragment_cache: Option<PathBuf>,
+    /// Library lookups shared with other builds from the same donor
+    libraries: Option<Arc<LibraryCache>>,
+    /// Write files with the same contents as hard links in the tarball
+    dedup: bool,
+    /// Carry POSIX ACLs of donor files into the tarball
+    acls: bool,
+    /// Keep, drop or have the target relabel SELinux labels
+    selinux: SelinuxLabels,
+    /// Wait for another build using the output directory instead of failing
+    wait_lock: bool,
+    /// Steps to run
+    steps: StepSelection,
 }
 
 impl Stage3Builder {
@@ -25,38 +180,746 @@ impl Stage3Builder {
         Self {
             source_dir: source_dir.as_ref().to_path_buf(),
             output_dir: output_dir.as_ref().to_path_buf(),
+            arch: None,
             recipe_binary: None,
+            output_name: DEFAULT_OUTPUT_NAME.to_string(),
+            profile: DEFAULT_PROFILE.to_string(),
+            policies: Vec::new(),
+            custom_steps: Vec::new(),
+            step_jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            container_safe: false,
+            sign_key: None,
+            source_date_epoch: None,
+            random_seed: RandomSeedPolicy::default(),
+            secrets: Vec::new(),
+            verify_units: false,
+            sanitize_patterns: sanitize::DEFAULT_PATTERNS
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            sanitize_keep: Vec::new(),
+            offline: false,
+            ldd: false,
+            host_fallback: true,
+            strict: false,
+            accessibility: false,
+            upgrade_timer: None,
+            busybox_static: None,
+            lockdown: false,
+            authorized_keys: None,
+            keep_staging: false,
+            template_dir: None,
+            max_size: None,
+            largest_files: inspect::LARGEST_FILES,
+            largest_files_json: false,
+            baseline: None,
+            compression: Compression::default(),
+            remaps: Vec::new(),
+            user_services: Vec::new(),
+            binaries: BinaryOverrides::default(),
+            units: UnitOverrides::default(),
+            conditional_services: Vec::new(),
+            conditional_settings: Vec::new(),
+            dlopen_hints: dlopen::builtin_hints(),
+            healthcheck: false,
+            offline_help: false,
+            help_pages: None,
+            progress: None,
+            package_source: None,
+            cancel: CancelToken::new(),
+            resume: false,
+            incremental: false,
+            object_store: None,
+            fragment_cache: None,
+            libraries: None,
+            dedup: false,
+            acls: false,
+            selinux: SelinuxLabels::Drop,
+            wait_lock: false,
+            steps: StepSelection::default(),
         }
     }
 
+    /// Build from another source rootfs.
+    pub fn with_source(mut self, source_dir: impl AsRef<Path>) -> Self {
+        self.source_dir = source_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Write the tarball and its artifacts to another directory.
+    pub fn with_output(mut self, output_dir: impl AsRef<Path>) -> Self {
+        self.output_dir = output_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Fail unless the source rootfs is built for `arch`, and assume it
+    /// when the architecture can't be detected.
+    pub fn with_arch(mut self, arch: impl Into<String>) -> Self {
+        self.arch = Some(arch.into());
+        self
+    }
+
     /// Set the path to the recipe binary.
     pub fn with_recipe(mut self, recipe_binary: impl AsRef<Path>) -> Self {
         self.recipe_binary = Some(recipe_binary.as_ref().to_path_buf());
         self
     }
 
+    /// Set the output filename template.
+    ///
+    /// Supports `{version}`, `{arch}`, `{date}`, `{profile}` and `{ext}`
+    /// placeholders.
+    pub fn with_output_name(mut self, template: impl Into<String>) -> Self {
+        self.output_name = template.into();
+        self
+    }
+
+    /// Set the build profile name.
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// Add an admission policy evaluated for every staged entry.
+    pub fn with_policy(mut self, policy: impl AdmissionPolicy + 'static) -> Self {
+        self.policies.push(Arc::new(policy));
+        self
+    }
+
+    /// Add a rootfs step, or replace the built-in step it is named after
+    /// (see [`steps`](crate::steps)).
+    pub fn with_step(mut self, step: impl Step + 'static) -> Self {
+        self.custom_steps.push(Arc::new(step));
+        self
+    }
+
+    /// Run up to `jobs` rootfs steps at the same time (as many as there are
+    /// CPUs by default); 1 runs them one after the other.
+    pub fn with_step_jobs(mut self, jobs: usize) -> Self {
+        self.step_jobs = jobs.max(1);
+        self
+    }
+
+    /// Enforce that the build works unprivileged inside a container.
+    pub fn with_container_safe(mut self, container_safe: bool) -> Self {
+        self.container_safe = container_safe;
+        self
+    }
+
+    /// Sign the finished tarball with a minisign secret key.
+    pub fn with_sign_key(mut self, sign_key: impl AsRef<Path>) -> Self {
+        self.sign_key = Some(sign_key.as_ref().to_path_buf());
+        self
+    }
+
+    /// Use this time for everything the build writes (overrides
+    /// `SOURCE_DATE_EPOCH`).
+    pub fn with_source_date_epoch(mut self, epoch: u64) -> Self {
+        self.source_date_epoch = Some(epoch);
+        self
+    }
+
+    /// Set the random seed policy.
+    pub fn with_random_seed(mut self, random_seed: RandomSeedPolicy) -> Self {
+        self.random_seed = random_seed;
+        self
+    }
+
+    /// Inject a secret into the rootfs (mode 0600, never logged).
+    pub fn with_secret(mut self, secret: Secret) -> Self {
+        self.secrets.push(secret);
+        self
+    }
+
+    /// Run `systemd-analyze verify` over the enabled units after staging.
+    pub fn with_verify_units(mut self, verify_units: bool) -> Self {
+        self.verify_units = verify_units;
+        self
+    }
+
+    /// Also remove rootfs paths matching this glob during sanitization.
+    pub fn with_sanitize_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.sanitize_patterns.push(pattern.into());
+        self
+    }
+
+    /// Keep rootfs paths matching this glob even if a sanitize pattern matches.
+    pub fn with_sanitize_keep(mut self, pattern: impl Into<String>) -> Self {
+        self.sanitize_keep.push(pattern.into());
+        self
+    }
+
+    /// Guarantee the build touches no network.
+    ///
+    /// Helpers run in an empty network namespace.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Find the libraries binaries need with the host's `ldd`, as older
+    /// builds did, instead of reading their ELF headers and searching the
+    /// donor.
+    ///
+    /// `ldd` resolves against the host's libraries, so only libraries it
+    /// finds there are looked up in the donor; use it to compare with an
+    /// older build or when a donor's loader setup isn't understood.
+    pub fn with_ldd(mut self, ldd: bool) -> Self {
+        self.ldd = ldd;
+        self
+    }
+
+    /// Allow or forbid taking libraries missing from the donor from the host.
+    pub fn with_host_fallback(mut self, host_fallback: bool) -> Self {
+        self.host_fallback = host_fallback;
+        self
+    }
+
+    /// Fail the build on validation findings that are otherwise warnings,
+    /// and on any binary, library, PAM module or unit that couldn't be
+    /// copied.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Include brltty and espeakup for vision-impaired users.
+    ///
+    /// Pair with a dedicated `--profile` so the artifact is distinguishable.
+    /// Wins over the conditional settings of a config.
+    pub fn with_accessibility(mut self, accessibility: bool) -> Self {
+        self.accessibility = accessibility;
+        for settings in &mut self.conditional_settings {
+            settings.accessibility = None;
+        }
+        self
+    }
+
+    /// Install an enabled `recipe-upgrade.timer` for unattended upgrades.
+    pub fn with_upgrade_timer(mut self, upgrade_timer: UpgradeTimer) -> Self {
+        self.upgrade_timer = Some(upgrade_timer);
+        self
+    }
+
+    /// Ship a statically linked busybox as a last-resort recovery shell.
+    pub fn with_static_busybox(mut self, busybox: impl AsRef<Path>) -> Self {
+        self.busybox_static = Some(busybox.as_ref().to_path_buf());
+        self
+    }
+
+    /// Lock the image down for appliances: no gettys, no rescue or
+    /// emergency shell, root's password locked, SSH with keys only.
+    pub fn with_lockdown(mut self, lockdown: bool) -> Self {
+        self.lockdown = lockdown;
+        self
+    }
+
+    /// Install these public keys as root's authorized_keys under lockdown.
+    pub fn with_authorized_keys(mut self, keys: impl AsRef<Path>) -> Self {
+        self.authorized_keys = Some(keys.as_ref().to_path_buf());
+        self
+    }
+
+    /// Keep the staging directory after the build, e.g. for `stage3 shell`.
+    pub fn with_keep_staging(mut self, keep_staging: bool) -> Self {
+        self.keep_staging = keep_staging;
+        self
+    }
+
+    /// Override built-in config templates with the files in `dir`.
+    ///
+    /// See the `templates` module for the layout and variables. Wins over
+    /// the conditional settings of a config.
+    pub fn with_template_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.template_dir = Some(dir.as_ref().to_path_buf());
+        for settings in &mut self.conditional_settings {
+            settings.templates = None;
+        }
+        self
+    }
+
+    /// Fail the build if the compressed tarball exceeds `max_size`.
+    pub fn with_max_size(mut self, max_size: ByteSize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Print the `count` largest staged files after archiving (0 disables).
+    pub fn with_largest_files(mut self, count: usize) -> Self {
+        self.largest_files = count;
+        self
+    }
+
+    /// Also write the largest files to `inspect::LARGEST_FILES_NAME`.
+    pub fn with_largest_files_json(mut self, largest_files_json: bool) -> Self {
+        self.largest_files_json = largest_files_json;
+        self
+    }
+
+    /// Report what changed compared to the manifest of a previous build.
+    pub fn with_baseline(mut self, manifest: impl AsRef<Path>) -> Self {
+        self.baseline = Some(manifest.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the tarball compression (xz by default).
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Put donor files under `remap.from` at `remap.to` in the rootfs.
+    pub fn with_remap(mut self, remap: PathRemap) -> Self {
+        self.remaps.push(remap);
+        self
+    }
+
+    /// Enable a user unit (e.g. `pipewire.socket`) for every user.
+    pub fn with_user_service(mut self, service: UserService) -> Self {
+        self.user_services.push(service);
+        self
+    }
+
+    /// Also copy this binary (and its libraries) to /usr/bin.
+    pub fn with_binary(mut self, name: impl Into<String>) -> Self {
+        self.binaries.add.push(name.into());
+        self
+    }
+
+    /// Also copy this binary (and its libraries) to /usr/sbin.
+    pub fn with_sbin_binary(mut self, name: impl Into<String>) -> Self {
+        self.binaries.add_sbin.push(name.into());
+        self
+    }
+
+    /// Leave a binary out of the built-in coreutils and sbin lists.
+    pub fn without_binary(mut self, name: impl Into<String>) -> Self {
+        self.binaries.remove.push(name.into());
+        self
+    }
+
+    /// Also ship this system unit from the donor.
+    pub fn with_unit(mut self, unit: impl Into<String>) -> Self {
+        self.units.add.push(unit.into());
+        self
+    }
+
+    /// Ship this system unit from the donor and enable it through its
+    /// `[Install]` section.
+    pub fn with_enabled_unit(mut self, unit: impl Into<String>) -> Self {
+        self.units.enable.push(unit.into());
+        self
+    }
+
+    /// Copy donor `paths` (files or directories, absolute) wherever the
+    /// binary or library `name` goes, with the libraries they link
+    /// against, for what it loads with dlopen. Adds to the built-in hints
+    /// for NSS and PAM modules.
+    pub fn with_dlopen_hint<I, S>(mut self, name: impl Into<String>, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let hinted = self.dlopen_hints.entry(name.into()).or_default();
+        for path in paths {
+            let path = path.into();
+            if !hinted.contains(&path) {
+                hinted.push(path);
+            }
+        }
+        self
+    }
+
+    /// Take the settings a config file sets.
+    pub fn with_config(mut self, config: &Stage3Config) -> Self {
+        if let Some(ref profile) = config.profile {
+            self.profile = profile.clone();
+        }
+        if let Some(ref arch) = config.arch {
+            self.arch = Some(arch.clone());
+        }
+        if let Some(ref name) = config.output.name {
+            self.output_name = name.clone();
+        }
+        if let Some(compression) = config.output.compression {
+            self.compression = compression;
+        }
+        if let Some(ref dir) = config.etc.templates {
+            self.template_dir = Some(dir.clone());
+        }
+        self.strict = config.strict.unwrap_or(self.strict);
+        self.accessibility = config.accessibility.unwrap_or(self.accessibility);
+        self.healthcheck = config.healthcheck.unwrap_or(self.healthcheck);
+        self.offline_help = config.offline_help.unwrap_or(self.offline_help);
+
+        self.binaries.merge(&config.binaries);
+        let services = &config.services;
+        self.units.add.extend(services.add.iter().cloned());
+        self.units.enable.extend(services.enable.iter().cloned());
+        self.user_services.extend(services.user.iter().cloned());
+        self.conditional_services
+            .extend(services.conditional.iter().cloned());
+        self.conditional_settings
+            .extend(config.conditional.iter().cloned());
+        for (name, paths) in &config.dlopen {
+            self = self.with_dlopen_hint(name, paths.iter().cloned());
+        }
+        self
+    }
+
+    /// Run `levitate-healthcheck.service` at boot: critical units, disk
+    /// space, time sync and DNS, with a status file for fleet tooling.
+    /// Wins over the conditional settings of a config.
+    pub fn with_healthcheck(mut self, healthcheck: bool) -> Self {
+        self.healthcheck = healthcheck;
+        for settings in &mut self.conditional_settings {
+            settings.healthcheck = None;
+        }
+        self
+    }
+
+    /// Ship quickstart, recovery and recipe pages as plain text in
+    /// /usr/share/levitate/help. Wins over the conditional settings of a
+    /// config.
+    pub fn with_offline_help(mut self, offline_help: bool) -> Self {
+        self.offline_help = offline_help;
+        for settings in &mut self.conditional_settings {
+            settings.offline_help = None;
+        }
+        self
+    }
+
+    /// Replace or add offline help pages with the `*.md` files in `dir`.
+    pub fn with_help_pages(mut self, dir: impl AsRef<Path>) -> Self {
+        self.help_pages = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Send start, diagnostic and finish events of every step to `reporter`.
+    pub fn with_progress(mut self, reporter: impl ProgressReporter + 'static) -> Self {
+        self.progress = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Copy binaries, libraries and donor files from `source` instead of
+    /// the source directory tree.
+    pub fn with_package_source(mut self, source: impl PackageSource + 'static) -> Self {
+        self.package_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Stop the build when `cancel` is cancelled, removing the staging
+    /// directory and partial outputs.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Wait for another build holding the output directory's lock to
+    /// finish instead of failing.
+    pub fn with_wait_lock(mut self, wait_lock: bool) -> Self {
+        self.wait_lock = wait_lock;
+        self
+    }
+
+    /// Keep the staging directory of an earlier, unfinished build and run
+    /// only the steps it didn't complete with the same inputs.
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Keep the staging directory of a successful build in the output
+    /// directory, and move donor files that haven't changed since out of
+    /// it instead of copying them again (see [`cache`](crate::cache)).
+    pub fn with_incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Keep staged binaries and libraries in the content-addressed store at
+    /// `dir` and hard-link them from there (see [`store`](crate::store)),
+    /// so builds sharing it store each file once.
+    pub fn with_object_store(mut self, dir: impl AsRef<Path>) -> Self {
+        self.object_store = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Keep what the timezone, locale, i18n and udev rules components stage
+    /// as fragments in `dir`, and unpack them from there in later builds
+    /// with the same donor files and options (see
+    /// [`fragments`](crate::fragments)).
+    pub fn with_fragment_cache(mut self, dir: impl AsRef<Path>) -> Self {
+        self.fragment_cache = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Resolve libraries through `cache`, shared with other builds from the
+    /// same donor and architecture, instead of a cache of this build's own.
+    pub fn with_library_cache(mut self, cache: Arc<LibraryCache>) -> Self {
+        self.libraries = Some(cache);
+        self
+    }
+
+    /// Write staged files with the same contents, mode, owner and mtime as
+    /// an earlier one as hard links to it in the tarball. Files hard-linked
+    /// in the donor are linked either way.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Carry the POSIX ACLs of donor files and directories into the tarball
+    /// (see [`xattrs`](crate::xattrs)); off by default, since not every
+    /// filesystem the rootfs is installed to supports them.
+    pub fn with_acls(mut self, acls: bool) -> Self {
+        self.acls = acls;
+        self
+    }
+
+    /// Keep the SELinux labels of donor files, or leave them out and mark
+    /// the rootfs for relabeling on first boot (see
+    /// [`xattrs`](crate::xattrs)); they are left out by default.
+    pub fn with_selinux(mut self, selinux: SelinuxLabels) -> Self {
+        self.selinux = selinux;
+        self
+    }
+
+    /// Run only `step` and the other `with_only_step` steps, on the
+    /// staging directory of an earlier build.
+    pub fn with_only_step(mut self, step: BuildStep) -> Self {
+        self.steps.only.push(step);
+        self
+    }
+
+    /// Leave out `step`, keeping what an earlier build staged for it.
+    pub fn with_skip_step(mut self, step: BuildStep) -> Self {
+        self.steps.skip.push(step);
+        self
+    }
+
+    /// The donor rootfs a build copies from.
+    pub fn source_dir(&self) -> &Path {
+        &self.source_dir
+    }
+
+    fn package_source(&self) -> Arc<dyn PackageSource> {
+        match self.package_source {
+            Some(ref source) => source.clone(),
+            None => Arc::new(DonorTree::new(&self.source_dir)),
+        }
+    }
+
+    /// Plan the binaries and libraries a build would copy, without
+    /// writing anything.
+    pub fn plan(&self) -> Result<BuildPlan, Stage3Error> {
+        status!("Planning stage3 build...");
+        if !self.source_dir.exists() {
+            return Err(Stage3Error::SourceMissing {
+                path: self.source_dir.clone(),
+            });
+        }
+        let sandbox = Sandbox::new(self.offline);
+        sandbox.preflight()?;
+        let remaps = PathRemaps::new(self.remaps.clone())?;
+        let source = self.package_source();
+        let arch = detect_rootfs_arch(source.as_ref())
+            .or(self.arch.as_deref())
+            .unwrap_or(std::env::consts::ARCH);
+        let options = BuildOptions {
+            arch: arch.to_string(),
+            profile: self.profile.clone(),
+            ..BuildOptions::default()
+        };
+
+        Ok(plan::plan_build(
+            source.as_ref(),
+            &remaps,
+            PlanOptions {
+                host_fallback: self.host_fallback && arch::is_host(arch),
+                ldd: self.ldd,
+                sandbox,
+                dlopen_hints: self.dlopen_hints.clone(),
+                accessibility: self.components_for(&options).accessibility,
+                lockdown: self.lockdown,
+                binaries: self.binaries.for_build(&options),
+                layout: LibraryLayout::detect(source.as_ref(), arch),
+            },
+        )?)
+    }
+
     /// Build the stage3 tarball.
-    pub fn build(&self) -> Result<PathBuf> {
-        println!("Building stage3 tarball...");
-        println!("  Source: {}", self.source_dir.display());
-        println!("  Output: {}", self.output_dir.display());
+    pub fn build(&self) -> Result<PathBuf, Stage3Error> {
+        self.run_build()
+    }
+
+    fn run_build(&self) -> Result<PathBuf> {
+        status!("Building stage3 tarball...");
+        detail!("  Source: {}", self.package_source().describe());
+        detail!("  Output: {}", self.output_dir.display());
 
         // Validate source directory
         if !self.source_dir.exists() {
-            anyhow::bail!(
-                "Source directory does not exist: {}",
-                self.source_dir.display()
-            );
+            return Err(Stage3Error::SourceMissing {
+                path: self.source_dir.clone(),
+            });
         }
 
-        // Create output directory
-        fs::create_dir_all(&self.output_dir)?;
+        // Nothing in the output directory is touched before this
+        let _lock = OutputLock::acquire(&self.output_dir, self.wait_lock, &self.cancel)?;
 
-        // Create staging directory
+        // Detect the target architecture from the source rootfs
+        let source = self.package_source();
+        let arch = match (detect_rootfs_arch(source.as_ref()), self.arch.as_deref()) {
+            (Some(arch), Some(expected)) if arch != expected => {
+                return Err(Stage3Error::ArchMismatch {
+                    rootfs: self.source_dir.clone(),
+                    found: arch.to_string(),
+                    expected: expected.to_string(),
+                });
+            }
+            (Some(arch), _) => arch,
+            (None, Some(expected)) => expected,
+            (None, None) => {
+                tracing::warn!(
+                    "  Warning: could not detect source rootfs architecture, assuming {}",
+                    std::env::consts::ARCH
+                );
+                std::env::consts::ARCH
+            }
+        };
+
+        let plan = steps::plan(&self.custom_steps)?;
         let staging_dir = self.output_dir.join("staging");
-        if staging_dir.exists() {
-            fs::remove_dir_all(&staging_dir)?;
+        let partial = !self.steps.is_all();
+        if partial && !staging_dir.is_dir() {
+            return Err(anyhow!(
+                "--only-step and --skip-step need the staging directory of an earlier build in {} (see --keep-staging)",
+                staging_dir.display()
+            )
+            .into());
+        }
+        let previous = match self.resume || partial {
+            true => Checkpoint::read(&staging_dir),
+            false => None,
+        };
+
+        // One timestamp for the whole build, also when it was resumed
+        let mut clock = BuildClock::resolve(self.source_date_epoch)?;
+        if let Some(ref previous) = previous {
+            if !clock.is_fixed() {
+                clock = BuildClock::started_at(previous.timestamp);
+            }
         }
+
+        // Resolve the output filename before doing any work
+        let mut info = ArtifactInfo::new(&self.profile, arch, &clock);
+        info.compression = self.compression;
+        let output_name = render_output_name(&self.output_name, &info)?;
+        let components = self.components_for(&BuildOptions {
+            arch: info.arch.clone(),
+            profile: info.profile.clone(),
+            ..BuildOptions::default()
+        });
+        let templates = Templates::new(&info, components.template_dir.as_deref())?;
+        let baseline = self.baseline.as_deref().map(manifest::read).transpose()?;
+        let provenance = Provenance::new(&info, &clock, SourceIdentity::read(source.as_ref())?);
+        detail!("  Version: {}", info.version);
+        detail!("  Arch: {}", info.arch);
+        detail!("  Tarball: {}", output_name);
+        if clock.is_fixed() {
+            detail!("  Timestamp: {} (fixed)", clock.timestamp());
+        }
+
+        let layout = LibraryLayout::detect(self.package_source().as_ref(), arch);
+        detail!("  Libraries: /{}", layout.lib_dir().display());
+        if !arch::is_host(arch) {
+            if self.ldd {
+                return Err(anyhow!(
+                    "--ldd can't resolve the libraries of {} binaries on this {} host",
+                    arch,
+                    std::env::consts::ARCH
+                )
+                .into());
+            }
+            detail!("  Cross-architecture build from {}", std::env::consts::ARCH);
+        }
+        if let Some(ref recipe) = self.recipe_binary {
+            match elf_arch(recipe) {
+                Some(found) if found != arch => {
+                    return Err(Stage3Error::InvalidInput {
+                        path: recipe.clone(),
+                        message: format!(
+                            "Recipe binary {} is {}, not {}",
+                            recipe.display(),
+                            found,
+                            arch
+                        ),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        let sandbox = Sandbox::new(self.offline);
+        if self.container_safe {
+            container::preflight(&self.output_dir, self.ldd, sandbox)?;
+        }
+        if sandbox.is_offline() {
+            sandbox.preflight()?;
+            detail!("  Offline: helpers run without network access");
+        }
+        secrets::preflight(&self.secrets)?;
+        let remaps = PathRemaps::new(self.remaps.clone())?;
+        for rule in remaps.rules() {
+            detail!(
+                "  Remap: /{} -> /{}",
+                rule.from.display(),
+                rule.to.display()
+            );
+        }
+        if let Some(ref busybox) = self.busybox_static {
+            rescue::read_static_busybox(busybox, arch)?;
+        }
+        if let Some(ref keys) = self.authorized_keys {
+            lockdown::read_authorized_keys(keys)?;
+        }
+        if components.offline_help {
+            help::load_pages(self.help_pages.as_deref())?;
+        }
+
+        // Create staging directory, unless resuming in it
+        match previous {
+            _ if partial => status!("Running selected steps in {}", staging_dir.display()),
+            Some(ref previous) => status!(
+                "Resuming in {} ({} steps completed)",
+                staging_dir.display(),
+                previous.steps.len()
+            ),
+            None => {
+                if self.resume {
+                    status!("No checkpoint in {}, starting over", staging_dir.display());
+                }
+                if staging_dir.exists() {
+                    fs::remove_dir_all(&staging_dir)?;
+                }
+            }
+        }
+        // Only a build on an empty staging directory copies every donor file
+        let cache = match self.incremental && !partial && previous.is_none() {
+            true => Some(BuildCache::open(&self.output_dir)),
+            false => None,
+        };
+        let store = self
+            .object_store
+            .as_deref()
+            .map(ObjectStore::open)
+            .transpose()?;
+        let fragments = self
+            .fragment_cache
+            .as_deref()
+            .map(FragmentCache::open)
+            .transpose()?;
         fs::create_dir_all(&staging_dir)?;
 
         // Create build context
@@ -64,171 +927,1248 @@ impl Stage3Builder {
             self.source_dir.clone(),
             staging_dir.clone(),
             self.output_dir.clone(),
-        );
+        )
+        .with_options(BuildOptions {
+            arch: info.arch.clone(),
+            profile: info.profile.clone(),
+            layout,
+            strict: self.strict,
+            dry_run: false,
+            container_safe: self.container_safe,
+        })
+        .with_random_seed(self.random_seed)
+        .with_ldd(self.ldd)
+        .with_sandbox(sandbox)
+        .with_acls(self.acls)
+        .with_selinux(self.selinux)
+        .with_dlopen_hints(self.dlopen_hints.clone())
+        .with_cache(cache)
+        .with_store(store)
+        .with_fragments(fragments)
+        .with_host_fallback(self.host_fallback)
+        .with_upgrade_timer(self.upgrade_timer.clone())
+        .with_clock(clock)
+        .with_templates(templates)
+        .with_provenance(provenance)
+        .with_remaps(remaps)
+        .with_package_source(self.package_source())
+        .with_cancel(self.cancel.clone());
 
         if let Some(ref recipe_path) = self.recipe_binary {
             ctx = ctx.with_recipe(recipe_path.clone());
         }
+        if let Some(ref libraries) = self.libraries {
+            ctx = ctx.with_libraries(libraries.clone());
+        }
+        if let Some(ref progress) = self.progress {
+            ctx = ctx.with_progress(progress.clone());
+        }
+
+        ctx.progress.report(&ProgressEvent::BuildStarted {
+            version: info.version.clone(),
+            arch: info.arch.clone(),
+            profile: info.profile.clone(),
+            tarball: output_name.clone(),
+        });
+
+        let inputs = self.checkpoint_inputs(&ctx)?;
+        let mut checkpoints = Checkpoints::new(
+            &staging_dir,
+            clock.timestamp(),
+            &inputs,
+            previous,
+            self.steps.clone(),
+        );
+
+        // Summarize every finding, also when the build failed halfway
+        let mut written = Vec::new();
+        let built = self.assemble(
+            &ctx,
+            &mut checkpoints,
+            &plan,
+            &output_name,
+            baseline.as_ref(),
+            &mut written,
+        );
+        console::print_timings(&ctx.timings.summary());
+        console::print_summary(&ctx.report);
+        let skipped_path = self.output_dir.join(report::SKIPPED_NAME);
+        ctx.report.write_skipped(&skipped_path, &output_name)?;
+        console::print_skipped(&ctx.report, &skipped_path);
+        ctx.progress.report(&ProgressEvent::BuildFinished {
+            tarball: built.as_ref().ok().map(|_| output_name.clone()),
+            error: built.as_ref().err().map(describe),
+            errors: ctx.report.count(Severity::Error),
+            warnings: ctx.report.count(Severity::Warning),
+            skipped: ctx.report.count(Severity::Skipped),
+        });
+        if let Err(ref err) = built {
+            if err.kind() == Some(FailureKind::Cancelled) {
+                self.remove_partial_outputs(&staging_dir, &output_name, &written);
+            }
+        }
+        let tarball_path = built?;
+        if !self.steps.runs(BuildStep::Tarball) {
+            status!("\nStaging directory updated: {}", staging_dir.display());
+            return Ok(staging_dir);
+        }
+
+        if let Some(ref store) = ctx.store {
+            store.print_summary();
+        }
+        if let Some(ref fragments) = ctx.fragments {
+            fragments.print_summary();
+        }
 
+        // Clean up staging directory
+        if self.keep_staging {
+            status!("Kept staging directory: {}", staging_dir.display());
+        } else if let Some(ref cache) = ctx.cache {
+            cache.save(&staging_dir)?;
+        } else {
+            detail!("Cleaning up staging directory...");
+            fs::remove_dir_all(&staging_dir)?;
+        }
+
+        status!(
+            "\n{}",
+            console::paint(
+                &format!("Stage3 tarball created: {}", tarball_path.display()),
+                Color::Green
+            )
+        );
+        status!(
+            "  LevitateOS {} ({}, profile {})",
+            info.version,
+            info.arch,
+            info.profile
+        );
+        Ok(tarball_path)
+    }
+
+    /// Build, validate and archive the rootfs, then write the artifacts
+    /// next to the tarball, adding the tarball, sidecars and manifest to
+    /// `written` as they are.
+    fn assemble(
+        &self,
+        ctx: &BuildContext,
+        checkpoints: &mut Checkpoints,
+        plan: &[PlannedStep],
+        output_name: &str,
+        baseline: Option<&Manifest>,
+        written: &mut Vec<PathBuf>,
+    ) -> Result<PathBuf> {
         // Build the rootfs
-        self.build_rootfs(&ctx)?;
+        let built = self.build_rootfs(ctx, checkpoints, plan);
+        report_host_contamination(ctx);
+        built?;
+
+        // The checkpoint is no part of the rootfs; back if staging stays
+        checkpoints.set_aside()?;
+        if self.steps.runs(BuildStep::Tarball) && !checkpoints.unrecorded().is_empty() {
+            tracing::warn!(
+                "  Warning: no checkpoint for skipped steps {}; their ownership and device nodes are missing from the tarball",
+                checkpoints.unrecorded().join(", ")
+            );
+        }
+        let packaged = self.package(ctx, output_name, baseline, written);
+        // Also for working on a kept or unarchived staging directory later
+        if packaged.is_err() || self.keep_staging || !self.steps.runs(BuildStep::Tarball) {
+            if let Err(err) = checkpoints.save() {
+                tracing::warn!("  Warning: could not keep the checkpoint: {:#}", err);
+            }
+        }
+        packaged
+    }
+
+    /// Validate and archive the rootfs, then write the artifacts.
+    fn package(
+        &self,
+        ctx: &BuildContext,
+        output_name: &str,
+        baseline: Option<&Manifest>,
+        written: &mut Vec<PathBuf>,
+    ) -> Result<PathBuf> {
+        if self.steps.runs(BuildStep::Validate) {
+            self.validate_rootfs(ctx)?;
+        }
+
+        let errors: Vec<String> = ctx
+            .report
+            .diagnostics()
+            .into_iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| match d.subject {
+                Some(subject) => format!("  {}: {}: {}", d.check, subject, d.message),
+                None => format!("  {}: {}", d.check, d.message),
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(Stage3Error::BuildErrors { errors });
+        }
+
+        if !self.steps.runs(BuildStep::Tarball) {
+            return Ok(ctx.staging.clone());
+        }
+
+        console::section("Packaging");
+
+        // Enforce admission policies before anything is archived
+        console::step(ctx, "Admission policies", || {
+            policy::enforce(&ctx.staging, ctx.package_source.as_ref(), &self.policies)
+        })?;
+
+        if ctx.options.container_safe {
+            console::step(ctx, "Container audit", || {
+                container::audit_staging(&ctx.staging)
+            })?;
+        }
 
         // Create the tarball
-        let tarball_path = self.create_tarball(&staging_dir)?;
+        let tarball_path = console::step(ctx, "Tarball", || {
+            secrets::inject(ctx, &self.secrets)?;
+            let tarball = self.create_tarball(ctx, output_name).map_err(|source| {
+                anyhow::Error::new(Stage3Error::TarballFailed {
+                    path: self.output_dir.join(output_name),
+                    source: source.into(),
+                })
+            });
+            written.extend(tarball.as_ref().ok().cloned());
+            // Don't leave secrets behind in staging, even if archiving failed
+            secrets::scrub(&ctx.staging, &self.secrets);
+            tarball
+        })?;
 
-        // Clean up staging directory
-        println!("Cleaning up staging directory...");
-        fs::remove_dir_all(&staging_dir)?;
+        // Staging is still what the tarball holds, minus the secrets
+        let largest = match self.largest_files {
+            0 => None,
+            count => Some(inspect::largest_files(&ctx.staging, count)?),
+        };
+
+        if let Some(max_size) = self.max_size {
+            console::step(ctx, "Size budget", || {
+                Ok(check_size_budget(&tarball_path, max_size)?)
+            })?;
+        }
+
+        let file_manifest = console::step(ctx, "Checksum and manifest", || {
+            // Write the checksum sidecar
+            let sidecar = checksum::write_sidecar(&tarball_path)?;
+            written.push(sidecar.clone());
+            detail!("  Checksum: {}", sidecar.display());
+
+            // Write the per-file manifest from what actually shipped
+            let manifest_path = self.output_dir.join(manifest::MANIFEST_NAME);
+            let mut file_manifest = manifest::from_tarball(&tarball_path)?;
+            let (omitted, entries) = file_manifest
+                .entries
+                .into_iter()
+                .partition(|entry| secrets::is_secret(&self.secrets, &entry.path));
+            file_manifest.entries = entries;
+            file_manifest.omitted = omitted.into_iter().map(|entry| entry.path).collect();
+            file_manifest.provenance = Some(ctx.provenance.clone());
+            file_manifest.remaps = ctx.remaps.rules().to_vec();
+            file_manifest.timings = Some(ctx.timings.summary());
+            manifest::write(&file_manifest, &manifest_path)?;
+            written.push(manifest_path.clone());
+            detail!(
+                "  Manifest: {} ({} entries)",
+                manifest_path.display(),
+                file_manifest.entries.len()
+            );
+            Ok(file_manifest)
+        })?;
+
+        // Sign the tarball
+        if let Some(ref key) = self.sign_key {
+            console::step(ctx, "Signature", || {
+                let signature = signing::sign_file(&tarball_path, key, ctx.sandbox)?;
+                written.push(signature.clone());
+                detail!("  Signature: {}", signature.display());
+                Ok(())
+            })?;
+        }
+
+        if let Some(ref largest) = largest {
+            console::section("Largest files");
+            largest.print();
+            if self.largest_files_json {
+                let path = self.output_dir.join(inspect::LARGEST_FILES_NAME);
+                fs::write(&path, serde_json::to_string_pretty(largest)? + "\n")?;
+                detail!("  Largest files: {}", path.display());
+            }
+        }
+
+        if let Some(baseline) = baseline {
+            console::section("Changes since baseline");
+            let comparison = diff::compare_to_baseline(baseline, &file_manifest);
+            comparison.print();
+            let path = self.output_dir.join(diff::BASELINE_DIFF_NAME);
+            fs::write(&path, serde_json::to_string_pretty(&comparison)? + "\n")?;
+            detail!("  Comparison: {}", path.display());
+        }
+
+        // Write the build report
+        let report_path = self.output_dir.join(report::REPORT_NAME);
+        ctx.report.write(&report_path, output_name)?;
+        detail!(
+            "  Report: {} ({} warnings, {} errors)",
+            report_path.display(),
+            ctx.report.count(Severity::Warning),
+            ctx.report.count(Severity::Error)
+        );
 
-        println!("Stage3 tarball created: {}", tarball_path.display());
         Ok(tarball_path)
     }
 
-    /// Build the complete rootfs in staging directory.
-    fn build_rootfs(&self, ctx: &BuildContext) -> Result<()> {
-        println!("\n=== Building rootfs ===\n");
+    /// Build the complete rootfs in staging directory, running the built-in
+    /// and custom steps of `plan` as their dependencies allow.
+    fn build_rootfs(
+        &self,
+        ctx: &BuildContext,
+        checkpoints: &mut Checkpoints,
+        plan: &[PlannedStep],
+    ) -> Result<()> {
+        console::section("Building rootfs");
+
+        let mut jobs = Vec::new();
+        for planned in plan {
+            let (inputs, run) = match planned.kind {
+                StepKind::BuiltIn(step) => self.step_job(step, &ctx.options)?,
+                StepKind::Custom(ref step, _) => {
+                    let run: StepRun = Box::new(|ctx| step.run(ctx));
+                    (step.inputs()?, Some(run))
+                }
+            };
+            jobs.push(Job {
+                name: planned.name().to_string(),
+                title: match planned.built_in() {
+                    Some(step) => step.title().to_string(),
+                    None => planned.name().to_string(),
+                },
+                step: planned.built_in(),
+                inputs,
+                dependencies: planned.dependencies.clone(),
+                run,
+            });
+        }
+        Ok(checkpoints.run(ctx, jobs, self.step_jobs)?)
+    }
+
+    /// The inputs of built-in rootfs step `step` of a build with
+    /// `options`, and what it runs; None for an optional step that is off.
+    fn step_job(
+        &self,
+        step: BuildStep,
+        options: &BuildOptions,
+    ) -> Result<(String, Option<StepRun<'_>>)> {
+        fn job<'a>(
+            inputs: impl Into<String>,
+            run: impl FnOnce(&BuildContext) -> Result<()> + Send + 'a,
+        ) -> Result<(String, Option<StepRun<'a>>)> {
+            Ok((inputs.into(), Some(Box::new(|ctx| Ok(run(ctx)?)))))
+        }
+        let overrides = format!("{:?}", self.binaries.for_build(options));
+        let components = self.components_for(options);
 
-        // 1. Create FHS directory structure
-        filesystem::create_fhs_structure(&ctx.staging)?;
+        match step {
+            // 1. Create FHS directory structure
+            BuildStep::Fhs => job("", |ctx| {
+                filesystem::create_fhs_structure(&ctx.staging)?;
+                filesystem::create_spool_dirs(ctx)?;
+                filesystem::create_device_nodes(ctx)
+            }),
 
-        // 2. Create symlinks (must be after dirs but before binaries)
-        filesystem::create_symlinks(&ctx.staging)?;
+            // 2. Create symlinks (must be after dirs but before binaries)
+            BuildStep::Symlinks => job("", |ctx| {
+                filesystem::create_symlinks(&ctx.staging)?;
+                filesystem::create_abi_links(ctx)
+            }),
 
-        // 3. Copy shell (bash) first
-        binaries::copy_shell(ctx)?;
+            // 3. Copy shell (bash) first
+            BuildStep::Shell => job("", binaries::copy_shell),
 
-        // 4. Copy coreutils binaries
-        binaries::copy_coreutils(ctx)?;
+            // 4. Copy coreutils binaries
+            BuildStep::Coreutils => job(overrides, |ctx| {
+                binaries::copy_coreutils(ctx, &self.binaries.for_build(&ctx.options))
+            }),
 
-        // 5. Copy sbin utilities
-        binaries::copy_sbin_utils(ctx)?;
+            // 5. Copy sbin utilities
+            BuildStep::Sbin => job(overrides, |ctx| {
+                binaries::copy_sbin_utils(ctx, &self.binaries.for_build(&ctx.options))?;
+                binaries::copy_login_binaries(ctx)
+            }),
 
-        // 6. Copy systemd binaries and setup
-        binaries::copy_systemd_binaries(ctx)?;
-        binaries::copy_login_binaries(ctx)?;
+            // 6. Copy systemd binaries and setup
+            BuildStep::SystemdBinaries => job("", binaries::copy_systemd_binaries),
 
-        // 7. Copy systemd units
-        systemd::copy_systemd_units(ctx)?;
-        systemd::copy_dbus_symlinks(ctx)?;
+            // 7. Copy systemd units
+            // 8. Set up systemd services
+            // 9. Copy udev rules and tmpfiles
+            BuildStep::Units => {
+                let units = self.units_for(options);
+                let inputs = format!("{:?} {:?}", self.random_seed, units);
+                job(inputs, move |ctx| {
+                    systemd::copy_systemd_units(ctx)?;
+                    systemd::setup_extra_units(ctx, &units)?;
+                    systemd::copy_dbus_symlinks(ctx)?;
+                    systemd::setup_getty(ctx)?;
+                    systemd::setup_serial_console(ctx)?;
+                    systemd::setup_networkd(ctx)?;
+                    systemd::set_default_target(ctx)?;
+                    systemd::setup_dbus(ctx)?;
+                    systemd::setup_random_seed(ctx)?;
+                    fragments::cached(
+                        ctx,
+                        "udev-rules",
+                        &[Path::new("usr/lib/udev/rules.d")],
+                        systemd::copy_udev_rules,
+                    )?;
+                    systemd::copy_tmpfiles(ctx)?;
+                    systemd::copy_sysctl(ctx)
+                })
+            }
 
-        // 8. Set up systemd services
-        systemd::setup_getty(ctx)?;
-        systemd::setup_serial_console(ctx)?;
-        systemd::setup_networkd(ctx)?;
-        systemd::set_default_target(ctx)?;
-        systemd::setup_dbus(ctx)?;
+            // 10. Create /etc configuration files
+            BuildStep::Etc => job("", |ctx| {
+                etc::create_etc_files(ctx)?;
+                fragments::cached(
+                    ctx,
+                    "timezone",
+                    &[Path::new("usr/share/zoneinfo")],
+                    etc::copy_timezone_data,
+                )?;
+                fragments::cached(
+                    ctx,
+                    "locales",
+                    &[Path::new("usr/lib/locale")],
+                    etc::copy_locales,
+                )?;
+                let gconv = ctx.options.layout.relocate("usr/lib64/gconv");
+                fragments::cached(
+                    ctx,
+                    "i18n",
+                    &[Path::new("usr/share/i18n"), &gconv],
+                    etc::copy_i18n_data,
+                )
+            }),
 
-        // 9. Copy udev rules and tmpfiles
-        systemd::copy_udev_rules(ctx)?;
-        systemd::copy_tmpfiles(ctx)?;
-        systemd::copy_sysctl(ctx)?;
+            // 11. Set up PAM
+            BuildStep::Pam => job("", |ctx| {
+                pam::setup_pam(ctx)?;
+                pam::copy_pam_modules(ctx)?;
+                pam::create_security_config(ctx)
+            }),
 
-        // 10. Create /etc configuration files
-        etc::create_etc_files(ctx)?;
-        etc::copy_timezone_data(ctx)?;
-        etc::copy_locales(ctx)?;
+            // 12. Copy recipe package manager
+            BuildStep::Recipe => {
+                let recipe_inputs = format!(
+                    "{:?} {}",
+                    self.upgrade_timer,
+                    checkpoint::hash_path(self.recipe_binary.as_deref())?
+                );
+                job(recipe_inputs, |ctx| {
+                    recipe::copy_recipe(ctx)?;
+                    recipe::setup_recipe_config(ctx)?;
+                    recipe::setup_upgrade_timer(ctx)
+                })
+            }
 
-        // 11. Set up PAM
-        pam::setup_pam(ctx)?;
-        pam::copy_pam_modules(ctx)?;
-        pam::create_security_config(ctx)?;
+            BuildStep::UserServices => {
+                let services = self.user_services_for(options);
+                if services.is_empty() {
+                    return Ok((String::new(), None));
+                }
+                job(format!("{:?}", services), move |ctx| {
+                    user_services::setup_user_services(ctx, &services)
+                })
+            }
+            BuildStep::Accessibility if components.accessibility => {
+                job("", accessibility::setup_accessibility)
+            }
+            BuildStep::Healthcheck if components.healthcheck => {
+                job("", healthcheck::install_healthcheck)
+            }
+            BuildStep::Help if components.offline_help => {
+                let pages = checkpoint::hash_path(self.help_pages.as_deref())?;
+                job(pages, |ctx| {
+                    let pages = help::load_pages(self.help_pages.as_deref())?;
+                    help::install_help(ctx, &pages)
+                })
+            }
+            BuildStep::Rescue if self.busybox_static.is_some() => {
+                let busybox = self.busybox_static.as_deref().unwrap();
+                let hash = checkpoint::hash_path(Some(busybox))?;
+                job(hash, move |ctx| {
+                    rescue::install_static_busybox(ctx, busybox)
+                })
+            }
+            BuildStep::Lockdown if self.lockdown => {
+                let keys = checkpoint::hash_path(self.authorized_keys.as_deref())?;
+                job(keys, |ctx| {
+                    lockdown::apply_lockdown(ctx, self.authorized_keys.as_deref())
+                })
+            }
 
-        // 12. Copy recipe package manager
-        recipe::copy_recipe(ctx)?;
-        recipe::setup_recipe_config(ctx)?;
+            // 13. Remove donor branding and package manager leftovers
+            // 14. Drop kernel headers, sources and sysroots whatever copied them
+            // 15. Mark the rootfs for SELinux relabeling if asked to
+            BuildStep::Sanitize => {
+                let patterns = format!("{:?} {:?}", self.sanitize_patterns, self.sanitize_keep);
+                job(patterns, |ctx| {
+                    sanitize::sanitize(ctx, &self.sanitize_patterns, &self.sanitize_keep)?;
+                    sanitize::purge_excluded(ctx)?;
+                    Ok(xattrs::mark_relabel(ctx)?)
+                })
+            }
+
+            // Optional steps that are off, and the steps after the rootfs
+            BuildStep::Accessibility
+            | BuildStep::Healthcheck
+            | BuildStep::Help
+            | BuildStep::Rescue
+            | BuildStep::Lockdown
+            | BuildStep::Validate
+            | BuildStep::Tarball => Ok((String::new(), None)),
+        }
+    }
+
+    /// Extra units of a build with `options`, with those of the
+    /// conditional services it matches.
+    fn units_for(&self, options: &BuildOptions) -> UnitOverrides {
+        let mut units = self.units.clone();
+        for services in self.matching_services(options) {
+            units.add.extend(services.add.iter().cloned());
+            units.enable.extend(services.enable.iter().cloned());
+        }
+        units
+    }
+
+    /// User units of a build with `options`, with those of the
+    /// conditional services it matches.
+    fn user_services_for(&self, options: &BuildOptions) -> Vec<UserService> {
+        let mut user = self.user_services.clone();
+        for services in self.matching_services(options) {
+            user.extend(services.user.iter().cloned());
+        }
+        user
+    }
+
+    /// Components and templates of a build with `options`: the builder's,
+    /// with the conditional settings it matches applied in order.
+    fn components_for(&self, options: &BuildOptions) -> Components {
+        let mut components = Components {
+            accessibility: self.accessibility,
+            healthcheck: self.healthcheck,
+            offline_help: self.offline_help,
+            template_dir: self.template_dir.clone(),
+        };
+        let matching = self.conditional_settings.iter();
+        for settings in matching.filter(|settings| settings.when.matches(options)) {
+            components.accessibility = settings.accessibility.unwrap_or(components.accessibility);
+            components.healthcheck = settings.healthcheck.unwrap_or(components.healthcheck);
+            components.offline_help = settings.offline_help.unwrap_or(components.offline_help);
+            if let Some(ref dir) = settings.templates {
+                components.template_dir = Some(dir.clone());
+            }
+        }
+        components
+    }
+
+    fn matching_services<'a>(
+        &'a self,
+        options: &'a BuildOptions,
+    ) -> impl Iterator<Item = &'a ConditionalServices> {
+        self.conditional_services
+            .iter()
+            .filter(|services| services.when.matches(options))
+    }
+
+    /// Check the staged rootfs for problems that would show up at boot.
+    fn validate_rootfs(&self, ctx: &BuildContext) -> Result<()> {
+        console::section("Validating rootfs");
+        let (staging, report) = (&ctx.staging, &ctx.report);
+
+        console::step(ctx, "Accounts", || {
+            validate::accounts::check_accounts(staging, report, ctx.options.strict)
+        })?;
+        console::step(ctx, "Config files", || {
+            validate::configs::check_configs(staging, report, ctx.options.strict, ctx.sandbox)
+        })?;
+        console::step(ctx, "Environment files", || {
+            validate::units::check_environment_files(staging, report)
+        })?;
+        console::step(ctx, "Exec paths", || {
+            validate::units::check_exec_paths(staging, report, ctx.options.strict)
+        })?;
+        console::step(ctx, "Symlinks", || {
+            validate::symlinks::check_symlinks(staging, report)
+        })?;
+        console::step(ctx, "PAM modules", || {
+            let module_dir = ctx
+                .remaps
+                .apply(&ctx.options.layout.relocate(pam::MODULE_DIR));
+            validate::pam::check_pam_modules(staging, &module_dir, report, ctx.options.strict)
+        })?;
+        // Lockdown masks rescue and emergency mode on purpose
+        if !self.lockdown {
+            console::step(ctx, "Rescue and emergency mode", || {
+                validate::rescue::check_rescue(staging, report, ctx.options.strict)
+            })?;
+        }
+        console::step(ctx, "Access paths", || {
+            validate::access::check_access(staging, report, &self.secrets)
+        })?;
+        if self.verify_units {
+            console::step(ctx, "systemd-analyze verify", || {
+                validate::units::verify_with_systemd_analyze(staging, report, ctx.sandbox)
+            })?;
+        }
 
-        println!("\n=== Rootfs build complete ===\n");
         Ok(())
     }
 
-    /// Create the tarball from the staging directory.
-    fn create_tarball(&self, staging: &Path) -> Result<PathBuf> {
-        println!("Creating tarball...");
+    /// What every rootfs step depends on, for its checkpoint.
+    fn checkpoint_inputs(&self, ctx: &BuildContext) -> Result<String> {
+        Ok(format!(
+            "{:?} {:?} {:?} {:?} {} {} {} {:?} {:?} {} {} {}",
+            ctx.provenance,
+            self.profile,
+            self.remaps,
+            ctx.package_source.describe(),
+            self.container_safe,
+            self.ldd,
+            self.acls,
+            self.selinux,
+            self.dlopen_hints,
+            self.host_fallback,
+            self.strict,
+            checkpoint::hash_path(self.components_for(&ctx.options).template_dir.as_deref())?
+        ))
+    }
 
-        let tarball_path = self.output_dir.join("levitateos-stage3.tar.xz");
+    /// Remove what a cancelled build left: staging, even with
+    /// `--keep-staging`, the partial tarball, and the tarball, sidecars and
+    /// manifest this run `written`. Those of an earlier build stay.
+    fn remove_partial_outputs(&self, staging_dir: &Path, output_name: &str, written: &[PathBuf]) {
+        status!("Cancelled, removing partial outputs...");
+        let _ = fs::remove_dir_all(staging_dir);
+        let partial = partial_path(&self.output_dir.join(output_name));
+        for path in std::iter::once(&partial).chain(written) {
+            if fs::remove_file(path).is_ok() {
+                detail!("  Removed {}", path.display());
+            }
+        }
+    }
 
-        // Use tar command for better compatibility and performance
-        let status = Command::new("tar")
-            .args([
-                "-cJf",
-                tarball_path.to_str().unwrap(),
-                "-C",
-                staging.to_str().unwrap(),
-                ".",
-            ])
-            .status()
-            .context("Failed to run tar command")?;
+    /// Create the tarball from the staging directory.
+    fn create_tarball(&self, ctx: &BuildContext, output_name: &str) -> Result<PathBuf> {
+        let tarball_path = self.output_dir.join(output_name);
+        // Only a complete tarball ever has the final name
+        let partial_path = partial_path(&tarball_path);
 
-        if !status.success() {
-            anyhow::bail!("tar command failed with status: {}", status);
+        let options = archive::WriteOptions {
+            // Ownership is assigned in the archive, never by chowning staging
+            metadata: Some(&ctx.metadata),
+            clock: ctx.clock,
+            compression: self.compression,
+            cancel: Some(&ctx.cancel),
+            dedup: self.dedup,
+        };
+        let stats = match archive::write_tarball(&ctx.staging, &partial_path, &options) {
+            Ok(stats) => stats,
+            Err(err) => {
+                let _ = fs::remove_file(&partial_path);
+                return Err(err.into());
+            }
+        };
+        fs::rename(&partial_path, &tarball_path)?;
+        ctx.timings.set_compression(CompressionTiming {
+            compression: self.compression,
+            elapsed_ms: stats.compression_time.as_millis() as u64,
+            input_bytes: stats.tar_bytes,
+            output_bytes: stats.compressed_bytes,
+        });
+        detail!("  Added {} device nodes", ctx.metadata.devices().len());
+        if stats.hardlinks > 0 {
+            detail!("  Wrote {} files as hard links", stats.hardlinks);
         }
 
-        // Print tarball size
         let metadata = fs::metadata(&tarball_path)?;
         let size_mb = metadata.len() as f64 / 1024.0 / 1024.0;
-        println!("  Tarball size: {:.2} MB", size_mb);
+        detail!("  Tarball size: {:.2} MB", size_mb);
 
         Ok(tarball_path)
     }
 }
 
-/// List contents of an existing tarball.
-pub fn list_tarball(path: &Path) -> Result<()> {
-    println!("Contents of {}:", path.display());
+/// Optional components and templates of one build.
+struct Components {
+    accessibility: bool,
+    healthcheck: bool,
+    offline_help: bool,
+    template_dir: Option<PathBuf>,
+}
+
+/// `err` and its causes on one line, like `anyhow`'s `{:#}`.
+fn describe(err: &Stage3Error) -> String {
+    let mut message = err.to_string();
+    let mut cause = std::error::Error::source(err);
+    while let Some(err) = cause {
+        message.push_str(&format!(": {}", err));
+        cause = err.source();
+    }
+    message
+}
+
+/// Where the tarball is written before it is complete.
+fn partial_path(tarball: &Path) -> PathBuf {
+    let mut name = tarball.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Fail if the compressed tarball is over budget, showing where the size went.
+fn check_size_budget(tarball: &Path, max_size: ByteSize) -> Result<()> {
+    let size = ByteSize(fs::metadata(tarball)?.len());
+    if size <= max_size {
+        detail!("  Size: {} of {}", size, max_size);
+        return Ok(());
+    }
+
+    // Through the log, so it stays off stdout with --progress json
+    status!("\n{}", inspect::inspect_tarball(tarball)?);
+    Err(Stage3Error::OverBudget {
+        tarball: tarball.to_path_buf(),
+        size,
+        budget: max_size,
+    })
+}
+
+/// List every file taken from the build host instead of the donor.
+fn report_host_contamination(ctx: &BuildContext) {
+    let host_files: Vec<_> = ctx
+        .report
+        .diagnostics()
+        .into_iter()
+        .filter(|d| d.check == HOST_FALLBACK_CHECK)
+        .collect();
+    if host_files.is_empty() {
+        return;
+    }
+
+    detail!("=== Host contamination ===\n");
+    for diagnostic in &host_files {
+        let marker = match diagnostic.severity {
+            Severity::Error => "refused",
+            _ => "copied from host",
+        };
+        detail!(
+            "  {} ({})",
+            diagnostic.subject.as_deref().unwrap_or("?"),
+            marker
+        );
+    }
+    detail!(
+        "\n  {} files did not come from the donor rootfs\n",
+        host_files.len()
+    );
+}
+
+/// Options for [`verify_tarball`].
+#[derive(Default)]
+pub struct VerifyOptions {
+    /// Validate the tarball against its `.sha256` sidecar
+    pub checksum: bool,
+    /// Check the detached minisign signature before content checks
+    pub signature: bool,
+    /// minisign public key for signature checks (minisign's default if unset)
+    pub public_key: Option<PathBuf>,
+    /// Check every entry's hash, size and mode against this per-file manifest
+    pub manifest: Option<PathBuf>,
+    /// Extract the tarball and run `systemd-analyze verify` over its enabled units
+    pub units: bool,
+}
+
+/// Whether a verification check passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// The check couldn't run (e.g. a helper isn't installed)
+    Skip,
+}
+
+/// Outcome of one check run by [`verify_tarball`].
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    /// Stable identifier, e.g. `checksum` or `essential-files`
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// One-line description of the outcome
+    pub summary: String,
+    /// Individual findings (missing files, mismatches, ...)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<String>,
+}
+
+impl CheckResult {
+    pub fn pass(name: &'static str, summary: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            summary: summary.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn fail(name: &'static str, summary: impl Into<String>, details: Vec<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            summary: summary.into(),
+            details,
+        }
+    }
 
-    let status = Command::new("tar")
-        .args(["-tJf", path.to_str().unwrap()])
-        .status()
-        .context("Failed to run tar command")?;
+    pub fn skip(name: &'static str, summary: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Skip,
+            summary: summary.into(),
+            details: Vec::new(),
+        }
+    }
 
-    if !status.success() {
-        anyhow::bail!("tar command failed with status: {}", status);
+    /// Turn an error from a check into a failed result.
+    pub fn from_error(name: &'static str, err: impl Into<anyhow::Error>) -> Self {
+        Self::fail(name, format!("{:#}", err.into()), Vec::new())
     }
 
-    Ok(())
+    /// Print the result and its details for a human.
+    pub fn print(&self) {
+        match self.status {
+            CheckStatus::Pass => {
+                println!(
+                    "  {} {}",
+                    console::paint(" ok ", Color::Green),
+                    self.summary
+                )
+            }
+            CheckStatus::Fail => println!(
+                "  {} {}: {}",
+                console::paint("FAIL", Color::Red),
+                self.name,
+                self.summary
+            ),
+            CheckStatus::Skip => println!(
+                "  {} {}: {}",
+                console::paint("skip", Color::Dim),
+                self.name,
+                self.summary
+            ),
+        }
+        for line in &self.details {
+            println!("    - {}", line);
+        }
+    }
 }
 
-/// Verify tarball contents.
-pub fn verify_tarball(path: &Path) -> Result<()> {
-    println!("Verifying {}...", path.display());
+/// Results of [`verify_tarball`].
+#[derive(Debug, Serialize)]
+pub struct VerifyResult {
+    pub tarball: PathBuf,
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl VerifyResult {
+    /// Number of failed checks.
+    pub fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .count()
+    }
 
-    // Check essential files exist in tarball
+    /// Print the results for a human.
+    pub fn print(&self) {
+        for check in &self.checks {
+            check.print();
+        }
+    }
+}
+
+/// Verify a tarball, collecting the outcome of every requested check.
+///
+/// A failed signature stops verification before anything inspects the
+/// contents. Errors are returned only when the tarball can't be read at all.
+pub fn verify_tarball(path: &Path, options: &VerifyOptions) -> Result<VerifyResult> {
+    let mut checks = Vec::new();
+
+    if options.signature {
+        let check = match signing::verify_signature(path, options.public_key.as_deref()) {
+            Ok(()) => CheckResult::pass(
+                "signature",
+                format!(
+                    "Signature valid ({})",
+                    signing::signature_path(path).display()
+                ),
+            ),
+            Err(err) => CheckResult::from_error("signature", err),
+        };
+        let failed = check.status == CheckStatus::Fail;
+        checks.push(check);
+        if failed {
+            return Ok(VerifyResult {
+                tarball: path.to_path_buf(),
+                passed: false,
+                checks,
+            });
+        }
+    }
+
+    if options.checksum {
+        checks.push(match checksum::verify_sidecar(path) {
+            Ok(()) => CheckResult::pass(
+                "checksum",
+                format!(
+                    "Checksum matches {}",
+                    checksum::sidecar_path(path).display()
+                ),
+            ),
+            Err(err) => CheckResult::from_error("checksum", err),
+        });
+    }
+
+    if let Some(ref manifest_path) = options.manifest {
+        checks.push(
+            verify_against_manifest(path, manifest_path)
+                .unwrap_or_else(|err| CheckResult::from_error("manifest", err)),
+        );
+    }
+
+    checks.extend(verify_contents(path)?);
+
+    if options.units {
+        checks.push(
+            verify_units_in_tarball(path)
+                .unwrap_or_else(|err| CheckResult::from_error("units", err)),
+        );
+    }
+
+    let passed = checks.iter().all(|check| check.status != CheckStatus::Fail);
+    Ok(VerifyResult {
+        tarball: path.to_path_buf(),
+        passed,
+        checks,
+    })
+}
+
+/// Check essential files, security-critical headers and the architecture
+/// in one pass.
+fn verify_contents(path: &Path) -> Result<[CheckResult; 3]> {
     let essential_files = [
-        "./usr/bin/bash",
-        "./usr/bin/sh",
-        "./usr/sbin/init",
-        "./etc/passwd",
-        "./etc/shadow",
-        "./etc/os-release",
-        "./usr/lib/systemd/systemd",
+        "usr/bin/bash",
+        "usr/bin/sh",
+        "usr/sbin/init",
+        "etc/passwd",
+        "etc/shadow",
+        "etc/os-release",
+        "usr/lib/systemd/systemd",
     ];
 
-    let output = Command::new("tar")
-        .args(["-tJf", path.to_str().unwrap()])
-        .output()
-        .context("Failed to run tar command")?;
+    let mut missing: BTreeSet<&str> = essential_files.into_iter().collect();
+    let mut pending: BTreeMap<&str, &HeaderCheck> = HEADER_CHECKS
+        .iter()
+        .map(|check| (check.path, check))
+        .collect();
+    let mut violations = Vec::new();
+    // Architecture of the binaries and the one the rootfs says it is for,
+    // once read
+    let mut binary_arch = None;
+    let mut release_arch = None;
+    let mut archive = Stage3Archive::open(path)?;
 
-    if !output.status.success() {
-        anyhow::bail!("tar command failed");
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        missing.remove(entry.path());
+        if let Some(check) = pending.remove(entry.path()) {
+            if let Some(problem) = check.evaluate(&entry)? {
+                violations.push(format!("/{}: {}", check.path, problem));
+            }
+        }
+        match entry.path() {
+            ARCH_BINARY if entry.header().entry_type().is_file() => {
+                let mut header = [0u8; 20];
+                binary_arch = Some(
+                    entry
+                        .read_exact(&mut header)
+                        .ok()
+                        .and_then(|_| elf_arch_from_header(&header)),
+                );
+            }
+            provenance::RELEASE_FILE => {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents)?;
+                release_arch = Some(release_field(&contents, "STAGE3_ARCH"));
+            }
+            _ => {}
+        }
+
+        // Stop decompressing as soon as every check is satisfied
+        if missing.is_empty()
+            && pending.is_empty()
+            && binary_arch.is_some()
+            && release_arch.is_some()
+        {
+            break;
+        }
     }
 
-    let contents = String::from_utf8_lossy(&output.stdout);
-    let mut missing = Vec::new();
+    for check in pending.values().filter(|check| check.required) {
+        violations.push(format!("/{}: not found", check.path));
+    }
 
-    for file in essential_files {
-        if !contents.contains(file) {
-            missing.push(file);
+    let essential = if missing.is_empty() {
+        CheckResult::pass(
+            "essential-files",
+            format!(
+                "All essential files present (checked {} entries)",
+                archive.entries_read()
+            ),
+        )
+    } else {
+        CheckResult::fail(
+            "essential-files",
+            format!("{} essential files missing", missing.len()),
+            missing.iter().map(|file| format!("/{}", file)).collect(),
+        )
+    };
+
+    let security = if violations.is_empty() {
+        CheckResult::pass("security", "Permissions, setuid bits and symlinks OK")
+    } else {
+        CheckResult::fail(
+            "security",
+            format!("{} security checks failed", violations.len()),
+            violations,
+        )
+    };
+
+    let arch = verify_arch(path, binary_arch.flatten(), release_arch.flatten());
+    Ok([essential, security, arch])
+}
+
+/// Binary whose ELF header tells what architecture a tarball is for.
+const ARCH_BINARY: &str = "usr/bin/bash";
+
+/// Check the binaries are for the architecture the rootfs and the tarball
+/// name say.
+fn verify_arch(path: &Path, binary: Option<&str>, release: Option<String>) -> CheckResult {
+    let Some(binary) = binary else {
+        return CheckResult::skip(
+            "arch",
+            format!(
+                "/{} is not an ELF binary of a known architecture",
+                ARCH_BINARY
+            ),
+        );
+    };
+
+    let mut mismatches = Vec::new();
+    if let Some(release) = release.filter(|release| release != binary) {
+        mismatches.push(format!("/{} says {}", provenance::RELEASE_FILE, release));
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    for named in name.split(['-', '.']) {
+        if named != binary && arch::multiarch_triplet(named).is_some() {
+            mismatches.push(format!("the tarball is named for {}", named));
         }
     }
 
-    if missing.is_empty() {
-        println!("  All essential files present");
-        Ok(())
+    if mismatches.is_empty() {
+        CheckResult::pass("arch", format!("Binaries are for {}", binary))
     } else {
-        println!("  Missing files:");
-        for file in &missing {
-            println!("    - {}", file);
+        CheckResult::fail("arch", format!("Binaries are for {}", binary), mismatches)
+    }
+}
+
+/// Unquoted value of `key` in an os-release style file.
+fn release_field(contents: &str, key: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix('=')?;
+        Some(value.trim_matches('"').replace('\\', ""))
+    })
+}
+
+/// Property a tarball entry's header must have.
+enum Expect {
+    /// Exact permission bits
+    Mode(u32),
+    /// Regular file with the setuid bit, owned by root
+    Setuid,
+    /// Symlink resolving to this rootfs path
+    Symlink(&'static str),
+}
+
+/// A security-critical header check run by [`verify_tarball`].
+struct HeaderCheck {
+    path: &'static str,
+    expect: Expect,
+    /// Fail if the entry is absent (otherwise only checked when present)
+    required: bool,
+}
+
+const HEADER_CHECKS: &[HeaderCheck] = &[
+    HeaderCheck {
+        path: "etc/shadow",
+        expect: Expect::Mode(0o600),
+        required: true,
+    },
+    HeaderCheck {
+        path: "usr/bin/su",
+        expect: Expect::Setuid,
+        required: true,
+    },
+    HeaderCheck {
+        path: "usr/bin/passwd",
+        expect: Expect::Setuid,
+        required: true,
+    },
+    HeaderCheck {
+        path: "usr/bin/sudo",
+        expect: Expect::Setuid,
+        required: false,
+    },
+    HeaderCheck {
+        path: "bin",
+        expect: Expect::Symlink("usr/bin"),
+        required: true,
+    },
+    HeaderCheck {
+        path: "sbin",
+        expect: Expect::Symlink("usr/sbin"),
+        required: true,
+    },
+    HeaderCheck {
+        path: "usr/sbin/init",
+        expect: Expect::Symlink("usr/lib/systemd/systemd"),
+        required: true,
+    },
+];
+
+impl HeaderCheck {
+    /// Describe how the entry violates the check, if it does.
+    fn evaluate(&self, entry: &Stage3Entry) -> Result<Option<String>> {
+        let header = entry.header();
+        let mode = header.mode()? & 0o7777;
+        let entry_type = header.entry_type();
+
+        let problem = match self.expect {
+            Expect::Mode(want) if mode != want => {
+                Some(format!("mode {:04o}, expected {:04o}", mode, want))
+            }
+            Expect::Setuid if !entry_type.is_file() => Some("not a regular file".to_string()),
+            Expect::Setuid if mode & 0o4000 == 0 => {
+                Some(format!("mode {:04o}, expected setuid", mode))
+            }
+            Expect::Setuid if header.uid()? != 0 => {
+                Some(format!("setuid but owned by uid {}", header.uid()?))
+            }
+            Expect::Symlink(want) => match entry.link_name()? {
+                Some(target) if !entry_type.is_symlink() => Some(format!(
+                    "hardlink to {}, expected symlink",
+                    target.display()
+                )),
+                Some(target) => {
+                    let resolved = resolve_link(self.path, &target);
+                    (resolved != want)
+                        .then(|| format!("points at {}, expected /{}", target.display(), want))
+                }
+                None => Some(format!("not a symlink, expected link to /{}", want)),
+            },
+            _ => None,
+        };
+
+        Ok(problem)
+    }
+}
+
+/// Resolve a symlink target lexically to a normalized rootfs path.
+fn resolve_link(link: &str, target: &Path) -> String {
+    let mut parts: Vec<String> = if target.is_absolute() {
+        Vec::new()
+    } else {
+        let mut parent: Vec<String> = link.split('/').map(str::to_string).collect();
+        parent.pop();
+        parent
+    };
+
+    for component in target.components() {
+        match component {
+            std::path::Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            std::path::Component::ParentDir => {
+                parts.pop();
+            }
+            _ => {}
         }
-        anyhow::bail!("Tarball verification failed: missing essential files");
     }
+
+    parts.join("/")
+}
+
+/// Extract the tarball and run `systemd-analyze verify` over its enabled units.
+fn verify_units_in_tarball(path: &Path) -> Result<CheckResult> {
+    let extracted = tempfile::Builder::new()
+        .prefix("stage3-verify-")
+        .tempdir()
+        .context("Failed to create a directory to extract into")?;
+    Stage3Archive::open(path)?.extract(extracted.path(), &ExtractOptions::default())?;
+    let outcome = validate::units::run_systemd_analyze(extracted.path(), Sandbox::default())?;
+
+    Ok(match outcome {
+        None => CheckResult::skip("units", "systemd-analyze not found"),
+        Some(outcome) if outcome.findings.is_empty() => CheckResult::pass(
+            "units",
+            format!("{} enabled units verified cleanly", outcome.units),
+        ),
+        Some(outcome) => CheckResult::fail(
+            "units",
+            format!(
+                "systemd-analyze reported {} problems",
+                outcome.findings.len()
+            ),
+            outcome.findings,
+        ),
+    })
+}
+
+/// Re-read every entry of the tarball and compare it against a manifest.
+fn verify_against_manifest(path: &Path, manifest_path: &Path) -> Result<CheckResult> {
+    let expected = manifest::read(manifest_path)?;
+    let actual = manifest::from_tarball(path)?;
+    let problems = manifest::compare(&expected, &actual);
+
+    if problems.is_empty() {
+        return Ok(CheckResult::pass(
+            "manifest",
+            format!(
+                "All {} entries match {}",
+                expected.entries.len(),
+                manifest_path.display()
+            ),
+        ));
+    }
+
+    Ok(CheckResult::fail(
+        "manifest",
+        format!(
+            "{} entries differ from {}",
+            problems.len(),
+            manifest_path.display()
+        ),
+        problems,
+    ))
 }
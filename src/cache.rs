@@ -0,0 +1,200 @@
+//! Donor files kept between builds.
+//!
+//! Every build copies the same few thousand files out of the donor, which
+//! rarely changes between two builds. With `--incremental`, a finished
+//! build's staging directory is kept in the output directory's
+//! `.stage3-cache/`, with an index of the donor file each staged copy
+//! came from and the SHA-256 of its contents. The next build still runs
+//! every step on an empty staging directory, so changed binary lists,
+//! config or templates take effect as they would without the cache, but
+//! a donor copy is moved out of the cache instead of copied when the same
+//! staging path was copied from the same donor file last time and its
+//! contents are unchanged. A donor file whose size and mtime didn't change
+//! is taken to be unchanged; one whose mtime did is hashed again.
+//!
+//! Staged copies a later step rewrote aren't reused. The cache is only
+//! read by builds that start from an empty staging directory (not with
+//! `--resume` or `--only-step`) and only written by ones that succeed
+//! without `--keep-staging`.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::checksum::sha256_file;
+use crate::context::BuildContext;
+use crate::reflink;
+use crate::xattrs;
+use crate::{detail, status};
+
+/// Cache directory in the output directory.
+pub const CACHE_DIR: &str = ".stage3-cache";
+
+/// Index of the cached files, in the cache directory.
+const INDEX_NAME: &str = "index.json";
+
+/// A staged copy of a donor file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFile {
+    /// Donor file it was copied from
+    source: PathBuf,
+    /// Size of the donor file
+    size: u64,
+    /// Modification time of the donor file, in nanoseconds
+    mtime: u64,
+    sha256: String,
+    /// Size and modification time of the staged copy once copied
+    staged: (u64, u64),
+}
+
+/// The donor copies of the last build, and the ones of this build.
+#[derive(Debug)]
+pub struct BuildCache {
+    dir: PathBuf,
+    /// Copies of the last build, by staging-relative path
+    previous: BTreeMap<PathBuf, CachedFile>,
+    /// Copies of this build, by staging-relative path
+    recorded: Mutex<BTreeMap<PathBuf, CachedFile>>,
+    reused: AtomicUsize,
+    copied: AtomicUsize,
+}
+
+impl BuildCache {
+    /// Open the cache in `output_dir`; empty if there is none or its
+    /// index can't be read.
+    pub fn open(output_dir: &Path) -> Self {
+        let dir = output_dir.join(CACHE_DIR);
+        let previous = fs::read(dir.join(INDEX_NAME))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self {
+            dir,
+            previous,
+            recorded: Mutex::default(),
+            reused: AtomicUsize::new(0),
+            copied: AtomicUsize::new(0),
+        }
+    }
+
+    /// Copy donor file `src` to `dest` in `staging`, reusing the last
+    /// build's copy if it can.
+    pub fn copy(&self, staging: &Path, src: &Path, dest: &Path) -> Result<()> {
+        let Ok(rel) = dest.strip_prefix(staging) else {
+            reflink::copy(src, dest)?;
+            return Ok(());
+        };
+        let meta =
+            fs::metadata(src).with_context(|| format!("Failed to read {}", src.display()))?;
+        let mtime = mtime_nanos(&meta);
+
+        let unchanged = match self.previous.get(rel) {
+            Some(prev) if prev.source == src && prev.size == meta.len() => {
+                match prev.mtime == mtime {
+                    true => Some(prev.sha256.clone()),
+                    false => Some(sha256_file(src)?).filter(|hash| *hash == prev.sha256),
+                }
+            }
+            _ => None,
+        };
+        let cached = self.dir.join("staging").join(rel);
+        let sha256 = match unchanged {
+            Some(hash) if cached.is_file() && fs::rename(&cached, dest).is_ok() => {
+                fs::set_permissions(dest, meta.permissions())?;
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                hash
+            }
+            _ => {
+                reflink::copy(src, dest)?;
+                self.copied.fetch_add(1, Ordering::Relaxed);
+                sha256_file(dest)?
+            }
+        };
+
+        let file = CachedFile {
+            source: src.to_path_buf(),
+            size: meta.len(),
+            mtime,
+            sha256,
+            staged: staged_stamp(dest)
+                .with_context(|| format!("Failed to read {}", dest.display()))?,
+        };
+        self.recorded
+            .lock()
+            .unwrap()
+            .insert(rel.to_path_buf(), file);
+        Ok(())
+    }
+
+    /// Keep the finished `staging` directory for the next build, in place
+    /// of the last one.
+    pub fn save(&self, staging: &Path) -> Result<()> {
+        let index = self.dir.join(INDEX_NAME);
+        let cached = self.dir.join("staging");
+        if index.exists() {
+            fs::remove_file(&index)?;
+        }
+        if cached.exists() {
+            fs::remove_dir_all(&cached)?;
+        }
+        fs::create_dir_all(&self.dir)?;
+        fs::rename(staging, &cached)
+            .with_context(|| format!("Failed to move {} to the build cache", staging.display()))?;
+
+        // Steps after the copy may have rewritten it
+        let files: BTreeMap<_, _> = self
+            .recorded
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(rel, file)| staged_stamp(&cached.join(rel)).ok() == Some(file.staged))
+            .map(|(rel, file)| (rel.clone(), file.clone()))
+            .collect();
+        fs::write(&index, serde_json::to_vec(&files)?)
+            .with_context(|| format!("Failed to write {}", index.display()))?;
+
+        let reused = self.reused.load(Ordering::Relaxed);
+        let total = reused + self.copied.load(Ordering::Relaxed);
+        status!(
+            "Reused {} of {} donor files from the build cache",
+            reused,
+            total
+        );
+        detail!("  Kept {} files in {}", files.len(), self.dir.display());
+        Ok(())
+    }
+}
+
+/// Copy donor file `src` to staged `dest`, through the build cache if the
+/// build has one, or link it to the copy of another name of it, and record
+/// its extended attributes.
+pub fn copy_donor(ctx: &BuildContext, src: &Path, dest: &Path) -> Result<()> {
+    xattrs::record(ctx, src, dest);
+    ctx.donor_links.copy(src, dest, || {
+        match ctx.cache {
+            Some(ref cache) => cache.copy(&ctx.staging, src, dest)?,
+            None => {
+                reflink::copy(src, dest)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn mtime_nanos(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// Size and modification time of a staged file.
+fn staged_stamp(path: &Path) -> std::io::Result<(u64, u64)> {
+    let meta = fs::symlink_metadata(path)?;
+    Ok((meta.len(), mtime_nanos(&meta)))
+}
@@ -0,0 +1,80 @@
+//! Cancelling a running build.
+//!
+//! A [`CancelToken`] is checked between build steps and while the tarball
+//! is written; once cancelled, the build stops with
+//! [`Stage3Error::Cancelled`] and removes its staging directory and
+//! partial outputs instead of leaving a half-written tree and a truncated
+//! tarball behind. Library users cancel the token they passed to the
+//! builder; the `stage3` binary cancels it on SIGINT and SIGTERM through
+//! [`cancel_on_signal`]. A second signal ends the process right away.
+
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use crate::error::Stage3Error;
+
+/// Shared flag asking a build to stop. Clones cancel together.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every build holding this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with [`Stage3Error::Cancelled`] once cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(Stage3Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// Token cancelled by the signal handler.
+static SIGNAL_TOKEN: OnceLock<CancelToken> = OnceLock::new();
+
+/// Cancel `token` on the first SIGINT or SIGTERM.
+///
+/// The handler is installed once per process; later calls only succeed
+/// for the same token.
+pub fn cancel_on_signal(token: &CancelToken) -> Result<()> {
+    if SIGNAL_TOKEN.set(token.clone()).is_err() {
+        let installed = SIGNAL_TOKEN.get().expect("token is set");
+        if !Arc::ptr_eq(&installed.0, &token.0) {
+            bail!("A signal handler for another build is already installed");
+        }
+        return Ok(());
+    }
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only does an atomic load and store and
+        // resets the disposition, all async-signal-safe
+        let previous =
+            unsafe { libc::signal(signal, on_signal as *const () as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            bail!("Failed to install the handler for signal {}", signal);
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    if let Some(token) = SIGNAL_TOKEN.get() {
+        token.cancel();
+    }
+    // The next one terminates as usual
+    // SAFETY: signal() is async-signal-safe
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
@@ -0,0 +1,93 @@
+//! Comparison of a local build against a published release channel.
+//!
+//! Every channel (`stable`, `testing`, ...) publishes the build manifest of
+//! its latest release at `<base>/<channel>/levitateos-stage3.manifest.json`
+//! with a `.sha256` sidecar. `stage3 diff --against-channel` fetches it
+//! like any other published artifact and reports how the local build
+//! drifted from it, so a promotion gate can refuse unexpected growth.
+
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use crate::diff::{self, BaselineComparison};
+use crate::download;
+use crate::failure::{Failure, FailureKind};
+use crate::inspect::ByteSize;
+use crate::manifest::{self, Manifest, MANIFEST_NAME};
+use crate::status;
+
+/// Where the release channels are published.
+pub const DEFAULT_CHANNEL_URL: &str = "https://download.levitateos.org/stage3";
+
+/// URL of the manifest a channel publishes for its latest release.
+pub fn manifest_url(base: &str, channel: &str) -> Result<String> {
+    let valid = !channel.is_empty()
+        && !channel.starts_with('.')
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!("Invalid channel name: {:?}", channel);
+    }
+    Ok(format!(
+        "{}/{}/{}",
+        base.trim_end_matches('/'),
+        channel,
+        MANIFEST_NAME
+    ))
+}
+
+/// Fetch and verify the manifest of a channel's latest release.
+pub fn fetch_manifest(base: &str, channel: &str) -> Result<Manifest> {
+    let path = download::fetch(&manifest_url(base, channel)?)?;
+    manifest::read(&path)
+}
+
+/// Manifest of a local build: its output directory, its manifest or the
+/// tarball itself.
+pub fn local_manifest(path: &Path) -> Result<Manifest> {
+    if path.is_dir() {
+        manifest::read(&path.join(MANIFEST_NAME))
+    } else if path.extension().is_some_and(|ext| ext == "json") {
+        manifest::read(path)
+    } else {
+        manifest::from_tarball(path)
+    }
+}
+
+/// Compare a local build against the latest release of `channel`.
+///
+/// Fails when the uncompressed contents grew by more than `max_growth`.
+pub fn compare_to_channel(
+    local: &Path,
+    base: &str,
+    channel: &str,
+    max_growth: Option<ByteSize>,
+) -> Result<BaselineComparison> {
+    let current = local_manifest(local)?;
+    let published = fetch_manifest(base, channel)?;
+    status!(
+        "Comparing {} against {} ({})...",
+        local.display(),
+        channel,
+        published.tarball
+    );
+
+    let comparison = diff::compare_to_baseline(&published, &current);
+    if let Some(max_growth) = max_growth {
+        let growth = comparison.diff.size_delta();
+        if growth > max_growth.0 as i64 {
+            comparison.print();
+            bail!(Failure::new(
+                FailureKind::OverBudget,
+                format!(
+                    "Build grew by {} over {}, more than the --max-growth budget of {}",
+                    ByteSize(growth as u64),
+                    channel,
+                    max_growth
+                )
+            ));
+        }
+    }
+    Ok(comparison)
+}
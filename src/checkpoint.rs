@@ -0,0 +1,493 @@
+//! Resumable builds.
+//!
+//! Each rootfs step that completes is recorded in a checkpoint file in the
+//! staging directory, with a hash of its inputs: the build options every
+//! step depends on, the step's own options and files, and the hashes of
+//! the steps it depends on. `build --resume` keeps the staging directory,
+//! replays the steps whose hash still matches (their diagnostics and
+//! recorded ownership included), and runs every step that changed or
+//! never finished, and every step depending on one that ran.
+//!
+//! The donor tree itself is not hashed; build without `--resume` after
+//! updating it. A step that runs again runs on top of what the earlier run
+//! left in staging, which is what the copy routines expect, but files of a
+//! step that was dropped since stay behind.
+//!
+//! With `--only-step` or `--skip-step`, selected steps always run and the
+//! others are replayed from the checkpoint (see [`crate::steps`]).
+//!
+//! The checkpoint is set aside while the rootfs is validated and archived
+//! and put back if either fails, so it never ends up in the tarball.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use walkdir::WalkDir;
+
+use crate::archive::DeviceNode;
+use crate::checksum::sha256_file;
+use crate::console::{self, paint, Color};
+use crate::context::BuildContext;
+use crate::fakeroot::{Attributes, Xattrs};
+use crate::report::Diagnostic;
+use crate::status;
+use crate::steps::{BuildStep, StepSelection};
+
+/// Checkpoint file in the staging directory.
+pub const CHECKPOINT_NAME: &str = ".stage3-checkpoint.json";
+
+/// Steps of a build so far.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Build clock of the run that started the staging tree
+    pub timestamp: u64,
+    pub steps: Vec<CompletedStep>,
+}
+
+/// A step that completed, with what it recorded outside the staging tree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletedStep {
+    pub name: String,
+    /// Hash of the step's inputs, chained to the previous step's
+    pub input: String,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Ownership and modes the step recorded in the metadata layer
+    pub attributes: BTreeMap<PathBuf, Attributes>,
+    /// Extended attributes the step recorded in the metadata layer
+    #[serde(default)]
+    pub xattrs: BTreeMap<PathBuf, Xattrs>,
+    /// Device nodes the step recorded
+    pub devices: Vec<DeviceNode>,
+}
+
+impl Checkpoint {
+    /// The checkpoint in `staging`, if there is a readable one.
+    pub fn read(staging: &Path) -> Option<Self> {
+        let data = fs::read(staging.join(CHECKPOINT_NAME)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn write(&self, staging: &Path) -> Result<()> {
+        let path = staging.join(CHECKPOINT_NAME);
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// A rootfs step for [`Checkpoints::run`] to run or replay.
+pub struct Job<'a> {
+    /// Name in the checkpoint
+    pub name: String,
+    /// Title in build output
+    pub title: String,
+    /// The built-in step it is or replaces, selected like it; other custom
+    /// steps run unless `--only-step` is given
+    pub step: Option<BuildStep>,
+    /// What the step itself depends on
+    pub inputs: String,
+    /// Earlier jobs it runs after, by index
+    pub dependencies: Vec<usize>,
+    /// Stages what the step adds; None for an optional step that is off
+    pub run: Option<StepRun<'a>>,
+}
+
+/// What a [`Job`] does, on the context it is given.
+pub type StepRun<'a> = Box<dyn FnOnce(&BuildContext) -> Result<()> + Send + 'a>;
+
+/// Runs rootfs steps, replaying those an earlier run completed with the
+/// same inputs or that the step selection leaves out.
+pub struct Checkpoints {
+    staging: PathBuf,
+    /// Steps of the earlier run
+    previous: Vec<CompletedStep>,
+    /// Whether steps that match the earlier run are replayed
+    resuming: bool,
+    selection: StepSelection,
+    /// Steps left out that the earlier run has no record of
+    unrecorded: Vec<String>,
+    checkpoint: Checkpoint,
+    /// Hash of what every step depends on
+    inputs: String,
+}
+
+impl Checkpoints {
+    /// Record steps in `staging`, replaying `previous` where it matches.
+    ///
+    /// `inputs` describes what every step depends on.
+    pub fn new(
+        staging: &Path,
+        timestamp: u64,
+        inputs: &str,
+        previous: Option<Checkpoint>,
+        selection: StepSelection,
+    ) -> Self {
+        Self {
+            staging: staging.to_path_buf(),
+            previous: previous.map(|c| c.steps).unwrap_or_default(),
+            resuming: selection.is_all(),
+            selection,
+            unrecorded: Vec::new(),
+            checkpoint: Checkpoint {
+                timestamp,
+                steps: Vec::new(),
+            },
+            inputs: hash(&[inputs]),
+        }
+    }
+
+    /// Run every job once the jobs it depends on are done, up to `workers`
+    /// at a time, each through [`console::step`] on a [fork](BuildContext::fork)
+    /// of `ctx`. A job is replayed instead if it isn't selected, or if the
+    /// earlier run completed it with the same inputs and none of the jobs
+    /// it depends on ran again.
+    ///
+    /// What the jobs recorded outside the staging tree is merged into `ctx`
+    /// in job order, so it doesn't depend on which finished first; a job's
+    /// fork starts with what the jobs done by then recorded. The
+    /// first failure in job order is returned once the running jobs are
+    /// done; jobs not started by then don't run. The checkpoint is written
+    /// each time a job finishes, so a failed build resumes from there.
+    pub fn run(&mut self, ctx: &BuildContext, mut jobs: Vec<Job>, workers: usize) -> Result<()> {
+        let count = jobs.len();
+        let mut runs: Vec<Option<StepRun>> = jobs.iter_mut().map(|job| job.run.take()).collect();
+        let off: Vec<bool> = runs.iter().map(Option::is_none).collect();
+
+        let mut hashes = vec![String::new(); count];
+        let mut started = vec![false; count];
+        let mut finished = vec![false; count];
+        let mut ran = vec![false; count];
+        // With whether it goes in the checkpoint
+        let mut completed: Vec<Option<(CompletedStep, bool)>> = vec![None; count];
+        let mut failures: Vec<(usize, anyhow::Error)> = Vec::new();
+        let mut running = 0;
+
+        // Several steps' spinners would fight over one line
+        let step_bar = match workers > 1 {
+            true => Some(console::set_step_bar(false)),
+            false => None,
+        };
+        let span = tracing::Span::current();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::scope(|scope| {
+            loop {
+                // Start or replay every job that can, in job order
+                let mut progressed = true;
+                while progressed && failures.is_empty() {
+                    progressed = false;
+                    for index in 0..count {
+                        let job = &jobs[index];
+                        if started[index] || !job.dependencies.iter().all(|&dep| finished[dep]) {
+                            continue;
+                        }
+                        let mut parts = vec![self.inputs.as_str(), &job.name, &job.inputs];
+                        parts.extend(job.dependencies.iter().map(|&dep| hashes[dep].as_str()));
+                        let input = hash(&parts);
+                        if off[index] {
+                            // Passes its dependencies on to its dependents
+                            hashes[index] = input;
+                            started[index] = true;
+                            finished[index] = true;
+                            ran[index] = job.dependencies.iter().any(|&dep| ran[dep]);
+                            progressed = true;
+                            continue;
+                        }
+
+                        let done = self
+                            .previous
+                            .iter()
+                            .find(|done| done.name == job.name)
+                            .cloned();
+                        if !self.selected(job) {
+                            match done {
+                                Some(done) => completed[index] = Some((done, true)),
+                                None => self.unrecorded.push(job.name.clone()),
+                            }
+                            status!("  {} {}", paint("skip", Color::Dim), job.title);
+                        } else if let Some(done) = done.filter(|done| {
+                            self.resuming
+                                && done.input == input
+                                && !job.dependencies.iter().any(|&dep| ran[dep])
+                        }) {
+                            status!("  {} {} (resumed)", paint("done", Color::Dim), job.title);
+                            completed[index] = Some((done, true));
+                        } else if running < workers {
+                            let run = runs[index].take().expect("every job runs once");
+                            let (name, title) = (job.name.clone(), job.title.clone());
+                            let (sender, span, hashed) =
+                                (sender.clone(), span.clone(), input.clone());
+                            // With what the jobs done so far recorded
+                            let fork = ctx.fork();
+                            for (done, _) in completed.iter().flatten() {
+                                replay_metadata(&fork, done);
+                            }
+                            scope.spawn(move || {
+                                let _span = span.enter();
+                                let (done, result) = execute(&fork, name, &title, hashed, run);
+                                let _ = sender.send((index, done, result));
+                            });
+                            hashes[index] = input;
+                            started[index] = true;
+                            ran[index] = true;
+                            running += 1;
+                            continue;
+                        } else {
+                            continue;
+                        }
+                        hashes[index] = input;
+                        started[index] = true;
+                        finished[index] = true;
+                        progressed = true;
+                    }
+                }
+                if running == 0 {
+                    break;
+                }
+
+                let (index, done, result) = receiver.recv().expect("a step is running");
+                running -= 1;
+                // A failed step's findings still go in the report
+                completed[index] = Some((done, result.is_ok()));
+                match result {
+                    Ok(()) => finished[index] = true,
+                    Err(err) => failures.push((index, err)),
+                }
+                if let Err(err) = self.save_progress(&jobs, &started, &completed) {
+                    tracing::warn!("  Warning: could not write the checkpoint: {:#}", err);
+                }
+            }
+        });
+        if let Some(enabled) = step_bar {
+            console::set_step_bar(enabled);
+        }
+
+        for (done, recorded) in completed.into_iter().flatten() {
+            replay_metadata(ctx, &done);
+            ctx.report.extend(done.diagnostics.iter().cloned());
+            if recorded {
+                self.checkpoint.steps.push(done);
+            }
+        }
+        match failures.into_iter().min_by_key(|(index, _)| *index) {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the checkpoint of a run still going: the jobs completed so
+    /// far, and what the earlier run recorded of the jobs not started yet.
+    fn save_progress(
+        &self,
+        jobs: &[Job],
+        started: &[bool],
+        completed: &[Option<(CompletedStep, bool)>],
+    ) -> Result<()> {
+        let mut steps = Vec::new();
+        for (index, job) in jobs.iter().enumerate() {
+            match &completed[index] {
+                Some((done, true)) => steps.push(done.clone()),
+                Some((_, false)) => {}
+                None if !started[index] => steps.extend(
+                    self.previous
+                        .iter()
+                        .find(|done| done.name == job.name)
+                        .cloned(),
+                ),
+                None => {}
+            }
+        }
+        Checkpoint {
+            timestamp: self.checkpoint.timestamp,
+            steps,
+        }
+        .write(&self.staging)
+    }
+
+    /// Whether the step selection runs `job`.
+    fn selected(&self, job: &Job) -> bool {
+        match job.step {
+            Some(step) => self.selection.runs(step),
+            None => self.selection.only.is_empty(),
+        }
+    }
+
+    /// Steps left out whose warnings, ownership and device nodes are
+    /// missing because no earlier run recorded them.
+    pub fn unrecorded(&self) -> &[String] {
+        &self.unrecorded
+    }
+
+    /// Write the checkpoint to the staging directory.
+    pub fn save(&self) -> Result<()> {
+        self.checkpoint.write(&self.staging)
+    }
+
+    /// Take the checkpoint out of the staging tree before it is validated
+    /// and archived.
+    pub fn set_aside(&self) -> Result<()> {
+        let path = self.staging.join(CHECKPOINT_NAME);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `run` as the step `name` on `ctx`, returning what it recorded
+/// outside the staging tree with the `input` hash it ran with.
+fn execute(
+    ctx: &BuildContext,
+    name: String,
+    title: &str,
+    input: String,
+    run: StepRun,
+) -> (CompletedStep, Result<()>) {
+    let diagnostics = ctx.report.len();
+    let attributes = ctx.metadata.recorded();
+    let xattrs = ctx.metadata.recorded_xattrs();
+    let devices = ctx.metadata.devices().len();
+    let result = console::step(ctx, title, || run(ctx));
+
+    let done = CompletedStep {
+        name,
+        input,
+        diagnostics: ctx.report.diagnostics_since(diagnostics),
+        attributes: ctx
+            .metadata
+            .recorded()
+            .into_iter()
+            .filter(|(path, recorded)| attributes.get(path) != Some(recorded))
+            .collect(),
+        xattrs: ctx
+            .metadata
+            .recorded_xattrs()
+            .into_iter()
+            .filter(|(path, recorded)| xattrs.get(path) != Some(recorded))
+            .collect(),
+        devices: ctx.metadata.devices()[devices..].to_vec(),
+    };
+    (done, result)
+}
+
+/// Record in `ctx` everything a completed step recorded in the metadata
+/// layer.
+fn replay_metadata(ctx: &BuildContext, done: &CompletedStep) {
+    for (path, attributes) in &done.attributes {
+        ctx.metadata.set_attributes(path, *attributes);
+    }
+    for (path, xattrs) in &done.xattrs {
+        ctx.metadata.set_xattrs(path, xattrs.clone());
+    }
+    for node in &done.devices {
+        ctx.metadata.mknod(node.clone());
+    }
+}
+
+fn hash(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.len().to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hash of a file, or of every file under a directory, for step inputs;
+/// empty for `None`.
+pub fn hash_path(path: Option<&Path>) -> Result<String> {
+    let Some(path) = path else {
+        return Ok(String::new());
+    };
+    if !path.is_dir() {
+        return sha256_file(path);
+    }
+    let mut listing = String::new();
+    for entry in WalkDir::new(path).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let rel = entry.path().strip_prefix(path)?;
+            listing.push_str(&format!(
+                "{} {}\n",
+                sha256_file(entry.path())?,
+                rel.display()
+            ));
+        }
+    }
+    Ok(hash(&[&listing]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn job<'a>(name: &str, run: impl FnOnce(&BuildContext) -> Result<()> + Send + 'a) -> Job<'a> {
+        Job {
+            name: name.to_string(),
+            title: name.to_string(),
+            step: None,
+            inputs: String::new(),
+            dependencies: Vec::new(),
+            run: Some(Box::new(run)),
+        }
+    }
+
+    fn context(root: &Path) -> BuildContext {
+        let staging = root.join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        BuildContext::new(root.join("source"), staging, root.join("output"))
+    }
+
+    #[test]
+    fn metadata_is_merged_in_job_order() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = context(root.path());
+        let (first_done, wait) = mpsc::channel();
+        let jobs = vec![
+            job("first", move |ctx| {
+                // Finish after the second job
+                wait.recv_timeout(Duration::from_secs(10))?;
+                ctx.metadata.chmod("etc/shadow", 0o600);
+                Ok(())
+            }),
+            job("second", move |ctx| {
+                ctx.metadata.chmod("etc/shadow", 0o400);
+                first_done.send(())?;
+                Ok(())
+            }),
+        ];
+        let mut checkpoints = Checkpoints::new(&ctx.staging, 0, "", None, Default::default());
+        checkpoints.run(&ctx, jobs, 2).unwrap();
+        let mode = ctx.metadata.attributes(Path::new("etc/shadow")).mode;
+        assert_eq!(mode, Some(0o400));
+    }
+
+    #[test]
+    fn resumed_steps_replay_their_device_nodes() {
+        let root = tempfile::tempdir().unwrap();
+        let kmsg = DeviceNode::char("dev/kmsg", 1, 11, 0o644);
+        let ctx = context(root.path());
+        let node = kmsg.clone();
+        let jobs = vec![job("devices", move |ctx| {
+            ctx.metadata.mknod(node);
+            Ok(())
+        })];
+        let mut checkpoints = Checkpoints::new(&ctx.staging, 0, "", None, Default::default());
+        checkpoints.run(&ctx, jobs, 1).unwrap();
+        checkpoints.save().unwrap();
+
+        let resumed = context(root.path());
+        let previous = Checkpoint::read(&resumed.staging);
+        let jobs = vec![job("devices", |_| anyhow::bail!("replayed, not run"))];
+        let mut checkpoints =
+            Checkpoints::new(&resumed.staging, 0, "", previous, Default::default());
+        checkpoints.run(&resumed, jobs, 1).unwrap();
+        assert_eq!(resumed.metadata.devices(), [kmsg]);
+    }
+}
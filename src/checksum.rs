@@ -0,0 +1,72 @@
+//! SHA-256 helpers for artifacts.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Compute the hex SHA-256 digest of everything read from `reader`.
+pub fn sha256_reader(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Compute the hex SHA-256 digest of a file.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    sha256_reader(file).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Path of the `.sha256` sidecar for a file.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// Write a sha256sum(1)-compatible sidecar next to `path`.
+pub fn write_sidecar(path: &Path) -> Result<PathBuf> {
+    let hash = sha256_file(path)?;
+    let name = path
+        .file_name()
+        .with_context(|| format!("Path has no filename: {}", path.display()))?;
+
+    let sidecar = sidecar_path(path);
+    fs::write(&sidecar, format!("{}  {}\n", hash, name.to_string_lossy()))
+        .with_context(|| format!("Failed to write {}", sidecar.display()))?;
+    Ok(sidecar)
+}
+
+/// Validate a file against its `.sha256` sidecar.
+pub fn verify_sidecar(path: &Path) -> Result<()> {
+    let sidecar = sidecar_path(path);
+    let contents = fs::read_to_string(&sidecar)
+        .with_context(|| format!("Failed to read checksum sidecar {}", sidecar.display()))?;
+
+    let expected = contents
+        .split_whitespace()
+        .next()
+        .with_context(|| format!("Checksum sidecar is empty: {}", sidecar.display()))?;
+
+    let actual = sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        anyhow::bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            path.display(),
+            expected,
+            actual
+        );
+    }
+
+    Ok(())
+}
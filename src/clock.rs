@@ -0,0 +1,99 @@
+//! The build clock.
+//!
+//! Every timestamp a build produces (archive mtimes, synthesized entries,
+//! the `{date}` in artifact names, release metadata) comes from one
+//! [`BuildClock`] read once per command. With `SOURCE_DATE_EPOCH` or an
+//! explicit epoch it is fixed to that time, so rebuilding the same inputs
+//! gives the same bytes. Otherwise it is the time the command started, and
+//! files written at different moments of the build still share one mtime.
+
+use anyhow::{Context, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single timestamp used for everything a build writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildClock {
+    timestamp: u64,
+    fixed: bool,
+}
+
+impl BuildClock {
+    /// The current time.
+    pub fn system() -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            timestamp,
+            fixed: false,
+        }
+    }
+
+    /// The time an earlier run of the same build started, so a resumed
+    /// build keeps one timestamp.
+    pub fn started_at(timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            fixed: false,
+        }
+    }
+
+    /// A clock fixed to `timestamp` seconds since the epoch.
+    pub fn fixed(timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            fixed: true,
+        }
+    }
+
+    /// `explicit` if given, else `SOURCE_DATE_EPOCH`, else the current time.
+    pub fn resolve(explicit: Option<u64>) -> Result<Self> {
+        Ok(match explicit.or(source_date_epoch()?) {
+            Some(timestamp) => Self::fixed(timestamp),
+            None => Self::system(),
+        })
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Whether the time was pinned rather than read from the system.
+    pub fn is_fixed(&self) -> bool {
+        self.fixed
+    }
+
+    /// UTC date formatted as YYYYMMDD.
+    pub fn date(&self) -> String {
+        let (year, month, day) = civil_from_days((self.timestamp / 86400) as i64);
+        format!("{:04}{:02}{:02}", year, month, day)
+    }
+}
+
+/// Read `SOURCE_DATE_EPOCH` from the environment, if set.
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH: {:?}", value)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,315 @@
+//! Conditions on what a build is for.
+//!
+//! Conditional tables of a `stage3.toml` apply to the builds their `when`
+//! expression matches, so one config covers every profile and
+//! architecture:
+//!
+//! ```toml
+//! [[binaries.conditional]]
+//! when = 'profile == "server" && arch == "aarch64"'
+//! add = ["tmux"]
+//! ```
+//!
+//! An expression compares the variables `profile` and `arch` to quoted
+//! strings with `==` or `!=`, and combines comparisons with `&&`, `||`,
+//! `!` and parentheses (`&&` binding tighter than `||`). Anything else,
+//! unknown variables included, is rejected when the config is read.
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::context::BuildOptions;
+
+/// Variables an expression can compare.
+const VARIABLES: &[&str] = &["profile", "arch"];
+
+/// A parsed `when` expression, displayed as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Compare {
+        variable: &'static str,
+        value: String,
+        equal: bool,
+    },
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Condition {
+    /// Whether a build with `options` matches.
+    pub fn matches(&self, options: &BuildOptions) -> bool {
+        self.expr.eval(options)
+    }
+}
+
+impl Expr {
+    fn eval(&self, options: &BuildOptions) -> bool {
+        match self {
+            Expr::Compare {
+                variable,
+                value,
+                equal,
+            } => {
+                let actual = match *variable {
+                    "profile" => &options.profile,
+                    _ => &options.arch,
+                };
+                (actual == value) == *equal
+            }
+            Expr::Not(expr) => !expr.eval(options),
+            Expr::And(a, b) => a.eval(options) && b.eval(options),
+            Expr::Or(a, b) => a.eval(options) || b.eval(options),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            next: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.next) {
+            return Err(format!("unexpected {} in {:?}", token, s));
+        }
+        Ok(Condition {
+            source: s.to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Eq,
+    Ne,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{}`", word),
+            Token::Str(value) => write!(f, "{:?}", value),
+            Token::Eq => f.write_str("`==`"),
+            Token::Ne => f.write_str("`!=`"),
+            Token::And => f.write_str("`&&`"),
+            Token::Or => f.write_str("`||`"),
+            Token::Not => f.write_str("`!`"),
+            Token::Open => f.write_str("`(`"),
+            Token::Close => f.write_str("`)`"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Eq,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Ne,
+            '!' => Token::Not,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(ch) => value.push(ch),
+                        None => return Err(format!("unterminated string in {:?}", s)),
+                    }
+                }
+                Token::Str(value)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(ch) = chars.next_if(|ch| ch.is_ascii_alphanumeric() || *ch == '_') {
+                    word.push(ch);
+                }
+                Token::Word(word)
+            }
+            c => return Err(format!("unexpected {:?} in {:?}", c, s)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn take(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn take_if(&mut self, token: &Token) -> bool {
+        let matched = self.tokens.get(self.next) == Some(token);
+        if matched {
+            self.next += 1;
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.take_if(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.take_if(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.take() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.take() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("missing `)`".to_string()),
+                }
+            }
+            Some(Token::Word(word)) => {
+                let Some(&variable) = VARIABLES.iter().find(|v| **v == word) else {
+                    return Err(format!(
+                        "unknown variable `{}` (expected {})",
+                        word,
+                        VARIABLES.join(" or ")
+                    ));
+                };
+                let equal = match self.take() {
+                    Some(Token::Eq) => true,
+                    Some(Token::Ne) => false,
+                    _ => return Err(format!("expected == or != after `{}`", word)),
+                };
+                match self.take() {
+                    Some(Token::Str(value)) => Ok(Expr::Compare {
+                        variable,
+                        value,
+                        equal,
+                    }),
+                    _ => Err(format!("expected a quoted string to compare `{}` to", word)),
+                }
+            }
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("expression ends early".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(when: &str, profile: &str, arch: &str) -> bool {
+        let options = BuildOptions {
+            profile: profile.to_string(),
+            arch: arch.to_string(),
+            ..BuildOptions::default()
+        };
+        when.parse::<Condition>().unwrap().matches(&options)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let when = "profile == 'desktop' || profile == \"server\" && arch == \"aarch64\"";
+        assert!(matches(when, "desktop", "x86_64"));
+        assert!(matches(when, "server", "aarch64"));
+        assert!(!matches(when, "server", "x86_64"));
+
+        let when = "(profile == 'desktop' || profile == 'server') && arch != 'x86_64'";
+        assert!(!matches(when, "desktop", "x86_64"));
+        assert!(matches(when, "desktop", "riscv64"));
+        assert!(matches("!(profile == 'minimal')", "server", "x86_64"));
+        assert!(!matches("!!(profile == 'server')", "minimal", "x86_64"));
+    }
+
+    #[test]
+    fn conditions_display_as_written() {
+        let when = "profile=='server'&&arch=='aarch64'";
+        assert_eq!(when.parse::<Condition>().unwrap().to_string(), when);
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        let error = |when: &str| when.parse::<Condition>().unwrap_err();
+        assert_eq!(
+            error("kernel == 'lts'"),
+            "unknown variable `kernel` (expected profile or arch)"
+        );
+        assert_eq!(
+            error("profile = 'server'"),
+            "unexpected '=' in \"profile = 'server'\""
+        );
+        assert_eq!(
+            error("profile == 'server"),
+            "unterminated string in \"profile == 'server\""
+        );
+        assert_eq!(error("(profile == 'server'"), "missing `)`");
+        assert_eq!(
+            error("profile == 'a' arch"),
+            "unexpected `arch` in \"profile == 'a' arch\""
+        );
+        assert_eq!(error("profile == 'a' &&"), "expression ends early");
+        assert_eq!(error("profile server"), "expected == or != after `profile`");
+        assert_eq!(
+            error("profile == arch"),
+            "expected a quoted string to compare `profile` to"
+        );
+    }
+}
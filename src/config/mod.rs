@@ -0,0 +1,138 @@
+//! Build configuration file.
+//!
+//! `stage3 build --config stage3.toml` reads build settings from a file
+//! instead of flags; flags given as well win over it. Library users load
+//! one with [`Stage3Config::load`] or fill in the struct themselves, and
+//! hand it to [`Stage3Builder::with_config`](crate::Stage3Builder::with_config).
+//!
+//! ```toml
+//! profile = "server"
+//! healthcheck = true
+//!
+//! [output]
+//! name = "levitateos-{profile}-{version}-{arch}{ext}"
+//! compression = "zstd"
+//!
+//! [binaries]
+//! add = ["strace", "tmux"]
+//! add-sbin = ["nft"]
+//! remove = ["uptime"]
+//!
+//! [binaries.arch.riscv64]
+//! remove = ["hwclock"]
+//!
+//! [[binaries.conditional]]
+//! when = 'profile == "server" && arch == "aarch64"'
+//! add = ["tmux"]
+//!
+//! [services]
+//! enable = ["chronyd.service"]
+//! user = ["pipewire.socket"]
+//!
+//! [[services.conditional]]
+//! when = 'profile != "minimal"'
+//! enable = ["sshd.service"]
+//!
+//! [etc]
+//! templates = "./templates"
+//!
+//! [dlopen]
+//! "libc.so.6" = ["/usr/lib64/libnss_sss.so.2"]
+//!
+//! [[conditional]]
+//! when = 'profile == "desktop"'
+//! accessibility = true
+//! templates = "./templates-desktop"
+//! ```
+//!
+//! Every key is optional, and unknown ones are rejected. Relative paths
+//! are relative to the file. Conditional tables apply to the builds their
+//! `when` expression matches (see [`condition`](crate::condition)).
+//! `stage3 validate-config` checks a file without building and prints it
+//! as the build reads it.
+//!
+//! Parsing and [`validate`](Stage3Config::validate) don't touch the
+//! filesystem (they live in `schema.rs`, which doesn't use `std::fs`);
+//! [`load`](Stage3Config::load) and [`check`](Stage3Config::check) add
+//! reading the file and checking the paths it names.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+mod schema;
+
+pub use schema::{
+    ConditionalServices, ConditionalSettings, EtcConfig, OutputConfig, ServiceConfig, Stage3Config,
+};
+
+/// Conventional config filename.
+pub const CONFIG_NAME: &str = "stage3.toml";
+
+impl Stage3Config {
+    /// Read a config file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut config =
+            Self::parse(&contents).with_context(|| format!("Invalid config {}", path.display()))?;
+        config.resolve_paths(path.parent().unwrap_or(Path::new(".")));
+        Ok(config)
+    }
+
+    /// Check what the file alone can't rule out: what
+    /// [`validate`](Self::validate) checks, and that the paths it names
+    /// exist.
+    pub fn check(&self) -> Result<()> {
+        self.validate()?;
+        if let Some(ref templates) = self.etc.templates {
+            check_templates("[etc]", templates)?;
+        }
+        for settings in &self.conditional {
+            if let Some(ref templates) = settings.templates {
+                let table = format!("[[conditional]] when = {:?}", settings.when.to_string());
+                check_templates(&table, templates)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_templates(table: &str, templates: &Path) -> Result<()> {
+    if !templates.is_dir() {
+        bail!(
+            "{} templates: {} is not a directory",
+            table,
+            templates.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditional_templates_are_relative_to_the_file() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("templates-desktop")).unwrap();
+        let path = root.path().join(CONFIG_NAME);
+        fs::write(
+            &path,
+            "[[conditional]]\nwhen = 'profile == \"desktop\"'\naccessibility = true\ntemplates = \"templates-desktop\"\n",
+        )
+        .unwrap();
+
+        let config = Stage3Config::load(&path).unwrap();
+        config.check().unwrap();
+        let settings = &config.conditional[0];
+        assert_eq!(settings.when.to_string(), "profile == \"desktop\"");
+        assert_eq!(settings.accessibility, Some(true));
+        assert_eq!(settings.healthcheck, None);
+        assert_eq!(
+            settings.templates.as_deref(),
+            Some(root.path().join("templates-desktop").as_path())
+        );
+    }
+}
@@ -0,0 +1,281 @@
+//! Parsing and validation of build configuration files.
+//!
+//! Nothing here touches the filesystem, so a config can be checked
+//! wherever its text is at hand; reading files and checking the paths a
+//! config names is left to [`config`](super).
+
+use anyhow::{bail, Context, Result};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::arch;
+use crate::archive::Compression;
+use crate::artifact::{self, ArtifactInfo, DEFAULT_PROFILE};
+use crate::clock::BuildClock;
+use crate::condition::Condition;
+use crate::rootfs::binaries::BinaryOverrides;
+use crate::rootfs::user_services::UserService;
+
+/// Settings of a `stage3.toml`; unset ones keep the builder's.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Stage3Config {
+    /// Build profile name
+    pub profile: Option<String>,
+    /// Architecture the source rootfs must be
+    pub arch: Option<String>,
+    pub strict: Option<bool>,
+    pub accessibility: Option<bool>,
+    pub healthcheck: Option<bool>,
+    pub offline_help: Option<bool>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub output: OutputConfig,
+    #[serde(skip_serializing_if = "is_default")]
+    pub binaries: BinaryOverrides,
+    #[serde(skip_serializing_if = "is_default")]
+    pub services: ServiceConfig,
+    #[serde(skip_serializing_if = "is_default")]
+    pub etc: EtcConfig,
+    /// Donor files loaded with dlopen, by the binary or library loading
+    /// them (see [`dlopen`](crate::dlopen))
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dlopen: BTreeMap<String, Vec<String>>,
+    /// Components and templates for the builds their condition matches
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conditional: Vec<ConditionalSettings>,
+}
+
+/// Components and templates of the builds `when` matches
+/// (`[[conditional]]`); later tables win over earlier ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConditionalSettings {
+    pub when: Condition,
+    pub accessibility: Option<bool>,
+    pub healthcheck: Option<bool>,
+    pub offline_help: Option<bool>,
+    /// Directory of templates overriding the generated /etc files
+    pub templates: Option<PathBuf>,
+}
+
+/// The `[output]` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutputConfig {
+    /// Output filename template
+    pub name: Option<String>,
+    pub compression: Option<Compression>,
+}
+
+/// The `[services]` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServiceConfig {
+    /// System units copied from the donor
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    /// System units copied from the donor and enabled
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub enable: Vec<String>,
+    /// User units enabled for every user
+    #[serde(deserialize_with = "parse_each", skip_serializing_if = "Vec::is_empty")]
+    pub user: Vec<UserService>,
+    /// Further units for the builds their condition matches
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conditional: Vec<ConditionalServices>,
+}
+
+/// Units shipped in the builds `when` matches (`[[services.conditional]]`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConditionalServices {
+    pub when: Condition,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub enable: Vec<String>,
+    #[serde(
+        default,
+        deserialize_with = "parse_each",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub user: Vec<UserService>,
+}
+
+/// The `[etc]` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EtcConfig {
+    /// Directory of templates overriding the generated /etc files
+    pub templates: Option<PathBuf>,
+}
+
+impl Stage3Config {
+    /// Make the relative paths of a config read from directory `dir`
+    /// relative to it.
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        let conditional = self.conditional.iter_mut();
+        for templates in conditional.filter_map(|settings| settings.templates.as_mut()) {
+            *templates = dir.join(&*templates);
+        }
+        if let Some(ref mut templates) = self.etc.templates {
+            *templates = dir.join(&*templates);
+        }
+    }
+
+    /// Check what parsing alone can't rule out: the architecture, the
+    /// output name's placeholders, and names both added and removed (or
+    /// added and enabled) in one table.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref arch) = self.arch {
+            check_arch("arch", arch)?;
+        }
+        if let Some(ref name) = self.output.name {
+            let arch = self.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+            let profile = self.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+            let mut info = ArtifactInfo::new(profile, arch, &BuildClock::fixed(0));
+            // Without a compression here, the flags' has to match the name
+            info.compression = self
+                .output
+                .compression
+                .or_else(|| Compression::from_filename(name))
+                .unwrap_or_default();
+            artifact::render_output_name(name, &info).context("[output] name")?;
+        }
+        check_binaries("[binaries]", &self.binaries)?;
+        check_disjoint(
+            "[services]",
+            ("add", &self.services.add),
+            ("enable", &self.services.enable),
+        )?;
+        for conditional in &self.services.conditional {
+            check_disjoint(
+                &format!(
+                    "[[services.conditional]] when = {:?}",
+                    conditional.when.to_string()
+                ),
+                ("add", &conditional.add),
+                ("enable", &conditional.enable),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The config as TOML, with paths resolved.
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Parse the contents of a config file.
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// Check the overrides of binaries table `table` and the tables in it.
+fn check_binaries(table: &str, overrides: &BinaryOverrides) -> Result<()> {
+    check_disjoint(
+        table,
+        ("add", &overrides.add),
+        ("remove", &overrides.remove),
+    )?;
+    check_disjoint(
+        table,
+        ("add-sbin", &overrides.add_sbin),
+        ("remove", &overrides.remove),
+    )?;
+    for (arch, extra) in &overrides.arch {
+        let table = format!("[binaries.arch.{}]", arch);
+        check_arch(&table, arch)?;
+        check_binaries(&table, extra)?;
+    }
+    for conditional in &overrides.conditional {
+        let table = format!(
+            "[[binaries.conditional]] when = {:?}",
+            conditional.when.to_string()
+        );
+        check_disjoint(
+            &table,
+            ("add", &conditional.add),
+            ("remove", &conditional.remove),
+        )?;
+        check_disjoint(
+            &table,
+            ("add-sbin", &conditional.add_sbin),
+            ("remove", &conditional.remove),
+        )?;
+    }
+    Ok(())
+}
+
+fn check_arch(key: &str, arch: &str) -> Result<()> {
+    if !arch::SUPPORTED.contains(&arch) {
+        bail!(
+            "{}: unsupported architecture {:?} (expected {})",
+            key,
+            arch,
+            arch::SUPPORTED.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Fail if a name is in both lists of `table`.
+fn check_disjoint(
+    table: &str,
+    (a_key, a): (&str, &[String]),
+    (b_key, b): (&str, &[String]),
+) -> Result<()> {
+    if let Some(name) = a.iter().find(|name| b.contains(name)) {
+        bail!("{}: {} is in both {} and {}", table, name, a_key, b_key);
+    }
+    Ok(())
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Deserialize a list of strings with `FromStr`.
+fn parse_each<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation_needs_no_files() {
+        let config = Stage3Config::parse(
+            "arch = \"aarch64\"\n[binaries]\nadd = [\"tmux\"]\n[etc]\ntemplates = \"missing\"\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let config =
+            Stage3Config::parse("[binaries]\nadd = [\"tmux\"]\nremove = [\"tmux\"]\n").unwrap();
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[binaries]: tmux is in both add and remove"
+        );
+        let err = Stage3Config::parse("arch = \"sparc\"\n")
+            .unwrap()
+            .validate()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("arch: unsupported architecture"));
+    }
+}
@@ -0,0 +1,506 @@
+//! Console output levels and build status lines.
+//!
+//! A build prints one status line per component and ends with a table of
+//! every warning and skipped item from the [`BuildReport`], so nothing
+//! important scrolls by between routine lines. The routine per-file lines
+//! are still there with `--verbose`, printed through [`detail!`].
+//!
+//! All of it goes through `tracing`: [`detail!`] is a debug event,
+//! [`status!`] an info event, failures and the warnings table are warnings
+//! or errors. The `stage3` binary installs a plain subscriber on stderr
+//! with [`init`]; library users install their own or get silence. Command
+//! output (JSON, listings, `print()` methods) stays on stdout.
+//!
+//! While a step runs on a terminal, a spinner shows its name, elapsed time
+//! and how many binaries and libraries it copied so far. Log lines suspend
+//! the spinner; without a terminal there are only the plain lines.
+//!
+//! The subscriber colors warnings yellow and errors red, section headings
+//! bold and the final summary by outcome. Color is used on a terminal
+//! unless `NO_COLOR` is set or `--no-color` given. It is decided in
+//! [`init`], so library users with their own subscriber get plain text.
+
+use anyhow::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::context::BuildContext;
+use crate::inspect::human_size;
+use crate::progress::{ProgressEvent, StepStatus};
+use crate::report::{BuildReport, Diagnostic, Severity};
+use crate::timings::{StagingSnapshot, Timings};
+
+/// How much a command prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Failures and the final warnings table only
+    Quiet,
+    /// Status lines per component
+    Normal,
+    /// Everything, including per-file detail lines
+    Verbose,
+}
+
+impl Verbosity {
+    /// The most verbose `tracing` level shown at this verbosity.
+    pub fn level(self) -> LevelFilter {
+        match self {
+            Verbosity::Quiet => LevelFilter::WARN,
+            Verbosity::Normal => LevelFilter::INFO,
+            Verbosity::Verbose => LevelFilter::DEBUG,
+        }
+    }
+}
+
+/// Install the `stage3` binary's subscriber: bare messages on stderr,
+/// colored by level when `color` is allowed and stderr is a terminal.
+///
+/// Fails if the process already has a global subscriber.
+pub fn init(verbosity: Verbosity, color: bool) -> Result<()> {
+    COLOR.store(color && terminal_color(), Ordering::Relaxed);
+    let format = tracing_subscriber::fmt::format()
+        .without_time()
+        .with_level(false)
+        .with_target(false);
+    tracing_subscriber::fmt()
+        .with_max_level(verbosity.level())
+        .with_writer(|| StepBarWriter)
+        // Status lines carry their own colors on a terminal
+        .with_ansi_sanitization(!color_enabled())
+        .event_format(LevelColors(format))
+        .try_init()
+        .map_err(|err| anyhow::anyhow!("Failed to install the log subscriber: {}", err))
+}
+
+/// Event format painting warnings and errors, except lines that already
+/// carry colors of their own (the warnings table).
+struct LevelColors<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for LevelColors<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let color = match *event.metadata().level() {
+            Level::ERROR => Color::Red,
+            Level::WARN => Color::Yellow,
+            _ => return self.0.format_event(ctx, writer, event),
+        };
+        if !color_enabled() || paints_itself(event) {
+            return self.0.format_event(ctx, writer, event);
+        }
+        let mut line = String::new();
+        self.0.format_event(ctx, Writer::new(&mut line), event)?;
+        writeln!(writer, "{}", paint(line.trim_end_matches('\n'), color))
+    }
+}
+
+/// Whether the event's message contains escape codes.
+fn paints_itself(event: &Event<'_>) -> bool {
+    struct Escapes(bool);
+    impl Visit for Escapes {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0 |= field.name() == "message" && value.contains('\x1b');
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0 |= field.name() == "message" && format!("{:?}", value).contains('\x1b');
+        }
+    }
+    let mut escapes = Escapes(false);
+    event.record(&mut escapes);
+    escapes.0
+}
+
+/// Stderr, with the running step's spinner hidden while a line is written.
+struct StepBarWriter;
+
+impl Write for StepBarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match STEP_BAR.lock().unwrap().as_ref() {
+            Some(step) => step.bar.suspend(|| io::stderr().write(buf)),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Spinner of the step running now, if stderr is a terminal.
+static STEP_BAR: Mutex<Option<StepBar>> = Mutex::new(None);
+
+/// Whether steps show a spinner; off while several builds or steps share
+/// stderr.
+static STEP_BAR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Allow or forbid the step spinner, returning whether it was allowed.
+pub fn set_step_bar(enabled: bool) -> bool {
+    STEP_BAR_ENABLED.swap(enabled, Ordering::Relaxed)
+}
+
+struct StepBar {
+    bar: ProgressBar,
+    name: String,
+    binaries: usize,
+    libraries: usize,
+}
+
+impl StepBar {
+    fn update(&self) {
+        let mut counts = Vec::new();
+        match self.binaries {
+            0 => {}
+            1 => counts.push("1 binary".to_string()),
+            n => counts.push(format!("{} binaries", n)),
+        }
+        match self.libraries {
+            0 => {}
+            1 => counts.push("1 library".to_string()),
+            n => counts.push(format!("{} libraries", n)),
+        }
+        if counts.is_empty() {
+            self.bar.set_message(self.name.clone());
+        } else {
+            self.bar
+                .set_message(format!("{}: {} copied", self.name, counts.join(", ")));
+        }
+    }
+}
+
+/// What a copy routine copied, for the running step's spinner.
+#[derive(Debug, Clone, Copy)]
+pub enum Copied {
+    Binary,
+    Library,
+}
+
+/// Count a copied file on the running step's spinner.
+pub fn copied(item: Copied) {
+    if let Some(step) = STEP_BAR.lock().unwrap().as_mut() {
+        match item {
+            Copied::Binary => step.binaries += 1,
+            Copied::Library => step.libraries += 1,
+        }
+        step.update();
+    }
+}
+
+/// Show a spinner for `name`, like the status lines only on a terminal
+/// and unless `--quiet`.
+fn start_step_bar(name: &str) {
+    if !STEP_BAR_ENABLED.load(Ordering::Relaxed)
+        || !io::stderr().is_terminal()
+        || !tracing::enabled!(tracing::Level::INFO)
+    {
+        return;
+    }
+    let bar = ProgressBar::new_spinner().with_style(
+        ProgressStyle::with_template("  {spinner} {msg} ({elapsed})")
+            .expect("spinner template is valid"),
+    );
+    bar.enable_steady_tick(Duration::from_millis(100));
+    let step = StepBar {
+        bar,
+        name: name.to_string(),
+        binaries: 0,
+        libraries: 0,
+    };
+    step.update();
+    *STEP_BAR.lock().unwrap() = Some(step);
+}
+
+fn finish_step_bar() {
+    if let Some(step) = STEP_BAR.lock().unwrap().take() {
+        step.bar.finish_and_clear();
+    }
+}
+
+/// Log a routine line that only shows with `--verbose`.
+#[macro_export]
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        $crate::tracing::debug!($($arg)*)
+    };
+}
+
+/// Log a line unless `--quiet` was given.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::tracing::info!($($arg)*)
+    };
+}
+
+/// Print the heading of a group of status lines.
+pub fn section(title: &str) {
+    status!("\n{}", paint(&format!("{}:", title), Color::Bold));
+}
+
+/// Whether [`init`] turned color on; off for library users.
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Whether output may use ANSI colors.
+pub fn color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Whether the terminal allows color.
+///
+/// See <https://no-color.org>: any non-empty `NO_COLOR` disables color.
+/// Status lines and command output can go to different streams, so both
+/// have to be a terminal.
+fn terminal_color() -> bool {
+    env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && io::stdout().is_terminal()
+        && io::stderr().is_terminal()
+}
+
+/// Terminal colors used by status output.
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+    Green,
+    Yellow,
+    Red,
+    Dim,
+    Bold,
+}
+
+/// Wrap `text` in the escape codes for `color` when color is enabled.
+pub fn paint(text: &str, color: Color) -> String {
+    if !color_enabled() {
+        return text.to_string();
+    }
+    let code = match color {
+        Color::Green => "32",
+        Color::Yellow => "33",
+        Color::Red => "1;31",
+        Color::Dim => "2",
+        Color::Bold => "1",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// Run one build component and print its status line.
+///
+/// The status comes from what the component recorded in the report: `ok`,
+/// `warn` with counts, or `FAIL` when it recorded errors or returned one.
+/// The same goes to the context's progress reporter as start, diagnostic
+/// and finish events.
+pub fn step<T>(ctx: &BuildContext, name: &str, run: impl FnOnce() -> Result<T>) -> Result<T> {
+    ctx.cancel.check()?;
+    let report = &ctx.report;
+    let before = report.len();
+    detail!("{}...", name);
+    ctx.progress.report(&ProgressEvent::StepStarted {
+        step: name.to_string(),
+    });
+    let staged = StagingSnapshot::take(&ctx.staging);
+    let started = Instant::now();
+    start_step_bar(name);
+    // A cancelled step fails, whatever it returned
+    let result = run().and_then(|value| ctx.cancel.check().map(|()| value));
+    finish_step_bar();
+    let elapsed = started.elapsed();
+    ctx.timings
+        .record(name, elapsed, &staged, &StagingSnapshot::take(&ctx.staging));
+
+    let recorded = report.diagnostics_since(before);
+    let count = |severity| recorded.iter().filter(|d| d.severity == severity).count();
+    let (errors, warnings, skipped) = (
+        count(Severity::Error),
+        count(Severity::Warning),
+        count(Severity::Skipped),
+    );
+    for diagnostic in recorded {
+        ctx.progress.report(&ProgressEvent::Diagnostic {
+            step: name.to_string(),
+            diagnostic,
+        });
+    }
+    let status = StepStatus::from_counts(result.is_err(), errors, warnings, skipped);
+    ctx.progress.report(&ProgressEvent::StepFinished {
+        step: name.to_string(),
+        status,
+        errors,
+        warnings,
+        skipped,
+        elapsed_ms: elapsed.as_millis() as u64,
+    });
+
+    match status {
+        StepStatus::Fail => {
+            let suffix = match errors {
+                0 => String::new(),
+                n => format!(" ({})", plural(n, "error")),
+            };
+            // Failures show even with --quiet
+            tracing::error!("  {} {}{}", paint("FAIL", Color::Red), name, suffix);
+        }
+        StepStatus::Ok => status!("  {} {}", paint(" ok ", Color::Green), name),
+        StepStatus::Warn => {
+            let mut counts = Vec::new();
+            if warnings > 0 {
+                counts.push(plural(warnings, "warning"));
+            }
+            if skipped > 0 {
+                counts.push(format!("{} skipped", skipped));
+            }
+            status!(
+                "  {} {} ({})",
+                paint("warn", Color::Yellow),
+                name,
+                counts.join(", ")
+            );
+        }
+    }
+    result
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        n => format!("{} {}s", n, noun),
+    }
+}
+
+/// Log the time, files and bytes of every step, slowest first, and the
+/// compression time.
+pub fn print_timings(timings: &Timings) {
+    if timings.steps.is_empty() {
+        return;
+    }
+    let seconds = |ms: u64| format!("{:.1}s", ms as f64 / 1000.0);
+    let mut steps: Vec<_> = timings.steps.iter().collect();
+    // Stable, so steps of equal time keep their build order
+    steps.sort_by_key(|s| std::cmp::Reverse(s.elapsed_ms));
+    let width = steps
+        .iter()
+        .map(|s| s.step.len())
+        .max()
+        .unwrap_or(0)
+        .max("STEP".len());
+
+    section("Build profile");
+    status!(
+        "  {:<width$}  {:>8}  {:>6}  {:>10}",
+        "STEP",
+        "TIME",
+        "FILES",
+        "WRITTEN"
+    );
+    for step in steps {
+        status!(
+            "  {:<width$}  {:>8}  {:>6}  {:>10}",
+            step.step,
+            seconds(step.elapsed_ms),
+            step.files,
+            human_size(step.bytes)
+        );
+    }
+    if let Some(ref compression) = timings.compression {
+        status!(
+            "  {:<width$}  {:>8}  {} -> {} ({})",
+            "compression",
+            seconds(compression.elapsed_ms),
+            human_size(compression.input_bytes),
+            human_size(compression.output_bytes),
+            compression.compression.extension()
+        );
+    }
+    status!("  {:<width$}  {:>8}", "total", seconds(timings.total_ms));
+}
+
+/// Log how many binaries, libraries, PAM modules and units were left out,
+/// pointing at the full list in `path`.
+pub fn print_skipped(report: &BuildReport, path: &Path) {
+    let counts = report.skipped_counts();
+    if counts.is_empty() {
+        return;
+    }
+    let total: usize = counts.values().sum();
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(check, count)| format!("{} {}", count, check))
+        .collect();
+    tracing::warn!(
+        "Left out {} items ({}), listed in {}",
+        total,
+        counts.join(", "),
+        path.display()
+    );
+}
+
+/// Log every diagnostic in the report as a table, errors first.
+///
+/// The table is logged at warning level so it shows even with `--quiet`.
+pub fn print_summary(report: &BuildReport) {
+    let mut diagnostics = report.diagnostics();
+    if diagnostics.is_empty() {
+        status!("\n{}", paint("No warnings.", Color::Green));
+        return;
+    }
+    // Stable, so findings of one severity keep their build order
+    diagnostics.sort_by_key(|d| std::cmp::Reverse(d.severity));
+
+    let width = |field: fn(&Diagnostic) -> usize| diagnostics.iter().map(field).max().unwrap_or(0);
+    let check_width = width(|d| d.check.len()).max("CHECK".len());
+    let subject_width = width(|d| d.subject.as_deref().map_or(1, str::len))
+        .max("SUBJECT".len())
+        .min(48);
+
+    tracing::warn!(
+        "\n{:<7}  {:<check_width$}  {:<subject_width$}  MESSAGE",
+        "LEVEL",
+        "CHECK",
+        "SUBJECT"
+    );
+    for diagnostic in &diagnostics {
+        let (label, color) = match diagnostic.severity {
+            Severity::Error => ("error", Color::Red),
+            Severity::Warning => ("warning", Color::Yellow),
+            Severity::Skipped => ("skipped", Color::Dim),
+        };
+        tracing::warn!(
+            "{}  {:<check_width$}  {:<subject_width$}  {}",
+            paint(&format!("{:<7}", label), color),
+            diagnostic.check,
+            diagnostic.subject.as_deref().unwrap_or("-"),
+            diagnostic.message
+        );
+    }
+    let errors = report.count(Severity::Error);
+    let warnings = report.count(Severity::Warning);
+    let color = match (errors, warnings) {
+        (0, 0) => Color::Green,
+        (0, _) => Color::Yellow,
+        _ => Color::Red,
+    };
+    let counts = format!(
+        "{}, {}, {} skipped",
+        plural(errors, "error"),
+        plural(warnings, "warning"),
+        report.count(Severity::Skipped)
+    );
+    tracing::warn!("\n{}", paint(&counts, color));
+}
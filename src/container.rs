@@ -0,0 +1,88 @@
+//! Container-safe build checks.
+//!
+//! The build never needs root: ownership is assigned when the archive is
+//! written rather than with chown, and nothing in staging requires mknod.
+//! These checks enforce that contract when `--container-safe` is set, so
+//! the build behaves the same in an unprivileged CI pod as on a workstation.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::process::Stdio;
+use walkdir::WalkDir;
+
+use crate::detail;
+use crate::sandbox::Sandbox;
+
+/// Host tools the build shells out to.
+const REQUIRED_TOOLS: &[&str] = &["tar", "xz"];
+
+/// Check the host can run an unprivileged build before any work is done.
+///
+/// With `ldd`, the build resolves libraries with the host's `ldd`, so it
+/// has to be there too.
+pub fn preflight(output_dir: &Path, ldd: bool, sandbox: Sandbox) -> Result<()> {
+    detail!("Running container-safe preflight...");
+
+    let mut missing = Vec::new();
+    let ldd = ldd.then_some("ldd");
+    for tool in REQUIRED_TOOLS.iter().chain(ldd.as_ref()) {
+        let found = sandbox
+            .command("sh")
+            .args(["-c", &format!("command -v {}", tool)])
+            .stdout(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        if !found {
+            missing.push(*tool);
+        }
+    }
+    if !missing.is_empty() {
+        anyhow::bail!("Required host tools not found: {}", missing.join(", "));
+    }
+
+    // The output directory must be writable by the current user
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Cannot create output directory: {}", output_dir.display()))?;
+    let probe = output_dir.join(".stage3-write-test");
+    fs::write(&probe, b"")
+        .with_context(|| format!("Output directory not writable: {}", output_dir.display()))?;
+    fs::remove_file(&probe)?;
+
+    detail!("  Preflight passed");
+    Ok(())
+}
+
+/// Fail if staging contains anything that can only be created with privileges.
+pub fn audit_staging(staging: &Path) -> Result<()> {
+    detail!("Auditing staging for privileged entries...");
+
+    let mut special = Vec::new();
+    for entry in WalkDir::new(staging).min_depth(1) {
+        let entry = entry?;
+        let file_type = entry.file_type();
+        if file_type.is_block_device()
+            || file_type.is_char_device()
+            || file_type.is_fifo()
+            || file_type.is_socket()
+        {
+            special.push(entry.path().strip_prefix(staging)?.to_path_buf());
+        }
+    }
+
+    if !special.is_empty() {
+        tracing::error!("  Special files in staging:");
+        for path in &special {
+            tracing::error!("    - /{}", path.display());
+        }
+        anyhow::bail!(
+            "Container-safe build produced {} special files requiring mknod",
+            special.len()
+        );
+    }
+
+    detail!("  No privileged entries");
+    Ok(())
+}
@@ -1,26 +1,161 @@
 //! Build context shared across all stage3 modules.
 
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::arch::LibraryLayout;
+use crate::artifact::{ArtifactInfo, DEFAULT_PROFILE};
+use crate::cache::BuildCache;
+use crate::cancel::CancelToken;
+use crate::clock::BuildClock;
+use crate::dlopen::{builtin_hints, DlopenHints};
+use crate::donor::{DonorTree, PackageSource};
+use crate::fakeroot::MetadataLayer;
+use crate::fragments::FragmentCache;
+use crate::hardlink::DonorLinks;
+use crate::linker::LibraryCache;
+use crate::progress::{NoProgress, ProgressReporter};
+use crate::provenance::{Provenance, SourceIdentity};
+use crate::remap::PathRemaps;
+use crate::report::{BuildReport, Severity};
+use crate::rootfs::recipe::UpgradeTimer;
+use crate::rootfs::systemd::RandomSeedPolicy;
+use crate::sandbox::Sandbox;
+use crate::store::ObjectStore;
+use crate::templates::Templates;
+use crate::timings::BuildTimings;
+use crate::xattrs::SelinuxLabels;
+
+/// What a build is for and how strictly, consulted by every step.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// Target architecture (`x86_64`, `aarch64`)
+    pub arch: String,
+    /// Build profile name
+    pub profile: String,
+    /// Where the donor, and so the rootfs, keeps its libraries
+    pub layout: LibraryLayout,
+    /// Treat validation findings and files the build could not copy as
+    /// errors instead of warnings
+    pub strict: bool,
+    /// Only plan the build (`--dry-run`). Built-in steps never run with
+    /// it set; a custom [`Step`](crate::steps::Step) that does should not
+    /// write to staging
+    pub dry_run: bool,
+    /// Ownership policy: only use operations that work unprivileged inside
+    /// a container, leaving ownership and device nodes to the archive
+    pub container_safe: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            arch: std::env::consts::ARCH.to_string(),
+            profile: DEFAULT_PROFILE.to_string(),
+            layout: LibraryLayout::default(),
+            strict: false,
+            dry_run: false,
+            container_safe: false,
+        }
+    }
+}
 
 /// Shared context for stage3 build operations.
 pub struct BuildContext {
     /// Path to the source rootfs (Rocky rootfs with binaries)
     pub source: PathBuf,
+    /// What every donor file is read through (the source tree by default)
+    pub package_source: Arc<dyn PackageSource>,
     /// Path to the staging directory (where we build the stage3)
     pub staging: PathBuf,
     /// Path to the output directory (for the final tarball)
     pub output: PathBuf,
     /// Path to the recipe binary (optional)
     pub recipe_binary: Option<PathBuf>,
+    /// Target, strictness and other knobs every step consults
+    pub options: BuildOptions,
+    /// Intended ownership, modes and device nodes applied at archive time
+    pub metadata: MetadataLayer,
+    /// What to ship at /var/lib/systemd/random-seed
+    pub random_seed: RandomSeedPolicy,
+    /// Diagnostics collected by build checks
+    pub report: BuildReport,
+    /// Time and output of every step
+    pub timings: Arc<BuildTimings>,
+    /// Libraries resolved and copied so far, shared by every binary
+    pub libraries: Arc<LibraryCache>,
+    /// Files binaries load with dlopen, copied along with them
+    pub dlopen_hints: Arc<DlopenHints>,
+    /// Donor copies kept from the last build, with `--incremental`
+    pub cache: Option<Arc<BuildCache>>,
+    /// Staged copies of donor files with several names
+    pub donor_links: Arc<DonorLinks>,
+    /// Where staged binaries and libraries are linked from, with
+    /// `--object-store`
+    pub store: Option<Arc<ObjectStore>>,
+    /// Where cached component fragments are kept, with `--fragment-cache`
+    pub fragments: Option<Arc<FragmentCache>>,
+    /// Resolve library dependencies with the host's ldd instead of reading
+    /// the ELF files
+    pub ldd: bool,
+    /// How helpers are spawned, isolated from the network with `--offline`
+    pub sandbox: Sandbox,
+    /// Carry POSIX ACLs of donor files into the tarball
+    pub acls: bool,
+    /// Keep, drop or have the target relabel SELinux labels
+    pub selinux: SelinuxLabels,
+    /// Allow copying libraries missing from the donor from the build host
+    pub host_fallback: bool,
+    /// Unattended upgrade timer to install (none by default)
+    pub upgrade_timer: Option<UpgradeTimer>,
+    /// Timestamp for everything the build writes
+    pub clock: BuildClock,
+    /// Templates for the generated configuration files
+    pub templates: Arc<Templates>,
+    /// Build provenance written to /etc/levitate-release and the manifest
+    pub provenance: Provenance,
+    /// Where donor paths land in the rootfs
+    pub remaps: PathRemaps,
+    /// Receives start, diagnostic and finish events of every build step
+    pub progress: Arc<dyn ProgressReporter>,
+    /// Checked between steps; stops the build once cancelled
+    pub cancel: CancelToken,
 }
 
 impl BuildContext {
     pub fn new(source: PathBuf, staging: PathBuf, output: PathBuf) -> Self {
+        let clock = BuildClock::system();
+        let info = ArtifactInfo::new(DEFAULT_PROFILE, std::env::consts::ARCH, &clock);
         Self {
+            package_source: Arc::new(DonorTree::new(&source)),
             source,
             staging,
             output,
             recipe_binary: None,
+            options: BuildOptions::default(),
+            metadata: MetadataLayer::default(),
+            random_seed: RandomSeedPolicy::default(),
+            report: BuildReport::default(),
+            timings: Arc::default(),
+            libraries: Arc::default(),
+            dlopen_hints: Arc::new(DlopenHints::new(builtin_hints())),
+            cache: None,
+            donor_links: Arc::default(),
+            store: None,
+            fragments: None,
+            ldd: false,
+            sandbox: Sandbox::default(),
+            acls: false,
+            selinux: SelinuxLabels::Drop,
+            host_fallback: true,
+            upgrade_timer: None,
+            clock,
+            templates: Arc::new(Templates::new(&info, None).expect("built-in templates are valid")),
+            provenance: Provenance::new(&info, &clock, SourceIdentity::default()),
+            remaps: PathRemaps::default(),
+            progress: Arc::new(NoProgress),
+            cancel: CancelToken::new(),
         }
     }
 
@@ -28,4 +163,155 @@ impl BuildContext {
         self.recipe_binary = Some(recipe_binary);
         self
     }
+
+    pub fn with_options(mut self, options: BuildOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_random_seed(mut self, random_seed: RandomSeedPolicy) -> Self {
+        self.random_seed = random_seed;
+        self
+    }
+
+    pub fn with_host_fallback(mut self, host_fallback: bool) -> Self {
+        self.host_fallback = host_fallback;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Option<BuildCache>) -> Self {
+        self.cache = cache.map(Arc::new);
+        self
+    }
+
+    pub fn with_store(mut self, store: Option<ObjectStore>) -> Self {
+        self.store = store.map(Arc::new);
+        self
+    }
+
+    pub fn with_fragments(mut self, fragments: Option<FragmentCache>) -> Self {
+        self.fragments = fragments.map(Arc::new);
+        self
+    }
+
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    pub fn with_acls(mut self, acls: bool) -> Self {
+        self.acls = acls;
+        self
+    }
+
+    pub fn with_selinux(mut self, selinux: SelinuxLabels) -> Self {
+        self.selinux = selinux;
+        self
+    }
+
+    pub fn with_ldd(mut self, ldd: bool) -> Self {
+        self.ldd = ldd;
+        self
+    }
+
+    pub fn with_dlopen_hints(mut self, hints: BTreeMap<String, Vec<String>>) -> Self {
+        self.dlopen_hints = Arc::new(DlopenHints::new(hints));
+        self
+    }
+
+    pub fn with_upgrade_timer(mut self, upgrade_timer: Option<UpgradeTimer>) -> Self {
+        self.upgrade_timer = upgrade_timer;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: BuildClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = Arc::new(templates);
+        self
+    }
+
+    pub fn with_provenance(mut self, provenance: Provenance) -> Self {
+        self.provenance = provenance;
+        self
+    }
+
+    pub fn with_remaps(mut self, remaps: PathRemaps) -> Self {
+        self.remaps = remaps;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressReporter>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_package_source(mut self, package_source: Arc<dyn PackageSource>) -> Self {
+        self.package_source = package_source;
+        self
+    }
+
+    pub fn with_libraries(mut self, libraries: Arc<LibraryCache>) -> Self {
+        self.libraries = libraries;
+        self
+    }
+
+    /// A context for one of several steps running at once: the same build,
+    /// with a report of its own and a copy of the metadata recorded so far,
+    /// so what the step records can be told apart from the others.
+    pub fn fork(&self) -> Self {
+        Self {
+            source: self.source.clone(),
+            package_source: self.package_source.clone(),
+            staging: self.staging.clone(),
+            output: self.output.clone(),
+            recipe_binary: self.recipe_binary.clone(),
+            options: self.options.clone(),
+            metadata: self.metadata.snapshot(),
+            random_seed: self.random_seed,
+            report: BuildReport::default(),
+            timings: self.timings.clone(),
+            libraries: self.libraries.clone(),
+            dlopen_hints: self.dlopen_hints.clone(),
+            cache: self.cache.clone(),
+            donor_links: self.donor_links.clone(),
+            store: self.store.clone(),
+            fragments: self.fragments.clone(),
+            ldd: self.ldd,
+            sandbox: self.sandbox,
+            acls: self.acls,
+            selinux: self.selinux,
+            host_fallback: self.host_fallback,
+            upgrade_timer: self.upgrade_timer.clone(),
+            clock: self.clock,
+            templates: self.templates.clone(),
+            provenance: self.provenance.clone(),
+            remaps: self.remaps.clone(),
+            progress: self.progress.clone(),
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    /// Severity for a binary, library, PAM module or unit the build could
+    /// not copy: an error under `--strict`, `lenient` otherwise.
+    pub fn missing_severity(&self, lenient: Severity) -> Severity {
+        if self.options.strict {
+            Severity::Error
+        } else {
+            lenient
+        }
+    }
+
+    /// Staging path for the donor file at rootfs-relative `path`, remapped.
+    pub fn target(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.staging.join(self.remaps.apply(path.as_ref()))
+    }
 }
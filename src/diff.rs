@@ -0,0 +1,315 @@
+//! Comparison of two stage3 tarballs, or of a build against a baseline.
+//!
+//! Both archives are streamed once into manifests, so comparing them needs
+//! no extraction and sees metadata (modes, ownership, link targets) that
+//! `diff -r` on extracted trees misses. A build given `--baseline` compares
+//! its manifest against the one a previous build wrote, so a release review
+//! starts from a generated report.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::inspect::{self, human_size};
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::status;
+
+/// Filename of the baseline comparison written next to the tarball.
+pub const BASELINE_DIFF_NAME: &str = "levitateos-stage3.baseline-diff.json";
+
+/// An entry present in both tarballs with different attributes.
+#[derive(Debug, Serialize)]
+pub struct ChangedEntry {
+    pub path: String,
+    /// Size change in bytes
+    pub size_delta: i64,
+    /// Human-readable differences (`mode 0644 -> 0755`, ...)
+    pub changes: Vec<String>,
+}
+
+/// Differences between two tarballs.
+#[derive(Debug, Default, Serialize)]
+pub struct TarballDiff {
+    pub added: Vec<ManifestEntry>,
+    pub removed: Vec<ManifestEntry>,
+    pub changed: Vec<ChangedEntry>,
+}
+
+impl TarballDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Net change of the uncompressed contents in bytes.
+    pub fn size_delta(&self) -> i64 {
+        let added: i64 = self.added.iter().map(|e| e.size as i64).sum();
+        let removed: i64 = self.removed.iter().map(|e| e.size as i64).sum();
+        let changed: i64 = self.changed.iter().map(|c| c.size_delta).sum();
+        added - removed + changed
+    }
+}
+
+/// Compare the entries of two tarballs.
+pub fn diff_tarballs(old: &Path, new: &Path) -> Result<TarballDiff> {
+    let old = manifest::from_tarball(old)?;
+    let new = manifest::from_tarball(new)?;
+    Ok(diff_manifests(&old, &new))
+}
+
+/// Compare the entries of two manifests.
+pub fn diff_manifests(old: &Manifest, new: &Manifest) -> TarballDiff {
+    let mut remaining: BTreeMap<&str, &ManifestEntry> =
+        old.entries.iter().map(|e| (e.path.as_str(), e)).collect();
+    let mut diff = TarballDiff::default();
+
+    for entry in &new.entries {
+        match remaining.remove(entry.path.as_str()) {
+            Some(before) => {
+                let changes = entry_changes(before, entry);
+                if !changes.is_empty() {
+                    diff.changed.push(ChangedEntry {
+                        path: entry.path.clone(),
+                        size_delta: entry.size as i64 - before.size as i64,
+                        changes,
+                    });
+                }
+            }
+            None => diff.added.push(entry.clone()),
+        }
+    }
+    diff.removed = remaining.into_values().cloned().collect();
+
+    diff.added.sort_by(|a, b| a.path.cmp(&b.path));
+    diff.changed.sort_by(|a, b| a.path.cmp(&b.path));
+    diff
+}
+
+fn entry_changes(before: &ManifestEntry, after: &ManifestEntry) -> Vec<String> {
+    let mut changes = Vec::new();
+    if before.kind != after.kind {
+        changes.push(format!("type {:?} -> {:?}", before.kind, after.kind));
+    }
+    if before.size != after.size {
+        changes.push(format!(
+            "size {} -> {} ({})",
+            before.size,
+            after.size,
+            signed_size(after.size as i64 - before.size as i64)
+        ));
+    }
+    if before.sha256 != after.sha256 && before.kind == after.kind {
+        changes.push("contents changed".to_string());
+    }
+    if before.mode != after.mode {
+        changes.push(format!("mode {} -> {}", before.mode, after.mode));
+    }
+    if (before.uid, before.gid) != (after.uid, after.gid) {
+        changes.push(format!(
+            "owner {}:{} -> {}:{}",
+            before.uid, before.gid, after.uid, after.gid
+        ));
+    }
+    if before.target != after.target {
+        changes.push(format!(
+            "target {} -> {}",
+            before.target.as_deref().unwrap_or("-"),
+            after.target.as_deref().unwrap_or("-")
+        ));
+    }
+    if before.device != after.device {
+        changes.push(format!(
+            "device {} -> {}",
+            before.device.as_deref().unwrap_or("-"),
+            after.device.as_deref().unwrap_or("-")
+        ));
+    }
+    if before.needed != after.needed && before.needed.is_some() {
+        let libraries = |entry: &ManifestEntry| -> BTreeSet<String> {
+            entry.needed.iter().flatten().cloned().collect()
+        };
+        let (old, new) = (libraries(before), libraries(after));
+        let mut needs: Vec<String> = new.difference(&old).map(|l| format!("+{}", l)).collect();
+        needs.extend(old.difference(&new).map(|l| format!("-{}", l)));
+        if !needs.is_empty() {
+            changes.push(format!("needs {}", needs.join(" ")));
+        }
+    }
+    changes
+}
+
+/// Format a byte delta with its sign (`+1.2 KiB`, `-300 B`).
+fn signed_size(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    let bytes = delta.unsigned_abs();
+    let human = match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    };
+    format!("{}{}", sign, human)
+}
+
+/// Compare two tarballs and print the differences.
+pub fn print_diff(old: &Path, new: &Path) -> Result<()> {
+    println!("Comparing {} -> {}...", old.display(), new.display());
+
+    let diff = diff_tarballs(old, new)?;
+    if diff.is_empty() {
+        println!("  No differences");
+        return Ok(());
+    }
+
+    for entry in &diff.added {
+        println!("  + /{} ({})", entry.path, signed_size(entry.size as i64));
+    }
+    for entry in &diff.removed {
+        println!(
+            "  - /{} ({})",
+            entry.path,
+            signed_size(-(entry.size as i64))
+        );
+    }
+    for entry in &diff.changed {
+        println!("  ~ /{}: {}", entry.path, entry.changes.join(", "));
+    }
+
+    println!(
+        "\n  {} added, {} removed, {} changed, {} total",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len(),
+        signed_size(diff.size_delta())
+    );
+    Ok(())
+}
+
+/// Size of one `inspect` category before and after.
+#[derive(Debug, Serialize)]
+pub struct CategoryDelta {
+    pub name: &'static str,
+    pub before: u64,
+    pub after: u64,
+}
+
+/// What changed in a build compared to a baseline manifest.
+#[derive(Debug, Serialize)]
+pub struct BaselineComparison {
+    /// Tarball the baseline manifest describes
+    pub baseline: String,
+    #[serde(flatten)]
+    pub diff: TarballDiff,
+    /// Categories whose size changed, largest change first
+    pub categories: Vec<CategoryDelta>,
+    /// Libraries nothing in the baseline linked against, with the files
+    /// that need them now; `None` if the baseline manifest predates
+    /// dependency tracking
+    pub new_libraries: Option<BTreeMap<String, Vec<String>>>,
+}
+
+/// Compare a build's manifest against a baseline manifest.
+pub fn compare_to_baseline(baseline: &Manifest, current: &Manifest) -> BaselineComparison {
+    let sizes = |manifest: &Manifest| {
+        let mut sizes: BTreeMap<&'static str, u64> = BTreeMap::new();
+        for entry in &manifest.entries {
+            *sizes.entry(inspect::categorize(&entry.path)).or_default() += entry.size;
+        }
+        sizes
+    };
+    let (before, after) = (sizes(baseline), sizes(current));
+    let names: BTreeSet<&'static str> = before.keys().chain(after.keys()).copied().collect();
+    let mut categories: Vec<CategoryDelta> = names
+        .into_iter()
+        .map(|name| CategoryDelta {
+            name,
+            before: before.get(name).copied().unwrap_or(0),
+            after: after.get(name).copied().unwrap_or(0),
+        })
+        .filter(|c| c.before != c.after)
+        .collect();
+    categories.sort_by_key(|c| std::cmp::Reverse((c.after as i64 - c.before as i64).abs()));
+
+    let tracked = baseline.entries.iter().any(|e| e.needed.is_some());
+    let new_libraries = tracked.then(|| {
+        let known: BTreeSet<&str> = baseline
+            .entries
+            .iter()
+            .flat_map(|e| e.needed.iter().flatten())
+            .map(String::as_str)
+            .collect();
+        let mut new_libraries: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for entry in &current.entries {
+            for library in entry.needed.iter().flatten() {
+                if !known.contains(library.as_str()) {
+                    new_libraries
+                        .entry(library.clone())
+                        .or_default()
+                        .push(entry.path.clone());
+                }
+            }
+        }
+        new_libraries
+    });
+
+    BaselineComparison {
+        baseline: baseline.tarball.clone(),
+        diff: diff_manifests(baseline, current),
+        categories,
+        new_libraries,
+    }
+}
+
+impl BaselineComparison {
+    /// Print the comparison; unchanged files are only counted.
+    pub fn print(&self) {
+        let diff = &self.diff;
+        if diff.is_empty() && self.new_libraries.is_some() {
+            status!("  No differences from {}", self.baseline);
+            return;
+        }
+        status!(
+            "  {} added, {} removed, {} changed compared to {} ({} total)",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len(),
+            self.baseline,
+            signed_size(diff.size_delta())
+        );
+        for entry in &diff.added {
+            status!("  + /{} ({})", entry.path, signed_size(entry.size as i64));
+        }
+        for entry in &diff.removed {
+            status!(
+                "  - /{} ({})",
+                entry.path,
+                signed_size(-(entry.size as i64))
+            );
+        }
+
+        if !self.categories.is_empty() {
+            status!("\n  Size by category:");
+            for category in &self.categories {
+                status!(
+                    "    {:<14} {:>10} -> {:>10}  ({})",
+                    category.name,
+                    human_size(category.before),
+                    human_size(category.after),
+                    signed_size(category.after as i64 - category.before as i64)
+                );
+            }
+        }
+
+        match self.new_libraries {
+            Some(ref libraries) if !libraries.is_empty() => {
+                status!("\n  New library dependencies:");
+                for (library, users) in libraries {
+                    status!("    {} (needed by /{})", library, users.join(", /"));
+                }
+            }
+            Some(_) => {}
+            None => status!(
+                "\n  Library dependencies not compared: the baseline manifest does not record them"
+            ),
+        }
+    }
+}
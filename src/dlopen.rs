@@ -0,0 +1,120 @@
+//! Files binaries load with dlopen.
+//!
+//! ELF headers (and ldd) only name the libraries a binary links against.
+//! NSS modules, named in nsswitch.conf, and PAM modules, named in the
+//! pam.d stacks, are loaded at run time with dlopen, so nothing points
+//! the build at them or at the libraries they need in turn. A hint maps a
+//! binary, or a library binaries link against (`libc.so.6`), to donor
+//! files or directories that go wherever it goes: each is copied to its
+//! place in the donor, with the libraries it links against and their
+//! hints. The built-in hints cover glibc's NSS modules and the PAM
+//! modules; the `[dlopen]` table of `stage3.toml` adds more. Hinted
+//! /usr/lib64 paths are [relocated](crate::arch::LibraryLayout::relocate)
+//! on donors keeping their libraries elsewhere.
+//!
+//! Hinted paths the donor doesn't have are left out quietly, since the
+//! built-in hints name modules not every donor ships.
+
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+use crate::binary::copy_libraries;
+use crate::context::BuildContext;
+use crate::detail;
+use crate::remap::{copy_donor_dir, copy_donor_file};
+use crate::rootfs::pam;
+
+/// NSS modules for the sources nsswitch.conf names.
+const NSS_MODULES: &[&str] = &[
+    "/usr/lib64/libnss_files.so.2",
+    "/usr/lib64/libnss_dns.so.2",
+    "/usr/lib64/libnss_systemd.so.2",
+    "/usr/lib64/libnss_resolve.so.2",
+    "/usr/lib64/libnss_myhostname.so.2",
+];
+
+/// Built-in hints: NSS modules go with glibc, PAM modules with libpam.
+pub fn builtin_hints() -> BTreeMap<String, Vec<String>> {
+    let pam_modules = pam::ESSENTIAL_MODULES
+        .iter()
+        .map(|module| format!("/{}/{}", pam::MODULE_DIR, module))
+        .collect();
+    BTreeMap::from([
+        (
+            "libc.so.6".to_string(),
+            NSS_MODULES.iter().map(|m| m.to_string()).collect(),
+        ),
+        ("libpam.so.0".to_string(), pam_modules),
+    ])
+}
+
+/// Hints of a build, and which ones it has followed.
+#[derive(Debug, Default)]
+pub struct DlopenHints {
+    /// Binary or library name to absolute donor paths
+    hints: BTreeMap<String, Vec<String>>,
+    /// Names whose hints were followed already
+    followed: Mutex<HashSet<String>>,
+}
+
+impl DlopenHints {
+    pub fn new(hints: BTreeMap<String, Vec<String>>) -> Self {
+        Self {
+            hints,
+            followed: Mutex::default(),
+        }
+    }
+
+    /// Hints for `binary` and the `libraries` it needs (absolute paths)
+    /// that haven't been followed yet, as name and hinted path, marked
+    /// followed.
+    pub fn take(&self, binary: &str, libraries: &[String]) -> Vec<(String, String)> {
+        let names = libraries
+            .iter()
+            .filter_map(|lib| Path::new(lib).file_name())
+            .map(|name| name.to_string_lossy().into_owned());
+        let mut followed = self.followed.lock().unwrap();
+        std::iter::once(binary.to_string())
+            .chain(names)
+            .filter(|name| self.hints.contains_key(name) && followed.insert(name.clone()))
+            .flat_map(|name| {
+                let paths = self.hints[&name].clone();
+                paths.into_iter().map(move |path| (name.clone(), path))
+            })
+            .collect()
+    }
+}
+
+/// Copy what the hints for `binary` and its `libraries` name, with the
+/// libraries those files need.
+pub fn copy_hinted(ctx: &BuildContext, binary: &str, libraries: &[String]) -> Result<()> {
+    for (name, hinted) in ctx.dlopen_hints.take(binary, libraries) {
+        let relocated = ctx.options.layout.relocate(hinted.trim_start_matches('/'));
+        let path = relocated.as_path();
+        let Some(src) = ctx.package_source.find_file(path) else {
+            detail!("  {} (dlopen hint for {}) not in the donor", hinted, name);
+            continue;
+        };
+
+        if src.is_dir() {
+            copy_donor_dir(ctx, path)?;
+            for entry in WalkDir::new(&src).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() {
+                    let file = entry.file_name().to_string_lossy().into_owned();
+                    copy_libraries(ctx, &file, entry.path())?;
+                }
+            }
+        } else {
+            if !ctx.target(path).exists() {
+                copy_donor_file(ctx, path)?;
+            }
+            let file = path.file_name().unwrap_or_default().to_string_lossy();
+            copy_libraries(ctx, &file, &src)?;
+        }
+        detail!("  Copied {} (dlopen hint for {})", hinted, name);
+    }
+    Ok(())
+}
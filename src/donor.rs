@@ -0,0 +1,130 @@
+//! Where donor files come from.
+//!
+//! The build copies binaries, libraries, units and modules out of a
+//! [`PackageSource`]: it finds files by rootfs path, lists directories and
+//! reads symlinks, and every donor read of a build goes through it. Today's
+//! donor is an unpacked Rocky tree, [`DonorTree`]; deb-based trees or
+//! recipe-built sysroots plug in as further implementations without
+//! touching the copy routines.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::resolve;
+
+/// Directories searched for a binary, in order.
+const BIN_DIRS: &[&str] = &["usr/bin", "bin", "usr/sbin", "sbin"];
+
+/// Directories searched for an sbin binary, in order.
+const SBIN_DIRS: &[&str] = &["usr/sbin", "sbin", "usr/bin", "bin"];
+
+/// A donor of files.
+pub trait PackageSource: Send + Sync {
+    /// Where the files are, for build output.
+    fn describe(&self) -> String;
+
+    /// The file for rootfs-relative `path`, if the source has it.
+    fn find_file(&self, path: &Path) -> Option<PathBuf>;
+
+    /// Rootfs-relative path of a file [`find_file`](Self::find_file)
+    /// returned, if the source can tell.
+    fn rootfs_path(&self, _file: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// Names in rootfs-relative directory `path`, sorted; None if the
+    /// source has no directory there.
+    fn read_dir(&self, path: &Path) -> Option<Vec<OsString>>;
+
+    /// Target of the symlink at rootfs-relative `path`, if the source has
+    /// one there.
+    fn read_link(&self, path: &Path) -> Option<PathBuf>;
+
+    /// The file for rootfs-relative `path` with symlinks followed inside
+    /// the source, so absolute ones point into it rather than at the
+    /// build host.
+    fn find_resolved(&self, path: &Path) -> Option<PathBuf> {
+        let resolved = resolve::resolve(path, |rel| match self.read_link(rel) {
+            Some(target) => resolve::Node::Symlink(target),
+            None if self.find_file(rel).is_some() => resolve::Node::Present,
+            None => resolve::Node::Missing,
+        })?;
+        self.find_file(&resolved)
+    }
+
+    /// Whether the source has a file, or a link to one, at `path`.
+    fn is_file(&self, path: &Path) -> bool {
+        self.find_file(path).is_some_and(|file| file.is_file())
+    }
+
+    /// Whether the source has a directory, or a link to one, at `path`.
+    fn is_dir(&self, path: &Path) -> bool {
+        self.find_file(path).is_some_and(|dir| dir.is_dir())
+    }
+
+    /// Find a binary in the bin directories, then the sbin ones.
+    fn find_binary(&self, name: &str) -> Option<PathBuf> {
+        find_in(self, BIN_DIRS, name)
+    }
+
+    /// Find a binary in the sbin directories, then the bin ones.
+    fn find_sbin_binary(&self, name: &str) -> Option<PathBuf> {
+        find_in(self, SBIN_DIRS, name)
+    }
+
+    /// Find a library by its absolute path, also under /usr for donors
+    /// with a merged /usr the host's ldd doesn't know about.
+    fn find_library(&self, lib_path: &str) -> Option<PathBuf> {
+        let rel = Path::new(lib_path.trim_start_matches('/'));
+        self.find_file(rel)
+            .or_else(|| self.find_file(&Path::new("usr").join(rel)))
+    }
+}
+
+fn find_in<S: PackageSource + ?Sized>(source: &S, dirs: &[&str], name: &str) -> Option<PathBuf> {
+    dirs.iter()
+        .find_map(|dir| source.find_file(&Path::new(dir).join(name)))
+}
+
+/// An unpacked donor rootfs, such as a Rocky tree.
+#[derive(Debug, Clone)]
+pub struct DonorTree {
+    root: PathBuf,
+}
+
+impl DonorTree {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl PackageSource for DonorTree {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn find_file(&self, path: &Path) -> Option<PathBuf> {
+        let path = self.root.join(path);
+        path.exists().then_some(path)
+    }
+
+    fn rootfs_path(&self, file: &Path) -> Option<PathBuf> {
+        file.strip_prefix(&self.root).ok().map(Path::to_path_buf)
+    }
+
+    fn read_dir(&self, path: &Path) -> Option<Vec<OsString>> {
+        let mut names: Vec<OsString> = fs::read_dir(self.root.join(path))
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.file_name()))
+            .collect();
+        names.sort();
+        Some(names)
+    }
+
+    fn read_link(&self, path: &Path) -> Option<PathBuf> {
+        fs::read_link(self.root.join(path)).ok()
+    }
+}
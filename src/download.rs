@@ -0,0 +1,155 @@
+//! Fetching published artifacts for inspection.
+//!
+//! Commands that read a tarball also accept an `https://` URL. The artifact
+//! is downloaded with curl into a per-user cache, resuming an interrupted
+//! download, and must match the `.sha256` sidecar published next to it
+//! before anything reads it. A `.minisig` signature and `.json` release
+//! manifest are fetched too when published, so `verify --signature` and
+//! `attest` work on URLs as well.
+//!
+//! Progress goes to stderr so `--json` output on stdout stays parseable.
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::attest;
+use crate::checksum;
+use crate::sandbox;
+use crate::signing;
+use crate::status;
+
+/// curl's exit code when the server can't resume a partial download.
+const CURL_CANNOT_RESUME: i32 = 33;
+
+/// Use `input` as a local path, or download it first if it is a URL.
+pub fn resolve(input: &Path) -> Result<PathBuf> {
+    let Some(url) = input.to_str().filter(|s| s.contains("://")) else {
+        return Ok(input.to_path_buf());
+    };
+    if !url.starts_with("https://") {
+        bail!("Only https:// URLs are supported: {}", url);
+    }
+    fetch(url)
+}
+
+/// Download `url` into the cache and return the verified local copy.
+///
+/// A cached copy is reused when it still matches the published checksum.
+pub fn fetch(url: &str) -> Result<PathBuf> {
+    if sandbox::find_program("curl").is_none() {
+        bail!("curl not found; it is needed to download {}", url);
+    }
+
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .with_context(|| format!("URL has no file name: {}", url))?;
+    let dir = cache_dir()?.join(url_key(url));
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let dest = dir.join(name);
+
+    status!("Fetching {}...", url);
+    let sidecar = checksum::sidecar_path(&dest);
+    if let Err(code) = curl(&format!("{}.sha256", url), &sidecar, &[])? {
+        bail!(
+            "Failed to fetch the checksum {}.sha256 (curl exit code {})",
+            url,
+            code
+        );
+    }
+
+    if dest.exists() && checksum::verify_sidecar(&dest).is_ok() {
+        status!("  Using cached {}", dest.display());
+    } else {
+        download(url, &dest)?;
+    }
+
+    let signature = signing::signature_path(&dest);
+    if curl(&format!("{}.minisig", url), &signature, &[])?.is_err() {
+        fs::remove_file(&signature).ok();
+    }
+    let manifest = attest::release_manifest_path(&dest);
+    if curl(&format!("{}.json", url), &manifest, &[])?.is_err() {
+        fs::remove_file(&manifest).ok();
+    }
+    Ok(dest)
+}
+
+/// Download `url` to `dest` via a `.part` file that later runs resume.
+fn download(url: &str, dest: &Path) -> Result<()> {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    if part.exists() {
+        status!("  Resuming partial download");
+    }
+    let mut result = curl(url, &part, &["--continue-at", "-", "--progress-bar"])?;
+    if result == Err(CURL_CANNOT_RESUME) {
+        tracing::warn!("  Warning: server cannot resume, downloading from scratch");
+        fs::remove_file(&part)?;
+        result = curl(url, &part, &["--progress-bar"])?;
+    }
+    if let Err(code) = result {
+        bail!(
+            "Failed to download {} (curl exit code {}); rerun to resume",
+            url,
+            code
+        );
+    }
+
+    fs::rename(&part, dest)?;
+    if let Err(err) = checksum::verify_sidecar(dest) {
+        // A corrupt copy must not be resumed or reused
+        fs::remove_file(dest).ok();
+        return Err(err);
+    }
+    status!("  Downloaded {} (checksum verified)", dest.display());
+    Ok(())
+}
+
+/// Run curl, returning its exit code on failure.
+///
+/// Without `--progress-bar` curl stays silent; callers report failures.
+fn curl(url: &str, output: &Path, args: &[&str]) -> Result<Result<(), i32>> {
+    let output_mode: &[&str] = if args.contains(&"--progress-bar") {
+        &["--show-error"]
+    } else {
+        &["--silent"]
+    };
+    let status = sandbox::command("curl")
+        .args(["--fail", "--location", "--proto", "=https"])
+        .args(output_mode)
+        .args(args)
+        .arg("--output")
+        .arg(output)
+        .arg(url)
+        .status()
+        .context("Failed to run curl")?;
+
+    Ok(match status.code() {
+        Some(0) => Ok(()),
+        code => Err(code.unwrap_or(-1)),
+    })
+}
+
+/// Cache directory for downloads, following the XDG base directory spec.
+fn cache_dir() -> Result<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".cache"))
+            .context("Neither XDG_CACHE_HOME nor HOME is set")?,
+    };
+    Ok(base.join("stage3/downloads"))
+}
+
+/// Short stable key so same-named artifacts from different URLs don't clash.
+fn url_key(url: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(url.as_bytes()));
+    digest[..16].to_string()
+}
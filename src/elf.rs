@@ -0,0 +1,44 @@
+//! ELF dynamic-linking metadata.
+
+use goblin::elf::{header, Elf};
+
+/// Dynamic-linking requirements of an executable or shared object.
+pub struct DynamicInfo {
+    /// Program interpreter (`PT_INTERP`), e.g. `/lib64/ld-linux-x86-64.so.2`
+    pub interpreter: Option<String>,
+    /// `DT_NEEDED` entries
+    pub needed: Vec<String>,
+    /// `DT_RUNPATH` and `DT_RPATH` directories, unexpanded
+    pub runpath: Vec<String>,
+}
+
+/// Whether the bytes start with the ELF magic.
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x7fELF")
+}
+
+/// Parse the dynamic-linking requirements of an ELF file.
+///
+/// Returns `None` for anything that isn't a parseable executable or shared
+/// object (relocatable objects such as kernel modules, core files, ...).
+pub fn dynamic_info(bytes: &[u8]) -> Option<DynamicInfo> {
+    let elf = Elf::parse(bytes).ok()?;
+    if !matches!(elf.header.e_type, header::ET_EXEC | header::ET_DYN) {
+        return None;
+    }
+
+    let runpath = elf
+        .runpaths
+        .iter()
+        .chain(elf.rpaths.iter())
+        .flat_map(|paths| paths.split(':'))
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    Some(DynamicInfo {
+        interpreter: elf.interpreter.map(str::to_string),
+        needed: elf.libraries.iter().map(|l| l.to_string()).collect(),
+        runpath,
+    })
+}
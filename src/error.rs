@@ -0,0 +1,170 @@
+//! Typed build errors.
+//!
+//! [`Stage3Builder::build`](crate::Stage3Builder::build) and
+//! [`plan`](crate::Stage3Builder::plan) fail with a [`Stage3Error`], so
+//! library users can tell a missing source rootfs from a missing binary or
+//! a failed tarball, and get at the offending path or binary, without
+//! parsing messages.
+//!
+//! The builder, binary copying and the rootfs steps return it too, raising
+//! a variant where a library user needs to tell the failure apart. The
+//! modules under them (archive writing, linking, the donor) return
+//! `anyhow` errors, which come up as [`Stage3Error::Other`] with their
+//! whole context chain, or as the variant they carry if they were raised
+//! with one.
+
+use std::io;
+use std::path::{PathBuf, StripPrefixError};
+use thiserror::Error;
+
+use crate::failure::{failure_kind, FailureKind};
+use crate::inspect::ByteSize;
+
+/// Result of the build API and the steps under it.
+pub type Result<T, E = Stage3Error> = std::result::Result<T, E>;
+
+/// Why a build failed.
+#[derive(Debug, Error)]
+pub enum Stage3Error {
+    /// The source rootfs directory doesn't exist
+    #[error("Source directory does not exist: {}", path.display())]
+    SourceMissing { path: PathBuf },
+    /// The source rootfs is for another architecture than asked for
+    #[error("Source rootfs {} is {found}, not {expected}", rootfs.display())]
+    ArchMismatch {
+        rootfs: PathBuf,
+        found: String,
+        expected: String,
+    },
+    /// A binary the rootfs can't do without isn't in the donor
+    #[error("Could not find {binary} in source rootfs")]
+    RequiredBinary { binary: String },
+    /// A library a binary links against is in neither the donor nor, with
+    /// `host_fallback`, on the build host
+    #[error("{}", missing_library(library, *host_fallback))]
+    MissingLibrary {
+        library: String,
+        host_fallback: bool,
+    },
+    /// A file given to the build (busybox, keys, help pages) can't be used
+    #[error("{message}")]
+    InvalidInput { path: PathBuf, message: String },
+    /// Steps recorded errors in the report, one line each
+    #[error("Build reported {} errors:\n{}", errors.len(), errors.join("\n"))]
+    BuildErrors { errors: Vec<String> },
+    /// Admission policies denied these staged entries
+    #[error("Admission policy denied {} entries", denied.len())]
+    PolicyDenied { denied: Vec<String> },
+    /// Writing the tarball failed
+    #[error("Failed to create the tarball")]
+    TarballFailed {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    /// The compressed tarball is over the `--max-size` budget
+    #[error(
+        "Tarball is {size} ({} bytes), over the --max-size budget of {budget} ({} bytes)",
+        size.0,
+        budget.0
+    )]
+    OverBudget {
+        tarball: PathBuf,
+        size: ByteSize,
+        budget: ByteSize,
+    },
+    /// Another build holds the output directory's lock
+    #[error(
+        "Another build{} is using {}; wait for it with --wait-lock or pick another --output",
+        pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default(),
+        output.display()
+    )]
+    OutputLocked { output: PathBuf, pid: Option<u32> },
+    /// The build's cancel token was cancelled
+    #[error("Build cancelled")]
+    Cancelled,
+    /// Anything else; displayed as the error itself, so its causes come next
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+fn missing_library(library: &str, host_fallback: bool) -> String {
+    match host_fallback {
+        true => format!("Could not find library: {}", library),
+        false => format!(
+            "Library {} is not in the donor rootfs (host fallback disabled)",
+            library
+        ),
+    }
+}
+
+impl Stage3Error {
+    /// Failure class, for the exit code.
+    pub fn kind(&self) -> Option<FailureKind> {
+        match self {
+            Stage3Error::SourceMissing { .. } => Some(FailureKind::SourceMissing),
+            Stage3Error::RequiredBinary { .. } => Some(FailureKind::RequiredBinary),
+            Stage3Error::BuildErrors { .. } => Some(FailureKind::BuildErrors),
+            Stage3Error::PolicyDenied { .. } => Some(FailureKind::PolicyDenied),
+            Stage3Error::TarballFailed { .. } => Some(FailureKind::TarballFailed),
+            Stage3Error::OverBudget { .. } => Some(FailureKind::OverBudget),
+            Stage3Error::OutputLocked { .. } => Some(FailureKind::OutputLocked),
+            Stage3Error::Cancelled => Some(FailureKind::Cancelled),
+            Stage3Error::ArchMismatch { .. } => Some(FailureKind::ArchMismatch),
+            Stage3Error::MissingLibrary { .. } => Some(FailureKind::MissingLibrary),
+            Stage3Error::InvalidInput { .. } => Some(FailureKind::InvalidInput),
+            Stage3Error::Other(err) => failure_kind(err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Stage3Error {
+    fn from(err: anyhow::Error) -> Self {
+        // A cancellation stays one, whatever a step wrapped it in
+        if failure_kind(&err) == Some(FailureKind::Cancelled) {
+            return Stage3Error::Cancelled;
+        }
+        match err.downcast::<Stage3Error>() {
+            Ok(err) => err,
+            Err(err) => Stage3Error::Other(err),
+        }
+    }
+}
+
+/// Errors of the std and library calls under the steps, unclassified.
+macro_rules! other_from {
+    ($($error:ty),* $(,)?) => {$(
+        impl From<$error> for Stage3Error {
+            fn from(err: $error) -> Self {
+                Stage3Error::Other(err.into())
+            }
+        }
+    )*};
+}
+
+other_from!(
+    io::Error,
+    StripPrefixError,
+    globset::Error,
+    serde_json::Error,
+    walkdir::Error,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failure::exit_code;
+    use anyhow::Context;
+
+    #[test]
+    fn variants_keep_their_class_under_context() {
+        let err: Result<()> = Err(Stage3Error::MissingLibrary {
+            library: "libfoo.so.1".to_string(),
+            host_fallback: false,
+        });
+        let err = err.context("Failed to copy /usr/bin/foo").unwrap_err();
+        assert_eq!(exit_code(&err), 20);
+
+        let err = Stage3Error::from(err);
+        assert_eq!(err.kind(), Some(FailureKind::MissingLibrary));
+    }
+}
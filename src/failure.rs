@@ -0,0 +1,151 @@
+//! Exit codes by failure class.
+//!
+//! Errors that CI may want to branch on carry a [`Failure`] naming their
+//! class, or are a [`Stage3Error`] of a class; the CLI exits with that
+//! class's code and with 1 for everything else. clap exits with 2 for
+//! usage errors before anything runs.
+//!
+//! | Code | Class                | Raised by                               |
+//! |------|----------------------|-----------------------------------------|
+//! | 1    | any other error      |                                         |
+//! | 2    | usage                | clap                                    |
+//! | 3    | modified content     | `attest`                                |
+//! | 4    | bad signature        | `attest`, `verify --signature`          |
+//! | 5    | missing metadata     | `attest`                                |
+//! | 10   | source missing       | `build`                                 |
+//! | 11   | required binary      | `build` (no bash in the donor)          |
+//! | 12   | build errors         | `build` (errors in the warnings table)  |
+//! | 13   | policy denied        | `build --setuid-allowlist`, ...         |
+//! | 14   | tarball failed       | `build`                                 |
+//! | 15   | over budget          | `build --max-size`, `diff --max-growth` |
+//! | 16   | verification failed  | `verify`                                |
+//! | 17   | boot test failed     | `boot-test`                             |
+//! | 18   | output locked        | `build` (another build in `--output`)   |
+//! | 19   | arch mismatch        | `build --arch`                          |
+//! | 20   | missing library      | `build` (a library not in the donor)    |
+//! | 21   | invalid input        | `build --busybox-static`, ...           |
+//! | 130  | cancelled            | `build` (SIGINT, SIGTERM)               |
+
+use serde::Serialize;
+use std::fmt;
+
+use crate::error::Stage3Error;
+
+/// Class of a failure, each with its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    ModifiedContent,
+    BadSignature,
+    MissingMetadata,
+    SourceMissing,
+    RequiredBinary,
+    BuildErrors,
+    PolicyDenied,
+    TarballFailed,
+    OverBudget,
+    VerificationFailed,
+    BootTestFailed,
+    OutputLocked,
+    ArchMismatch,
+    MissingLibrary,
+    InvalidInput,
+    Cancelled,
+}
+
+impl FailureKind {
+    /// Process exit code (1 stays for unclassified errors, 2 for usage).
+    pub fn exit_code(self) -> u8 {
+        match self {
+            FailureKind::ModifiedContent => 3,
+            FailureKind::BadSignature => 4,
+            FailureKind::MissingMetadata => 5,
+            FailureKind::SourceMissing => 10,
+            FailureKind::RequiredBinary => 11,
+            FailureKind::BuildErrors => 12,
+            FailureKind::PolicyDenied => 13,
+            FailureKind::TarballFailed => 14,
+            FailureKind::OverBudget => 15,
+            FailureKind::VerificationFailed => 16,
+            FailureKind::BootTestFailed => 17,
+            FailureKind::OutputLocked => 18,
+            FailureKind::ArchMismatch => 19,
+            FailureKind::MissingLibrary => 20,
+            FailureKind::InvalidInput => 21,
+            // As if killed by SIGINT, like shells report it
+            FailureKind::Cancelled => 130,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::ModifiedContent => "modified content",
+            FailureKind::BadSignature => "bad signature",
+            FailureKind::MissingMetadata => "missing metadata",
+            FailureKind::SourceMissing => "source missing",
+            FailureKind::RequiredBinary => "required binary missing",
+            FailureKind::BuildErrors => "build errors",
+            FailureKind::PolicyDenied => "policy denied",
+            FailureKind::TarballFailed => "tarball failed",
+            FailureKind::OverBudget => "over budget",
+            FailureKind::VerificationFailed => "verification failed",
+            FailureKind::BootTestFailed => "boot test failed",
+            FailureKind::OutputLocked => "output locked",
+            FailureKind::ArchMismatch => "arch mismatch",
+            FailureKind::MissingLibrary => "missing library",
+            FailureKind::InvalidInput => "invalid input",
+            FailureKind::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// An error message tagged with its failure class.
+///
+/// Raise it with `bail!(Failure::new(..))`, or attach it to an existing
+/// error with `.context(Failure::new(..))`; the class survives any
+/// context added on the way up.
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: FailureKind,
+    message: String,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// The class `err` was tagged with, if any.
+pub fn failure_kind(err: &anyhow::Error) -> Option<FailureKind> {
+    let kinds: Vec<FailureKind> = err
+        .chain()
+        .filter_map(|cause| match cause.downcast_ref::<Failure>() {
+            Some(failure) => Some(failure.kind),
+            None => cause.downcast_ref::<Stage3Error>()?.kind(),
+        })
+        .collect();
+    // A cancellation stays one, whatever class a caller wrapped it in
+    if kinds.contains(&FailureKind::Cancelled) {
+        return Some(FailureKind::Cancelled);
+    }
+    kinds.first().copied()
+}
+
+/// Exit code for `err`: its class's, or 1.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    failure_kind(err).map_or(1, FailureKind::exit_code)
+}
@@ -0,0 +1,120 @@
+//! Fakeroot-style metadata layer.
+//!
+//! Build steps record the ownership, permissions, extended attributes and
+//! device nodes they intend instead of applying them to the staging tree,
+//! which would need root. The archive writer applies the recorded metadata to each entry, so
+//! the whole build can run as an unprivileged user.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::archive::DeviceNode;
+
+/// Metadata recorded for a single rootfs path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    pub uid: Option<u64>,
+    pub gid: Option<u64>,
+    /// Permission bits including setuid/setgid/sticky
+    pub mode: Option<u32>,
+}
+
+/// Extended attributes of a path, by name (`security.capability`).
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Intended metadata for staged paths, keyed by path inside the rootfs.
+#[derive(Default)]
+pub struct MetadataLayer {
+    attributes: Mutex<BTreeMap<PathBuf, Attributes>>,
+    xattrs: Mutex<BTreeMap<PathBuf, Xattrs>>,
+    devices: Mutex<Vec<DeviceNode>>,
+}
+
+impl MetadataLayer {
+    /// Record the owner of a path.
+    pub fn chown(&self, path: impl AsRef<Path>, uid: u64, gid: u64) {
+        let mut attributes = self.attributes.lock().unwrap();
+        let entry = attributes.entry(normalize(path.as_ref())).or_default();
+        entry.uid = Some(uid);
+        entry.gid = Some(gid);
+    }
+
+    /// Record the permission bits of a path.
+    pub fn chmod(&self, path: impl AsRef<Path>, mode: u32) {
+        let mut attributes = self.attributes.lock().unwrap();
+        attributes.entry(normalize(path.as_ref())).or_default().mode = Some(mode);
+    }
+
+    /// Record the extended attributes of a path, replacing earlier ones.
+    pub fn set_xattrs(&self, path: impl AsRef<Path>, xattrs: Xattrs) {
+        let mut recorded = self.xattrs.lock().unwrap();
+        match xattrs.is_empty() {
+            true => recorded.remove(&normalize(path.as_ref())),
+            false => recorded.insert(normalize(path.as_ref()), xattrs),
+        };
+    }
+
+    /// Extended attributes recorded for a path (empty if none were).
+    pub fn xattrs(&self, path: &Path) -> Xattrs {
+        self.xattrs
+            .lock()
+            .unwrap()
+            .get(&normalize(path))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Extended attributes recorded so far, by path.
+    pub fn recorded_xattrs(&self) -> BTreeMap<PathBuf, Xattrs> {
+        self.xattrs.lock().unwrap().clone()
+    }
+
+    /// Record a device node to be created in the archive.
+    pub fn mknod(&self, node: DeviceNode) {
+        self.devices.lock().unwrap().push(node);
+    }
+
+    /// Recorded metadata for a path (empty if none was recorded).
+    pub fn attributes(&self, path: &Path) -> Attributes {
+        self.attributes
+            .lock()
+            .unwrap()
+            .get(&normalize(path))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Replace the metadata recorded for a path.
+    pub fn set_attributes(&self, path: impl AsRef<Path>, attributes: Attributes) {
+        self.attributes
+            .lock()
+            .unwrap()
+            .insert(normalize(path.as_ref()), attributes);
+    }
+
+    /// Metadata recorded so far, by path.
+    pub fn recorded(&self) -> BTreeMap<PathBuf, Attributes> {
+        self.attributes.lock().unwrap().clone()
+    }
+
+    /// All recorded device nodes, in recording order.
+    pub fn devices(&self) -> Vec<DeviceNode> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// A copy of everything recorded so far.
+    pub fn snapshot(&self) -> Self {
+        Self {
+            attributes: Mutex::new(self.recorded()),
+            xattrs: Mutex::new(self.recorded_xattrs()),
+            devices: Mutex::new(self.devices()),
+        }
+    }
+}
+
+/// Strip a leading `/` so `/etc/shadow` and `etc/shadow` are the same key.
+fn normalize(path: &Path) -> PathBuf {
+    path.strip_prefix("/").unwrap_or(path).to_path_buf()
+}
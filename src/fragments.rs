@@ -0,0 +1,325 @@
+//! Cached component fragments.
+//!
+//! A few components copy the same large donor trees on every build:
+//! timezone data, locales, i18n data and udev rules. With
+//! `--fragment-cache DIR`, such a component runs on a staging directory
+//! of its own the first time, and what it staged is kept in `DIR` as a
+//! tarball fragment, next to what it recorded in the metadata layer and
+//! the build report. A later build whose component has the same key, of
+//! any profile and in any output directory, unpacks the fragment into
+//! staging and replays the records instead of running it.
+//!
+//! The key hashes the component's name, the stage3 version, the build
+//! options the component depends on (architecture, library layout,
+//! remaps, strictness, ACL and SELinux handling), and the size, mtime and
+//! link target of everything in the donor directories it reads. Fragments
+//! are written to temporary files and renamed into place, so builds
+//! sharing a directory may run at the same time.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+use crate::archive::DeviceNode;
+use crate::context::BuildContext;
+use crate::donor::PackageSource;
+use crate::error;
+use crate::fakeroot::{Attributes, MetadataLayer, Xattrs};
+use crate::report::Diagnostic;
+use crate::{detail, status};
+
+/// Tells temporary files of one process apart.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// What a component recorded outside the staging tree.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Recorded {
+    diagnostics: Vec<Diagnostic>,
+    attributes: BTreeMap<PathBuf, Attributes>,
+    xattrs: BTreeMap<PathBuf, Xattrs>,
+    devices: Vec<DeviceNode>,
+}
+
+/// A fragment cache directory, and what one build took from it.
+#[derive(Debug)]
+pub struct FragmentCache {
+    dir: PathBuf,
+    reused: AtomicUsize,
+    written: AtomicUsize,
+}
+
+impl FragmentCache {
+    /// Use the fragment cache in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create fragment cache {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            reused: AtomicUsize::new(0),
+            written: AtomicUsize::new(0),
+        })
+    }
+
+    /// Print how many components came from the cache.
+    pub fn print_summary(&self) {
+        status!(
+            "Fragment cache: {} components reused from {}, {} added",
+            self.reused.load(Ordering::Relaxed),
+            self.dir.display(),
+            self.written.load(Ordering::Relaxed)
+        );
+    }
+
+    /// The fragment tarball and records of component `name` with `key`.
+    fn paths(&self, name: &str, key: &str) -> (PathBuf, PathBuf) {
+        let stem = self.dir.join(format!("{}-{}", name, key));
+        (stem.with_extension("tar"), stem.with_extension("json"))
+    }
+}
+
+/// Run component `name`, which reads the donor under `donor_dirs`, or
+/// unpack it from the build's fragment cache.
+pub fn cached(
+    ctx: &BuildContext,
+    name: &str,
+    donor_dirs: &[&Path],
+    run: impl FnOnce(&BuildContext) -> error::Result<()>,
+) -> error::Result<()> {
+    let Some(ref cache) = ctx.fragments else {
+        return run(ctx);
+    };
+    let key = fragment_key(ctx, name, donor_dirs)?;
+    let (tarball, records) = cache.paths(name, &key);
+
+    if let Some(recorded) = read_records(&records) {
+        if tarball.is_file() {
+            unpack(&tarball, &ctx.staging)?;
+            replay(ctx, recorded);
+            cache.reused.fetch_add(1, Ordering::Relaxed);
+            detail!("  Unpacked {} from the fragment cache", name);
+            return Ok(());
+        }
+    }
+
+    // A staging directory of its own, next to the build's
+    let scratch = ctx
+        .staging
+        .with_file_name(format!(".stage3-fragment-{}", name));
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch)?;
+    }
+    fs::create_dir_all(&scratch)?;
+    let mut fork = ctx.fork();
+    fork.staging = scratch.clone();
+    fork.metadata = MetadataLayer::default();
+    fork.donor_links = Arc::default();
+    fork.cache = None;
+    fork.store = None;
+
+    let stored = run(&fork).and_then(|()| {
+        let recorded = Recorded {
+            diagnostics: fork.report.diagnostics(),
+            attributes: fork.metadata.recorded(),
+            xattrs: fork.metadata.recorded_xattrs(),
+            devices: fork.metadata.devices(),
+        };
+        write_fragment(&scratch, &tarball, &records, &recorded)?;
+        unpack(&tarball, &ctx.staging)?;
+        replay(ctx, recorded);
+        cache.written.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    });
+    if stored.is_err() {
+        // A failed component's findings still go in the report
+        ctx.report.extend(fork.report.diagnostics());
+    }
+    let _ = fs::remove_dir_all(&scratch);
+    stored
+}
+
+/// Hash of what component `name` of this build depends on.
+fn fragment_key(ctx: &BuildContext, name: &str, donor_dirs: &[&Path]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(format!(
+        "{} {} {} {:?} {:?} {} {} {:?}\n",
+        env!("CARGO_PKG_VERSION"),
+        name,
+        ctx.options.arch,
+        ctx.options.layout,
+        ctx.remaps,
+        ctx.options.strict,
+        ctx.acls,
+        ctx.selinux
+    ));
+    for dir in donor_dirs {
+        hash_donor_dir(ctx.package_source.as_ref(), dir, &mut hasher)?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hash the path, size, mtime and link target of everything under donor
+/// directory `dir`.
+fn hash_donor_dir(source: &dyn PackageSource, dir: &Path, hasher: &mut Sha256) -> Result<()> {
+    let Some(names) = source.read_dir(dir) else {
+        return Ok(());
+    };
+    for name in names {
+        let path = dir.join(name);
+        if let Some(target) = source.read_link(&path) {
+            hasher.update(format!("{} -> {}\n", path.display(), target.display()));
+        } else if source.is_dir(&path) {
+            hasher.update(format!("{}/\n", path.display()));
+            hash_donor_dir(source, &path, hasher)?;
+        } else if let Some(file) = source.find_file(&path) {
+            let meta = fs::metadata(&file)
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            hasher.update(format!(
+                "{} {} {}.{}\n",
+                path.display(),
+                meta.len(),
+                meta.mtime(),
+                meta.mtime_nsec()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The records of a fragment, if there is a readable one.
+fn read_records(path: &Path) -> Option<Recorded> {
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Write what a component staged in `scratch` to `tarball`, and what it
+/// recorded to `records`.
+fn write_fragment(
+    scratch: &Path,
+    tarball: &Path,
+    records: &Path,
+    recorded: &Recorded,
+) -> Result<()> {
+    let tmp = tmp_path(tarball);
+    let written = (|| {
+        let mut builder = tar::Builder::new(BufWriter::new(File::create(&tmp)?));
+        builder.follow_symlinks(false);
+        for entry in WalkDir::new(scratch).min_depth(1).sort_by_file_name() {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(scratch)?;
+            builder.append_path_with_name(entry.path(), rel)?;
+        }
+        builder.into_inner()?.flush()?;
+        // The records first, so a complete tarball always has them
+        let tmp_records = tmp_path(records);
+        fs::write(&tmp_records, serde_json::to_vec(recorded)?)?;
+        fs::rename(&tmp_records, records)?;
+        fs::rename(&tmp, tarball)?;
+        anyhow::Ok(())
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written.with_context(|| format!("Failed to write fragment {}", tarball.display()))
+}
+
+/// Unpack fragment `tarball` into `staging`.
+fn unpack(tarball: &Path, staging: &Path) -> Result<()> {
+    let file =
+        File::open(tarball).with_context(|| format!("Failed to open {}", tarball.display()))?;
+    let mut archive = tar::Archive::new(BufReader::new(file));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    archive
+        .unpack(staging)
+        .with_context(|| format!("Failed to unpack fragment {}", tarball.display()))
+}
+
+/// Record in `ctx` what a component recorded outside the staging tree.
+fn replay(ctx: &BuildContext, recorded: Recorded) {
+    for (path, attributes) in recorded.attributes {
+        ctx.metadata.set_attributes(path, attributes);
+    }
+    for (path, xattrs) in recorded.xattrs {
+        ctx.metadata.set_xattrs(path, xattrs);
+    }
+    for node in recorded.devices {
+        ctx.metadata.mknod(node);
+    }
+    ctx.report.extend(recorded.diagnostics);
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Severity;
+
+    /// Stage the donor's udev rules of `ctx`, with a mode and a warning
+    /// recorded on the side.
+    fn stage_rules(ctx: &BuildContext) -> error::Result<()> {
+        crate::rootfs::systemd::copy_udev_rules(ctx)?;
+        ctx.metadata.chmod("usr/lib/udev/rules.d/50-a.rules", 0o600);
+        ctx.report.warn("udev", None, "staged");
+        Ok(())
+    }
+
+    /// Build a context staging to `staging` with the fragment cache in
+    /// `cache`.
+    fn context(source: &Path, staging: &Path, cache: &Path) -> BuildContext {
+        fs::create_dir_all(staging).unwrap();
+        BuildContext::new(source.to_path_buf(), staging.to_path_buf(), PathBuf::new())
+            .with_fragments(Some(FragmentCache::open(cache).unwrap()))
+    }
+
+    #[test]
+    fn unchanged_components_are_unpacked_from_the_cache() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("source");
+        let rules = source.join("usr/lib/udev/rules.d");
+        fs::create_dir_all(&rules).unwrap();
+        fs::write(rules.join("50-a.rules"), "a\n").unwrap();
+        let cache = root.path().join("fragments");
+        let rules_dir = Path::new("usr/lib/udev/rules.d");
+
+        let first = context(&source, &root.path().join("first"), &cache);
+        cached(&first, "udev-rules", &[rules_dir], stage_rules).unwrap();
+        let second = context(&source, &root.path().join("second"), &cache);
+        let run = |_: &BuildContext| panic!("the fragment is reused");
+        cached(&second, "udev-rules", &[rules_dir], run).unwrap();
+
+        let fragments = second.fragments.as_ref().unwrap();
+        assert_eq!(fragments.reused.load(Ordering::Relaxed), 1);
+        for ctx in [&first, &second] {
+            let staged = ctx.target("usr/lib/udev/rules.d/50-a.rules");
+            assert_eq!(fs::read(staged).unwrap(), b"a\n");
+            assert_eq!(ctx.metadata.recorded(), first.metadata.recorded());
+            assert_eq!(ctx.report.count(Severity::Warning), 1);
+        }
+        assert!(!root.path().join(".stage3-fragment-udev-rules").exists());
+
+        // A changed donor file makes the component run again
+        fs::write(rules.join("50-a.rules"), "changed\n").unwrap();
+        let third = context(&source, &root.path().join("third"), &cache);
+        cached(&third, "udev-rules", &[rules_dir], stage_rules).unwrap();
+        let staged = third.target("usr/lib/udev/rules.d/50-a.rules");
+        assert_eq!(fs::read(staged).unwrap(), b"changed\n");
+    }
+}
@@ -0,0 +1,67 @@
+//! Donor files with several names.
+//!
+//! Some donor files are hard links to one another (`xz` and `unxz`, say).
+//! Copied name by name, each would be staged, and archived, as a file of
+//! its own. Instead, a later name of a donor file that is already staged
+//! is hard-linked to the first copy, so the tarball writes it as a link.
+//! A first copy that a step changed since isn't linked to. Such files are
+//! copied one at a time, so steps running at once that copy two names of
+//! one donor file still end up with one copy and a link.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Size and modification time of a staged copy.
+type Stamp = (u64, i64, i64);
+
+/// First staged copies of donor files with several names.
+#[derive(Debug, Default)]
+pub struct DonorLinks {
+    /// By donor inode, with the copy's stamp once copied
+    staged: Mutex<HashMap<(u64, u64), (PathBuf, Stamp)>>,
+}
+
+impl DonorLinks {
+    /// Stage donor file `src` at `dest` as a hard link to an earlier copy
+    /// of it, or with `copy` if there is none.
+    pub fn copy(&self, src: &Path, dest: &Path, copy: impl FnOnce() -> Result<()>) -> Result<()> {
+        let Some(key) = inode(src) else {
+            return copy();
+        };
+        let mut staged = self.staged.lock().unwrap();
+        if let Some((first, stamp)) = staged.get(&key) {
+            if first != dest && staged_stamp(first) == Some(*stamp) {
+                if dest.symlink_metadata().is_ok() {
+                    fs::remove_file(dest)?;
+                }
+                fs::hard_link(first, dest).with_context(|| {
+                    format!("Failed to link {} to {}", dest.display(), first.display())
+                })?;
+                return Ok(());
+            }
+        }
+
+        copy()?;
+        if let Some(stamp) = staged_stamp(dest) {
+            staged
+                .entry(key)
+                .or_insert_with(|| (dest.to_path_buf(), stamp));
+        }
+        Ok(())
+    }
+}
+
+/// Device and inode of a donor file with more than one name.
+fn inode(src: &Path) -> Option<(u64, u64)> {
+    let meta = fs::metadata(src).ok()?;
+    (meta.is_file() && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+fn staged_stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    Some((meta.len(), meta.mtime(), meta.mtime_nsec()))
+}
@@ -0,0 +1,336 @@
+//! Size breakdown of a stage3 tarball.
+//!
+//! Entries are sorted into coarse categories by path so the size of each
+//! part of the artifact can be compared between releases. Sizes are the
+//! uncompressed file sizes recorded in the tar headers; hardlinks count
+//! once.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use walkdir::WalkDir;
+
+use crate::archive::Stage3Archive;
+use crate::manifest::EntryKind;
+use crate::status;
+
+/// Categories and the path prefixes that select them, first match wins.
+///
+/// Specific trees come before the broad `etc/` and library directories
+/// they live under.
+const CATEGORIES: &[(&str, &[&str])] = &[
+    (
+        "recipe",
+        &[
+            "usr/bin/recipe",
+            "etc/recipe/",
+            "var/lib/recipe/",
+            "var/cache/recipe/",
+        ],
+    ),
+    (
+        "systemd units",
+        &[
+            "usr/lib/systemd/system/",
+            "usr/lib/systemd/user/",
+            "etc/systemd/system/",
+            "etc/systemd/user/",
+        ],
+    ),
+    (
+        "locales",
+        &["usr/lib/locale/", "usr/share/locale/", "usr/share/i18n/"],
+    ),
+    ("zoneinfo", &["usr/share/zoneinfo/"]),
+    ("binaries", &["usr/bin/", "usr/sbin/", "bin/", "sbin/"]),
+    ("libraries", &["usr/lib64/", "usr/lib/", "lib64/", "lib/"]),
+    ("etc config", &["etc/"]),
+];
+
+/// Category of entries no rule matches.
+const OTHER: &str = "other";
+
+/// How many of the largest files to list by default.
+pub const LARGEST_FILES: usize = 10;
+
+/// Filename of the largest-files report written next to the tarball.
+pub const LARGEST_FILES_NAME: &str = "levitateos-stage3.largest-files.json";
+
+/// Size of one category.
+#[derive(Debug, Serialize)]
+pub struct CategorySize {
+    pub name: &'static str,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// A single large file.
+#[derive(Debug, Serialize)]
+pub struct FileSize {
+    pub path: String,
+    pub bytes: u64,
+}
+
+/// Size breakdown of a tarball.
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub tarball: PathBuf,
+    /// Size of the compressed tarball on disk
+    pub compressed_bytes: u64,
+    /// Uncompressed size of all entries
+    pub total_bytes: u64,
+    pub entries: u64,
+    /// Categories by descending size
+    pub categories: Vec<CategorySize>,
+    /// Largest files by descending size
+    pub largest: Vec<FileSize>,
+}
+
+/// The category of a rootfs-relative path.
+pub fn categorize(path: &str) -> &'static str {
+    CATEGORIES
+        .iter()
+        .find(|(_, prefixes)| {
+            prefixes
+                .iter()
+                .any(|prefix| path == prefix.trim_end_matches('/') || path.starts_with(prefix))
+        })
+        .map(|(name, _)| *name)
+        .unwrap_or(OTHER)
+}
+
+/// Stream a tarball and add up its sizes per category.
+pub fn inspect_tarball(path: &Path) -> Result<Inspection> {
+    let compressed_bytes = fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+
+    let mut categories: Vec<CategorySize> = CATEGORIES
+        .iter()
+        .map(|(name, _)| *name)
+        .chain([OTHER])
+        .map(|name| CategorySize {
+            name,
+            entries: 0,
+            bytes: 0,
+        })
+        .collect();
+    let mut files = Vec::new();
+
+    let mut archive = Stage3Archive::open(path)?;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let size = entry.size()?;
+
+        let name = categorize(entry.path());
+        if let Some(category) = categories.iter_mut().find(|c| c.name == name) {
+            category.entries += 1;
+            category.bytes += size;
+        }
+        if entry.kind() == EntryKind::File {
+            files.push(FileSize {
+                path: entry.path().to_string(),
+                bytes: size,
+            });
+        }
+    }
+
+    categories.retain(|c| c.entries > 0);
+    categories.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.name.cmp(b.name)));
+    files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.path.cmp(&b.path)));
+    files.truncate(LARGEST_FILES);
+
+    Ok(Inspection {
+        tarball: path.to_path_buf(),
+        compressed_bytes,
+        total_bytes: categories.iter().map(|c| c.bytes).sum(),
+        entries: archive.entries_read(),
+        categories,
+        largest: files,
+    })
+}
+
+impl Inspection {
+    /// Print the breakdown as a table.
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+}
+
+/// The breakdown as a table, one line per category and large file.
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Contents of {}:", self.tarball.display())?;
+        for category in &self.categories {
+            writeln!(
+                f,
+                "  {:<14} {:>10} {:>5.1}%  ({} entries)",
+                category.name,
+                human_size(category.bytes),
+                percent(category.bytes, self.total_bytes),
+                category.entries
+            )?;
+        }
+        writeln!(
+            f,
+            "  {:<14} {:>10}          ({} entries, {} compressed)",
+            "total",
+            human_size(self.total_bytes),
+            self.entries,
+            human_size(self.compressed_bytes)
+        )?;
+
+        if !self.largest.is_empty() {
+            writeln!(f, "\nLargest files:")?;
+            for file in &self.largest {
+                writeln!(f, "  {:>10}  /{}", human_size(file.bytes), file.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Largest files of a rootfs tree, grouped by directory.
+#[derive(Debug, Serialize)]
+pub struct LargestFiles {
+    /// Directories by descending size of their listed files
+    pub directories: Vec<DirectoryFiles>,
+}
+
+/// The listed files in one directory.
+#[derive(Debug, Serialize)]
+pub struct DirectoryFiles {
+    pub directory: String,
+    /// Combined size of the listed files
+    pub bytes: u64,
+    /// Files by descending size, paths relative to the rootfs
+    pub files: Vec<FileSize>,
+}
+
+/// Find the `count` largest regular files under `root`.
+///
+/// Hardlinked files are listed once, like in the tarball.
+pub fn largest_files(root: &Path, count: usize) -> Result<LargestFiles> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(root)?;
+        files.push(FileSize {
+            path: rel.to_string_lossy().to_string(),
+            bytes: metadata.len(),
+        });
+    }
+    files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.path.cmp(&b.path)));
+    files.truncate(count);
+
+    let mut by_dir: BTreeMap<String, Vec<FileSize>> = BTreeMap::new();
+    for file in files {
+        let dir = match file.path.rsplit_once('/') {
+            Some((dir, _)) => dir.to_string(),
+            None => String::new(),
+        };
+        by_dir.entry(dir).or_default().push(file);
+    }
+    let mut directories: Vec<DirectoryFiles> = by_dir
+        .into_iter()
+        .map(|(directory, files)| DirectoryFiles {
+            directory,
+            bytes: files.iter().map(|f| f.bytes).sum(),
+            files,
+        })
+        .collect();
+    directories.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.directory.cmp(&b.directory)));
+
+    Ok(LargestFiles { directories })
+}
+
+impl LargestFiles {
+    /// Print the files under a line per directory.
+    pub fn print(&self) {
+        for dir in &self.directories {
+            status!(
+                "  /{}  ({} in {})",
+                dir.directory,
+                human_size(dir.bytes),
+                match dir.files.len() {
+                    1 => "1 file".to_string(),
+                    n => format!("{} files", n),
+                }
+            );
+            for file in &dir.files {
+                let name = file.path.rsplit('/').next().unwrap_or(&file.path);
+                status!("    {:>10}  {}", human_size(file.bytes), name);
+            }
+        }
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64 * 100.0
+    }
+}
+
+/// Format a byte count with a binary unit (`12.3 MiB`).
+pub fn human_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+/// A size given on the command line, like `350M` or `1.5GiB`.
+///
+/// `K`, `M` and `G` (optionally with `iB`) are binary units, `KB`, `MB` and
+/// `GB` decimal ones; a plain number is bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let multiplier: u64 = match unit.trim() {
+            "" | "B" => 1,
+            "K" | "KiB" => 1 << 10,
+            "M" | "MiB" => 1 << 20,
+            "G" | "GiB" => 1 << 30,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            other => return Err(format!("unknown size unit {:?} in {:?}", other, s)),
+        };
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid size {:?} (e.g. 350M)", s))?;
+        Ok(Self((number * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&human_size(self.0))
+    }
+}
@@ -12,10 +12,70 @@
 //! - **pam**: Real PAM authentication (not permissive like live)
 //! - **recipe**: Package manager integration
 
+pub mod arch;
+pub mod archive;
+pub mod artifact;
+pub mod attest;
+pub mod audit;
 pub mod binary;
+pub mod boottest;
 pub mod builder;
+pub mod cache;
+pub mod cancel;
+pub mod channel;
+pub mod checkpoint;
+pub mod checksum;
+pub mod clock;
+pub mod condition;
+pub mod config;
+pub mod console;
+pub mod container;
 pub mod context;
+pub mod diff;
+pub mod dlopen;
+pub mod donor;
+pub mod download;
+pub mod elf;
+pub mod error;
+pub mod failure;
+pub mod fakeroot;
+pub mod fragments;
+pub mod hardlink;
+pub mod inspect;
+pub mod linker;
+pub mod list;
+pub mod lock;
+pub mod manifest;
+pub mod placeholders;
+pub mod plan;
+pub mod policy;
+pub mod progress;
+pub mod provenance;
+pub mod reflink;
+pub mod release;
+pub mod remap;
+pub mod report;
+pub mod resolve;
+pub mod respin;
 pub mod rootfs;
+pub mod sandbox;
+pub mod secrets;
+pub mod shell;
+pub mod signing;
+pub mod sparse;
+pub mod steps;
+pub mod store;
+pub mod targets;
+pub mod templates;
+pub mod timings;
+pub mod validate;
+pub mod xattrs;
 
 pub use builder::Stage3Builder;
-pub use context::BuildContext;
+pub use config::Stage3Config;
+pub use context::{BuildContext, BuildOptions};
+pub use error::Stage3Error;
+
+// For `detail!` and `status!`
+#[doc(hidden)]
+pub use tracing;
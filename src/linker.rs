@@ -0,0 +1,287 @@
+//! Library dependencies, resolved inside the donor.
+//!
+//! Running the build host's `ldd` on donor binaries is slow, one process
+//! per binary, and resolves against the host's libraries rather than the
+//! donor's: a library the donor lacks but the host has looks present, and
+//! the host's loader configuration decides which copy is found. Instead,
+//! [`library_dependencies`] reads `PT_INTERP`, `DT_NEEDED` and
+//! `DT_RUNPATH`/`DT_RPATH` from the ELF files themselves and searches the
+//! donor the way its loader would: the object's run path, the directories
+//! in the donor's ld.so.conf, then the default library directories (with
+//! the multiarch ones of Debian-style donors and riscv64's lp64d first),
+//! skipping libraries of another architecture. Symlinks are followed
+//! inside the donor. `stage3 build --ldd` goes back to `ldd`.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::arch::arch_lib_dirs;
+use crate::binary::elf_arch;
+use crate::donor::PackageSource;
+use crate::elf;
+
+/// Library directories the dynamic loader always searches.
+const DEFAULT_LIB_DIRS: &[&str] = &["lib64", "usr/lib64", "lib", "usr/lib"];
+
+/// Maximum nesting of ld.so.conf `include` directives.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// What a binary needs at run time.
+#[derive(Debug, Default)]
+pub struct Dependencies {
+    /// Absolute rootfs paths of the interpreter and every library needed,
+    /// directly or through another library, in the order found
+    pub libraries: Vec<String>,
+    /// Interpreter or `DT_NEEDED` names not found in the donor
+    pub missing: Vec<String>,
+}
+
+/// Libraries resolved and copied so far in a build.
+///
+/// Binaries share most of their libraries (glibc, libm, ...), so each
+/// library's own dependencies are looked up once, and each is copied once
+/// whichever binaries and threads need it. Binaries are claimed the same
+/// way, for steps running at once that copy the same one.
+#[derive(Default)]
+pub struct LibraryCache {
+    /// Directories from the donor's ld.so.conf
+    conf_dirs: OnceLock<Vec<String>>,
+    /// Direct dependencies of every library resolved so far, by rootfs path
+    objects: Mutex<HashMap<PathBuf, Arc<OnceLock<Arc<Object>>>>>,
+    /// Staged libraries and binaries copied, or being copied by another
+    /// thread
+    copied: Mutex<HashSet<PathBuf>>,
+}
+
+/// Direct dependencies of one ELF object.
+#[derive(Debug, Default)]
+struct Object {
+    /// `DT_NEEDED` names found, with the rootfs path each resolves to
+    found: Vec<(String, PathBuf)>,
+    /// `DT_NEEDED` names not found
+    missing: Vec<String>,
+}
+
+impl LibraryCache {
+    /// Claim staged library or binary `dest` for copying; false if it was
+    /// claimed before, by this or another thread.
+    pub fn claim(&self, dest: &Path) -> bool {
+        self.copied.lock().unwrap().insert(dest.to_path_buf())
+    }
+
+    fn conf_dirs(&self, source: &dyn PackageSource) -> &[String] {
+        self.conf_dirs.get_or_init(|| ld_conf_dirs(source))
+    }
+
+    /// Direct dependencies of the library at rootfs path `path`.
+    fn object(&self, source: &dyn PackageSource, path: &Path, arch: Option<&str>) -> Arc<Object> {
+        let entry = self
+            .objects
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        // Resolved outside the map's lock, so threads only wait for the
+        // libraries they both need
+        entry
+            .get_or_init(|| {
+                let parsed = donor_file(source, path)
+                    .and_then(|file| fs::read(file).ok())
+                    .and_then(|bytes| elf::dynamic_info(&bytes));
+                let object = match parsed {
+                    Some(info) => self.resolve(source, &info, path.parent(), arch),
+                    None => Object::default(),
+                };
+                Arc::new(object)
+            })
+            .clone()
+    }
+
+    /// Look up the `DT_NEEDED` entries of an object in directory `origin`.
+    fn resolve(
+        &self,
+        source: &dyn PackageSource,
+        info: &elf::DynamicInfo,
+        origin: Option<&Path>,
+        arch: Option<&str>,
+    ) -> Object {
+        let search = search_dirs(&info.runpath, origin, self.conf_dirs(source), arch);
+        let mut object = Object::default();
+        for needed in &info.needed {
+            match find_library(source, &search, needed, arch) {
+                Some(path) => object.found.push((needed.clone(), path)),
+                None => object.missing.push(needed.clone()),
+            }
+        }
+        object
+    }
+}
+
+/// Resolve the interpreter and libraries `binary` (a file of `source`)
+/// needs, inside `source`, reusing what `cache` resolved before.
+///
+/// Static binaries and files that aren't dynamic ELF objects need nothing.
+pub fn library_dependencies(
+    source: &dyn PackageSource,
+    cache: &LibraryCache,
+    binary: &Path,
+) -> Result<Dependencies> {
+    let bytes = fs::read(binary).with_context(|| format!("Failed to read {}", binary.display()))?;
+    let mut deps = Dependencies::default();
+    let Some(info) = elf::dynamic_info(&bytes) else {
+        return Ok(deps);
+    };
+    let arch = elf_arch(binary);
+
+    let mut seen = BTreeSet::new();
+    if let Some(ref interpreter) = info.interpreter {
+        match donor_file(source, Path::new(interpreter.trim_start_matches('/'))) {
+            Some(_) => deps.libraries.push(interpreter.clone()),
+            None => deps.missing.push(interpreter.clone()),
+        }
+        // Libraries needing the loader by name get the one already loaded
+        if let Some(name) = Path::new(interpreter).file_name() {
+            seen.insert(name.to_string_lossy().into_owned());
+        }
+    }
+
+    // Breadth first, like the loader, so the first copy of a name wins
+    let origin = source.rootfs_path(binary);
+    let origin = origin.as_deref().and_then(Path::parent);
+    let mut pending = VecDeque::from([Arc::new(cache.resolve(source, &info, origin, arch))]);
+    while let Some(object) = pending.pop_front() {
+        for (name, path) in &object.found {
+            if seen.insert(name.clone()) {
+                deps.libraries.push(format!("/{}", path.display()));
+                pending.push_back(cache.object(source, path, arch));
+            }
+        }
+        for name in &object.missing {
+            if seen.insert(name.clone()) {
+                deps.missing.push(name.clone());
+            }
+        }
+    }
+    Ok(deps)
+}
+
+/// Directories searched for the libraries of one object for `arch`,
+/// rootfs-relative.
+///
+/// Run path entries using `$ORIGIN` are dropped when the object's own
+/// rootfs path isn't known, as are ones with other loader variables.
+fn search_dirs(
+    runpath: &[String],
+    origin: Option<&Path>,
+    conf_dirs: &[String],
+    arch: Option<&str>,
+) -> Vec<String> {
+    let origin = origin.map(|dir| format!("/{}", dir.display()));
+    runpath
+        .iter()
+        .filter_map(|dir| match origin {
+            Some(ref origin) => Some(dir.replace("${ORIGIN}", origin).replace("$ORIGIN", origin)),
+            None if dir.contains("ORIGIN") => None,
+            None => Some(dir.clone()),
+        })
+        .filter(|dir| !dir.contains('$'))
+        .map(|dir| dir.trim_start_matches('/').to_string())
+        .chain(conf_dirs.iter().cloned())
+        .chain(arch.map(arch_lib_dirs).unwrap_or_default())
+        .chain(DEFAULT_LIB_DIRS.iter().map(|dir| dir.to_string()))
+        .collect()
+}
+
+/// Rootfs path of the first library called `needed` in `search` that is
+/// for `arch`.
+fn find_library(
+    source: &dyn PackageSource,
+    search: &[String],
+    needed: &str,
+    arch: Option<&str>,
+) -> Option<PathBuf> {
+    // A slash means the loader uses the path as-is
+    let candidates: Vec<PathBuf> = if needed.contains('/') {
+        vec![PathBuf::from(needed.trim_start_matches('/'))]
+    } else {
+        search
+            .iter()
+            .map(|dir| Path::new(dir).join(needed))
+            .collect()
+    };
+    candidates.into_iter().find_map(|path| {
+        let file = donor_file(source, &path)?;
+        match (arch, elf_arch(&file)) {
+            (Some(arch), Some(found)) if found != arch => None,
+            _ => Some(path),
+        }
+    })
+}
+
+/// The donor file at rootfs-relative `path`, with symlinks followed
+/// inside the donor.
+fn donor_file(source: &dyn PackageSource, path: &Path) -> Option<PathBuf> {
+    source.find_resolved(path).filter(|file| file.is_file())
+}
+
+/// Library directories listed in the donor's ld.so.conf, rootfs-relative.
+fn ld_conf_dirs(source: &dyn PackageSource) -> Vec<String> {
+    let mut dirs = Vec::new();
+    read_ld_conf(source, Path::new("etc/ld.so.conf"), 0, &mut dirs);
+    dirs
+}
+
+fn read_ld_conf(source: &dyn PackageSource, path: &Path, depth: usize, dirs: &mut Vec<String>) {
+    let Some(contents) = donor_file(source, path).and_then(|f| fs::read_to_string(f).ok()) else {
+        return;
+    };
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            if depth < MAX_INCLUDE_DEPTH {
+                for included in expand_include(source, pattern.trim()) {
+                    read_ld_conf(source, &included, depth + 1, dirs);
+                }
+            }
+        } else if line.starts_with('/') {
+            let dir = line.trim_start_matches('/').to_string();
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+}
+
+/// Files matched by an `include` pattern such as
+/// `/etc/ld.so.conf.d/*.conf`, sorted like the loader's glob; only a `*`
+/// in the file name is supported.
+fn expand_include(source: &dyn PackageSource, pattern: &str) -> Vec<PathBuf> {
+    let pattern = Path::new(pattern.trim_start_matches('/'));
+    let name = pattern
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dir = pattern.parent().unwrap_or(Path::new(""));
+    let Some((prefix, suffix)) = name.split_once('*') else {
+        return vec![pattern.to_path_buf()];
+    };
+    let Some(entries) = source.find_file(dir).and_then(|d| fs::read_dir(d).ok()) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|file| {
+            file.len() >= prefix.len() + suffix.len()
+                && file.starts_with(prefix)
+                && file.ends_with(suffix)
+        })
+        .map(|file| dir.join(file))
+        .collect();
+    files.sort();
+    files
+}
@@ -0,0 +1,170 @@
+//! Listing the contents of a tarball.
+//!
+//! Entries are read in-process (without hashing file contents) and can be
+//! filtered by a glob, shown with their metadata, drawn as a tree or
+//! emitted as JSON in the manifest's entry format.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::archive;
+use crate::manifest::{self, EntryKind, ManifestEntry};
+
+/// Options for [`list_tarball`].
+#[derive(Default)]
+pub struct ListOptions {
+    /// Show type, mode, owner and size like `ls -l`
+    pub long: bool,
+    /// Draw the entries as a directory tree
+    pub tree: bool,
+    /// Print the entries as JSON
+    pub json: bool,
+    /// Only list entries whose path matches this glob
+    pub filter: Option<String>,
+}
+
+/// List the entries of a tarball.
+pub fn list_tarball(path: &Path, options: &ListOptions) -> Result<()> {
+    let matcher = options
+        .filter
+        .as_ref()
+        .map(|filter| archive::compile_globs(std::slice::from_ref(filter)))
+        .transpose()?;
+    let mut entries = manifest::read_entries(path, false)?;
+    if let Some(ref matcher) = matcher {
+        entries.retain(|entry| matcher.is_match(&entry.path));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let written = if options.json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&entries)?)
+    } else {
+        writeln!(out, "Contents of {}:", path.display())
+            .and_then(|()| match options.tree {
+                true => write_tree(&mut out, &entries, options.long),
+                false => entries
+                    .iter()
+                    .try_for_each(|entry| writeln!(out, "{}", entry_line(entry, options.long))),
+            })
+            .and_then(|()| writeln!(out, "{} entries", entries.len()))
+    };
+    match written {
+        // Output piped into something like `head` that has exited
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// One entry as a line, optionally with its metadata.
+fn entry_line(entry: &ManifestEntry, long: bool) -> String {
+    let name = format!("{}{}", entry.path, link_suffix(entry));
+    if !long {
+        return name;
+    }
+    format!("{}  {}", metadata_columns(entry), name)
+}
+
+/// `-rwxr-xr-x 0/0    12345`, like `tar -tv`.
+fn metadata_columns(entry: &ManifestEntry) -> String {
+    let size = match entry.device {
+        Some(ref device) => device.replace(':', ","),
+        None => entry.size.to_string(),
+    };
+    format!(
+        "{}{} {:>5} {:>10}",
+        type_char(entry.kind),
+        permissions(&entry.mode),
+        format!("{}/{}", entry.uid, entry.gid),
+        size
+    )
+}
+
+fn link_suffix(entry: &ManifestEntry) -> String {
+    match (entry.kind, &entry.target) {
+        (EntryKind::Symlink, Some(target)) => format!(" -> {}", target),
+        (EntryKind::Hardlink, Some(target)) => format!(" link to {}", target),
+        _ => String::new(),
+    }
+}
+
+fn type_char(kind: EntryKind) -> char {
+    match kind {
+        EntryKind::File | EntryKind::Other => '-',
+        EntryKind::Dir => 'd',
+        EntryKind::Symlink => 'l',
+        EntryKind::Hardlink => 'h',
+        EntryKind::Char => 'c',
+        EntryKind::Block => 'b',
+        EntryKind::Fifo => 'p',
+    }
+}
+
+/// Render octal permission bits (`4755`) as `rwsr-xr-x`.
+fn permissions(mode: &str) -> String {
+    let mode = u32::from_str_radix(mode, 8).unwrap_or(0);
+    let mut out = String::with_capacity(9);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 2 != 0 { 'w' } else { '-' });
+        out.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    out
+}
+
+/// A directory in the tree view; `entry` is unset for directories only
+/// implied by the paths below them.
+#[derive(Default)]
+struct TreeNode<'a> {
+    entry: Option<&'a ManifestEntry>,
+    children: BTreeMap<&'a str, TreeNode<'a>>,
+}
+
+fn write_tree(out: &mut impl Write, entries: &[ManifestEntry], long: bool) -> io::Result<()> {
+    let mut root = TreeNode::default();
+    for entry in entries.iter().filter(|e| e.path != ".") {
+        let node = entry.path.split('/').fold(&mut root, |node, part| {
+            node.children.entry(part).or_default()
+        });
+        node.entry = Some(entry);
+    }
+
+    writeln!(out, "/")?;
+    write_children(out, &root, "", long)
+}
+
+fn write_children(
+    out: &mut impl Write,
+    node: &TreeNode,
+    prefix: &str,
+    long: bool,
+) -> io::Result<()> {
+    let count = node.children.len();
+    for (i, (name, child)) in node.children.iter().enumerate() {
+        let last = i + 1 == count;
+        let branch = if last { "└── " } else { "├── " };
+        let line = match child.entry {
+            Some(entry) if long => format!(
+                "{}  {}{}",
+                metadata_columns(entry),
+                name,
+                link_suffix(entry)
+            ),
+            Some(entry) => format!("{}{}", name, link_suffix(entry)),
+            None => name.to_string(),
+        };
+        writeln!(out, "{}{}{}", prefix, branch, line)?;
+
+        let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        write_children(out, child, &child_prefix, long)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! One build per output directory.
+//!
+//! A build deletes and recreates `staging/` and the tarball in its output
+//! directory, so two builds sharing one (parallel CI jobs, say) would
+//! corrupt each other. [`OutputLock::acquire`] takes an advisory `flock`
+//! on a lock file in the output directory for as long as the build runs.
+//! A second build fails right away with [`Stage3Error::OutputLocked`], or
+//! with `--wait-lock` waits until the first one is done. The kernel drops
+//! the lock when its holder exits, so a killed build never leaves a stale
+//! one behind.
+
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancel::CancelToken;
+use crate::error::Stage3Error;
+use crate::status;
+
+/// Lock file in the output directory.
+pub const LOCK_NAME: &str = ".stage3.lock";
+
+/// How often a waiting build tries the lock again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Exclusive hold on an output directory, released when dropped.
+#[derive(Debug)]
+pub struct OutputLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl OutputLock {
+    /// Lock `output_dir`, creating it if needed.
+    ///
+    /// If another build holds the lock, fail, or with `wait` try again
+    /// until it is released or `cancel` is cancelled.
+    pub fn acquire(output_dir: &Path, wait: bool, cancel: &CancelToken) -> Result<Self> {
+        fs::create_dir_all(output_dir)
+            .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+        let path = output_dir.join(LOCK_NAME);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let mut waiting = false;
+        while !try_lock(&file).with_context(|| format!("Failed to lock {}", path.display()))? {
+            let pid = fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse().ok());
+            if !wait {
+                bail!(Stage3Error::OutputLocked {
+                    output: output_dir.to_path_buf(),
+                    pid,
+                });
+            }
+            if !waiting {
+                let holder = pid.map(|pid: u32| format!(" (pid {})", pid));
+                status!(
+                    "Waiting for the build{} using {}...",
+                    holder.unwrap_or_default(),
+                    output_dir.display()
+                );
+                waiting = true;
+            }
+            cancel.check()?;
+            thread::sleep(RETRY_INTERVAL);
+        }
+
+        let mut lock = Self { file, path };
+        lock.write_pid()
+            .with_context(|| format!("Failed to write {}", lock.path.display()))?;
+        Ok(lock)
+    }
+
+    /// Leave our pid for builds that find the directory locked.
+    fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        (&self.file).write_all(format!("{}\n", std::process::id()).as_bytes())
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Nobody holds it any more; closing the file releases the lock
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Take an exclusive lock on `file` without blocking; false if it's held.
+fn try_lock(file: &File) -> io::Result<bool> {
+    // SAFETY: flock on a descriptor we own
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        err if err.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(false),
+        err => Err(err),
+    }
+}
@@ -10,9 +10,39 @@
 
 use anyhow::Result;
 use clap::Parser;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
-use stage3::builder::{list_tarball, verify_tarball, Stage3Builder};
+use stage3::archive::{Compression, ExtractOptions, Stage3Archive};
+use stage3::attest::attest_tarball;
+use stage3::audit::audit_tarball;
+use stage3::boottest::{boot_test, BootTestOptions, QemuOptions};
+use stage3::builder::{verify_tarball, CheckStatus, Stage3Builder, VerifyOptions};
+use stage3::cancel::{cancel_on_signal, CancelToken};
+use stage3::channel::{compare_to_channel, DEFAULT_CHANNEL_URL};
+use stage3::config::{Stage3Config, CONFIG_NAME};
+use stage3::console::{self, Verbosity};
+use stage3::diff::print_diff;
+use stage3::download;
+use stage3::failure::{self, Failure, FailureKind};
+use stage3::inspect::{inspect_tarball, ByteSize, LARGEST_FILES};
+use stage3::list::{list_tarball, ListOptions};
+use stage3::manifest::MANIFEST_NAME;
+use stage3::placeholders::{finalize, FinalizeValues};
+use stage3::policy::SetuidAllowlist;
+use stage3::progress::{JsonLinesProgress, ProgressFormat};
+use stage3::release::create_release;
+use stage3::remap::PathRemap;
+use stage3::respin::respin;
+use stage3::rootfs::recipe::{RebootPolicy, UpgradeTimer};
+use stage3::rootfs::systemd::RandomSeedPolicy;
+use stage3::rootfs::user_services::UserService;
+use stage3::secrets::Secret;
+use stage3::shell::shell;
+use stage3::steps::{BuildStep, StepSelection};
+use stage3::targets::{build_targets, check_outcomes, print_outcomes, BuildTarget};
+use stage3::xattrs::SelinuxLabels;
 
 #[derive(Parser)]
 #[command(name = "stage3")]
@@ -20,8 +50,22 @@ use stage3::builder::{list_tarball, verify_tarball, Stage3Builder};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Only print failures and the final warnings table
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also print every routine step, not just one line per component
+    #[arg(long, short, global = true)]
+    verbose: bool,
+
+    /// Plain text even on a terminal (also with NO_COLOR set)
+    #[arg(long, global = true)]
+    no_color: bool,
 }
 
+// Parsed once at startup, so the size of the Build variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(clap::Subcommand)]
 enum Commands {
     /// Build the stage3 tarball
@@ -37,44 +81,930 @@ enum Commands {
         /// Path to recipe binary (optional)
         #[arg(short, long)]
         recipe: Option<PathBuf>,
+
+        /// Build settings file (see stage3.toml in the README); flags win over it
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// Output filename template ({version}, {arch}, {date}, {profile}, {ext});
+        /// default levitateos-stage3-{version}-{arch}{ext}
+        #[arg(long)]
+        output_name: Option<String>,
+
+        /// Build profile name (default base)
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Fail unless the source rootfs is for ARCH (x86_64, aarch64, ...);
+        /// the default is whatever it is for
+        #[arg(long, value_name = "ARCH", conflicts_with = "targets")]
+        arch: Option<String>,
+
+        /// Deny setuid/setgid files except these rootfs paths (comma-separated)
+        #[arg(long, value_delimiter = ',', num_args = 0..)]
+        setuid_allowlist: Option<Vec<PathBuf>>,
+
+        /// Enforce a build that works unprivileged inside a container
+        #[arg(long)]
+        container_safe: bool,
+
+        /// minisign secret key used to sign the tarball
+        #[arg(long)]
+        sign_key: Option<PathBuf>,
+
+        /// Random seed to ship: none (generated on first boot) or per-build
+        #[arg(long, default_value = "none")]
+        random_seed: RandomSeedPolicy,
+
+        /// Inject a secret: DEST=file:PATH or DEST=env:VAR (repeatable)
+        #[arg(long = "secret", value_name = "DEST=SOURCE")]
+        secrets: Vec<Secret>,
+
+        /// Put donor files under FROM at TO instead, e.g. usr/lib64/security=usr/lib/security (repeatable)
+        #[arg(long = "remap", value_name = "FROM=TO")]
+        remaps: Vec<PathRemap>,
+
+        /// Enable a user unit for every user, e.g. pipewire.socket (repeatable)
+        #[arg(long = "user-service", value_name = "UNIT")]
+        user_services: Vec<UserService>,
+
+        /// Run systemd-analyze verify over the enabled units
+        #[arg(long)]
+        verify_units: bool,
+
+        /// Also remove rootfs paths matching this glob (repeatable)
+        #[arg(long = "sanitize", value_name = "GLOB")]
+        sanitize: Vec<String>,
+
+        /// Keep rootfs paths matching this glob despite sanitization (repeatable)
+        #[arg(long = "sanitize-keep", value_name = "GLOB")]
+        sanitize_keep: Vec<String>,
+
+        /// Fail if the build needs the network; helpers run without network access
+        #[arg(long)]
+        offline: bool,
+
+        /// Find libraries with the host's ldd instead of reading the ELF files
+        #[arg(long)]
+        ldd: bool,
+
+        /// Fail instead of copying libraries missing from the donor from the host
+        #[arg(long)]
+        no_host_fallback: bool,
+
+        /// Fail the build on validation findings and on binaries, libraries, PAM modules
+        /// or units that could not be copied, instead of warning
+        #[arg(long)]
+        strict: bool,
+
+        /// Include braille (brltty) and speech (espeakup) console support
+        #[arg(long)]
+        accessibility: bool,
+
+        /// Enable unattended recipe upgrades on this OnCalendar= schedule (e.g. weekly)
+        #[arg(long, value_name = "SCHEDULE")]
+        upgrade_timer: Option<String>,
+
+        /// After an unattended upgrade: never or always reboot
+        #[arg(
+            long,
+            value_name = "POLICY",
+            default_value = "never",
+            requires = "upgrade_timer"
+        )]
+        upgrade_reboot: RebootPolicy,
+
+        /// Ship this statically linked busybox as /usr/bin/busybox.static for recovery
+        #[arg(long, value_name = "PATH")]
+        busybox_static: Option<PathBuf>,
+
+        /// Check units, disk space, time sync and DNS at boot (levitate-healthcheck.service)
+        #[arg(long)]
+        healthcheck: bool,
+
+        /// Ship quickstart, recovery and recipe pages in /usr/share/levitate/help
+        #[arg(long)]
+        offline_help: bool,
+
+        /// Markdown pages (NAME.md) replacing or adding to the built-in help
+        #[arg(long, value_name = "DIR", requires = "offline_help")]
+        help_pages: Option<PathBuf>,
+
+        /// Appliance lockdown: no gettys or rescue shells, root locked, SSH with keys only
+        #[arg(long, conflicts_with = "busybox_static")]
+        lockdown: bool,
+
+        /// Install these public keys as root's authorized_keys (with --lockdown)
+        #[arg(long, value_name = "FILE", requires = "lockdown")]
+        authorized_keys: Option<PathBuf>,
+
+        /// Timestamp for every generated file and archive entry (default: SOURCE_DATE_EPOCH)
+        #[arg(long, value_name = "EPOCH")]
+        source_date_epoch: Option<u64>,
+
+        /// Keep the staging directory after the build (for `stage3 shell`)
+        #[arg(long)]
+        keep_staging: bool,
+
+        /// Continue a failed build in its staging directory, skipping the
+        /// steps it completed with the same inputs
+        #[arg(long, conflicts_with = "dry_run")]
+        resume: bool,
+
+        /// Keep the finished staging directory in output/.stage3-cache and
+        /// reuse its copies of donor files that haven't changed since
+        #[arg(long, conflicts_with = "keep_staging")]
+        incremental: bool,
+
+        /// Store staged binaries and libraries once in this
+        /// content-addressed directory, shared by builds, and hard-link
+        /// them from there
+        #[arg(long, value_name = "DIR")]
+        object_store: Option<PathBuf>,
+
+        /// Keep timezone, locale, i18n and udev rules output as tarball
+        /// fragments in this directory, and unpack them from there when
+        /// their donor files and options are unchanged
+        #[arg(long, value_name = "DIR")]
+        fragment_cache: Option<PathBuf>,
+
+        /// Also write files with the same contents as an earlier one as
+        /// hard links in the tarball
+        #[arg(long)]
+        dedup: bool,
+
+        /// Carry POSIX ACLs of donor files into the tarball (for targets
+        /// whose filesystem supports them)
+        #[arg(long)]
+        acls: bool,
+
+        /// SELinux labels of donor files: drop, preserve (into the tarball)
+        /// or relabel (drop them and create /.autorelabel)
+        #[arg(long, value_name = "MODE", default_value = "drop")]
+        selinux: SelinuxLabels,
+
+        /// Rootfs steps run at the same time, as their dependencies allow
+        /// (default: number of CPUs; 1 runs them in order)
+        #[arg(long, value_name = "N")]
+        step_jobs: Option<usize>,
+
+        /// Wait for another build using the output directory to finish
+        /// instead of failing
+        #[arg(long)]
+        wait_lock: bool,
+
+        /// Run only these steps on the staging directory of an earlier
+        /// build (fhs, symlinks, shell, coreutils, sbin, systemd-binaries,
+        /// units, etc, pam, recipe, ..., sanitize, validate, tarball)
+        #[arg(
+            long,
+            value_name = "STEP",
+            value_delimiter = ',',
+            conflicts_with = "dry_run"
+        )]
+        only_step: Vec<BuildStep>,
+
+        /// Leave out these steps, keeping what an earlier build staged
+        #[arg(
+            long,
+            value_name = "STEP",
+            value_delimiter = ',',
+            conflicts_with = "dry_run"
+        )]
+        skip_step: Vec<BuildStep>,
+
+        /// Directory of config templates overriding the built-in ones by rootfs path
+        #[arg(long, value_name = "DIR")]
+        templates: Option<PathBuf>,
+
+        /// Fail if the compressed tarball is larger than this (e.g. 350M, 1.5GiB, 350MB)
+        #[arg(long, value_name = "SIZE")]
+        max_size: Option<ByteSize>,
+
+        /// Print this many of the largest files after archiving, by directory (0 to disable)
+        #[arg(long, value_name = "N", default_value_t = LARGEST_FILES)]
+        largest_files: usize,
+
+        /// Also write the largest files to levitateos-stage3.largest-files.json
+        #[arg(long)]
+        largest_files_json: bool,
+
+        /// Manifest of a previous build; report added, removed and grown files against it
+        #[arg(long, value_name = "MANIFEST")]
+        baseline: Option<PathBuf>,
+
+        /// Tarball compression: xz (default), zstd, gzip or none (plain tar)
+        #[arg(long)]
+        compression: Option<Compression>,
+
+        /// Only list the binaries and libraries the build would copy, without
+        /// staging or archiving; as one JSON object with --progress=json
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Progress reporting: human, or json for JSON lines events on stdout
+        #[arg(long, value_name = "FORMAT", default_value = "human")]
+        progress: ProgressFormat,
+
+        /// Build ARCH/PROFILE from SOURCE (default: --source) into OUTPUT/ARCH-PROFILE;
+        /// repeatable, replaces --profile
+        #[arg(
+            long = "target",
+            value_name = "ARCH/PROFILE[=SOURCE]",
+            conflicts_with_all = ["profile", "dry_run"]
+        )]
+        targets: Vec<BuildTarget>,
+
+        /// Targets built at the same time (default: number of CPUs)
+        #[arg(long, value_name = "N", requires = "targets")]
+        jobs: Option<usize>,
     },
 
     /// List contents of an existing tarball
     List {
         /// Path to tarball
         path: PathBuf,
+
+        /// Only list paths matching this glob (e.g. 'usr/lib/*.so*')
+        filter: Option<String>,
+
+        /// Show type, permissions, owner and size
+        #[arg(long, short)]
+        long: bool,
+
+        /// Draw the entries as a directory tree
+        #[arg(long)]
+        tree: bool,
+
+        /// Print the entries as JSON
+        #[arg(long, conflicts_with_all = ["long", "tree"])]
+        json: bool,
     },
 
-    /// Verify tarball contains essential files
+    /// Verify tarball contents, permissions and symlinks
     Verify {
+        /// Path or https:// URL of the tarball
+        path: PathBuf,
+
+        /// Also validate the tarball against its .sha256 sidecar
+        #[arg(long)]
+        checksum: bool,
+
+        /// Check the detached .minisig signature before content checks
+        #[arg(long)]
+        signature: bool,
+
+        /// minisign public key for --signature
+        #[arg(long, requires = "signature")]
+        public_key: Option<PathBuf>,
+
+        /// Check every entry against a per-file manifest (default: next to the tarball)
+        #[arg(long, value_name = "MANIFEST", num_args = 0..=1, require_equals = true)]
+        against_manifest: Option<Option<PathBuf>>,
+
+        /// Run systemd-analyze verify over the tarball's enabled units
+        #[arg(long)]
+        units: bool,
+
+        /// Print per-check results as JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check a deployed tarball against its release manifest and signature
+    ///
+    /// Exits with 3 if the content was modified, 4 if the signature is bad
+    /// and 5 if the release manifest or build metadata is missing.
+    Attest {
+        /// Path or https:// URL of the tarball
+        path: PathBuf,
+
+        /// Release manifest (default: <tarball>.json)
+        #[arg(long, value_name = "PATH")]
+        manifest: Option<PathBuf>,
+
+        /// minisign public key for the signature check
+        #[arg(long)]
+        public_key: Option<PathBuf>,
+
+        /// Print per-check results as JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Extract a tarball, or only the paths matching some globs
+    Extract {
+        /// Path or https:// URL of the tarball
+        path: PathBuf,
+
+        /// Directory to extract into
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Only extract paths matching these globs (e.g. 'etc/**')
+        patterns: Vec<String>,
+
+        /// Restore ownership, modes, device nodes and xattrs (needs root)
+        #[arg(long)]
+        rootfs: bool,
+    },
+
+    /// Fill in the fstab, crypttab and loader entry placeholders of an extracted tree
+    Finalize {
+        /// Root of the extracted stage3
+        root: PathBuf,
+
+        /// Filesystem UUID of the root partition (@ROOT_UUID@)
+        #[arg(long, value_name = "UUID")]
+        root_uuid: String,
+
+        /// Filesystem type of the root partition (@ROOT_FSTYPE@)
+        #[arg(long, value_name = "TYPE", default_value = "ext4")]
+        root_fstype: String,
+
+        /// Filesystem UUID of the EFI System Partition (@ESP_UUID@)
+        #[arg(long, value_name = "UUID")]
+        esp_uuid: Option<String>,
+
+        /// UUID of the LUKS container holding the root filesystem (@LUKS_UUID@)
+        #[arg(long, value_name = "UUID")]
+        luks_uuid: Option<String>,
+    },
+
+    /// Check a build config file and print it as the build reads it
+    ValidateConfig {
+        /// Config file
+        #[arg(default_value = CONFIG_NAME)]
+        path: PathBuf,
+    },
+
+    /// Show per-category and total sizes of a tarball
+    Inspect {
+        /// Path or https:// URL of the tarball
+        path: PathBuf,
+
+        /// Print the breakdown as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Audit a tarball for missing shared libraries and dangling symlinks
+    Audit {
         /// Path to tarball
         path: PathBuf,
     },
+
+    /// Boot a tarball with systemd-nspawn or QEMU and watch the console (needs root)
+    BootTest {
+        /// Path to tarball
+        path: PathBuf,
+
+        /// Seconds to wait for every expected console marker
+        #[arg(long, default_value_t = 120)]
+        timeout: u64,
+
+        /// Console log file (default: <tarball>.boot.log)
+        #[arg(long, value_name = "PATH")]
+        log: Option<PathBuf>,
+
+        /// Boot a disk image under QEMU instead of systemd-nspawn
+        #[arg(long, requires = "kernel")]
+        qemu: bool,
+
+        /// Kernel to boot with --qemu (needs virtio-blk and ext4)
+        #[arg(long, value_name = "PATH", requires = "qemu")]
+        kernel: Option<PathBuf>,
+
+        /// Initramfs to boot with --qemu
+        #[arg(long, value_name = "PATH", requires = "qemu")]
+        initrd: Option<PathBuf>,
+
+        /// Guest memory in MiB for --qemu
+        #[arg(long, default_value_t = 1024, requires = "qemu")]
+        memory: u32,
+
+        /// Also require this text on the console (repeatable)
+        #[arg(long, value_name = "TEXT")]
+        expect: Vec<String>,
+    },
+
+    /// Chroot into a tarball or kept staging directory with /proc, /sys and /dev (needs root)
+    Shell {
+        /// Tarball, rootfs directory or build output directory
+        path: PathBuf,
+
+        /// Command to run instead of a login shell
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+
+    /// Compare two tarballs: added, removed and changed entries
+    Diff {
+        /// Old tarball (path or https:// URL); with --against-channel, the
+        /// local build (output directory, manifest or tarball)
+        old: PathBuf,
+
+        /// New tarball (path or https:// URL)
+        #[arg(
+            required_unless_present = "against_channel",
+            conflicts_with = "against_channel"
+        )]
+        new: Option<PathBuf>,
+
+        /// Compare against the latest release published on this channel (e.g. stable)
+        #[arg(long, value_name = "CHANNEL")]
+        against_channel: Option<String>,
+
+        /// Where the release channels are published
+        #[arg(long, value_name = "URL", default_value = DEFAULT_CHANNEL_URL, requires = "against_channel")]
+        channel_url: String,
+
+        /// Fail if the contents grew by more than this over the channel (e.g. 20M)
+        #[arg(long, value_name = "SIZE", requires = "against_channel")]
+        max_growth: Option<ByteSize>,
+
+        /// Print the channel comparison as JSON
+        #[arg(long, requires = "against_channel")]
+        json: bool,
+    },
+
+    /// Rewrite a tarball with an overlay directory added, without rebuilding
+    Respin {
+        /// Base tarball
+        base: PathBuf,
+
+        /// Directory whose contents are added to or replace the base rootfs
+        #[arg(long)]
+        overlay: PathBuf,
+
+        /// Output tarball (compressed like the base)
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Create a release bundle (tarball, SHA256SUMS, signature, manifest)
+    Release {
+        /// Path to tarball
+        path: PathBuf,
+
+        /// Output directory for the bundle
+        #[arg(short, long, default_value = "release")]
+        output: PathBuf,
+
+        /// minisign secret key used to sign the tarball
+        #[arg(long)]
+        sign_key: Option<PathBuf>,
+    },
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(failure::exit_code(&err))
+        }
+    }
+}
+
+fn run() -> Result<()> {
     let cli = Cli::parse();
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+    console::init(verbosity, !cli.no_color)?;
 
     match cli.command {
         Commands::Build {
             source,
             output,
             recipe,
+            config,
+            output_name,
+            profile,
+            arch,
+            setuid_allowlist,
+            container_safe,
+            sign_key,
+            random_seed,
+            secrets,
+            remaps,
+            user_services,
+            verify_units,
+            sanitize,
+            sanitize_keep,
+            offline,
+            ldd,
+            no_host_fallback,
+            strict,
+            accessibility,
+            upgrade_timer,
+            upgrade_reboot,
+            busybox_static,
+            healthcheck,
+            offline_help,
+            help_pages,
+            lockdown,
+            authorized_keys,
+            source_date_epoch,
+            keep_staging,
+            resume,
+            incremental,
+            object_store,
+            fragment_cache,
+            dedup,
+            acls,
+            selinux,
+            step_jobs,
+            wait_lock,
+            only_step,
+            skip_step,
+            templates,
+            max_size,
+            largest_files,
+            largest_files_json,
+            baseline,
+            compression,
+            dry_run,
+            progress,
+            targets,
+            jobs,
         } => {
             let mut builder = Stage3Builder::new(&source, &output);
+            if let Some(path) = config {
+                let config = Stage3Config::load(&path)?;
+                config.check()?;
+                builder = builder.with_config(&config);
+            }
+
+            // Flags win over the config file
+            if let Some(name) = output_name {
+                builder = builder.with_output_name(name);
+            }
+            if let Some(profile) = profile {
+                builder = builder.with_profile(profile);
+            }
+            if let Some(arch) = arch {
+                builder = builder.with_arch(arch);
+            }
+            if let Some(compression) = compression {
+                builder = builder.with_compression(compression);
+            }
+            if strict {
+                builder = builder.with_strict(true);
+            }
+            if accessibility {
+                builder = builder.with_accessibility(true);
+            }
+            if healthcheck {
+                builder = builder.with_healthcheck(true);
+            }
+            if offline_help {
+                builder = builder.with_offline_help(true);
+            }
+
+            builder = builder
+                .with_container_safe(container_safe)
+                .with_random_seed(random_seed)
+                .with_verify_units(verify_units)
+                .with_offline(offline)
+                .with_ldd(ldd)
+                .with_host_fallback(!no_host_fallback)
+                .with_lockdown(lockdown)
+                .with_keep_staging(keep_staging)
+                .with_resume(resume)
+                .with_incremental(incremental)
+                .with_dedup(dedup)
+                .with_acls(acls)
+                .with_selinux(selinux)
+                .with_wait_lock(wait_lock)
+                .with_largest_files(largest_files)
+                .with_largest_files_json(largest_files_json);
+
+            if let Some(key) = sign_key {
+                builder = builder.with_sign_key(key);
+            }
+            if let Some(jobs) = step_jobs {
+                builder = builder.with_step_jobs(jobs);
+            }
+            if let Some(dir) = object_store {
+                builder = builder.with_object_store(dir);
+            }
+            if let Some(dir) = fragment_cache {
+                builder = builder.with_fragment_cache(dir);
+            }
+
+            if let Some(busybox) = busybox_static {
+                builder = builder.with_static_busybox(busybox);
+            }
+
+            if let Some(dir) = help_pages {
+                builder = builder.with_help_pages(dir);
+            }
+
+            if let Some(keys) = authorized_keys {
+                builder = builder.with_authorized_keys(keys);
+            }
+
+            if let Some(epoch) = source_date_epoch {
+                builder = builder.with_source_date_epoch(epoch);
+            }
+
+            if let Some(dir) = templates {
+                builder = builder.with_template_dir(dir);
+            }
+
+            if let Some(max_size) = max_size {
+                builder = builder.with_max_size(max_size);
+            }
+
+            if let Some(manifest) = baseline {
+                builder = builder.with_baseline(manifest);
+            }
+
+            if let Some(schedule) = upgrade_timer {
+                builder = builder.with_upgrade_timer(UpgradeTimer::new(schedule, upgrade_reboot)?);
+            }
+
+            if let Some(allowed) = setuid_allowlist {
+                builder = builder.with_policy(SetuidAllowlist::new(allowed));
+            }
+
+            for pattern in sanitize {
+                builder = builder.with_sanitize_pattern(pattern);
+            }
+            for pattern in sanitize_keep {
+                builder = builder.with_sanitize_keep(pattern);
+            }
+
+            for secret in secrets {
+                builder = builder.with_secret(secret);
+            }
+
+            for remap in remaps {
+                builder = builder.with_remap(remap);
+            }
+
+            for service in user_services {
+                builder = builder.with_user_service(service);
+            }
 
             if let Some(recipe_path) = recipe {
                 builder = builder.with_recipe(recipe_path);
             }
 
+            let archives = StepSelection {
+                only: only_step.clone(),
+                skip: skip_step.clone(),
+            }
+            .runs(BuildStep::Tarball);
+            for step in only_step {
+                builder = builder.with_only_step(step);
+            }
+            for step in skip_step {
+                builder = builder.with_skip_step(step);
+            }
+
+            // Ctrl-C stops at the next step and removes partial outputs
+            let cancel = CancelToken::new();
+            cancel_on_signal(&cancel)?;
+            builder = builder.with_cancel_token(cancel);
+
+            if !targets.is_empty() {
+                if progress == ProgressFormat::Json {
+                    anyhow::bail!("--progress json supports a single target");
+                }
+                let jobs = jobs
+                    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+                let outcomes = build_targets(&builder, &targets, &output, jobs)?;
+                print_outcomes(&outcomes);
+                return check_outcomes(&outcomes);
+            }
+
+            if dry_run {
+                let plan = builder.plan()?;
+                match progress {
+                    ProgressFormat::Json => println!("{}", serde_json::to_string(&plan)?),
+                    ProgressFormat::Human => plan.print(),
+                }
+                return Ok(());
+            }
+
+            if progress == ProgressFormat::Json {
+                builder = builder.with_progress(JsonLinesProgress);
+            }
+
             let tarball_path = builder.build()?;
-            println!("\nBuild complete: {}", tarball_path.display());
+            // Keep stdout to the events in JSON mode
+            if progress == ProgressFormat::Human && archives {
+                println!("\nBuild complete: {}", tarball_path.display());
+            }
+        }
+        Commands::List {
+            path,
+            filter,
+            long,
+            tree,
+            json,
+        } => {
+            let options = ListOptions {
+                long,
+                tree,
+                json,
+                filter,
+            };
+            list_tarball(&path, &options)?;
+        }
+        Commands::Verify {
+            path,
+            checksum,
+            signature,
+            public_key,
+            against_manifest,
+            units,
+            json,
+        } => {
+            let path = download::resolve(&path)?;
+            let manifest = against_manifest.map(|manifest| {
+                manifest
+                    .unwrap_or_else(|| path.parent().unwrap_or(Path::new(".")).join(MANIFEST_NAME))
+            });
+            let options = VerifyOptions {
+                checksum,
+                signature,
+                public_key,
+                manifest,
+                units,
+            };
+            if !json {
+                stage3::status!("Verifying {}...", path.display());
+            }
+            let result = verify_tarball(&path, &options)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&result)?);
+            } else {
+                result.print();
+            }
+            if !result.passed {
+                let signature_failed = result
+                    .checks
+                    .iter()
+                    .any(|check| check.name == "signature" && check.status == CheckStatus::Fail);
+                let kind = if signature_failed {
+                    FailureKind::BadSignature
+                } else {
+                    FailureKind::VerificationFailed
+                };
+                anyhow::bail!(Failure::new(
+                    kind,
+                    format!(
+                        "Tarball verification failed: {} of {} checks failed",
+                        result.failures(),
+                        result.checks.len()
+                    )
+                ));
+            }
+        }
+        Commands::Attest {
+            path,
+            manifest,
+            public_key,
+            json,
+        } => {
+            let path = download::resolve(&path)?;
+            if !json {
+                stage3::status!("Attesting {}...", path.display());
+            }
+            let attestation = attest_tarball(&path, manifest.as_deref(), public_key.as_deref())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&attestation)?);
+            } else {
+                attestation.print();
+            }
+            if let Some(failure) = attestation.failure {
+                anyhow::bail!(Failure::new(
+                    failure.into(),
+                    format!("Attestation failed: {}", failure)
+                ));
+            }
+        }
+        Commands::Extract {
+            path,
+            output,
+            patterns,
+            rootfs,
+        } => {
+            let options = ExtractOptions {
+                include: patterns,
+                preserve: rootfs,
+            };
+            let archive = Stage3Archive::open(&download::resolve(&path)?)?;
+            let extracted = archive.extract(&output, &options)?;
+            stage3::status!("Extracted {} entries to {}", extracted, output.display());
+        }
+        Commands::Finalize {
+            root,
+            root_uuid,
+            root_fstype,
+            esp_uuid,
+            luks_uuid,
+        } => {
+            let values = FinalizeValues {
+                root_uuid,
+                root_fstype,
+                esp_uuid,
+                luks_uuid,
+            };
+            let changed = finalize(&root, &values)?;
+            stage3::status!("Finalized {} files in {}", changed, root.display());
         }
-        Commands::List { path } => {
-            list_tarball(&path)?;
+        Commands::ValidateConfig { path } => {
+            let config = Stage3Config::load(&path)?;
+            config.check()?;
+            print!("{}", config.to_toml()?);
+            stage3::status!("{} is valid", path.display());
         }
-        Commands::Verify { path } => {
-            verify_tarball(&path)?;
+        Commands::Inspect { path, json } => {
+            let inspection = inspect_tarball(&download::resolve(&path)?)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspection)?);
+            } else {
+                inspection.print();
+            }
+        }
+        Commands::Audit { path } => {
+            audit_tarball(&path)?;
+        }
+        Commands::BootTest {
+            path,
+            timeout,
+            log,
+            qemu,
+            kernel,
+            initrd,
+            memory,
+            expect,
+        } => {
+            let log = log.unwrap_or_else(|| {
+                let mut name = path.file_name().unwrap_or_default().to_os_string();
+                name.push(".boot.log");
+                path.with_file_name(name)
+            });
+            let qemu = match kernel {
+                Some(kernel) if qemu => Some(QemuOptions {
+                    kernel,
+                    initrd,
+                    memory,
+                }),
+                _ => None,
+            };
+            let options = BootTestOptions {
+                timeout: Duration::from_secs(timeout),
+                log,
+                qemu,
+                expect,
+            };
+            boot_test(&path, &options)?;
+        }
+        Commands::Shell { path, command } => {
+            shell(&download::resolve(&path)?, &command)?;
+        }
+        Commands::Diff {
+            old,
+            new,
+            against_channel,
+            channel_url,
+            max_growth,
+            json,
+        } => match (against_channel, new) {
+            (Some(channel), _) => {
+                let comparison = compare_to_channel(&old, &channel_url, &channel, max_growth)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&comparison)?);
+                } else {
+                    comparison.print();
+                }
+            }
+            (None, Some(new)) => {
+                print_diff(&download::resolve(&old)?, &download::resolve(&new)?)?;
+            }
+            (None, None) => unreachable!("clap requires NEW without --against-channel"),
+        },
+        Commands::Respin {
+            base,
+            overlay,
+            output,
+        } => {
+            respin(&base, &overlay, &output)?;
+        }
+        Commands::Release {
+            path,
+            output,
+            sign_key,
+        } => {
+            create_release(&path, &output, sign_key.as_deref())?;
         }
     }
 
@@ -0,0 +1,212 @@
+//! Per-file manifest of a stage3 tarball.
+//!
+//! Lists every entry that actually shipped (type, size, mode, owner, the
+//! SHA-256 of regular files and the libraries ELF files link against) so
+//! the installer and QA tooling can audit an artifact without unpacking it.
+//! Manifests written by a build also carry the build's provenance.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use crate::archive::Stage3Archive;
+use crate::provenance::Provenance;
+use crate::remap::PathRemap;
+use crate::timings::Timings;
+
+/// Filename of the manifest written next to the tarball.
+pub const MANIFEST_NAME: &str = "levitateos-stage3.manifest.json";
+
+/// Type of a tarball entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Char,
+    Block,
+    Fifo,
+    Other,
+}
+
+impl From<tar::EntryType> for EntryKind {
+    fn from(entry_type: tar::EntryType) -> Self {
+        match entry_type {
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => {
+                Self::File
+            }
+            tar::EntryType::Directory => Self::Dir,
+            tar::EntryType::Symlink => Self::Symlink,
+            tar::EntryType::Link => Self::Hardlink,
+            tar::EntryType::Char => Self::Char,
+            tar::EntryType::Block => Self::Block,
+            tar::EntryType::Fifo => Self::Fifo,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// One entry of the tarball.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path inside the rootfs (e.g. `usr/bin/bash`)
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    /// Size in bytes (0 for anything but regular files)
+    pub size: u64,
+    /// Permission bits as four octal digits (e.g. `0755`)
+    pub mode: String,
+    pub uid: u64,
+    pub gid: u64,
+    /// SHA-256 of the contents, for regular files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Link target, for symlinks and hardlinks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// `major:minor`, for device nodes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Shared libraries (`DT_NEEDED`) of dynamically linked ELF files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub needed: Option<Vec<String>>,
+}
+
+/// Manifest of every entry in a tarball, in archive order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Tarball filename the manifest describes
+    pub tarball: String,
+    pub entries: Vec<ManifestEntry>,
+    /// Paths of the entries deliberately left out (injected secrets)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub omitted: Vec<String>,
+    /// Where and how the tarball was built, for manifests written by a build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Donor paths the build moved, for manifests written by a build
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remaps: Vec<PathRemap>,
+    /// Time and output of the build steps up to the tarball, for manifests
+    /// written by a build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+/// Build a manifest by streaming the entries of a tarball.
+pub fn from_tarball(path: &Path) -> Result<Manifest> {
+    Ok(Manifest {
+        tarball: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        entries: read_entries(path, true)?,
+        omitted: Vec::new(),
+        provenance: None,
+        remaps: Vec::new(),
+        timings: None,
+    })
+}
+
+/// Read the entries of a tarball, hashing regular files if `hash` is set.
+pub fn read_entries(path: &Path, hash: bool) -> Result<Vec<ManifestEntry>> {
+    let mut archive = Stage3Archive::open(path)?;
+    let entries = archive
+        .entries()?
+        .map(|entry| entry?.metadata(hash))
+        .collect();
+    entries
+}
+
+/// Write a manifest as pretty-printed JSON.
+pub fn write(manifest: &Manifest, path: &Path) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(manifest)? + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Read a manifest written by [`write`].
+pub fn read(path: &Path) -> Result<Manifest> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Invalid manifest: {}", path.display()))
+}
+
+/// Compare the entries of a tarball against its expected manifest.
+///
+/// Returns one line per discrepancy; an empty list means the tarball
+/// matches. Entries the manifest leaves out must be exactly the ones its
+/// `omitted` names.
+pub fn compare(expected: &Manifest, actual: &Manifest) -> Vec<String> {
+    let mut remaining: BTreeMap<&str, &ManifestEntry> = expected
+        .entries
+        .iter()
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+    let mut problems = Vec::new();
+    let mut omitted: BTreeSet<&str> = expected.omitted.iter().map(String::as_str).collect();
+
+    for entry in &actual.entries {
+        let want = match remaining.remove(entry.path.as_str()) {
+            Some(want) => want,
+            None => {
+                if !omitted.remove(entry.path.as_str()) {
+                    problems.push(format!("/{}: not in manifest", entry.path));
+                }
+                continue;
+            }
+        };
+
+        let mut diffs = Vec::new();
+        if want.kind != entry.kind {
+            diffs.push(format!("type {:?} (expected {:?})", entry.kind, want.kind));
+        }
+        if want.size != entry.size {
+            diffs.push(format!("size {} (expected {})", entry.size, want.size));
+        }
+        if want.mode != entry.mode {
+            diffs.push(format!("mode {} (expected {})", entry.mode, want.mode));
+        }
+        if (want.uid, want.gid) != (entry.uid, entry.gid) {
+            diffs.push(format!(
+                "owner {}:{} (expected {}:{})",
+                entry.uid, entry.gid, want.uid, want.gid
+            ));
+        }
+        if want.sha256 != entry.sha256 {
+            diffs.push("sha256 mismatch".to_string());
+        }
+        if want.target != entry.target {
+            diffs.push(format!(
+                "target {:?} (expected {:?})",
+                entry.target, want.target
+            ));
+        }
+        if want.device != entry.device {
+            diffs.push(format!(
+                "device {:?} (expected {:?})",
+                entry.device, want.device
+            ));
+        }
+
+        if !diffs.is_empty() {
+            problems.push(format!("/{}: {}", entry.path, diffs.join(", ")));
+        }
+    }
+
+    for path in remaining.keys() {
+        problems.push(format!("/{}: missing from tarball", path));
+    }
+    for path in omitted {
+        problems.push(format!(
+            "/{}: omitted from manifest but not in tarball",
+            path
+        ));
+    }
+
+    problems
+}
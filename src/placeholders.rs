@@ -0,0 +1,295 @@
+//! Placeholder contract with the installer.
+//!
+//! The generated fstab, crypttab and systemd-boot entry name the install's
+//! filesystems with `@TOKEN@` placeholders instead of commented examples.
+//! The installer extracts the tarball and runs `stage3 finalize` on the
+//! tree with the real values, so rewording a template never breaks it.
+//! Until then the lines are commented out with [`PENDING`], so a tree
+//! booted unfinalized (in a container, say) doesn't wait for filesystems
+//! that don't exist:
+//!
+//! | Placeholder     | Option          | Without it                   |
+//! |-----------------|-----------------|------------------------------|
+//! | `@ROOT_UUID@`   | `--root-uuid`   | required                     |
+//! | `@ROOT_FSTYPE@` | `--root-fstype` | `ext4`                       |
+//! | `@ESP_UUID@`    | `--esp-uuid`    | lines using it commented out |
+//! | `@LUKS_UUID@`   | `--luks-uuid`   | lines using it commented out |
+//!
+//! Values are checked before anything is written, every file is replaced
+//! atomically, and no placeholder is left behind. The loader entry lands
+//! in /boot/loader/entries; the installer copies it to the ESP with the
+//! kernel and initramfs.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::status;
+
+/// Files carrying placeholders, rootfs-relative.
+pub const PLACEHOLDER_FILES: &[&str] = &[
+    "etc/fstab",
+    "etc/crypttab",
+    "boot/loader/entries/levitateos.conf",
+];
+
+/// Prefix commenting out a placeholder line until `finalize` fills it in.
+pub const PENDING: &str = "#finalize: ";
+
+/// `line` without its [`PENDING`] prefix, if it has one.
+pub fn pending(line: &str) -> Option<&str> {
+    line.strip_prefix(PENDING)
+}
+
+/// A placeholder and what it may be replaced with.
+pub struct Placeholder {
+    pub token: &'static str,
+    /// Lines using it are commented out when no value is given
+    pub optional: bool,
+    check: fn(&str) -> bool,
+}
+
+pub const ROOT_UUID: &str = "@ROOT_UUID@";
+pub const ROOT_FSTYPE: &str = "@ROOT_FSTYPE@";
+pub const ESP_UUID: &str = "@ESP_UUID@";
+pub const LUKS_UUID: &str = "@LUKS_UUID@";
+
+/// Every placeholder the templates may use.
+pub const PLACEHOLDERS: &[Placeholder] = &[
+    Placeholder {
+        token: ROOT_UUID,
+        optional: false,
+        check: is_uuid,
+    },
+    Placeholder {
+        token: ROOT_FSTYPE,
+        optional: false,
+        check: is_fstype,
+    },
+    Placeholder {
+        token: ESP_UUID,
+        optional: true,
+        check: is_uuid,
+    },
+    Placeholder {
+        token: LUKS_UUID,
+        optional: true,
+        check: is_uuid,
+    },
+];
+
+/// Filesystem UUIDs: hex groups separated by dashes (vfat's `ABCD-1234`
+/// included).
+fn is_uuid(value: &str) -> bool {
+    !value.is_empty()
+        && value.split('-').all(|group| !group.is_empty())
+        && value.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
+
+fn is_fstype(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+}
+
+/// Placeholder-looking `@WORD@` tokens in `line` that are not part of the
+/// contract.
+pub fn unknown_tokens(line: &str) -> Vec<String> {
+    let mut unknown = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('@') {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('@') else {
+            break;
+        };
+        let word = &after[..end];
+        if !word.is_empty() && word.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+            let token = format!("@{}@", word);
+            if !PLACEHOLDERS.iter().any(|p| p.token == token) {
+                unknown.push(token);
+            }
+            rest = &after[end + 1..];
+        } else {
+            rest = after;
+        }
+    }
+    unknown
+}
+
+/// Values for the placeholders.
+#[derive(Debug, Clone)]
+pub struct FinalizeValues {
+    pub root_uuid: String,
+    pub root_fstype: String,
+    pub esp_uuid: Option<String>,
+    pub luks_uuid: Option<String>,
+}
+
+impl FinalizeValues {
+    fn get(&self, token: &str) -> Option<&str> {
+        match token {
+            ROOT_UUID => Some(&self.root_uuid),
+            ROOT_FSTYPE => Some(&self.root_fstype),
+            ESP_UUID => self.esp_uuid.as_deref(),
+            LUKS_UUID => self.luks_uuid.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Check every given value, and that the required ones are there.
+    fn validate(&self) -> Result<()> {
+        for placeholder in PLACEHOLDERS {
+            match self.get(placeholder.token) {
+                Some(value) if !(placeholder.check)(value) => {
+                    bail!("Invalid value {:?} for {}", value, placeholder.token)
+                }
+                None if !placeholder.optional => bail!("No value for {}", placeholder.token),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill in the placeholders of an extracted tree at `root`.
+///
+/// Returns the number of files changed.
+pub fn finalize(root: &Path, values: &FinalizeValues) -> Result<usize> {
+    values.validate()?;
+    if !root.join("etc").is_dir() {
+        bail!("{} does not look like an extracted stage3", root.display());
+    }
+
+    let mut changed = 0;
+    for file in PLACEHOLDER_FILES {
+        let path = root.join(file);
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        // Never follow a link out of the tree
+        if !metadata.is_file() {
+            bail!("/{} is not a regular file", file);
+        }
+
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read /{}", file))?;
+        let finalized = substitute(&contents, values).with_context(|| format!("In /{}", file))?;
+        if finalized == contents {
+            continue;
+        }
+
+        let tmp = path.with_extension("stage3-finalize");
+        fs::remove_file(&tmp).ok();
+        fs::write(&tmp, &finalized).with_context(|| format!("Failed to write /{}", file))?;
+        fs::set_permissions(
+            &tmp,
+            fs::Permissions::from_mode(metadata.permissions().mode()),
+        )?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to replace /{}", file))?;
+        status!("  Finalized /{}", file);
+        changed += 1;
+    }
+
+    if changed == 0 {
+        tracing::warn!("  Warning: no placeholders found; was the tree already finalized?");
+    }
+    Ok(changed)
+}
+
+/// Substitute the placeholders of one file, uncommenting its pending
+/// lines, and commenting out again those whose optional placeholders have
+/// no value.
+fn substitute(contents: &str, values: &FinalizeValues) -> Result<String> {
+    let mut out = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let line = match pending(line) {
+            Some(line) => line,
+            None if line.trim_start().starts_with('#') => {
+                out.push_str(line);
+                continue;
+            }
+            None => line,
+        };
+        if let Some(token) = unknown_tokens(line).first() {
+            bail!("Unknown placeholder {}", token);
+        }
+
+        let unset = PLACEHOLDERS
+            .iter()
+            .find(|p| line.contains(p.token) && values.get(p.token).is_none());
+        if let Some(placeholder) = unset {
+            out.push_str(&format!("# no {}: {}", placeholder.token, line));
+            continue;
+        }
+
+        let mut line = line.to_string();
+        for placeholder in PLACEHOLDERS {
+            if let Some(value) = values.get(placeholder.token) {
+                line = line.replace(placeholder.token, value);
+            }
+        }
+        out.push_str(&line);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(esp_uuid: Option<&str>) -> FinalizeValues {
+        FinalizeValues {
+            root_uuid: "0b4f3e2a-9c1d-4e6f-8a7b-5c2d1e0f9a8b".to_string(),
+            root_fstype: "ext4".to_string(),
+            esp_uuid: esp_uuid.map(str::to_string),
+            luks_uuid: None,
+        }
+    }
+
+    #[test]
+    fn pending_lines_are_filled_in_or_commented_out() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        let fstab = root.path().join("etc/fstab");
+        fs::write(
+            &fstab,
+            "# UUID=@ROOT_UUID@ stays a comment\n\
+             #finalize: UUID=@ROOT_UUID@ / @ROOT_FSTYPE@ defaults 0 1\n\
+             #finalize: UUID=@ESP_UUID@ /boot vfat umask=0077 0 2\n\
+             tmpfs /tmp tmpfs defaults 0 0\n",
+        )
+        .unwrap();
+        fs::set_permissions(&fstab, fs::Permissions::from_mode(0o640)).unwrap();
+
+        assert_eq!(finalize(root.path(), &values(None)).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(&fstab).unwrap(),
+            "# UUID=@ROOT_UUID@ stays a comment\n\
+             UUID=0b4f3e2a-9c1d-4e6f-8a7b-5c2d1e0f9a8b / ext4 defaults 0 1\n\
+             # no @ESP_UUID@: UUID=@ESP_UUID@ /boot vfat umask=0077 0 2\n\
+             tmpfs /tmp tmpfs defaults 0 0\n"
+        );
+        let mode = fs::metadata(&fstab).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+        // Finalizing again finds nothing left to fill in
+        assert_eq!(
+            finalize(root.path(), &values(Some("ABCD-1234"))).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn bad_values_and_unknown_tokens_are_rejected() {
+        let err = values(Some("not a uuid")).validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value \"not a uuid\" for @ESP_UUID@"
+        );
+        let mut missing = values(None);
+        missing.root_fstype = String::new();
+        assert!(missing.validate().is_err());
+
+        let err = substitute("#finalize: @SWAP_UUID@ none swap\n", &values(None)).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown placeholder @SWAP_UUID@");
+        assert_eq!(unknown_tokens("user@host @ROOT_UUID@ @NEW@"), ["@NEW@"]);
+    }
+}
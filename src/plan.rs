@@ -0,0 +1,316 @@
+//! Build planning without copying.
+//!
+//! `stage3 build --dry-run` resolves the binaries a build would copy and
+//! their libraries the same way the copy routines do, donor first and the
+//! build host second, and lists where each one would land with its size.
+//! Nothing is written: no staging directory, no tarball. Configuration,
+//! units and data directories are not part of the plan, so the estimate
+//! covers the binaries and libraries, which are most of the tarball.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::arch::LibraryLayout;
+use crate::binary::{library_dest, parse_ldd_output};
+use crate::dlopen::DlopenHints;
+use crate::donor::PackageSource;
+use crate::inspect::human_size;
+use crate::linker::{self, LibraryCache};
+use crate::remap::PathRemaps;
+use crate::rootfs::binaries::BinaryOverrides;
+use crate::rootfs::{accessibility, binaries};
+use crate::sandbox::Sandbox;
+
+/// What a planned file is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlannedKind {
+    Binary,
+    Library,
+}
+
+/// A file the build would copy.
+#[derive(Debug, Serialize)]
+pub struct PlannedFile {
+    /// Where it lands in the rootfs, remapped
+    pub path: String,
+    /// Where it is copied from
+    pub source: PathBuf,
+    pub kind: PlannedKind,
+    pub size: u64,
+    /// Taken from the build host because the donor lacks it
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub host: bool,
+}
+
+/// Everything a build would copy and what it would miss.
+#[derive(Debug, Default, Serialize)]
+pub struct BuildPlan {
+    pub files: Vec<PlannedFile>,
+    /// Binaries not in the donor, skipped by the build
+    pub missing_binaries: Vec<String>,
+    /// Libraries neither the donor nor (when allowed) the host has
+    pub missing_libraries: Vec<String>,
+    /// Total size of the planned files in bytes
+    pub size: u64,
+}
+
+/// Which optional components the plan includes.
+#[derive(Debug, Clone)]
+pub struct PlanOptions {
+    pub host_fallback: bool,
+    /// Resolve libraries with the host's ldd
+    pub ldd: bool,
+    /// How ldd is spawned
+    pub sandbox: Sandbox,
+    /// Donor files loaded with dlopen, by binary or library name
+    pub dlopen_hints: BTreeMap<String, Vec<String>>,
+    pub accessibility: bool,
+    pub lockdown: bool,
+    pub binaries: BinaryOverrides,
+    /// Where the donor keeps its libraries
+    pub layout: LibraryLayout,
+}
+
+/// How a binary is looked up and where it goes.
+#[derive(Clone, Copy)]
+enum Lookup {
+    /// `copy_binary_with_libs` into the directory
+    Bin(&'static str),
+    /// `copy_sbin_binary_with_libs` into usr/sbin
+    Sbin,
+}
+
+/// Plan the binaries and libraries a build from `source` would copy.
+pub fn plan_build(
+    source: &dyn PackageSource,
+    remaps: &PathRemaps,
+    options: PlanOptions,
+) -> Result<BuildPlan> {
+    let mut planner = Planner {
+        source,
+        remaps,
+        host_fallback: options.host_fallback,
+        ldd: options.ldd,
+        sandbox: options.sandbox,
+        layout: options.layout,
+        libraries: LibraryCache::default(),
+        hints: DlopenHints::new(options.dlopen_hints),
+        seen: BTreeSet::new(),
+        plan: BuildPlan::default(),
+    };
+
+    planner.binary("bash", Lookup::Bin("usr/bin"))?;
+    for binary in options.binaries.coreutils() {
+        planner.binary(binary, Lookup::Bin("usr/bin"))?;
+    }
+    let sbin = options.binaries.sbin_utils();
+    for binary in sbin.iter().chain(binaries::LOGIN_BINARIES) {
+        planner.binary(binary, Lookup::Sbin)?;
+    }
+    planner.systemd()?;
+    if options.accessibility {
+        for binary in accessibility::BINARIES {
+            planner.binary(binary, Lookup::Bin("usr/bin"))?;
+        }
+    }
+    if options.lockdown {
+        planner.binary("sshd", Lookup::Sbin)?;
+        planner.binary("ssh-keygen", Lookup::Bin("usr/bin"))?;
+    }
+
+    let mut plan = planner.plan;
+    plan.size = plan.files.iter().map(|f| f.size).sum();
+    plan.missing_binaries.sort();
+    plan.missing_binaries.dedup();
+    plan.missing_libraries.sort();
+    plan.missing_libraries.dedup();
+    Ok(plan)
+}
+
+struct Planner<'a> {
+    source: &'a dyn PackageSource,
+    remaps: &'a PathRemaps,
+    host_fallback: bool,
+    ldd: bool,
+    sandbox: Sandbox,
+    layout: LibraryLayout,
+    libraries: LibraryCache,
+    hints: DlopenHints,
+    /// Rootfs paths already planned
+    seen: BTreeSet<PathBuf>,
+    plan: BuildPlan,
+}
+
+impl Planner<'_> {
+    fn binary(&mut self, name: &str, lookup: Lookup) -> Result<()> {
+        let (found, dest_dir) = match lookup {
+            Lookup::Bin(dir) => (self.source.find_binary(name), dir),
+            Lookup::Sbin => (self.source.find_sbin_binary(name), "usr/sbin"),
+        };
+        let Some(src) = found else {
+            self.plan.missing_binaries.push(name.to_string());
+            return Ok(());
+        };
+        if !self.add(
+            Path::new(dest_dir).join(name),
+            &src,
+            PlannedKind::Binary,
+            false,
+        ) {
+            return Ok(());
+        }
+
+        let libs = self.library_paths(&src)?;
+        for lib in &libs {
+            self.library(lib)?;
+        }
+        self.hinted(name, &libs)
+    }
+
+    /// Files the dlopen hints for a binary and its libraries name, like
+    /// [`copy_hinted`](crate::dlopen::copy_hinted).
+    fn hinted(&mut self, binary: &str, libs: &[String]) -> Result<()> {
+        for (_, hinted) in self.hints.take(binary, libs) {
+            let path = self.layout.relocate(hinted.trim_start_matches('/'));
+            let Some(src) = self.source.find_file(&path) else {
+                continue;
+            };
+            let files: Vec<(PathBuf, PathBuf)> = if src.is_dir() {
+                WalkDir::new(&src)
+                    .into_iter()
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_type().is_file())
+                    .filter_map(|entry| {
+                        let rel = entry.path().strip_prefix(&src).ok()?;
+                        Some((path.join(rel), entry.path().to_path_buf()))
+                    })
+                    .collect()
+            } else {
+                vec![(path, src)]
+            };
+
+            for (path, src) in files {
+                if !self.add(path.clone(), &src, PlannedKind::Library, false) {
+                    continue;
+                }
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let libs = self.library_paths(&src)?;
+                for lib in &libs {
+                    self.library(lib)?;
+                }
+                self.hinted(&name, &libs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Libraries of a donor binary, like [`library_paths`](crate::binary::library_paths).
+    fn library_paths(&mut self, src: &Path) -> Result<Vec<String>> {
+        if !self.ldd {
+            let deps = linker::library_dependencies(self.source, &self.libraries, src)?;
+            self.plan.missing_libraries.extend(deps.missing);
+            return Ok(deps.libraries);
+        }
+
+        let Ok(output) = self.sandbox.command("ldd").arg(src).output() else {
+            return Ok(Vec::new());
+        };
+        if !output.status.success() {
+            return Ok(Vec::new());
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        for line in output.lines().filter(|l| l.contains("not found")) {
+            if let Some(lib) = line.split_whitespace().next() {
+                self.plan.missing_libraries.push(lib.to_string());
+            }
+        }
+        Ok(parse_ldd_output(&output)?)
+    }
+
+    fn library(&mut self, lib_path: &str) -> Result<()> {
+        let dest = library_dest(lib_path)?;
+        match self.source.find_library(lib_path) {
+            Some(src) => {
+                self.add(dest, &src, PlannedKind::Library, false);
+            }
+            None if self.host_fallback && Path::new(lib_path).exists() => {
+                self.add(dest, Path::new(lib_path), PlannedKind::Library, true);
+            }
+            None => self.plan.missing_libraries.push(lib_path.to_string()),
+        }
+        Ok(())
+    }
+
+    /// systemd and its helpers are copied as they are, with the private
+    /// libraries instead of ldd.
+    fn systemd(&mut self) -> Result<()> {
+        let helpers = std::iter::once("systemd").chain(binaries::SYSTEMD_BINARIES.iter().copied());
+        for binary in helpers {
+            let path = Path::new("usr/lib/systemd").join(binary);
+            if let Some(src) = self.source.find_file(&path) {
+                self.add(path, &src, PlannedKind::Binary, false);
+            }
+        }
+
+        let private_dir = self.layout.relocate("usr/lib64/systemd");
+        let private = self.source.find_file(&private_dir);
+        if let Some(private) = private.filter(|p| p.is_dir()) {
+            for entry in fs::read_dir(&private)? {
+                let name = entry?.file_name();
+                let name_str = name.to_string_lossy();
+                if name_str.starts_with("libsystemd-") && name_str.ends_with(".so") {
+                    let path = private_dir.join(&name);
+                    self.add(path, &private.join(&name), PlannedKind::Library, false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Plan `src` at rootfs-relative `path`; false if something is already
+    /// planned there.
+    fn add(&mut self, path: PathBuf, src: &Path, kind: PlannedKind, host: bool) -> bool {
+        let path = self.remaps.apply(&path);
+        if !self.seen.insert(path.clone()) {
+            return false;
+        }
+        self.plan.files.push(PlannedFile {
+            path: path.to_string_lossy().into_owned(),
+            source: src.to_path_buf(),
+            kind,
+            size: fs::metadata(src).map(|m| m.len()).unwrap_or(0),
+            host,
+        });
+        true
+    }
+}
+
+impl BuildPlan {
+    /// Print the planned files, what is missing and the total size.
+    pub fn print(&self) {
+        for file in &self.files {
+            let host = if file.host { "  (from build host)" } else { "" };
+            println!("  /{:<50} {:>10}{}", file.path, human_size(file.size), host);
+        }
+        for binary in &self.missing_binaries {
+            println!("  missing binary: {}", binary);
+        }
+        for library in &self.missing_libraries {
+            println!("  missing library: {}", library);
+        }
+
+        let count = |kind| self.files.iter().filter(|f| f.kind == kind).count();
+        println!(
+            "\n  {} binaries, {} libraries, {} ({} missing)",
+            count(PlannedKind::Binary),
+            count(PlannedKind::Library),
+            human_size(self.size),
+            self.missing_binaries.len() + self.missing_libraries.len()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Admission policies for staged content.
+//!
+//! Every entry in the staging tree is evaluated against the configured
+//! policies before the tarball is created. A policy can allow an entry,
+//! deny it (failing the build), or rewrite its permission bits.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+use crate::detail;
+use crate::donor::PackageSource;
+use crate::error::Stage3Error;
+use crate::store;
+
+/// A staged entry presented to admission policies.
+pub struct Entry<'a> {
+    /// Path inside the rootfs (e.g. `usr/bin/su`)
+    pub path: &'a Path,
+    /// Donor rootfs file at the same location, if one exists
+    pub source: Option<PathBuf>,
+    /// Permission bits including setuid/setgid/sticky
+    pub mode: u32,
+    /// Owning donor package, when known
+    pub package: Option<String>,
+}
+
+/// Outcome of evaluating a policy for one entry.
+pub enum Decision {
+    /// Admit the entry unchanged
+    Allow,
+    /// Reject the entry; the build fails with this reason
+    Deny(String),
+    /// Admit the entry with new permission bits
+    Rewrite { mode: u32 },
+}
+
+/// A rule evaluated for every file entering the stage3.
+pub trait AdmissionPolicy: Send + Sync {
+    /// Short name used in build output.
+    fn name(&self) -> &str;
+
+    /// Evaluate a single staged entry.
+    fn evaluate(&self, entry: &Entry) -> Decision;
+}
+
+/// Deny setuid/setgid files outside an approved list.
+pub struct SetuidAllowlist {
+    allowed: Vec<PathBuf>,
+}
+
+impl SetuidAllowlist {
+    /// Create a policy allowing setuid/setgid only on the given rootfs paths.
+    pub fn new<I, P>(allowed: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self {
+            allowed: allowed
+                .into_iter()
+                .map(|p| {
+                    p.as_ref()
+                        .strip_prefix("/")
+                        .unwrap_or(p.as_ref())
+                        .to_path_buf()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl AdmissionPolicy for SetuidAllowlist {
+    fn name(&self) -> &str {
+        "setuid-allowlist"
+    }
+
+    fn evaluate(&self, entry: &Entry) -> Decision {
+        if entry.mode & 0o6000 == 0 || self.allowed.iter().any(|p| p == entry.path) {
+            Decision::Allow
+        } else {
+            Decision::Deny(format!(
+                "setuid/setgid bits ({:o}) not approved",
+                entry.mode & 0o7777
+            ))
+        }
+    }
+}
+
+/// Evaluate all policies against every entry in the staging tree.
+pub fn enforce(
+    staging: &Path,
+    source: &dyn PackageSource,
+    policies: &[Arc<dyn AdmissionPolicy>],
+) -> Result<()> {
+    if policies.is_empty() {
+        return Ok(());
+    }
+
+    detail!("Evaluating admission policies...");
+
+    let mut denied = Vec::new();
+    let mut rewritten = 0;
+
+    for entry in WalkDir::new(staging).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        // Symlink permissions are meaningless; only real entries are checked
+        if entry.path_is_symlink() {
+            continue;
+        }
+
+        let rel = entry.path().strip_prefix(staging)?;
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Failed to read metadata: {}", entry.path().display()))?;
+        let mut admission = Entry {
+            path: rel,
+            source: source.find_file(rel),
+            mode: metadata.permissions().mode() & 0o7777,
+            package: None,
+        };
+
+        for policy in policies {
+            match policy.evaluate(&admission) {
+                Decision::Allow => {}
+                Decision::Deny(reason) => {
+                    denied.push(format!(
+                        "/{} [{}]: {}",
+                        rel.display(),
+                        policy.name(),
+                        reason
+                    ));
+                }
+                Decision::Rewrite { mode } => {
+                    store::unshare(entry.path())?;
+                    fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))
+                        .with_context(|| {
+                            format!("Failed to set permissions: {}", entry.path().display())
+                        })?;
+                    admission.mode = mode;
+                    rewritten += 1;
+                }
+            }
+        }
+    }
+
+    if !denied.is_empty() {
+        tracing::error!("  Denied entries:");
+        for line in &denied {
+            tracing::error!("    - {}", line);
+        }
+        anyhow::bail!(Stage3Error::PolicyDenied { denied });
+    }
+
+    detail!("  All entries admitted ({} rewritten)", rewritten);
+    Ok(())
+}
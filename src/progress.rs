@@ -0,0 +1,131 @@
+//! Machine-readable build progress.
+//!
+//! Every build step reports to the [`ProgressReporter`] on the
+//! [`BuildContext`](crate::BuildContext): when it starts, what it recorded
+//! in the report, and how it finished. `stage3 build --progress=json`
+//! prints the events as JSON lines on stdout for orchestrators; library
+//! users can hook in their own reporter with
+//! [`Stage3Builder::with_progress`](crate::Stage3Builder::with_progress).
+
+use serde::Serialize;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::report::Diagnostic;
+
+/// Something that happened during a build.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ProgressEvent {
+    /// The build context is ready and the first step is about to run
+    BuildStarted {
+        version: String,
+        arch: String,
+        profile: String,
+        /// Tarball filename
+        tarball: String,
+    },
+    StepStarted {
+        step: String,
+    },
+    /// A warning, error or skipped item recorded by a step
+    Diagnostic {
+        step: String,
+        #[serde(flatten)]
+        diagnostic: Diagnostic,
+    },
+    StepFinished {
+        step: String,
+        status: StepStatus,
+        errors: usize,
+        warnings: usize,
+        skipped: usize,
+        elapsed_ms: u64,
+    },
+    /// The build ended, with `tarball` set on success and `error` on failure
+    BuildFinished {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tarball: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        errors: usize,
+        warnings: usize,
+        skipped: usize,
+    },
+}
+
+/// How a step ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+    Ok,
+    /// Finished, but recorded warnings or skipped items
+    Warn,
+    /// Recorded errors or returned one
+    Fail,
+}
+
+impl StepStatus {
+    /// Status of a step from what it recorded.
+    pub fn from_counts(failed: bool, errors: usize, warnings: usize, skipped: usize) -> Self {
+        if failed || errors > 0 {
+            StepStatus::Fail
+        } else if warnings > 0 || skipped > 0 {
+            StepStatus::Warn
+        } else {
+            StepStatus::Ok
+        }
+    }
+}
+
+/// Receives the progress events of a build.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, event: &ProgressEvent);
+}
+
+/// Ignores every event; the default.
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {
+    fn report(&self, _event: &ProgressEvent) {}
+}
+
+/// Prints each event as one line of JSON on stdout.
+pub struct JsonLinesProgress;
+
+impl ProgressReporter for JsonLinesProgress {
+    fn report(&self, event: &ProgressEvent) {
+        let Ok(line) = serde_json::to_string(event) else {
+            return;
+        };
+        let mut stdout = io::stdout().lock();
+        // A closed pipe must not fail the build
+        writeln!(stdout, "{}", line).ok();
+        stdout.flush().ok();
+    }
+}
+
+/// How `stage3 build` reports progress.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Status lines only
+    #[default]
+    Human,
+    /// Status lines on stderr plus JSON lines events on stdout
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ProgressFormat::Human),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!(
+                "unknown progress format {:?} (expected human or json)",
+                s
+            )),
+        }
+    }
+}
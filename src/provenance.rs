@@ -0,0 +1,131 @@
+//! Build provenance.
+//!
+//! Every stage3 records which builder made it, from what, and when, both in
+//! /etc/levitate-release (so an installed system can report which stage3
+//! it came from) and in the output manifest. All values come from the
+//! build's inputs and clock, so a reproducible build stays reproducible.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::artifact::ArtifactInfo;
+use crate::clock::BuildClock;
+use crate::context::BuildContext;
+use crate::donor::PackageSource;
+
+/// Path of the provenance file in the rootfs.
+pub const RELEASE_FILE: &str = "etc/levitate-release";
+
+/// Commit the builder was compiled from, when built from a git checkout.
+pub const GIT_COMMIT: Option<&str> = option_env!("STAGE3_GIT_COMMIT");
+
+/// Where and how a stage3 was built.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Version of the stage3 builder
+    pub builder_version: String,
+    /// Git commit of the stage3 builder, if known
+    pub builder_commit: Option<String>,
+    /// Build time (seconds since the Unix epoch)
+    pub build_timestamp: u64,
+    /// The donor rootfs the build copied from
+    pub source: SourceIdentity,
+    /// Build profile name
+    pub profile: String,
+    /// Target architecture
+    pub arch: String,
+}
+
+/// Identity of the donor rootfs, from its /etc/os-release.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceIdentity {
+    /// `ID`, e.g. `rocky`
+    pub id: Option<String>,
+    /// `VERSION_ID`, e.g. `10.0`
+    pub version_id: Option<String>,
+    /// `PRETTY_NAME`, e.g. `Rocky Linux 10.0 (Red Quartz)`
+    pub pretty_name: Option<String>,
+}
+
+impl SourceIdentity {
+    /// Read the identity of the donor `source`.
+    ///
+    /// Fields the donor doesn't declare are left unset.
+    pub fn read(source: &dyn PackageSource) -> Result<Self> {
+        let Some(path) = ["etc/os-release", "usr/lib/os-release"]
+            .iter()
+            .find_map(|path| source.find_resolved(Path::new(path)))
+        else {
+            return Ok(Self::default());
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let values: BTreeMap<&str, &str> = contents
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+            .collect();
+        let value = |key: &str| values.get(key).map(|v| v.to_string());
+        Ok(Self {
+            id: value("ID"),
+            version_id: value("VERSION_ID"),
+            pretty_name: value("PRETTY_NAME"),
+        })
+    }
+}
+
+impl Provenance {
+    pub fn new(info: &ArtifactInfo, clock: &BuildClock, source: SourceIdentity) -> Self {
+        Self {
+            builder_version: env!("CARGO_PKG_VERSION").to_string(),
+            builder_commit: GIT_COMMIT.map(str::to_string),
+            build_timestamp: clock.timestamp(),
+            source,
+            profile: info.profile.clone(),
+            arch: info.arch.clone(),
+        }
+    }
+
+    /// Render as os-release style `KEY="value"` lines.
+    ///
+    /// Unknown values are left out rather than written empty.
+    pub fn to_release_file(&self) -> String {
+        let timestamp = self.build_timestamp.to_string();
+        let fields = [
+            ("STAGE3_BUILDER_VERSION", Some(&self.builder_version)),
+            ("STAGE3_BUILDER_COMMIT", self.builder_commit.as_ref()),
+            ("STAGE3_BUILD_TIMESTAMP", Some(&timestamp)),
+            ("STAGE3_PROFILE", Some(&self.profile)),
+            ("STAGE3_ARCH", Some(&self.arch)),
+            ("SOURCE_ID", self.source.id.as_ref()),
+            ("SOURCE_VERSION_ID", self.source.version_id.as_ref()),
+            ("SOURCE_PRETTY_NAME", self.source.pretty_name.as_ref()),
+        ];
+        fields
+            .iter()
+            .filter_map(|(key, value)| value.map(|value| format!("{}=\"{}\"\n", key, quote(value))))
+            .collect()
+    }
+}
+
+/// Escape a value for a double-quoted os-release assignment.
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+/// Write /etc/levitate-release into the staging tree.
+pub fn install(ctx: &BuildContext) -> Result<()> {
+    let dest = ctx.staging.join(RELEASE_FILE);
+    fs::write(&dest, ctx.provenance.to_release_file())
+        .with_context(|| format!("Failed to write /{}", RELEASE_FILE))
+}
@@ -0,0 +1,44 @@
+//! File copies that share extents where the filesystem can.
+//!
+//! The build copies a few hundred MB out of the donor. On btrfs, XFS and
+//! other copy-on-write filesystems, [`copy`] clones the file instead
+//! (`FICLONE`), which takes no time and no space until either copy is
+//! changed. Elsewhere, or across filesystems, sparse files are copied
+//! [with their holes](crate::sparse::copy) and others with [`fs::copy`],
+//! which on Linux copies with `copy_file_range` inside the kernel
+//! (server-side on NFS) before resorting to reading and writing.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use crate::sparse;
+
+/// Copy `src` to `dest` with its permissions, like [`fs::copy`], cloning
+/// it if the filesystem can. Returns the number of bytes copied.
+pub fn copy(src: &Path, dest: &Path) -> io::Result<u64> {
+    if let Some(len) = clone(src, dest) {
+        return Ok(len);
+    }
+    if let Some(len) = sparse::copy(src, dest)? {
+        return Ok(len);
+    }
+    fs::copy(src, dest)
+}
+
+/// Clone `src` to `dest`; None if the filesystem can't.
+fn clone(src: &Path, dest: &Path) -> Option<u64> {
+    let from = File::open(src).ok()?;
+    let meta = from.metadata().ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    let to = File::create(dest).ok()?;
+    // SAFETY: FICLONE on two descriptors we own
+    if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } != 0 {
+        return None;
+    }
+    to.set_permissions(meta.permissions()).ok()?;
+    Some(meta.len())
+}
@@ -0,0 +1,178 @@
+//! Release bundle creation.
+//!
+//! Turns a built stage3 tarball into everything the download server needs:
+//! the tarball itself, SHA256SUMS, a detached minisign signature and a JSON
+//! manifest describing the artifact.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, Stage3Archive};
+use crate::binary::elf_arch_from_header;
+use crate::checksum::{sha256_file, sha256_reader};
+use crate::clock::BuildClock;
+use crate::reflink;
+use crate::sandbox::Sandbox;
+use crate::signing::sign_file;
+use crate::status;
+
+/// JSON manifest written alongside a release tarball.
+#[derive(Serialize, Deserialize)]
+pub struct ReleaseManifest {
+    /// Tarball filename
+    pub name: String,
+    /// Compressed size in bytes
+    pub size: u64,
+    /// SHA-256 of the compressed tarball
+    pub sha256: String,
+    /// SHA-256 of the uncompressed tar stream (independent of compression)
+    pub content_sha256: String,
+    /// Detached signature filename, if the bundle was signed
+    pub signature: Option<String>,
+    /// Metadata about the build that produced the tarball
+    pub build: BuildMetadata,
+}
+
+/// Build metadata recorded in the release manifest.
+#[derive(Serialize, Deserialize)]
+pub struct BuildMetadata {
+    /// NAME from /etc/os-release
+    pub os_name: Option<String>,
+    /// VERSION from /etc/os-release
+    pub os_version: Option<String>,
+    /// Architecture of the shipped binaries
+    pub arch: Option<String>,
+    /// Version of the stage3 builder creating the bundle
+    pub builder_version: String,
+    /// Bundle creation time (seconds since the Unix epoch, `SOURCE_DATE_EPOCH` if set)
+    pub created: u64,
+}
+
+/// Create a release bundle for `tarball` in `output_dir`.
+///
+/// Returns the path to the written JSON manifest.
+pub fn create_release(
+    tarball: &Path,
+    output_dir: &Path,
+    sign_key: Option<&Path>,
+) -> Result<PathBuf> {
+    status!("Creating release bundle for {}...", tarball.display());
+
+    if !tarball.is_file() {
+        anyhow::bail!("Tarball does not exist: {}", tarball.display());
+    }
+
+    let name = tarball
+        .file_name()
+        .with_context(|| format!("Tarball path has no filename: {}", tarball.display()))?
+        .to_string_lossy()
+        .into_owned();
+
+    fs::create_dir_all(output_dir)?;
+
+    // Copy the tarball into the bundle unless it's already there
+    let bundle_tarball = output_dir.join(&name);
+    let same_file =
+        bundle_tarball.exists() && fs::canonicalize(&bundle_tarball)? == fs::canonicalize(tarball)?;
+    if !same_file {
+        reflink::copy(tarball, &bundle_tarball)
+            .with_context(|| format!("Failed to copy {}", tarball.display()))?;
+    }
+
+    // Hashes
+    status!("  Hashing tarball...");
+    let sha256 = sha256_file(&bundle_tarball)?;
+    let content_sha256 = content_hash(&bundle_tarball)?;
+    let size = fs::metadata(&bundle_tarball)?.len();
+
+    // Build metadata from the tarball contents
+    let os_release = read_os_release(&bundle_tarball)?.unwrap_or_else(|| {
+        tracing::warn!("  Warning: /etc/os-release not found in tarball");
+        BTreeMap::new()
+    });
+    let arch = tarball_arch(&bundle_tarball)?;
+
+    // Detached signature
+    let signature = match sign_key {
+        Some(key) => Some(
+            sign_file(&bundle_tarball, key, Sandbox::default())?
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        ),
+        None => {
+            tracing::warn!("  Warning: no signing key given, bundle is unsigned");
+            None
+        }
+    };
+
+    let manifest = ReleaseManifest {
+        name: name.clone(),
+        size,
+        sha256: sha256.clone(),
+        content_sha256,
+        signature,
+        build: BuildMetadata {
+            os_name: os_release.get("NAME").cloned(),
+            os_version: os_release.get("VERSION").cloned(),
+            arch,
+            builder_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: BuildClock::resolve(None)?.timestamp(),
+        },
+    };
+
+    let manifest_name = format!("{}.json", name);
+    let manifest_path = output_dir.join(&manifest_name);
+    fs::write(
+        &manifest_path,
+        serde_json::to_string_pretty(&manifest)? + "\n",
+    )?;
+    status!("  Wrote {}", manifest_name);
+
+    // SHA256SUMS in sha256sum(1) format
+    let sums = format!(
+        "{}  {}\n{}  {}\n",
+        sha256,
+        name,
+        sha256_file(&manifest_path)?,
+        manifest_name
+    );
+    fs::write(output_dir.join("SHA256SUMS"), sums)?;
+    status!("  Wrote SHA256SUMS");
+
+    status!("Release bundle created in {}", output_dir.display());
+    Ok(manifest_path)
+}
+
+/// SHA-256 of the decompressed tar stream.
+pub fn content_hash(tarball: &Path) -> Result<String> {
+    let (decoder, _) = archive::decoder(tarball)?;
+    sha256_reader(decoder).context("Failed to read decompressed tarball")
+}
+
+/// Parse /etc/os-release from the tarball into key/value pairs.
+///
+/// Returns `None` if the tarball has no /etc/os-release.
+pub fn read_os_release(tarball: &Path) -> Result<Option<BTreeMap<String, String>>> {
+    let Some(contents) = Stage3Archive::open(tarball)?.read_member("etc/os-release")? else {
+        return Ok(None);
+    };
+
+    Ok(Some(
+        String::from_utf8_lossy(&contents)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.trim_matches('"').to_string()))
+            .collect(),
+    ))
+}
+
+/// Architecture of the tarball's /usr/bin/bash, if it has one.
+pub fn tarball_arch(tarball: &Path) -> Result<Option<String>> {
+    Ok(Stage3Archive::open(tarball)?
+        .read_member("usr/bin/bash")?
+        .and_then(|bash| elf_arch_from_header(&bash).map(str::to_string)))
+}
@@ -0,0 +1,154 @@
+//! Donor-to-target path remapping.
+//!
+//! The copy routines take files from where a Rocky donor keeps them and by
+//! default put them at the same path in the rootfs. Remap rules move them
+//! elsewhere, e.g. `usr/lib64/security=usr/lib/security` for a target
+//! without multilib directories. Every copy from the donor goes through
+//! [`BuildContext::target`], so a rule applies to binaries, libraries,
+//! units and data alike. The rules are recorded in the build manifest.
+//!
+//! Only where files land changes: symlink targets, generated configs and
+//! ELF interpreters still name the original paths, so remapping a library
+//! directory needs matching `ld.so.conf` (or template) changes.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::cache;
+use crate::context::BuildContext;
+use crate::xattrs;
+
+/// Move donor files under `from` to `to` in the rootfs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathRemap {
+    /// Rootfs-relative donor directory or file (e.g. `usr/lib64/security`)
+    pub from: PathBuf,
+    /// Where it lands in the rootfs (e.g. `usr/lib/security`)
+    pub to: PathBuf,
+}
+
+impl FromStr for PathRemap {
+    type Err = String;
+
+    /// Parse `FROM=TO`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected FROM=TO, got {:?}", s))?;
+        Ok(Self {
+            from: parse_path(from)?,
+            to: parse_path(to)?,
+        })
+    }
+}
+
+/// A rootfs-relative path without `.` or `..`, leading `/` stripped.
+fn parse_path(path: &str) -> std::result::Result<PathBuf, String> {
+    let path = Path::new(path);
+    let path = path.strip_prefix("/").unwrap_or(path).to_path_buf();
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(format!("invalid remap path {:?}", path));
+    }
+    Ok(path)
+}
+
+/// The remap rules of a build.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathRemaps {
+    rules: Vec<PathRemap>,
+}
+
+impl PathRemaps {
+    /// Check that no donor path is remapped twice.
+    pub fn new(rules: Vec<PathRemap>) -> Result<Self> {
+        for (i, rule) in rules.iter().enumerate() {
+            if rules[..i].iter().any(|other| other.from == rule.from) {
+                bail!("/{} is remapped more than once", rule.from.display());
+            }
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> &[PathRemap] {
+        &self.rules
+    }
+
+    /// Where the donor file at rootfs-relative `path` goes.
+    ///
+    /// The rule with the longest matching `from` wins; paths no rule
+    /// matches stay where they are.
+    pub fn apply(&self, path: &Path) -> PathBuf {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.from))
+            .max_by_key(|rule| rule.from.components().count());
+        match rule {
+            Some(rule) => rule.to.join(path.strip_prefix(&rule.from).unwrap()),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+/// Copy the donor file at rootfs-relative `path` to its target.
+///
+/// Returns the staging path it was copied to.
+pub fn copy_donor_file(ctx: &BuildContext, path: impl AsRef<Path>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let dest = ctx.target(path);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let src = ctx
+        .package_source
+        .find_file(path)
+        .with_context(|| format!("{} not found in the donor", path.display()))?;
+    cache::copy_donor(ctx, &src, &dest)?;
+    Ok(dest)
+}
+
+/// Copy the donor directory at rootfs-relative `path` recursively.
+///
+/// Each entry is remapped on its own, so a rule for a subdirectory or a
+/// single file applies inside the copy too. Links to directories are
+/// copied as directories, other symlinks as they are; device nodes, FIFOs
+/// and sockets are skipped.
+pub fn copy_donor_dir(ctx: &BuildContext, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let dest = ctx.target(path);
+    fs::create_dir_all(&dest)?;
+    let src = ctx
+        .package_source
+        .find_file(path)
+        .with_context(|| format!("{} not found in the donor", path.display()))?;
+    xattrs::record(ctx, &src, &dest);
+
+    let names = ctx
+        .package_source
+        .read_dir(path)
+        .with_context(|| format!("{} is not a directory in the donor", path.display()))?;
+    for name in names {
+        let rel = path.join(name);
+
+        if ctx.package_source.is_dir(&rel) {
+            copy_donor_dir(ctx, &rel)?;
+        } else if let Some(target) = ctx.package_source.read_link(&rel) {
+            let dest = ctx.target(&rel);
+            if !dest.exists() && !dest.is_symlink() {
+                fs::create_dir_all(dest.parent().unwrap())?;
+                std::os::unix::fs::symlink(target, &dest)?;
+            }
+        } else if ctx.package_source.is_file(&rel) {
+            copy_donor_file(ctx, &rel)?;
+        } else {
+            // Device nodes, FIFOs and sockets can't be copied as regular files
+            tracing::warn!("  Warning: skipping special file /{}", rel.display());
+        }
+    }
+
+    Ok(())
+}
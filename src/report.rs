@@ -0,0 +1,182 @@
+//! Structured build diagnostics.
+//!
+//! Checks record their findings here in addition to printing them, and the
+//! builder writes the collected report next to the tarball so CI and QA
+//! tooling don't have to scrape build output.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Filename of the report written next to the tarball.
+pub const REPORT_NAME: &str = "levitateos-stage3.report.json";
+
+/// Filename of the list of items the build left out, next to the tarball.
+pub const SKIPPED_NAME: &str = "skipped.json";
+
+/// Checks recording binaries, libraries, PAM modules and units the build
+/// could not copy.
+pub const SKIPPED_ITEM_CHECKS: &[&str] = &["binaries", "libraries", "pam-modules", "units"];
+
+/// How serious a diagnostic is, least serious first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Something optional was left out of the build
+    Skipped,
+    Warning,
+    Error,
+}
+
+/// A single finding from a build check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Check that produced the finding (e.g. `systemd-analyze`)
+    pub check: String,
+    /// Rootfs path or unit the finding is about, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub message: String,
+}
+
+/// Diagnostics collected over a build.
+#[derive(Default)]
+pub struct BuildReport {
+    diagnostics: Mutex<Vec<Diagnostic>>,
+}
+
+#[derive(Serialize)]
+struct ReportFile<'a> {
+    tarball: &'a str,
+    warnings: usize,
+    errors: usize,
+    skipped: usize,
+    diagnostics: &'a [Diagnostic],
+}
+
+#[derive(Serialize)]
+struct SkippedFile<'a> {
+    tarball: &'a str,
+    total: usize,
+    counts: BTreeMap<String, usize>,
+    items: &'a [Diagnostic],
+}
+
+impl BuildReport {
+    /// Record a diagnostic.
+    pub fn push(
+        &self,
+        severity: Severity,
+        check: &str,
+        subject: Option<&str>,
+        message: impl Into<String>,
+    ) {
+        self.diagnostics.lock().unwrap().push(Diagnostic {
+            severity,
+            check: check.to_string(),
+            subject: subject.map(str::to_string),
+            message: message.into(),
+        });
+    }
+
+    /// Record diagnostics of an earlier run again.
+    pub fn extend(&self, diagnostics: impl IntoIterator<Item = Diagnostic>) {
+        self.diagnostics.lock().unwrap().extend(diagnostics);
+    }
+
+    /// Record a warning.
+    pub fn warn(&self, check: &str, subject: Option<&str>, message: impl Into<String>) {
+        self.push(Severity::Warning, check, subject, message);
+    }
+
+    /// Record an optional item left out of the build.
+    pub fn skip(&self, check: &str, subject: Option<&str>, message: impl Into<String>) {
+        self.push(Severity::Skipped, check, subject, message);
+    }
+
+    /// Number of diagnostics recorded so far.
+    pub fn len(&self) -> usize {
+        self.diagnostics.lock().unwrap().len()
+    }
+
+    /// Whether nothing has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Diagnostics recorded after the first `start`.
+    pub fn diagnostics_since(&self, start: usize) -> Vec<Diagnostic> {
+        self.diagnostics.lock().unwrap()[start..].to_vec()
+    }
+
+    /// Sort the diagnostics recorded after the first `start` by check and
+    /// subject, for ones recorded from several threads at once.
+    pub fn sort_since(&self, start: usize) {
+        self.diagnostics.lock().unwrap()[start..]
+            .sort_by(|a, b| (&a.check, &a.subject).cmp(&(&b.check, &b.subject)));
+    }
+
+    /// All diagnostics, in the order they were recorded.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.lock().unwrap().clone()
+    }
+
+    /// Number of diagnostics with the given severity.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|d| d.severity == severity)
+            .count()
+    }
+
+    /// Write the report as pretty-printed JSON.
+    pub fn write(&self, path: &Path, tarball: &str) -> Result<()> {
+        let diagnostics = self.diagnostics();
+        let file = ReportFile {
+            tarball,
+            warnings: self.count(Severity::Warning),
+            errors: self.count(Severity::Error),
+            skipped: self.count(Severity::Skipped),
+            diagnostics: &diagnostics,
+        };
+        fs::write(path, serde_json::to_string_pretty(&file)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Binaries, libraries, PAM modules and units the build did not copy,
+    /// whatever severity `--strict` gave them.
+    pub fn skipped_items(&self) -> Vec<Diagnostic> {
+        self.diagnostics()
+            .into_iter()
+            .filter(|d| SKIPPED_ITEM_CHECKS.contains(&d.check.as_str()))
+            .collect()
+    }
+
+    /// Number of skipped items per check.
+    pub fn skipped_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for item in self.skipped_items() {
+            *counts.entry(item.check).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Write the skipped items as pretty-printed JSON.
+    pub fn write_skipped(&self, path: &Path, tarball: &str) -> Result<()> {
+        let items = self.skipped_items();
+        let file = SkippedFile {
+            tarball,
+            total: items.len(),
+            counts: self.skipped_counts(),
+            items: &items,
+        };
+        fs::write(path, serde_json::to_string_pretty(&file)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
@@ -0,0 +1,132 @@
+//! Symlink resolution inside a rootfs.
+//!
+//! Staging trees, donors and tarballs all hold rootfs paths whose absolute
+//! symlinks point into the rootfs rather than at the build host.
+//! [`resolve`] follows links one component at a time through a lookup the
+//! caller provides, so the same rules apply to each of them.
+
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+/// Maximum symlink hops followed while resolving a path.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// What a rootfs path is, as far as [`resolve`] is concerned.
+pub enum Node {
+    /// Nothing is there
+    Missing,
+    /// A symlink, with its target
+    Symlink(PathBuf),
+    /// A file, directory or anything else that isn't a symlink
+    Present,
+}
+
+/// Resolve rootfs path `path`, asking `lookup` what each rootfs-relative
+/// prefix of it is.
+///
+/// Absolute targets are taken relative to the rootfs, and `..` stops at
+/// its root. Returns the rootfs-relative path of the final target, or
+/// `None` if part of it is missing or the links loop.
+pub fn resolve(path: &Path, mut lookup: impl FnMut(&Path) -> Node) -> Option<PathBuf> {
+    let mut current = PathBuf::new();
+    let mut pending = components(path);
+    pending.reverse();
+    let mut hops = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            current.pop();
+            continue;
+        }
+        current.push(&component);
+
+        match lookup(&current) {
+            Node::Missing => return None,
+            Node::Symlink(target) => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return None;
+                }
+                current.pop();
+                if target.is_absolute() {
+                    current = PathBuf::new();
+                }
+                pending.extend(components(&target).into_iter().rev());
+            }
+            Node::Present => {}
+        }
+    }
+
+    Some(current)
+}
+
+/// What rootfs-relative `path` is in the tree at host directory `root`.
+pub fn host_node(root: &Path, path: &Path) -> Node {
+    let file = root.join(path);
+    match file.symlink_metadata() {
+        Err(_) => Node::Missing,
+        Ok(metadata) if metadata.file_type().is_symlink() => match file.read_link() {
+            Ok(target) => Node::Symlink(target),
+            Err(_) => Node::Missing,
+        },
+        Ok(_) => Node::Present,
+    }
+}
+
+/// Normal and parent components of a path (root and `.` are dropped).
+fn components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// A lookup over `links`, with every other path under `present`.
+    fn tree<'a>(
+        links: &'a BTreeMap<&str, &str>,
+        present: &'a [&str],
+    ) -> impl FnMut(&Path) -> Node + 'a {
+        |path: &Path| {
+            let path = path.to_str().unwrap();
+            match links.get(path) {
+                Some(target) => Node::Symlink(PathBuf::from(target)),
+                None if present.contains(&path) => Node::Present,
+                None => Node::Missing,
+            }
+        }
+    }
+
+    #[test]
+    fn absolute_links_stay_in_the_rootfs() {
+        let links = BTreeMap::from([
+            ("lib64", "usr/lib64"),
+            ("usr/lib64/libc.so", "/lib64/libc.so.6"),
+        ]);
+        let present = ["usr", "usr/lib64", "usr/lib64/libc.so.6"];
+        let resolved = resolve(Path::new("/lib64/libc.so"), tree(&links, &present));
+        assert_eq!(resolved, Some(PathBuf::from("usr/lib64/libc.so.6")));
+    }
+
+    #[test]
+    fn parent_components_stop_at_the_root() {
+        let links = BTreeMap::from([("etc/os-release", "../../../usr/lib/os-release")]);
+        let present = ["etc", "usr", "usr/lib", "usr/lib/os-release"];
+        let resolved = resolve(Path::new("etc/os-release"), tree(&links, &present));
+        assert_eq!(resolved, Some(PathBuf::from("usr/lib/os-release")));
+    }
+
+    #[test]
+    fn missing_targets_and_loops_resolve_to_nothing() {
+        let links = BTreeMap::from([("a", "b"), ("b", "a"), ("dangling", "nowhere")]);
+        assert_eq!(resolve(Path::new("a"), tree(&links, &[])), None);
+        assert_eq!(resolve(Path::new("dangling"), tree(&links, &[])), None);
+    }
+}
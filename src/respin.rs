@@ -0,0 +1,327 @@
+//! Overlay-only respins of an existing tarball.
+//!
+//! Branding or configuration-only changes don't need a full build: the base
+//! tarball is streamed entry by entry into a new archive, entries the
+//! overlay replaces are dropped, and the overlay is appended. Nothing is
+//! staged and unchanged entries keep their exact headers and contents.
+
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::archive::{self, Stage3Archive};
+use crate::checksum;
+use crate::clock::BuildClock;
+use crate::status;
+
+/// Rewrite `base` into `output` with the files under `overlay` added or
+/// replaced.
+///
+/// Overlay entries are owned by root and their mtimes are clamped to the
+/// build clock. Directories that already exist in the base keep their
+/// ownership and mode. The output is compressed like the base, only gets
+/// its name once complete, and gets a `.sha256` sidecar.
+pub fn respin(base: &Path, overlay: &Path, output: &Path) -> Result<()> {
+    status!(
+        "Respinning {} with overlay {}...",
+        base.display(),
+        overlay.display()
+    );
+
+    if !overlay.is_dir() {
+        bail!("Overlay is not a directory: {}", overlay.display());
+    }
+    if same_file(output, base) {
+        bail!("Output must differ from the base tarball");
+    }
+
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let counts = match write_respin(base, overlay, &partial) {
+        Ok(counts) => counts,
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+    };
+    fs::rename(&partial, output)
+        .with_context(|| format!("Failed to rename {} into place", partial.display()))?;
+
+    let (kept, replaced, added) = counts;
+    status!(
+        "  Kept {} entries, replaced {}, added {}",
+        kept,
+        replaced,
+        added
+    );
+    let sidecar = checksum::write_sidecar(output)?;
+    status!("  Checksum: {}", sidecar.display());
+    status!("Respun tarball: {}", output.display());
+    Ok(())
+}
+
+/// Whether `a` and `b` are the same existing file, under any name.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Write the respun tarball to `output`, returning how many base entries
+/// were kept and replaced and how many overlay entries were added.
+fn write_respin(base: &Path, overlay: &Path, output: &Path) -> Result<(usize, usize, usize)> {
+    let mut pending = overlay_entries(overlay)?;
+    // Overlay paths the base has no entry for
+    let mut new: BTreeSet<String> = pending.keys().cloned().collect();
+    let clock = BuildClock::resolve(None)?;
+
+    let file =
+        File::create(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    // Keep the compression of the base
+    let mut stream = Stage3Archive::open(base)?;
+    let encoder = archive::Encoder::new(BufWriter::new(file), stream.compression())?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+
+    let (mut kept, mut replaced) = (0, 0);
+
+    for entry in stream.entries()? {
+        let mut entry = entry?;
+        let path = entry.archive_path()?;
+        let rel = entry.path().to_string();
+        let entry_type = entry.header().entry_type();
+
+        if let Some(source) = pending.get(&rel) {
+            let source_is_dir = source.symlink_metadata()?.is_dir();
+            match (entry_type.is_dir(), source_is_dir) {
+                // Existing directories keep the base attributes
+                (true, true) => {
+                    pending.remove(&rel);
+                    new.remove(&rel);
+                }
+                (false, false) => {
+                    new.remove(&rel);
+                    replaced += 1;
+                    continue;
+                }
+                _ => bail!(
+                    "Overlay changes /{} between file and directory; rebuild instead",
+                    rel
+                ),
+            }
+        }
+
+        let mut header = entry.header().clone();
+        if entry_type.is_gnu_sparse() {
+            // Read back expanded, so written as the regular file it is
+            unsparse(&mut header, entry.size()?);
+        }
+        // Extended attributes and ACLs of the base entry
+        let records: Vec<(String, Vec<u8>)> = entry
+            .pax_records()?
+            .into_iter()
+            .filter(|(key, _)| carried_record(key))
+            .collect();
+        builder.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry
+                .link_name()?
+                .with_context(|| format!("Link without a target: /{}", rel))?;
+            if entry_type.is_hard_link() && pending.contains_key(&archive::normalize_path(&target))
+            {
+                bail!(
+                    "Overlay replaces /{}, which /{} is hardlinked to; rebuild instead",
+                    archive::normalize_path(&target),
+                    rel
+                );
+            }
+            builder.append_link(&mut header, &path, &target)?;
+        } else {
+            builder.append_data(&mut header, &path, &mut entry)?;
+        }
+        kept += 1;
+    }
+
+    for (rel, source) in &pending {
+        append_overlay_entry(&mut builder, rel, source, &clock)?;
+    }
+
+    let mut writer = builder.into_inner()?.finish()?;
+    writer.flush()?;
+    Ok((kept, replaced, new.len()))
+}
+
+/// Whether a PAX record of a base entry is written back with it; the path,
+/// link target, size and sparse map come from the header written.
+fn carried_record(key: &str) -> bool {
+    !matches!(key, "path" | "linkpath" | "size") && !key.starts_with("GNU.sparse.")
+}
+
+/// Make GNU sparse `header` the header of a regular file of `size` bytes.
+fn unsparse(header: &mut tar::Header, size: u64) {
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    if let Some(gnu) = header.as_gnu_mut() {
+        gnu.realsize = [0; 12];
+        gnu.isextended = [0];
+        for extent in gnu.sparse.iter_mut() {
+            extent.offset = [0; 12];
+            extent.numbytes = [0; 12];
+        }
+    }
+}
+
+/// Every path under the overlay, keyed by its rootfs path.
+fn overlay_entries(overlay: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut entries = BTreeMap::new();
+    for entry in WalkDir::new(overlay).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(overlay)?;
+        entries.insert(archive::normalize_path(rel), entry.path().to_path_buf());
+    }
+    Ok(entries)
+}
+
+fn append_overlay_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    rel: &str,
+    source: &Path,
+    clock: &BuildClock,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)
+        .with_context(|| format!("Failed to read metadata: {}", source.display()))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+    header.set_mtime(header.mtime()?.min(clock.timestamp()));
+    if let Some(gnu) = header.as_gnu_mut() {
+        gnu.atime = [0; 12];
+        gnu.ctime = [0; 12];
+    }
+    header.set_uid(0);
+    header.set_gid(0);
+
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        builder.append_link(&mut header, rel, fs::read_link(source)?)?;
+    } else if file_type.is_dir() {
+        builder.append_data(&mut header, rel, io::empty())?;
+    } else if file_type.is_file() {
+        builder.append_data(&mut header, rel, File::open(source)?)?;
+    } else {
+        tracing::warn!("  Warning: skipping special file {}", source.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Compression, WriteOptions};
+    use crate::fakeroot::{MetadataLayer, Xattrs};
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+
+    const SPARSE_SIZE: u64 = 1 << 20;
+
+    /// Write the tarball of `staging` to `base`.
+    fn write_base(staging: &Path, base: &Path) {
+        write_base_with(staging, base, None);
+    }
+
+    fn write_base_with(staging: &Path, base: &Path, metadata: Option<&MetadataLayer>) {
+        let options = WriteOptions {
+            metadata,
+            clock: BuildClock::fixed(1_700_000_000),
+            compression: Compression::None,
+            cancel: None,
+            dedup: false,
+        };
+        archive::write_tarball(staging, base, &options).unwrap();
+    }
+
+    /// Every entry of `tarball`: its type and contents, by rootfs path.
+    fn entries(tarball: &Path) -> BTreeMap<String, (tar::EntryType, Vec<u8>)> {
+        let mut archive = Stage3Archive::open(tarball).unwrap();
+        let mut entries = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            let kind = entry.header().entry_type();
+            entries.insert(entry.path().to_string(), (kind, contents));
+        }
+        entries
+    }
+
+    #[test]
+    fn sparse_entries_are_kept_with_what_follows() {
+        let root = tempfile::tempdir().unwrap();
+        let staging = root.path().join("staging");
+        fs::create_dir(&staging).unwrap();
+        let sparse = File::create(staging.join("sparse.img")).unwrap();
+        sparse.set_len(SPARSE_SIZE).unwrap();
+        sparse.write_all_at(b"data", SPARSE_SIZE - 4).unwrap();
+        fs::write(staging.join("z"), "after the sparse file\n").unwrap();
+        let base = root.path().join("base.tar");
+        write_base(&staging, &base);
+        assert_eq!(entries(&base)["sparse.img"].0, tar::EntryType::GNUSparse);
+
+        let overlay = root.path().join("overlay");
+        fs::create_dir(&overlay).unwrap();
+        fs::write(overlay.join("b"), "overlay\n").unwrap();
+        let output = root.path().join("out.tar");
+        respin(&base, &overlay, &output).unwrap();
+
+        let respun = entries(&output);
+        let paths: Vec<&str> = respun.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["", "b", "sparse.img", "z"]);
+        let (kind, contents) = &respun["sparse.img"];
+        assert_eq!(*kind, tar::EntryType::Regular);
+        assert_eq!(contents.len() as u64, SPARSE_SIZE);
+        assert!(contents.ends_with(b"data"));
+        assert!(contents[..contents.len() - 4].iter().all(|&b| b == 0));
+        assert_eq!(respun["z"].1, b"after the sparse file\n");
+        assert_eq!(respun["b"].1, b"overlay\n");
+    }
+
+    #[test]
+    fn extended_attributes_survive() {
+        let root = tempfile::tempdir().unwrap();
+        let staging = root.path().join("staging");
+        fs::create_dir_all(staging.join("usr/bin")).unwrap();
+        fs::write(staging.join("usr/bin/ping"), "ping\n").unwrap();
+        let capability = vec![1, 0, 0, 2, 0, 32, 0, 0];
+        let metadata = MetadataLayer::default();
+        metadata.set_xattrs(
+            "usr/bin/ping",
+            Xattrs::from([("security.capability".to_string(), capability.clone())]),
+        );
+        let base = root.path().join("base.tar");
+        write_base_with(&staging, &base, Some(&metadata));
+
+        let overlay = root.path().join("overlay");
+        fs::create_dir_all(overlay.join("etc")).unwrap();
+        fs::write(overlay.join("etc/motd"), "respun\n").unwrap();
+        let output = root.path().join("out.tar");
+        respin(&base, &overlay, &output).unwrap();
+
+        let mut archive = Stage3Archive::open(&output).unwrap();
+        let mut records = None;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path() == "usr/bin/ping" {
+                records = Some(entry.pax_records().unwrap());
+            }
+        }
+        assert_eq!(
+            records.expect("ping is in the respun tarball"),
+            [("SCHILY.xattr.security.capability".to_string(), capability)]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Optional accessibility component.
+//!
+//! Braille display support (brltty) and console speech (espeakup with
+//! espeak-ng), so vision-impaired users have a usable console on the
+//! installed system. udev rules come along with the regular rules copy.
+
+use std::fs;
+use std::path::Path;
+
+use crate::binary::copy_binary_with_libs;
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+use crate::remap::{copy_donor_dir, copy_donor_file};
+
+/// Binaries making up the component.
+pub const BINARIES: &[&str] = &[
+    "brltty",
+    "brltty-ctb",
+    "brltty-trtxt",
+    "espeakup",
+    "espeak-ng",
+];
+
+/// Drivers, tables and voice data.
+const DATA_DIRS: &[&str] = &[
+    "etc/brltty",
+    "usr/lib64/brltty",
+    "usr/share/brltty",
+    "usr/share/espeak-ng-data",
+];
+
+/// Configuration files.
+const CONFIG_FILES: &[&str] = &["etc/brltty.conf"];
+
+/// Units copied when present.
+const UNITS: &[&str] = &[
+    "brltty.service",
+    "brltty@.service",
+    "brltty-device@.service",
+    "espeakup.service",
+];
+
+/// Units enabled for multi-user.target.
+const ENABLED_UNITS: &[&str] = &["brltty.service", "espeakup.service"];
+
+/// Kernel module espeakup drives the console through.
+const SPEAKUP_MODULES: &str = "# Software speech synthesizer for espeakup\nspeakup_soft\n";
+
+/// Copy brltty and espeakup with their data and units, and enable them.
+pub fn setup_accessibility(ctx: &BuildContext) -> Result<()> {
+    detail!("Setting up accessibility tools...");
+
+    let mut copied = 0;
+    for binary in BINARIES {
+        if copy_binary_with_libs(ctx, binary, "usr/bin")? {
+            copied += 1;
+        }
+    }
+    detail!("  Copied {}/{} binaries", copied, BINARIES.len());
+
+    for dir in DATA_DIRS {
+        let dir = ctx.options.layout.relocate(dir);
+        if ctx.package_source.is_dir(&dir) {
+            copy_donor_dir(ctx, &dir)?;
+        }
+    }
+    for file in CONFIG_FILES {
+        if ctx.package_source.is_file(Path::new(file)) {
+            copy_donor_file(ctx, file)?;
+        }
+    }
+
+    let unit_dir = Path::new("usr/lib/systemd/system");
+    let wants = ctx
+        .staging
+        .join("etc/systemd/system/multi-user.target.wants");
+    fs::create_dir_all(&wants)?;
+
+    for unit in UNITS {
+        if ctx.package_source.find_file(&unit_dir.join(unit)).is_some() {
+            copy_donor_file(ctx, unit_dir.join(unit))?;
+        }
+    }
+    for unit in ENABLED_UNITS {
+        let link = wants.join(unit);
+        if ctx.target(unit_dir.join(unit)).exists() && !link.is_symlink() {
+            std::os::unix::fs::symlink(format!("/usr/lib/systemd/system/{}", unit), &link)?;
+            detail!("  Enabled {}", unit);
+        }
+    }
+
+    let modules_load = ctx.staging.join("usr/lib/modules-load.d");
+    fs::create_dir_all(&modules_load)?;
+    fs::write(modules_load.join("espeakup.conf"), SPEAKUP_MODULES)?;
+
+    Ok(())
+}
@@ -1,14 +1,31 @@
 //! Binary lists and copying for stage3.
 //!
 //! Contains the complete list of binaries needed for an installed system.
+//! [`BinaryOverrides`] (the `[binaries]` table of `stage3.toml`) adds to
+//! the coreutils and sbin lists or leaves binaries out of them, for every
+//! build or for builds of one architecture (`[binaries.arch.riscv64]`).
+//!
+//! Coreutils and sbin utilities are copied on every CPU: each binary's
+//! `ldd` run dominates the build, and binaries are independent apart from
+//! the libraries they share, which are copied once.
 
-use anyhow::Result;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::arch;
 use crate::binary::{copy_binary_with_libs, copy_bash, copy_sbin_binary_with_libs};
-use crate::context::BuildContext;
+use crate::condition::Condition;
+use crate::context::{BuildContext, BuildOptions};
+use crate::detail;
+use crate::error::Result;
+use crate::remap::copy_donor_file;
+use crate::report::Severity;
 
 /// Coreutils and essential user binaries.
-const COREUTILS: &[&str] = &[
+pub const COREUTILS: &[&str] = &[
     // File operations
     "ls",
     "cat",
@@ -40,6 +57,10 @@ const COREUTILS: &[&str] = &[
     "diff",
     "tee",
     "yes",
+    // Locale and charset conversion
+    "iconv",
+    "locale",
+    "localedef",
     // Search/find
     "grep",
     "find",
@@ -106,10 +127,12 @@ const COREUTILS: &[&str] = &[
     "localectl",
     "loginctl",
     "bootctl",
+    "systemd-ask-password",
+    "systemd-tty-ask-password-agent",
 ];
 
 /// Sbin utilities (system administration).
-const SBIN_UTILS: &[&str] = &[
+pub const SBIN_UTILS: &[&str] = &[
     // Filesystem
     "mount",
     "umount",
@@ -172,7 +195,7 @@ const SBIN_UTILS: &[&str] = &[
 ];
 
 /// Systemd binaries to copy.
-const SYSTEMD_BINARIES: &[&str] = &[
+pub const SYSTEMD_BINARIES: &[&str] = &[
     "systemd-executor",
     "systemd-shutdown",
     "systemd-sulogin-shell",
@@ -194,79 +217,226 @@ const SYSTEMD_BINARIES: &[&str] = &[
     "systemd-random-seed",
 ];
 
-/// Copy all coreutils binaries.
-pub fn copy_coreutils(ctx: &BuildContext) -> Result<()> {
-    println!("Copying coreutils binaries...");
+/// Getty and login binaries, copied like sbin utilities.
+pub const LOGIN_BINARIES: &[&str] = &["agetty", "login", "sulogin", "nologin"];
 
-    let mut copied = 0;
-    for binary in COREUTILS {
-        if copy_binary_with_libs(ctx, binary, "usr/bin")? {
-            copied += 1;
+/// Binaries added to or left out of the built-in lists.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BinaryOverrides {
+    /// Copied to /usr/bin along with coreutils
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    /// Copied to /usr/sbin along with the sbin utilities
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub add_sbin: Vec<String>,
+    /// Left out of the coreutils and sbin lists
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// Further overrides for builds of one architecture, by architecture
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub arch: BTreeMap<String, BinaryOverrides>,
+    /// Further overrides for the builds their condition matches
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conditional: Vec<ConditionalBinaries>,
+}
+
+/// Binaries added or left out in the builds `when` matches
+/// (`[[binaries.conditional]]`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConditionalBinaries {
+    pub when: Condition,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_sbin: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+impl BinaryOverrides {
+    /// These overrides with the ones for the build's architecture and
+    /// those whose condition it matches added.
+    pub fn for_build(&self, options: &BuildOptions) -> BinaryOverrides {
+        let mut overrides = BinaryOverrides {
+            add: self.add.clone(),
+            add_sbin: self.add_sbin.clone(),
+            remove: self.remove.clone(),
+            ..BinaryOverrides::default()
+        };
+        if let Some(extra) = self.arch.get(&options.arch) {
+            overrides.merge(&extra.for_build(options));
+        }
+        for conditional in self.conditional.iter().filter(|c| c.when.matches(options)) {
+            overrides.add.extend(conditional.add.iter().cloned());
+            overrides.add_sbin.extend(conditional.add_sbin.iter().cloned());
+            overrides.remove.extend(conditional.remove.iter().cloned());
+        }
+        overrides
+    }
+
+    /// Add `other`'s overrides to these.
+    pub fn merge(&mut self, other: &BinaryOverrides) {
+        self.add.extend(other.add.iter().cloned());
+        self.add_sbin.extend(other.add_sbin.iter().cloned());
+        self.remove.extend(other.remove.iter().cloned());
+        for (arch, overrides) in &other.arch {
+            self.arch.entry(arch.clone()).or_default().merge(overrides);
+        }
+        self.conditional.extend(other.conditional.iter().cloned());
+    }
+
+    /// Coreutils to copy, with these overrides.
+    pub fn coreutils(&self) -> Vec<&str> {
+        self.apply(COREUTILS, &self.add)
+    }
+
+    /// sbin utilities to copy, with these overrides.
+    pub fn sbin_utils(&self) -> Vec<&str> {
+        self.apply(SBIN_UTILS, &self.add_sbin)
+    }
+
+    fn apply<'a>(&'a self, builtin: &[&'a str], add: &'a [String]) -> Vec<&'a str> {
+        let mut binaries: Vec<&str> = builtin
+            .iter()
+            .copied()
+            .filter(|binary| !self.remove.iter().any(|removed| removed == binary))
+            .collect();
+        for binary in add {
+            if !binaries.contains(&binary.as_str()) {
+                binaries.push(binary);
+            }
         }
+        binaries
     }
+}
 
-    println!("  Copied {}/{} coreutils binaries", copied, COREUTILS.len());
+/// Copy all coreutils binaries.
+pub fn copy_coreutils(ctx: &BuildContext, overrides: &BinaryOverrides) -> Result<()> {
+    detail!("Copying coreutils binaries...");
+
+    let binaries = overrides.coreutils();
+    let copied = copy_parallel(ctx, &binaries, |binary| {
+        copy_binary_with_libs(ctx, binary, "usr/bin")
+    })?;
+
+    detail!("  Copied {}/{} coreutils binaries", copied, binaries.len());
     Ok(())
 }
 
 /// Copy all sbin utilities.
-pub fn copy_sbin_utils(ctx: &BuildContext) -> Result<()> {
-    println!("Copying sbin utilities...");
+pub fn copy_sbin_utils(ctx: &BuildContext, overrides: &BinaryOverrides) -> Result<()> {
+    detail!("Copying sbin utilities...");
+
+    let binaries = overrides.sbin_utils();
+    let copied = copy_parallel(ctx, &binaries, |binary| {
+        copy_sbin_binary_with_libs(ctx, binary)
+    })?;
+
+    detail!("  Copied {}/{} sbin utilities", copied, binaries.len());
+    Ok(())
+}
+
+/// Copy each of `binaries` with `copy` on rayon's pool, shared by every
+/// step running at once, and count the ones found.
+///
+/// What ends up in staging doesn't depend on the order the threads get
+/// to the binaries; the diagnostics the copies record are sorted so the
+/// report doesn't either. Once a copy fails no more are started, and the
+/// first failure in list order is returned.
+fn copy_parallel<F>(ctx: &BuildContext, binaries: &[&str], copy: F) -> Result<usize>
+where
+    F: Fn(&str) -> Result<bool> + Sync,
+{
+    let start = ctx.report.len();
+    let failed = AtomicBool::new(false);
+    // Keep the target prefix of multi-target builds on worker output
+    let span = tracing::Span::current();
+    let results: Vec<Option<Result<bool>>> = binaries
+        .par_iter()
+        .map(|binary| {
+            if failed.load(Ordering::Relaxed) {
+                return None;
+            }
+            let _span = span.enter();
+            let result = copy(binary);
+            if result.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            Some(result)
+        })
+        .collect();
+    ctx.report.sort_since(start);
 
     let mut copied = 0;
-    for binary in SBIN_UTILS {
-        if copy_sbin_binary_with_libs(ctx, binary)? {
+    for result in results.into_iter().flatten() {
+        if result? {
             copied += 1;
         }
     }
-
-    println!("  Copied {}/{} sbin utilities", copied, SBIN_UTILS.len());
-    Ok(())
+    Ok(copied)
 }
 
 /// Copy bash shell.
 pub fn copy_shell(ctx: &BuildContext) -> Result<()> {
-    println!("Copying bash shell...");
+    detail!("Copying bash shell...");
     copy_bash(ctx)?;
-    println!("  Copied bash");
+    detail!("  Copied bash");
     Ok(())
 }
 
 /// Copy systemd binaries and libraries.
 pub fn copy_systemd_binaries(ctx: &BuildContext) -> Result<()> {
-    println!("Copying systemd binaries...");
+    detail!("Copying systemd binaries...");
 
     // Copy main systemd binary
-    let systemd_src = ctx.source.join("usr/lib/systemd/systemd");
-    let systemd_dst = ctx.staging.join("usr/lib/systemd/systemd");
-    if systemd_src.exists() {
-        std::fs::create_dir_all(systemd_dst.parent().unwrap())?;
-        std::fs::copy(&systemd_src, &systemd_dst)?;
+    if ctx
+        .package_source
+        .find_file(Path::new("usr/lib/systemd/systemd"))
+        .is_some()
+    {
+        let systemd_dst = copy_donor_file(ctx, "usr/lib/systemd/systemd")?;
         crate::binary::make_executable(&systemd_dst)?;
-        println!("  Copied systemd");
+        detail!("  Copied systemd");
+    } else {
+        ctx.report.push(
+            ctx.missing_severity(Severity::Warning),
+            "binaries",
+            Some("/usr/lib/systemd/systemd"),
+            "not found in source",
+        );
     }
 
     // Copy helper binaries
     for binary in SYSTEMD_BINARIES {
-        let src = ctx.source.join("usr/lib/systemd").join(binary);
-        let dst = ctx.staging.join("usr/lib/systemd").join(binary);
-        if src.exists() {
-            std::fs::copy(&src, &dst)?;
+        let path = Path::new("usr/lib/systemd").join(binary);
+        if ctx.package_source.find_file(&path).is_some() {
+            let dst = copy_donor_file(ctx, &path)?;
             crate::binary::make_executable(&dst)?;
+        } else if arch::is_optional(&ctx.options.arch, binary) {
+            ctx.report.skip(
+                "binaries",
+                Some(&format!("/{}", path.display())),
+                format!("not found in source (optional on {})", ctx.options.arch),
+            );
+        } else {
+            ctx.report.push(
+                ctx.missing_severity(Severity::Skipped),
+                "binaries",
+                Some(&format!("/{}", path.display())),
+                "not found in source",
+            );
         }
     }
 
     // Copy systemd private libraries
-    let systemd_lib_src = ctx.source.join("usr/lib64/systemd");
-    if systemd_lib_src.exists() {
-        std::fs::create_dir_all(ctx.staging.join("usr/lib64/systemd"))?;
-        for entry in std::fs::read_dir(&systemd_lib_src)? {
-            let entry = entry?;
-            let name = entry.file_name();
+    let systemd_lib_dir = ctx.options.layout.relocate("usr/lib64/systemd");
+    if let Some(names) = ctx.package_source.read_dir(&systemd_lib_dir) {
+        for name in names {
             let name_str = name.to_string_lossy();
             if name_str.starts_with("libsystemd-") && name_str.ends_with(".so") {
-                let dst = ctx.staging.join("usr/lib64/systemd").join(&name);
-                std::fs::copy(entry.path(), &dst)?;
+                copy_donor_file(ctx, systemd_lib_dir.join(&name))?;
             }
         }
     }
@@ -277,20 +447,18 @@ pub fn copy_systemd_binaries(ctx: &BuildContext) -> Result<()> {
         std::os::unix::fs::symlink("/usr/lib/systemd/systemd", &init_link)?;
     }
 
-    println!("  Copied {} systemd binaries", SYSTEMD_BINARIES.len());
+    detail!("  Copied {} systemd binaries", SYSTEMD_BINARIES.len());
     Ok(())
 }
 
 /// Copy agetty and login binaries for getty/console.
 pub fn copy_login_binaries(ctx: &BuildContext) -> Result<()> {
-    println!("Copying login binaries...");
-
-    let login_binaries = ["agetty", "login", "sulogin", "nologin"];
+    detail!("Copying login binaries...");
 
-    for binary in login_binaries {
+    for binary in LOGIN_BINARIES {
         copy_sbin_binary_with_libs(ctx, binary)?;
     }
 
-    println!("  Copied login binaries");
+    detail!("  Copied login binaries");
     Ok(())
 }
@@ -3,14 +3,19 @@
 //! These are the complete configuration files needed for a real
 //! installed system (not the minimal live environment).
 
-use anyhow::Result;
 use std::fs;
+use std::path::Path;
 
 use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+use crate::provenance;
+use crate::remap::{copy_donor_dir, copy_donor_file};
+use crate::templates;
 
 /// Create all /etc configuration files.
 pub fn create_etc_files(ctx: &BuildContext) -> Result<()> {
-    println!("Creating /etc configuration files...");
+    detail!("Creating /etc configuration files...");
 
     create_passwd_files(ctx)?;
     create_system_identity(ctx)?;
@@ -20,8 +25,9 @@ pub fn create_etc_files(ctx: &BuildContext) -> Result<()> {
     create_network_config(ctx)?;
     create_shell_config(ctx)?;
     create_nsswitch(ctx)?;
+    create_sysconfig(ctx)?;
 
-    println!("  Created /etc configuration files");
+    detail!("  Created /etc configuration files");
     Ok(())
 }
 
@@ -30,36 +36,10 @@ fn create_passwd_files(ctx: &BuildContext) -> Result<()> {
     let etc = ctx.staging.join("etc");
 
     // /etc/passwd - basic system users
-    fs::write(
-        etc.join("passwd"),
-        r#"root:x:0:0:root:/root:/usr/bin/bash
-bin:x:1:1:bin:/bin:/usr/sbin/nologin
-daemon:x:2:2:daemon:/sbin:/usr/sbin/nologin
-nobody:x:65534:65534:Kernel Overflow User:/:/usr/sbin/nologin
-systemd-network:x:192:192:systemd Network Management:/:/usr/sbin/nologin
-systemd-resolve:x:193:193:systemd Resolver:/:/usr/sbin/nologin
-systemd-timesync:x:194:194:systemd Time Synchronization:/:/usr/sbin/nologin
-systemd-coredump:x:195:195:systemd Core Dumper:/:/usr/sbin/nologin
-dbus:x:81:81:System message bus:/:/usr/sbin/nologin
-chrony:x:996:993::/var/lib/chrony:/usr/sbin/nologin
-"#,
-    )?;
+    templates::install(ctx, "etc/passwd")?;
 
     // /etc/shadow - password hashes (root has no password initially)
-    fs::write(
-        etc.join("shadow"),
-        r#"root:!:19000:0:99999:7:::
-bin:*:19000:0:99999:7:::
-daemon:*:19000:0:99999:7:::
-nobody:*:19000:0:99999:7:::
-systemd-network:!*:19000::::::
-systemd-resolve:!*:19000::::::
-systemd-timesync:!*:19000::::::
-systemd-coredump:!*:19000::::::
-dbus:!*:19000::::::
-chrony:!*:19000::::::
-"#,
-    )?;
+    templates::install(ctx, "etc/shadow")?;
 
     // Set proper permissions on shadow
     use std::os::unix::fs::PermissionsExt;
@@ -67,55 +47,8 @@ chrony:!*:19000::::::
     perms.set_mode(0o600);
     fs::set_permissions(etc.join("shadow"), perms)?;
 
-    // /etc/group
-    fs::write(
-        etc.join("group"),
-        r#"root:x:0:
-bin:x:1:
-daemon:x:2:
-sys:x:3:
-adm:x:4:
-tty:x:5:
-disk:x:6:
-wheel:x:10:
-kmem:x:9:
-audio:x:11:
-video:x:12:
-users:x:100:
-nobody:x:65534:
-systemd-network:x:192:
-systemd-resolve:x:193:
-systemd-timesync:x:194:
-systemd-coredump:x:195:
-dbus:x:81:
-chrony:x:993:
-"#,
-    )?;
-
-    // /etc/gshadow
-    fs::write(
-        etc.join("gshadow"),
-        r#"root:::
-bin:::
-daemon:::
-sys:::
-adm:::
-tty:::
-disk:::
-wheel:::
-kmem:::
-audio:::
-video:::
-users:::
-nobody:::
-systemd-network:!::
-systemd-resolve:!::
-systemd-timesync:!::
-systemd-coredump:!::
-dbus:!::
-chrony:!::
-"#,
-    )?;
+    templates::install(ctx, "etc/group")?;
+    templates::install(ctx, "etc/gshadow")?;
 
     // Set proper permissions on gshadow
     let mut perms = fs::metadata(etc.join("gshadow"))?.permissions();
@@ -130,24 +63,13 @@ fn create_system_identity(ctx: &BuildContext) -> Result<()> {
     let etc = ctx.staging.join("etc");
 
     // /etc/hostname (empty - will be set during installation)
-    fs::write(etc.join("hostname"), "levitateos\n")?;
+    templates::install(ctx, "etc/hostname")?;
 
     // /etc/machine-id (empty - systemd generates on first boot)
     fs::write(etc.join("machine-id"), "")?;
 
-    // /etc/os-release
-    fs::write(
-        etc.join("os-release"),
-        r#"NAME="LevitateOS"
-ID=levitateos
-ID_LIKE=fedora
-VERSION="1.0"
-VERSION_ID=1
-PRETTY_NAME="LevitateOS 1.0"
-HOME_URL="https://levitateos.org"
-BUG_REPORT_URL="https://github.com/levitateos/levitateos/issues"
-"#,
-    )?;
+    templates::install(ctx, "etc/os-release")?;
+    provenance::install(ctx)?;
 
     Ok(())
 }
@@ -156,26 +78,11 @@ BUG_REPORT_URL="https://github.com/levitateos/levitateos/issues"
 fn create_filesystem_config(ctx: &BuildContext) -> Result<()> {
     let etc = ctx.staging.join("etc");
 
-    // /etc/fstab - template, will be updated during installation
-    fs::write(
-        etc.join("fstab"),
-        r#"# /etc/fstab - Static file system information
-# <device>  <mount>  <type>  <options>  <dump>  <fsck>
-
-# Root filesystem (set by installer)
-# /dev/xxx  /  ext4  defaults  0  1
-
-# EFI System Partition (set by installer)
-# /dev/xxx  /boot/efi  vfat  umask=0077  0  2
-
-# Proc and sys (always needed)
-proc  /proc  proc  defaults  0  0
-sysfs  /sys  sysfs  defaults  0  0
-devtmpfs  /dev  devtmpfs  mode=0755,nosuid  0  0
-tmpfs  /tmp  tmpfs  defaults,nosuid,nodev  0  0
-tmpfs  /run  tmpfs  mode=0755,nosuid,nodev  0  0
-"#,
-    )?;
+    // fstab, crypttab and the loader entry carry placeholders for
+    // `stage3 finalize` to fill in during installation
+    templates::install(ctx, "etc/fstab")?;
+    templates::install(ctx, "etc/crypttab")?;
+    templates::install(ctx, "boot/loader/entries/levitateos.conf")?;
 
     // /etc/mtab -> /proc/self/mounts
     let mtab = etc.join("mtab");
@@ -188,55 +95,12 @@ tmpfs  /run  tmpfs  mode=0755,nosuid,nodev  0  0
 
 /// Create authentication configuration.
 fn create_auth_config(ctx: &BuildContext) -> Result<()> {
-    let etc = ctx.staging.join("etc");
-
     // /etc/securetty - allowed tty for root login
-    fs::write(
-        etc.join("securetty"),
-        r#"console
-tty1
-tty2
-tty3
-tty4
-tty5
-tty6
-ttyS0
-ttyS1
-"#,
-    )?;
+    templates::install(ctx, "etc/securetty")?;
 
     // /etc/shells - valid login shells
-    fs::write(
-        etc.join("shells"),
-        r#"/usr/bin/bash
-/bin/bash
-/usr/bin/sh
-/bin/sh
-"#,
-    )?;
-
-    // /etc/login.defs
-    fs::write(
-        etc.join("login.defs"),
-        r#"# Login configuration
-MAIL_DIR /var/spool/mail
-PASS_MAX_DAYS 99999
-PASS_MIN_DAYS 0
-PASS_WARN_AGE 7
-UID_MIN 1000
-UID_MAX 60000
-SYS_UID_MIN 201
-SYS_UID_MAX 999
-GID_MIN 1000
-GID_MAX 60000
-SYS_GID_MIN 201
-SYS_GID_MAX 999
-CREATE_HOME yes
-UMASK 022
-USERGROUPS_ENAB yes
-ENCRYPT_METHOD SHA512
-"#,
-    )?;
+    templates::install(ctx, "etc/shells")?;
+    templates::install(ctx, "etc/login.defs")?;
 
     Ok(())
 }
@@ -251,20 +115,9 @@ fn create_locale_config(ctx: &BuildContext) -> Result<()> {
         std::os::unix::fs::symlink("/usr/share/zoneinfo/UTC", &localtime)?;
     }
 
-    // /etc/adjtime
-    fs::write(
-        etc.join("adjtime"),
-        r#"0.0 0 0.0
-0
-UTC
-"#,
-    )?;
-
-    // /etc/locale.conf
-    fs::write(etc.join("locale.conf"), "LANG=C.UTF-8\n")?;
-
-    // /etc/vconsole.conf
-    fs::write(etc.join("vconsole.conf"), "KEYMAP=us\n")?;
+    templates::install(ctx, "etc/adjtime")?;
+    templates::install(ctx, "etc/locale.conf")?;
+    templates::install(ctx, "etc/vconsole.conf")?;
 
     Ok(())
 }
@@ -273,13 +126,7 @@ UTC
 fn create_network_config(ctx: &BuildContext) -> Result<()> {
     let etc = ctx.staging.join("etc");
 
-    // /etc/hosts
-    fs::write(
-        etc.join("hosts"),
-        r#"127.0.0.1   localhost localhost.localdomain
-::1         localhost localhost.localdomain ip6-localhost ip6-loopback
-"#,
-    )?;
+    templates::install(ctx, "etc/hosts")?;
 
     // /etc/resolv.conf (stub - systemd-resolved manages this)
     let resolv = etc.join("resolv.conf");
@@ -293,151 +140,208 @@ fn create_network_config(ctx: &BuildContext) -> Result<()> {
 
 /// Create shell configuration.
 fn create_shell_config(ctx: &BuildContext) -> Result<()> {
-    let etc = ctx.staging.join("etc");
+    templates::install(ctx, "etc/profile")?;
+    templates::install(ctx, "etc/bashrc")?;
+    templates::install(ctx, "root/.bashrc")?;
+    templates::install(ctx, "root/.bash_profile")?;
 
-    // /etc/profile
-    fs::write(
-        etc.join("profile"),
-        r#"# System-wide profile
-export PATH="/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
-export EDITOR="vi"
-export PAGER="less"
-
-# Source profile.d scripts
-for script in /etc/profile.d/*.sh; do
-    [ -r "$script" ] && . "$script"
-done
-unset script
-
-# Interactive shell settings
-if [ -n "$PS1" ]; then
-    PS1='[\u@\h \W]\$ '
-fi
-"#,
-    )?;
-
-    // /etc/bashrc
-    fs::write(
-        etc.join("bashrc"),
-        r#"# System-wide bashrc
-# Source global profile
-[ -f /etc/profile ] && . /etc/profile
-
-# Interactive shell
-if [ -n "$PS1" ]; then
-    # History settings
-    HISTSIZE=1000
-    HISTFILESIZE=2000
-    HISTCONTROL=ignoredups:erasedups
-
-    # Enable color ls
-    alias ls='ls --color=auto'
-    alias ll='ls -la'
-    alias l='ls -l'
-fi
-"#,
-    )?;
-
-    // /root/.bashrc
-    let root_home = ctx.staging.join("root");
-    fs::write(
-        root_home.join(".bashrc"),
-        r#"# Root bashrc
-[ -f /etc/bashrc ] && . /etc/bashrc
-export PS1='[\u@\h \W]# '
-"#,
-    )?;
-
-    // /root/.bash_profile
-    fs::write(
-        root_home.join(".bash_profile"),
-        r#"# Root bash_profile
-[ -f ~/.bashrc ] && . ~/.bashrc
-"#,
-    )?;
-
-    // /etc/skel/.bashrc (for new users)
-    fs::write(
-        etc.join("skel/.bashrc"),
-        r#"# User bashrc
-[ -f /etc/bashrc ] && . /etc/bashrc
-"#,
-    )?;
-
-    // /etc/skel/.bash_profile
-    fs::write(
-        etc.join("skel/.bash_profile"),
-        r#"# User bash_profile
-[ -f ~/.bashrc ] && . ~/.bashrc
-"#,
-    )?;
+    // For new users
+    templates::install(ctx, "etc/skel/.bashrc")?;
+    templates::install(ctx, "etc/skel/.bash_profile")?;
 
     Ok(())
 }
 
 /// Create nsswitch.conf for name service lookup.
 fn create_nsswitch(ctx: &BuildContext) -> Result<()> {
+    Ok(templates::install(ctx, "etc/nsswitch.conf")?)
+}
+
+/// Create /etc/sysconfig and /etc/default entries.
+///
+/// Rocky units and scripts we copy expect these to exist.
+fn create_sysconfig(ctx: &BuildContext) -> Result<()> {
     let etc = ctx.staging.join("etc");
 
-    fs::write(
-        etc.join("nsswitch.conf"),
-        r#"# Name Service Switch configuration
-passwd:     files systemd
-shadow:     files
-group:      files systemd
-hosts:      files resolve [!UNAVAIL=return] dns myhostname
-networks:   files
-protocols:  files
-services:   files
-ethers:     files
-rpc:        files
-"#,
-    )?;
+    for dir in [
+        "sysconfig",
+        "sysconfig/console",
+        "sysconfig/network-scripts",
+        "default",
+    ] {
+        fs::create_dir_all(etc.join(dir))?;
+    }
+
+    // /etc/sysconfig/network - network-scripts compatibility stub
+    templates::install(ctx, "etc/sysconfig/network")?;
+
+    // /etc/sysconfig/chronyd - referenced by chronyd.service
+    templates::install(ctx, "etc/sysconfig/chronyd")?;
+
+    // /etc/default/useradd - defaults for useradd
+    templates::install(ctx, "etc/default/useradd")?;
 
     Ok(())
 }
 
 /// Copy timezone data from source rootfs.
 pub fn copy_timezone_data(ctx: &BuildContext) -> Result<()> {
-    println!("Copying timezone data...");
+    detail!("Copying timezone data...");
 
-    let src = ctx.source.join("usr/share/zoneinfo");
-    let dst = ctx.staging.join("usr/share/zoneinfo");
-    fs::create_dir_all(&dst)?;
+    let zoneinfo = Path::new("usr/share/zoneinfo");
+    fs::create_dir_all(ctx.target(zoneinfo))?;
 
-    if src.exists() {
+    if ctx.package_source.find_file(zoneinfo).is_some() {
         // Copy essential zones only (full zoneinfo is large)
         let zones = ["UTC", "America", "Europe", "Asia", "Etc"];
         for zone in zones {
-            let zone_src = src.join(zone);
-            let zone_dst = dst.join(zone);
-            if zone_src.exists() {
+            if let Some(zone_src) = ctx.package_source.find_file(&zoneinfo.join(zone)) {
                 if zone_src.is_dir() {
-                    super::filesystem::copy_dir_recursive(&zone_src, &zone_dst)?;
+                    copy_donor_dir(ctx, zoneinfo.join(zone))?;
                 } else {
                     // Single file (like UTC)
-                    fs::copy(&zone_src, &zone_dst)?;
+                    copy_donor_file(ctx, zoneinfo.join(zone))?;
                 }
             }
         }
-        println!("  Copied timezone data");
+        detail!("  Copied timezone data");
     }
 
     Ok(())
 }
 
+/// Charmaps shipped for localedef.
+const CHARMAPS: &[&str] = &[
+    "UTF-8.gz",
+    "ISO-8859-1.gz",
+    "ISO-8859-15.gz",
+    "ANSI_X3.4-1968.gz",
+];
+
+/// Locale source files needed to compile the default locales with localedef.
+///
+/// iso14651_t1* carry the LC_COLLATE tables the other locales copy from.
+const LOCALE_SOURCES: &[&str] = &[
+    "C",
+    "POSIX",
+    "en_US",
+    "en_GB",
+    "i18n",
+    "i18n_ctype",
+    "iso14651_t1",
+    "iso14651_t1_common",
+    "translit_circle",
+    "translit_cjk_compat",
+    "translit_combining",
+    "translit_compat",
+    "translit_font",
+    "translit_fraction",
+    "translit_narrow",
+    "translit_neutral",
+    "translit_small",
+    "translit_wide",
+];
+
+/// gconv modules loaded by iconv for common conversions.
+const GCONV_MODULES: &[&str] = &[
+    "gconv-modules",
+    "gconv-modules.cache",
+    "ISO8859-1.so",
+    "ISO8859-15.so",
+    "UNICODE.so",
+    "UTF-16.so",
+    "UTF-32.so",
+    "UTF-7.so",
+    "CP1252.so",
+    "IBM850.so",
+    "ISO646.so",
+];
+
 /// Copy locales from source rootfs.
 pub fn copy_locales(ctx: &BuildContext) -> Result<()> {
-    println!("Copying locales...");
+    detail!("Copying locales...");
 
     // Copy locale-archive if it exists (compiled locales)
-    let archive_src = ctx.source.join("usr/lib/locale/locale-archive");
-    let archive_dst = ctx.staging.join("usr/lib/locale/locale-archive");
+    let archive = Path::new("usr/lib/locale/locale-archive");
+    if ctx.package_source.find_file(archive).is_some() {
+        copy_donor_file(ctx, "usr/lib/locale/locale-archive")?;
+        detail!("  Copied locale-archive");
+    }
+
+    // C.UTF-8 (our default LANG) is shipped outside the archive
+    if ctx
+        .package_source
+        .is_dir(Path::new("usr/lib/locale/C.utf8"))
+    {
+        copy_donor_dir(ctx, "usr/lib/locale/C.utf8")?;
+        if ctx.target("usr/lib/locale/C.utf8/LC_COLLATE").exists() {
+            detail!("  Copied C.utf8 (with LC_COLLATE)");
+        } else {
+            detail!("  Warning: C.utf8 has no LC_COLLATE, sorting falls back to C");
+            ctx.report.warn(
+                "locales",
+                Some("C.utf8"),
+                "no LC_COLLATE, sorting falls back to C",
+            );
+        }
+    } else {
+        detail!("  Warning: C.utf8 locale not found in source");
+        ctx.report
+            .warn("locales", Some("C.utf8"), "not found in source");
+    }
+
+    Ok(())
+}
+
+/// Copy charmaps, locale sources and gconv modules.
+///
+/// These let localedef compile additional locales (including their
+/// LC_COLLATE data) on the installed system and let iconv convert between
+/// common charsets.
+pub fn copy_i18n_data(ctx: &BuildContext) -> Result<()> {
+    detail!("Copying i18n data...");
+
+    let gconv = ctx.options.layout.relocate("usr/lib64/gconv");
+    let groups: [(&Path, &[&str]); 3] = [
+        (Path::new("usr/share/i18n/charmaps"), CHARMAPS),
+        (Path::new("usr/share/i18n/locales"), LOCALE_SOURCES),
+        (&gconv, GCONV_MODULES),
+    ];
+
+    for (dir, files) in groups {
+        if ctx.package_source.find_file(dir).is_none() {
+            detail!(
+                "  Warning: /{} not found in source, skipping",
+                dir.display()
+            );
+            ctx.report.skip(
+                "i18n",
+                Some(&format!("/{}", dir.display())),
+                "not found in source",
+            );
+            continue;
+        }
+        fs::create_dir_all(ctx.target(dir))?;
+
+        let mut copied = 0;
+        for file in files {
+            if ctx.package_source.find_file(&dir.join(file)).is_some() {
+                copy_donor_file(ctx, dir.join(file))?;
+                copied += 1;
+            }
+        }
+        detail!(
+            "  Copied {}/{} files to /{}",
+            copied,
+            files.len(),
+            dir.display()
+        );
+    }
 
-    if archive_src.exists() {
-        fs::create_dir_all(archive_dst.parent().unwrap())?;
-        fs::copy(&archive_src, &archive_dst)?;
-        println!("  Copied locale-archive");
+    // Modular gconv configuration (glibc >= 2.34)
+    let gconv_d = gconv.join("gconv-modules.d");
+    if ctx.package_source.is_dir(&gconv_d) {
+        copy_donor_dir(ctx, &gconv_d)?;
     }
 
     Ok(())
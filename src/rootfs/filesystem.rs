@@ -3,13 +3,36 @@
 //! Creates the full FHS directory structure needed for a disk-based
 //! installed system (more complete than the live initramfs).
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 
+use crate::arch::{self, LibraryLayout};
+use crate::archive::DeviceNode;
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+
+/// Device nodes written into the archive.
+///
+/// devtmpfs provides these at runtime, but recovery and chroot use of the
+/// installed disk needs them present on the root filesystem. They are
+/// recorded in the metadata layer so the build host never needs to mknod.
+pub const DEVICE_NODES: &[DeviceNode] = &[
+    DeviceNode::char("dev/null", 1, 3, 0o666),
+    DeviceNode::char("dev/zero", 1, 5, 0o666),
+    DeviceNode::char("dev/full", 1, 7, 0o666),
+    DeviceNode::char("dev/random", 1, 8, 0o666),
+    DeviceNode::char("dev/urandom", 1, 9, 0o666),
+    DeviceNode::char("dev/tty", 5, 0, 0o666),
+    DeviceNode::char("dev/console", 5, 1, 0o600),
+    DeviceNode::char("dev/ptmx", 5, 2, 0o666),
+];
+
 /// Create full FHS directory structure for installed system.
 pub fn create_fhs_structure(staging: &Path) -> Result<()> {
-    println!("Creating FHS directory structure...");
+    detail!("Creating FHS directory structure...");
 
     let dirs = [
         // Essential directories
@@ -54,6 +77,8 @@ pub fn create_fhs_structure(staging: &Path) -> Result<()> {
         "var/cache",
         "var/lib",
         "var/spool",
+        "var/spool/mail",
+        "var/spool/cron",
         // Mount points
         "mnt",
         "media",
@@ -86,13 +111,68 @@ pub fn create_fhs_structure(staging: &Path) -> Result<()> {
             .with_context(|| format!("Failed to create directory: {}", dir))?;
     }
 
-    println!("  Created {} directories", dirs.len());
+    detail!("  Created {} directories", dirs.len());
+    Ok(())
+}
+
+/// Record the device nodes in the metadata layer.
+pub fn create_device_nodes(ctx: &BuildContext) -> Result<()> {
+    detail!("Recording device nodes...");
+
+    for node in DEVICE_NODES {
+        ctx.metadata.mknod(node.clone());
+    }
+
+    detail!("  Recorded {} device nodes", DEVICE_NODES.len());
+    Ok(())
+}
+
+/// Set up mail and cron spool directories.
+///
+/// login.defs points MAIL_DIR at /var/spool/mail, so it has to exist. The
+/// mail group is recorded in the metadata layer and the tmpfiles entry
+/// recreates the directories if /var is wiped.
+pub fn create_spool_dirs(ctx: &BuildContext) -> Result<()> {
+    detail!("Setting up spool directories...");
+
+    let staging = &ctx.staging;
+
+    let spool_modes = [("var/spool/mail", 0o775), ("var/spool/cron", 0o700)];
+    for (dir, mode) in spool_modes {
+        let path = staging.join(dir);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create directory: {}", dir))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions: {}", dir))?;
+    }
+
+    ctx.metadata.chown("var/spool/mail", 0, 8); // mail
+
+    // /var/mail -> spool/mail
+    let var_mail = staging.join("var/mail");
+    if !var_mail.exists() && !var_mail.is_symlink() {
+        std::os::unix::fs::symlink("spool/mail", &var_mail)
+            .context("Failed to create /var/mail symlink")?;
+    }
+
+    let tmpfiles_dir = staging.join("usr/lib/tmpfiles.d");
+    fs::create_dir_all(&tmpfiles_dir)?;
+    fs::write(
+        tmpfiles_dir.join("levitate-spool.conf"),
+        r#"# Mail and cron spool directories
+d /var/spool/mail 0775 root mail -
+d /var/spool/cron 0700 root root -
+L /var/mail - - - - spool/mail
+"#,
+    )?;
+
+    detail!("  Created /var/spool/mail and /var/spool/cron");
     Ok(())
 }
 
 /// Create essential symlinks for merged /usr.
 pub fn create_symlinks(staging: &Path) -> Result<()> {
-    println!("Creating symlinks...");
+    detail!("Creating symlinks...");
 
     // /var/run -> /run
     let var_run = staging.join("var/run");
@@ -154,30 +234,24 @@ pub fn create_symlinks(staging: &Path) -> Result<()> {
         std::os::unix::fs::symlink("bash", &sh_link).context("Failed to create /usr/bin/sh symlink")?;
     }
 
-    println!("  Created essential symlinks");
+    detail!("  Created essential symlinks");
     Ok(())
 }
 
-/// Copy a directory recursively.
-pub fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
-    fs::create_dir_all(dst)?;
-
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let path = entry.path();
-        let dest_path = dst.join(entry.file_name());
-
-        if path.is_dir() {
-            copy_dir_recursive(&path, &dest_path)?;
-        } else if path.is_symlink() {
-            let target = fs::read_link(&path)?;
-            if !dest_path.exists() {
-                std::os::unix::fs::symlink(&target, &dest_path)?;
-            }
-        } else {
-            fs::copy(&path, &dest_path)?;
-        }
+/// Link the ABI subdirectory the target's loader searches back to lib64
+/// (/usr/lib64/lp64d -> . on riscv64), where libraries are staged.
+pub fn create_abi_links(ctx: &BuildContext) -> Result<()> {
+    let Some(abi) = arch::abi_subdir(&ctx.options.arch) else {
+        return Ok(());
+    };
+    if ctx.options.layout != LibraryLayout::Lib64 {
+        return Ok(());
     }
-
+    let abi_link = ctx.staging.join("usr/lib64").join(abi);
+    if !abi_link.exists() && !abi_link.is_symlink() {
+        std::os::unix::fs::symlink(".", &abi_link)
+            .with_context(|| format!("Failed to create /usr/lib64/{} symlink", abi))?;
+    }
+    detail!("  Linked /usr/lib64/{} to /usr/lib64", abi);
     Ok(())
 }
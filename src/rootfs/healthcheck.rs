@@ -0,0 +1,65 @@
+//! Boot health check.
+//!
+//! `--healthcheck` ships `levitate-healthcheck.service`, a oneshot run once
+//! per boot that checks the critical units are active, the filesystems
+//! have space left, the clock is synchronized and DNS resolves. Results
+//! go to the journal and to a JSON status file fleet tooling can collect:
+//!
+//! ```json
+//! {"time": "...", "boot_id": "...", "passed": false, "failed": 1,
+//!  "checks": [{"check": "unit", "subject": "chronyd.service",
+//!              "status": "fail", "message": "inactive"}, ...]}
+//! ```
+//!
+//! The service fails when any check does, so `systemctl --failed` shows it
+//! too. The script, unit and /etc/levitate/healthcheck.conf are templates;
+//! the config can also be changed on the installed system.
+
+use std::fs;
+
+use crate::binary::{copy_binary_with_libs, make_executable};
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+use crate::templates;
+
+pub const HEALTHCHECK_SERVICE: &str = "levitate-healthcheck.service";
+
+/// Where the check script is installed.
+pub const HEALTHCHECK_SCRIPT: &str = "usr/lib/levitate/healthcheck";
+
+/// Settings read by the check script.
+pub const HEALTHCHECK_CONFIG: &str = "etc/levitate/healthcheck.conf";
+
+/// Status file the check script writes on the running system.
+pub const STATUS_FILE: &str = "/run/levitate/healthcheck.json";
+
+/// Install the health check script, its config and the enabled service.
+pub fn install_healthcheck(ctx: &BuildContext) -> Result<()> {
+    detail!("Installing boot health check...");
+
+    templates::install(ctx, HEALTHCHECK_SCRIPT)?;
+    make_executable(&ctx.staging.join(HEALTHCHECK_SCRIPT))?;
+    templates::install(ctx, HEALTHCHECK_CONFIG)?;
+    let unit = format!("usr/lib/systemd/system/{}", HEALTHCHECK_SERVICE);
+    templates::install(ctx, &unit)?;
+
+    // For the DNS check; skipped on the system without it
+    copy_binary_with_libs(ctx, "getent", "usr/bin")?;
+
+    let wants = ctx
+        .staging
+        .join("etc/systemd/system/multi-user.target.wants");
+    fs::create_dir_all(&wants)?;
+    let link = wants.join(HEALTHCHECK_SERVICE);
+    if !link.is_symlink() {
+        std::os::unix::fs::symlink(format!("/{}", unit), &link)?;
+    }
+
+    detail!(
+        "  Enabled {} (status in {})",
+        HEALTHCHECK_SERVICE,
+        STATUS_FILE
+    );
+    Ok(())
+}
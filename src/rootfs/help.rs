@@ -0,0 +1,231 @@
+//! Offline help bundle.
+//!
+//! Air-gapped machines can't look anything up, so `--offline-help` ships a
+//! few short pages in /usr/share/levitate/help: a quickstart, recovery
+//! steps and recipe usage. The pages are markdown (`help/` in this
+//! repository, embedded in the binary) rendered to plain text at build
+//! time, since the rootfs has no markdown viewer. `--help-pages DIR`
+//! replaces built-in pages with `DIR/<name>.md` and adds new ones.
+//!
+//! `levitate-help` lists the pages and `levitate-help NAME` shows one.
+
+use anyhow::Context;
+use std::fs;
+use std::path::Path;
+
+use crate::binary::make_executable;
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::{Result, Stage3Error};
+
+/// Where the rendered pages are installed.
+pub const HELP_DIR: &str = "usr/share/levitate/help";
+
+/// Command showing the pages.
+pub const HELP_COMMAND: &str = "usr/bin/levitate-help";
+
+macro_rules! builtin {
+    ($name:literal) => {
+        ($name, include_str!(concat!("../../help/", $name, ".md")))
+    };
+}
+
+/// Built-in pages by name, in index order.
+pub const BUILTIN_PAGES: &[(&str, &str)] = &[
+    builtin!("quickstart"),
+    builtin!("recovery"),
+    builtin!("recipe"),
+];
+
+const HELP_COMMAND_CONTENT: &str = r#"#!/bin/sh
+# Show the offline help pages installed by the stage3 build
+dir=/usr/share/levitate/help
+if [ $# -eq 0 ]; then
+    exec cat "$dir/index.txt"
+fi
+page="$dir/$1.txt"
+if [ ! -f "$page" ]; then
+    echo "levitate-help: no page named $1; run levitate-help for the list" >&2
+    exit 1
+fi
+if [ -t 1 ] && command -v less >/dev/null 2>&1; then
+    exec less "$page"
+fi
+exec cat "$page"
+"#;
+
+/// A help page, still markdown.
+#[derive(Debug, Clone)]
+pub struct HelpPage {
+    pub name: String,
+    pub markdown: String,
+}
+
+impl HelpPage {
+    /// The first heading, or the name without one.
+    pub fn title(&self) -> &str {
+        self.markdown
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map(str::trim)
+            .unwrap_or(&self.name)
+    }
+}
+
+/// The built-in pages, replaced or extended by the `*.md` files in `dir`.
+pub fn load_pages(dir: Option<&Path>) -> Result<Vec<HelpPage>> {
+    let mut pages: Vec<HelpPage> = BUILTIN_PAGES
+        .iter()
+        .map(|(name, markdown)| HelpPage {
+            name: name.to_string(),
+            markdown: markdown.to_string(),
+        })
+        .collect();
+
+    let Some(dir) = dir else {
+        return Ok(pages);
+    };
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Help page directory not found: {}", dir.display()))?;
+    let mut extra = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "md") {
+            continue;
+        }
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid || name == "index" {
+            return Err(Stage3Error::InvalidInput {
+                message: format!(
+                    "{}: help page names are lowercase letters, digits and dashes (and not index)",
+                    path.display()
+                ),
+                path,
+            });
+        }
+        let markdown = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        match pages.iter_mut().find(|page| page.name == name) {
+            Some(page) => page.markdown = markdown,
+            None => extra.push(HelpPage { name, markdown }),
+        }
+    }
+    extra.sort_by(|a, b| a.name.cmp(&b.name));
+    pages.extend(extra);
+    Ok(pages)
+}
+
+/// Render the pages into the rootfs with an index and `levitate-help`.
+pub fn install_help(ctx: &BuildContext, pages: &[HelpPage]) -> Result<()> {
+    detail!("Installing offline help...");
+
+    let dir = ctx.staging.join(HELP_DIR);
+    fs::create_dir_all(&dir)?;
+    let width = pages.iter().map(|page| page.name.len()).max().unwrap_or(0);
+    let mut index = String::from("Offline help pages; read one with levitate-help NAME\n\n");
+    for page in pages {
+        fs::write(
+            dir.join(format!("{}.txt", page.name)),
+            markdown_to_text(&page.markdown),
+        )?;
+        index.push_str(&format!("  {:<width$}  {}\n", page.name, page.title()));
+    }
+    fs::write(dir.join("index.txt"), index)?;
+
+    let command = ctx.staging.join(HELP_COMMAND);
+    fs::create_dir_all(command.parent().unwrap())?;
+    fs::write(&command, HELP_COMMAND_CONTENT)?;
+    make_executable(&command)?;
+
+    detail!("  Installed {} pages in /{}", pages.len(), HELP_DIR);
+    Ok(())
+}
+
+/// Render markdown to plain text for a console.
+///
+/// Headings are underlined, code blocks indented, and inline markup
+/// (code spans, bold, links) reduced to its text; links keep their target
+/// in parentheses. Everything else passes through unchanged.
+pub fn markdown_to_text(markdown: &str) -> String {
+    let mut out = String::new();
+    let mut in_code = false;
+    for line in markdown.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            out.push_str(&format!("    {}\n", line));
+            continue;
+        }
+
+        let level = line.chars().take_while(|&c| c == '#').count();
+        if level > 0 && line[level..].starts_with(' ') {
+            let text = inline_text(line[level..].trim());
+            let underline = match level {
+                1 => Some('='),
+                2 => Some('-'),
+                _ => None,
+            };
+            out.push_str(&text);
+            out.push('\n');
+            if let Some(c) = underline {
+                out.push_str(&c.to_string().repeat(text.chars().count()));
+                out.push('\n');
+            }
+            continue;
+        }
+        out.push_str(&inline_text(line));
+        out.push('\n');
+    }
+    out
+}
+
+/// Strip inline markup from one line.
+fn inline_text(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        // Code spans are taken literally
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                out.push_str(&rest[1..end + 1]);
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        if rest.starts_with("**") || rest.starts_with("__") {
+            rest = &rest[2..];
+            continue;
+        }
+        if c == '[' {
+            if let Some(link) = parse_link(rest) {
+                let (text, target, len) = link;
+                out.push_str(&inline_text(text));
+                if target != text {
+                    out.push_str(&format!(" ({})", target));
+                }
+                rest = &rest[len..];
+                continue;
+            }
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// `[text](target)` at the start of `s`: text, target and length.
+fn parse_link(s: &str) -> Option<(&str, &str, usize)> {
+    let close = s.find("](")?;
+    let end = s[close + 2..].find(')')? + close + 2;
+    Some((&s[1..close], &s[close + 2..end], end + 1))
+}
@@ -0,0 +1,255 @@
+//! Appliance lockdown.
+//!
+//! Kiosks and appliances ship without a console login: every getty is
+//! disabled, the rescue and emergency shells are masked and root's
+//! password is locked, so the only way in is SSH with a key. The OpenSSH
+//! server comes from the donor, configured to refuse passwords.
+//! `validate::access` makes sure the result still has a way in.
+
+use anyhow::Context;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::binary::{copy_binary_with_libs, copy_sbin_binary_with_libs};
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::{Result, Stage3Error};
+use crate::remap::{copy_donor_dir, copy_donor_file};
+use crate::templates;
+
+/// Where `--authorized-keys` are installed.
+pub const ROOT_AUTHORIZED_KEYS: &str = "root/.ssh/authorized_keys";
+
+/// sshd's helpers (sshd-session, sshd-keygen, sftp-server, ...).
+const SSH_LIBEXEC: &str = "usr/libexec/openssh";
+
+/// Donor configuration copied when present.
+const SSH_CONFIG: &[&str] = &[
+    "etc/ssh/sshd_config",
+    "etc/ssh/moduli",
+    "etc/sysconfig/sshd",
+];
+
+/// Drop-in directory holding the lockdown settings.
+const SSH_DROP_INS: &str = "etc/ssh/sshd_config.d";
+
+/// Units copied when present.
+const SSH_UNITS: &[&str] = &[
+    "sshd.service",
+    "sshd@.service",
+    "sshd.socket",
+    "sshd-keygen@.service",
+    "sshd-keygen.target",
+];
+
+/// sshd's privilege separation account.
+const SSHD_USER: (&str, &str, &str, &str) = (
+    "sshd:x:74:74:Privilege-separated SSH:/usr/share/empty.sshd:/usr/sbin/nologin\n",
+    "sshd:!*:19000::::::\n",
+    "sshd:x:74:\n",
+    "sshd:!::\n",
+);
+
+/// Console login units masked so nothing can start them.
+const GETTY_UNITS: &[&str] = &[
+    "getty@.service",
+    "serial-getty@.service",
+    "console-getty.service",
+    "container-getty@.service",
+    "autovt@.service",
+    "getty.target",
+];
+
+/// Shells systemd starts without a login.
+const SHELL_UNITS: &[&str] = &[
+    "rescue.service",
+    "rescue.target",
+    "emergency.service",
+    "emergency.target",
+    "debug-shell.service",
+];
+
+/// Check `--authorized-keys` before the build starts.
+pub fn read_authorized_keys(path: &Path) -> Result<String> {
+    let keys = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read authorized keys {}", path.display()))?;
+    let mut count = 0;
+    for (number, line) in keys.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.split_whitespace().any(is_key_type) {
+            return Err(Stage3Error::InvalidInput {
+                path: path.to_path_buf(),
+                message: format!("{}:{} is not an SSH public key", path.display(), number + 1),
+            });
+        }
+        count += 1;
+    }
+    if count == 0 {
+        return Err(Stage3Error::InvalidInput {
+            path: path.to_path_buf(),
+            message: format!("{} contains no SSH public keys", path.display()),
+        });
+    }
+    Ok(keys)
+}
+
+/// Whether a field names a public key algorithm (`ssh-ed25519`, ...).
+fn is_key_type(field: &str) -> bool {
+    ["ssh-", "ecdsa-sha2-", "sk-ssh-", "sk-ecdsa-"]
+        .iter()
+        .any(|prefix| field.starts_with(prefix))
+}
+
+/// Lock the image down to key-only SSH.
+pub fn apply_lockdown(ctx: &BuildContext, authorized_keys: Option<&Path>) -> Result<()> {
+    detail!("Applying appliance lockdown...");
+
+    install_ssh_server(ctx)?;
+    disable_console_logins(ctx)?;
+    lock_root(ctx)?;
+    if let Some(keys) = authorized_keys {
+        install_authorized_keys(ctx, &read_authorized_keys(keys)?)?;
+    }
+
+    Ok(())
+}
+
+/// Copy the OpenSSH server, restrict it to keys and enable it.
+fn install_ssh_server(ctx: &BuildContext) -> Result<()> {
+    copy_sbin_binary_with_libs(ctx, "sshd")?;
+    copy_binary_with_libs(ctx, "ssh-keygen", "usr/bin")?;
+    if ctx.package_source.is_dir(Path::new(SSH_LIBEXEC)) {
+        copy_donor_dir(ctx, SSH_LIBEXEC)?;
+    }
+    for file in SSH_CONFIG {
+        if ctx.package_source.is_file(Path::new(file)) {
+            copy_donor_file(ctx, file)?;
+        }
+    }
+    include_drop_ins(ctx)?;
+    templates::install(ctx, "etc/ssh/sshd_config.d/01-levitate-lockdown.conf")?;
+    templates::install(ctx, "etc/pam.d/sshd")?;
+
+    let (passwd, shadow, group, gshadow) = SSHD_USER;
+    for (file, line) in [
+        ("etc/passwd", passwd),
+        ("etc/shadow", shadow),
+        ("etc/group", group),
+        ("etc/gshadow", gshadow),
+    ] {
+        let path = ctx.staging.join(file);
+        let mut contents = fs::read_to_string(&path)?;
+        if !contents.lines().any(|l| l.starts_with("sshd:")) {
+            contents.push_str(line);
+            fs::write(&path, contents)?;
+        }
+    }
+    let empty = ctx.staging.join("usr/share/empty.sshd");
+    fs::create_dir_all(&empty)?;
+    fs::set_permissions(&empty, fs::Permissions::from_mode(0o711))?;
+
+    let unit_dir = Path::new("usr/lib/systemd/system");
+    for unit in SSH_UNITS {
+        if ctx.package_source.find_file(&unit_dir.join(unit)).is_some() {
+            copy_donor_file(ctx, unit_dir.join(unit))?;
+        }
+    }
+    let wants = ctx
+        .staging
+        .join("etc/systemd/system/multi-user.target.wants");
+    fs::create_dir_all(&wants)?;
+    let link = wants.join("sshd.service");
+    if ctx.target(unit_dir.join("sshd.service")).exists() && !link.is_symlink() {
+        std::os::unix::fs::symlink("/usr/lib/systemd/system/sshd.service", &link)?;
+        detail!("  Enabled sshd.service");
+    }
+
+    Ok(())
+}
+
+/// Make sure sshd_config reads the drop-in directory before anything else.
+///
+/// sshd takes the first value of each option, so the include has to come
+/// first for the lockdown drop-in to win over the donor's settings.
+fn include_drop_ins(ctx: &BuildContext) -> Result<()> {
+    let path = ctx.staging.join("etc/ssh/sshd_config");
+    let config = match path.exists() {
+        true => fs::read_to_string(&path)?,
+        false => String::new(),
+    };
+    let first = config
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'));
+    if first.is_some_and(|line| line.starts_with("Include") && line.contains(SSH_DROP_INS)) {
+        return Ok(());
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(
+        &path,
+        format!("Include /{}/*.conf\n\n{}", SSH_DROP_INS, config),
+    )?;
+    Ok(())
+}
+
+/// Disable every getty and mask the units that would give a shell.
+fn disable_console_logins(ctx: &BuildContext) -> Result<()> {
+    let system = ctx.staging.join("etc/systemd/system");
+
+    let getty_wants = system.join("getty.target.wants");
+    if getty_wants.is_dir() {
+        fs::remove_dir_all(&getty_wants)?;
+    }
+    let getty_target = system.join("multi-user.target.wants/getty.target");
+    if getty_target.is_symlink() {
+        fs::remove_file(&getty_target)?;
+    }
+
+    for unit in GETTY_UNITS.iter().chain(SHELL_UNITS) {
+        let link = system.join(unit);
+        if link.exists() || link.is_symlink() {
+            fs::remove_file(&link)?;
+        }
+        std::os::unix::fs::symlink("/dev/null", &link)?;
+    }
+    detail!(
+        "  Masked {} console and shell units",
+        GETTY_UNITS.len() + SHELL_UNITS.len()
+    );
+    Ok(())
+}
+
+/// Lock root's password so only a key can log it in.
+fn lock_root(ctx: &BuildContext) -> Result<()> {
+    let path = ctx.staging.join("etc/shadow");
+    let shadow = fs::read_to_string(&path)?;
+    let locked: String = shadow
+        .lines()
+        .map(|line| match line.split_once(':') {
+            Some(("root", rest)) => {
+                let (_, after) = rest.split_once(':').unwrap_or((rest, ""));
+                format!("root:!*:{}\n", after)
+            }
+            _ => format!("{}\n", line),
+        })
+        .collect();
+    fs::write(&path, locked)?;
+    detail!("  Locked the root password");
+    Ok(())
+}
+
+/// Install root's authorized keys with the modes sshd insists on.
+fn install_authorized_keys(ctx: &BuildContext, keys: &str) -> Result<()> {
+    let path = ctx.staging.join(ROOT_AUTHORIZED_KEYS);
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    fs::write(&path, keys)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    detail!("  Installed /{}", ROOT_AUTHORIZED_KEYS);
+    Ok(())
+}
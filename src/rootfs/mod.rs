@@ -3,9 +3,16 @@
 //! This module contains all the components needed to build a complete
 //! installed system rootfs for LevitateOS.
 
+pub mod accessibility;
 pub mod binaries;
 pub mod etc;
 pub mod filesystem;
+pub mod healthcheck;
+pub mod help;
+pub mod lockdown;
 pub mod pam;
 pub mod recipe;
+pub mod rescue;
+pub mod sanitize;
 pub mod systemd;
+pub mod user_services;
@@ -1,16 +1,21 @@
 //! Recipe package manager integration.
 //!
-//! Copies the recipe binary into the stage3 tarball.
+//! Copies the recipe binary into the stage3 tarball and optionally sets up
+//! unattended upgrades.
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use std::fs;
+use std::str::FromStr;
 
 use crate::binary::make_executable;
 use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+use crate::reflink;
 
 /// Copy recipe binary to the stage3.
 pub fn copy_recipe(ctx: &BuildContext) -> Result<()> {
-    println!("Copying recipe package manager...");
+    detail!("Copying recipe package manager...");
 
     // Check if recipe binary path is configured
     let recipe_path = match &ctx.recipe_binary {
@@ -21,30 +26,39 @@ pub fn copy_recipe(ctx: &BuildContext) -> Result<()> {
             if default_path.exists() {
                 default_path
             } else {
-                println!("  Warning: recipe binary not found, skipping");
+                detail!("  Warning: recipe binary not found, skipping");
+                ctx.report.skip("recipe", None, "recipe binary not found");
                 return Ok(());
             }
         }
     };
 
     if !recipe_path.exists() {
-        println!("  Warning: recipe binary not found at {:?}, skipping", recipe_path);
+        detail!(
+            "  Warning: recipe binary not found at {:?}, skipping",
+            recipe_path
+        );
+        ctx.report.skip(
+            "recipe",
+            None,
+            format!("recipe binary not found at {}", recipe_path.display()),
+        );
         return Ok(());
     }
 
     // Copy to /usr/bin/recipe
     let dest = ctx.staging.join("usr/bin/recipe");
-    fs::copy(&recipe_path, &dest)
+    reflink::copy(&recipe_path, &dest)
         .with_context(|| format!("Failed to copy recipe from {:?}", recipe_path))?;
     make_executable(&dest)?;
 
-    println!("  Copied recipe to /usr/bin/recipe");
+    detail!("  Copied recipe to /usr/bin/recipe");
     Ok(())
 }
 
 /// Create recipe configuration directory.
 pub fn setup_recipe_config(ctx: &BuildContext) -> Result<()> {
-    println!("Setting up recipe configuration...");
+    detail!("Setting up recipe configuration...");
 
     // Create recipe directories
     let recipe_dirs = [
@@ -73,6 +87,124 @@ db_dir = "/var/lib/recipe"
 "#,
     )?;
 
-    println!("  Created recipe configuration");
+    detail!("  Created recipe configuration");
+    Ok(())
+}
+
+/// What to do after an unattended upgrade succeeds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RebootPolicy {
+    /// Leave the system running; updates apply on the next manual reboot
+    #[default]
+    Never,
+    /// Reboot right after a successful upgrade
+    Always,
+}
+
+impl FromStr for RebootPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "always" => Ok(Self::Always),
+            other => Err(format!(
+                "unknown reboot policy {:?} (expected never or always)",
+                other
+            )),
+        }
+    }
+}
+
+/// Unattended upgrade settings.
+#[derive(Debug, Clone)]
+pub struct UpgradeTimer {
+    /// systemd calendar expression for `OnCalendar=`
+    pub schedule: String,
+    pub reboot: RebootPolicy,
+}
+
+impl UpgradeTimer {
+    pub fn new(schedule: impl Into<String>, reboot: RebootPolicy) -> Result<Self> {
+        let schedule = schedule.into();
+        // The schedule is pasted into the unit verbatim
+        if schedule.trim().is_empty() || schedule.contains(['\n', '\r']) {
+            return Err(anyhow::anyhow!("Invalid upgrade schedule {:?}", schedule).into());
+        }
+        Ok(Self { schedule, reboot })
+    }
+}
+
+const UPGRADE_SERVICE: &str = "recipe-upgrade.service";
+const UPGRADE_TIMER: &str = "recipe-upgrade.timer";
+
+/// Generate and enable the `recipe-upgrade` service and timer.
+///
+/// Does nothing unless an upgrade timer was requested, and skips it if the
+/// recipe binary wasn't shipped.
+pub fn setup_upgrade_timer(ctx: &BuildContext) -> Result<()> {
+    let Some(ref upgrade) = ctx.upgrade_timer else {
+        return Ok(());
+    };
+    detail!("Setting up unattended upgrades...");
+
+    if !ctx.staging.join("usr/bin/recipe").exists() {
+        detail!("  Warning: recipe not shipped, skipping upgrade timer");
+        ctx.report.skip("upgrade-timer", None, "recipe not shipped");
+        return Ok(());
+    }
+
+    let reboot = match upgrade.reboot {
+        RebootPolicy::Never => "",
+        RebootPolicy::Always => "ExecStartPost=/usr/bin/systemctl --no-block reboot\n",
+    };
+    let service = format!(
+        r#"[Unit]
+Description=Unattended recipe upgrade
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/recipe upgrade
+{}"#,
+        reboot
+    );
+    let timer = format!(
+        r#"[Unit]
+Description=Periodic unattended recipe upgrade
+
+[Timer]
+OnCalendar={}
+RandomizedDelaySec=1h
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"#,
+        upgrade.schedule
+    );
+
+    let unit_dir = ctx.staging.join("usr/lib/systemd/system");
+    fs::create_dir_all(&unit_dir)?;
+    fs::write(unit_dir.join(UPGRADE_SERVICE), service)?;
+    fs::write(unit_dir.join(UPGRADE_TIMER), timer)?;
+
+    let timers_wants = ctx.staging.join("etc/systemd/system/timers.target.wants");
+    fs::create_dir_all(&timers_wants)?;
+    let link = timers_wants.join(UPGRADE_TIMER);
+    if !link.is_symlink() {
+        std::os::unix::fs::symlink(format!("/usr/lib/systemd/system/{}", UPGRADE_TIMER), &link)?;
+    }
+
+    detail!(
+        "  Enabled {} ({}{})",
+        UPGRADE_TIMER,
+        upgrade.schedule,
+        match upgrade.reboot {
+            RebootPolicy::Never => "",
+            RebootPolicy::Always => ", reboots after upgrading",
+        }
+    );
     Ok(())
 }
@@ -0,0 +1,109 @@
+//! Static recovery shell.
+//!
+//! Everything else in the rootfs depends on the dynamic loader and shared
+//! libraries. A statically linked busybox at /usr/bin/busybox.static keeps
+//! the system recoverable when those break:
+//!
+//! - `systemd.unit=static-rescue.target` when systemd still starts but
+//!   bash, sulogin or their libraries don't
+//! - `init=/usr/bin/busybox.static sh` when nothing dynamic runs at all
+//!
+//! Like `init=`, the static rescue shell asks for no password; anyone who
+//! can edit the kernel command line already has that access.
+
+use anyhow::Context;
+use std::fs;
+use std::path::Path;
+
+use crate::binary::{elf_arch_from_header, make_executable};
+use crate::context::BuildContext;
+use crate::detail;
+use crate::elf;
+use crate::error::{Result, Stage3Error};
+
+/// Where the static busybox is installed.
+pub const BUSYBOX_STATIC: &str = "usr/bin/busybox.static";
+
+const RESCUE_SERVICE: &str = "static-rescue.service";
+const RESCUE_TARGET: &str = "static-rescue.target";
+
+const RESCUE_SERVICE_CONTENT: &str = r#"[Unit]
+Description=Static Rescue Shell
+Documentation=man:busybox(1)
+DefaultDependencies=no
+Conflicts=shutdown.target
+Before=shutdown.target
+
+[Service]
+Environment=HOME=/root
+WorkingDirectory=-/root
+ExecStart=/usr/bin/busybox.static sh
+Type=idle
+StandardInput=tty-force
+StandardOutput=inherit
+StandardError=inherit
+KillMode=process
+IgnoreSIGPIPE=no
+SendSIGHUP=yes
+"#;
+
+const RESCUE_TARGET_CONTENT: &str = r#"[Unit]
+Description=Static Rescue Mode
+Requires=static-rescue.service
+After=static-rescue.service
+AllowIsolate=yes
+"#;
+
+/// Read `busybox`, failing unless it is a statically linked ELF binary
+/// for `arch`.
+///
+/// A dynamically linked busybox would break along with everything else.
+pub fn read_static_busybox(busybox: &Path, arch: &str) -> Result<Vec<u8>> {
+    let bytes =
+        fs::read(busybox).with_context(|| format!("Failed to read {}", busybox.display()))?;
+    if !elf::is_elf(&bytes) {
+        return Err(Stage3Error::InvalidInput {
+            path: busybox.to_path_buf(),
+            message: format!("{} is not an ELF binary", busybox.display()),
+        });
+    }
+    match elf_arch_from_header(&bytes) {
+        Some(found) if found != arch => {
+            return Err(Stage3Error::InvalidInput {
+                path: busybox.to_path_buf(),
+                message: format!("{} is {}, not {}", busybox.display(), found, arch),
+            })
+        }
+        _ => {}
+    }
+    if let Some(info) = elf::dynamic_info(&bytes) {
+        if info.interpreter.is_some() || !info.needed.is_empty() {
+            return Err(Stage3Error::InvalidInput {
+                path: busybox.to_path_buf(),
+                message: format!(
+                    "{} is dynamically linked; the rescue busybox must be static",
+                    busybox.display()
+                ),
+            });
+        }
+    }
+    Ok(bytes)
+}
+
+/// Install a static busybox and the `static-rescue` target using it.
+pub fn install_static_busybox(ctx: &BuildContext, busybox: &Path) -> Result<()> {
+    detail!("Installing static rescue busybox...");
+
+    let bytes = read_static_busybox(busybox, &ctx.options.arch)?;
+    let dest = ctx.staging.join(BUSYBOX_STATIC);
+    fs::write(&dest, &bytes)?;
+    make_executable(&dest)?;
+
+    let unit_dir = ctx.staging.join("usr/lib/systemd/system");
+    fs::create_dir_all(&unit_dir)?;
+    fs::write(unit_dir.join(RESCUE_SERVICE), RESCUE_SERVICE_CONTENT)?;
+    fs::write(unit_dir.join(RESCUE_TARGET), RESCUE_TARGET_CONTENT)?;
+
+    detail!("  Installed /{} and {}", BUSYBOX_STATIC, RESCUE_TARGET);
+    Ok(())
+}
@@ -0,0 +1,289 @@
+//! Donor-distro branding and cruft cleanup.
+//!
+//! The rootfs is assembled from a Rocky donor, and some steps copy whole
+//! directories. This pass removes donor identity and package manager state
+//! that leaked in and rewrites identity files to LevitateOS.
+
+use anyhow::Context;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs;
+use walkdir::WalkDir;
+
+use crate::artifact::OS_VERSION;
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+
+/// Donor leftovers removed by default (globs relative to the rootfs root).
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    // Release and branding files
+    "etc/redhat-release",
+    "etc/rocky-release",
+    "etc/rocky-release-upstream",
+    "etc/centos-release",
+    "etc/system-release",
+    "etc/system-release-cpe",
+    "usr/share/redhat-release/**",
+    "usr/share/doc/rocky-release/**",
+    "usr/share/rocky-release/**",
+    // dnf/yum configuration and state
+    "etc/dnf/**",
+    "etc/yum.conf",
+    "etc/yum/**",
+    "etc/yum.repos.d/**",
+    "var/lib/dnf/**",
+    "var/cache/dnf/**",
+    "var/log/dnf*",
+    // rpm database
+    "var/lib/rpm/**",
+    "usr/lib/sysimage/rpm/**",
+    "etc/rpm/**",
+    // Vendor signing keys
+    "etc/pki/rpm-gpg/**",
+];
+
+/// Paths that must never ship, whatever copied them in.
+///
+/// Kernel headers, kernel sources and toolchain sysroots are large and
+/// build-only. Unlike [`DEFAULT_PATTERNS`] these can't be kept with
+/// `--sanitize-keep`. `*` doesn't cross `/`.
+pub const EXCLUDED_PATHS: &[&str] = &[
+    // Kernel and package sources
+    "usr/src",
+    "usr/lib/modules/*/build",
+    "usr/lib/modules/*/source",
+    // Kernel headers (kernel-headers package)
+    "usr/include/asm",
+    "usr/include/asm-generic",
+    "usr/include/drm",
+    "usr/include/linux",
+    "usr/include/misc",
+    "usr/include/mtd",
+    "usr/include/rdma",
+    "usr/include/sound",
+    "usr/include/video",
+    "usr/include/xen",
+    // Cross toolchain sysroots (/usr/<triplet>/sys-root) and those of
+    // toolchains installed under a prefix of their own
+    "usr/*-linux-gnu*",
+    "usr/*/sys-root",
+    "usr/*/sysroot",
+    "usr/local/*/sys-root",
+    "usr/local/*/sysroot",
+    "opt/*/sys-root",
+    "opt/*/sysroot",
+];
+
+/// Identity files rewritten to LevitateOS when present.
+const ISSUE_FILES: &[&str] = &["etc/issue", "etc/issue.net"];
+
+/// Remove donor cruft matching `patterns`, keeping anything matching `keep`.
+pub fn sanitize(ctx: &BuildContext, patterns: &[String], keep: &[String]) -> Result<()> {
+    detail!("Removing donor branding and cruft...");
+
+    let remove = build_globset(patterns)?;
+    let keep = build_globset(keep)?;
+
+    // Contents first, so a matched directory is empty by the time it's seen
+    // unless something inside it was kept
+    let mut removed = 0;
+    for entry in WalkDir::new(&ctx.staging)
+        .min_depth(1)
+        .contents_first(true)
+        .sort_by_file_name()
+    {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(&ctx.staging)?;
+        if !remove.is_match(rel) || keep.is_match(rel) {
+            continue;
+        }
+
+        let result = if entry.file_type().is_dir() {
+            fs::remove_dir(entry.path())
+        } else {
+            fs::remove_file(entry.path())
+        };
+        match result {
+            Ok(()) => {
+                detail!("  Removed /{}", rel.display());
+                removed += 1;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => {}
+            Err(e) => {
+                let context = format!("Failed to remove /{}", rel.display());
+                return Err(anyhow::Error::new(e).context(context).into());
+            }
+        }
+    }
+
+    rewrite_identity(ctx)?;
+
+    detail!("  Removed {} donor entries", removed);
+    Ok(())
+}
+
+/// Remove every [`EXCLUDED_PATHS`] entry from staging.
+///
+/// Runs last, after every component has copied its files. Anything found is
+/// reported, since it means some component's copy is too broad.
+pub fn purge_excluded(ctx: &BuildContext) -> Result<()> {
+    detail!("Enforcing excluded paths...");
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in EXCLUDED_PATHS {
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    let excluded = builder.build()?;
+
+    let mut matched = Vec::new();
+    let mut walker = WalkDir::new(&ctx.staging).min_depth(1).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(&ctx.staging)?.to_path_buf();
+        if excluded.is_match(&rel) {
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            matched.push((entry.path().to_path_buf(), rel, entry.file_type().is_dir()));
+        }
+    }
+
+    for (path, rel, is_dir) in &matched {
+        if *is_dir {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .with_context(|| format!("Failed to remove /{}", rel.display()))?;
+
+        let subject = format!("/{}", rel.display());
+        detail!("  Warning: removed excluded path {}", subject);
+        ctx.report.warn(
+            "excluded-paths",
+            Some(&subject),
+            "build-only content was copied into staging",
+        );
+    }
+
+    if matched.is_empty() {
+        detail!("  No excluded paths present");
+    }
+    Ok(())
+}
+
+/// Point leftover identity files at LevitateOS.
+fn rewrite_identity(ctx: &BuildContext) -> Result<()> {
+    // usr/lib/os-release is the vendor copy; keep it identical to ours
+    let vendor = ctx.staging.join("usr/lib/os-release");
+    if vendor.exists() || vendor.is_symlink() {
+        fs::remove_file(&vendor)?;
+        fs::copy(ctx.staging.join("etc/os-release"), &vendor)?;
+        detail!("  Rewrote /usr/lib/os-release");
+    }
+
+    for file in ISSUE_FILES {
+        let path = ctx.staging.join(file);
+        if path.exists() {
+            fs::write(
+                &path,
+                format!("LevitateOS {}\nKernel \\r on \\m (\\l)\n\n", OS_VERSION),
+            )?;
+            detail!("  Rewrote /{}", file);
+        }
+    }
+
+    Ok(())
+}
+
+/// Compile patterns; `dir/**` also matches `dir` itself.
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_start_matches('/');
+        let globs = match pattern.strip_suffix("/**") {
+            Some(dir) => vec![pattern, dir],
+            None => vec![pattern],
+        };
+        for glob in globs {
+            builder.add(Glob::new(glob).with_context(|| format!("Invalid pattern: {}", pattern))?);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Severity;
+
+    /// A context staging `files` (and `dirs`) in a temporary directory.
+    fn staging(files: &[&str], dirs: &[&str]) -> (tempfile::TempDir, BuildContext) {
+        let root = tempfile::tempdir().unwrap();
+        let staging = root.path().join("staging");
+        for dir in dirs {
+            fs::create_dir_all(staging.join(dir)).unwrap();
+        }
+        for file in files {
+            let path = staging.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+        }
+        let ctx = BuildContext::new(
+            root.path().join("source"),
+            staging,
+            root.path().join("output"),
+        );
+        (root, ctx)
+    }
+
+    fn exists(ctx: &BuildContext, path: &str) -> bool {
+        ctx.staging.join(path).symlink_metadata().is_ok()
+    }
+
+    #[test]
+    fn purge_excluded_removes_build_only_paths() {
+        let excluded = [
+            "usr/src/kernels/6.1/Makefile",
+            "usr/lib/modules/6.1/build/Makefile",
+            "usr/include/linux/types.h",
+            "usr/include/asm-generic/int-ll64.h",
+            "usr/aarch64-linux-gnu/lib/libc.so.6",
+            "usr/aarch64-redhat-linux/sys-root/usr/lib64/libc.so.6",
+            "opt/toolchain/sysroot/usr/include/stdio.h",
+        ];
+        let (_root, ctx) = staging(&excluded, &["usr/lib/modules/6.1/source"]);
+
+        purge_excluded(&ctx).unwrap();
+
+        for path in excluded {
+            assert!(!exists(&ctx, path), "{} survived", path);
+        }
+        assert!(!exists(&ctx, "usr/src"));
+        assert!(!exists(&ctx, "usr/include/linux"));
+        assert!(!exists(&ctx, "usr/lib/modules/6.1/source"));
+        assert!(ctx.report.count(Severity::Warning) > 0);
+    }
+
+    #[test]
+    fn purge_excluded_keeps_neighbours() {
+        let kept = [
+            "usr/include/stdio.h",
+            "usr/include/linuxthreads.h",
+            "usr/include/openssl/ssl.h",
+            "usr/include/sys/types.h",
+            "usr/lib/modules/6.1/modules.dep",
+            "usr/lib/x86_64-linux-gnu/libc.so.6",
+            "usr/lib/python3/site-packages/pip/sysroot/README",
+            "usr/share/doc/sys-root/README",
+            "usr/bin/bash",
+        ];
+        let (_root, ctx) = staging(&kept, &[]);
+
+        purge_excluded(&ctx).unwrap();
+
+        for path in kept {
+            assert!(exists(&ctx, path), "{} was removed", path);
+        }
+        assert_eq!(ctx.report.count(Severity::Warning), 0);
+    }
+}
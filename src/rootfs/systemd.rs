@@ -5,11 +5,25 @@
 //! - Real getty (not autologin by default)
 //! - Networking services (networkd, resolved)
 //! - Full service management
+//!
+//! [`UnitOverrides`] (the `[services]` table of `stage3.toml`) ships
+//! further units from the donor and enables them.
 
-use anyhow::Result;
+use anyhow::Context;
 use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
 
+use crate::arch;
 use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+use crate::remap::copy_donor_file;
+use crate::report::Severity;
+use crate::templates;
+use crate::validate::units::parse_unit;
 
 /// Essential systemd unit files for an installed system.
 const ESSENTIAL_UNITS: &[&str] = &[
@@ -42,6 +56,7 @@ const ESSENTIAL_UNITS: &[&str] = &[
     "umount.target",
     "final.target",
     "graphical.target",
+    "first-boot-complete.target",
     // Services - core systemd
     "systemd-journald.service",
     "systemd-journald@.service",
@@ -58,6 +73,11 @@ const ESSENTIAL_UNITS: &[&str] = &[
     "systemd-fsck@.service",
     "systemd-remount-fs.service",
     "systemd-fstab-generator",
+    // Services - rescue
+    "rescue.service",
+    "emergency.service",
+    "systemd-ask-password-console.service",
+    "systemd-ask-password-wall.service",
     // Services - authentication
     "systemd-logind.service",
     // Services - getty
@@ -105,40 +125,122 @@ const DBUS_SYMLINKS: &[&str] = &[
 
 /// Copy systemd unit files.
 pub fn copy_systemd_units(ctx: &BuildContext) -> Result<()> {
-    println!("Copying systemd units...");
+    detail!("Copying systemd units...");
 
-    let unit_src = ctx.source.join("usr/lib/systemd/system");
-    let unit_dst = ctx.staging.join("usr/lib/systemd/system");
+    let unit_dir = Path::new("usr/lib/systemd/system");
 
-    fs::create_dir_all(&unit_dst)?;
+    fs::create_dir_all(ctx.target(unit_dir))?;
 
     let mut copied = 0;
     for unit in ESSENTIAL_UNITS {
-        let src = unit_src.join(unit);
-        let dst = unit_dst.join(unit);
-        if src.exists() {
-            fs::copy(&src, &dst)?;
+        if ctx.package_source.find_file(&unit_dir.join(unit)).is_some() {
+            copy_donor_file(ctx, unit_dir.join(unit))?;
             copied += 1;
+        } else if arch::is_optional(&ctx.options.arch, unit) {
+            ctx.report.skip(
+                "units",
+                Some(unit),
+                format!("not found in source (optional on {})", ctx.options.arch),
+            );
+        } else {
+            ctx.report.push(
+                ctx.missing_severity(Severity::Skipped),
+                "units",
+                Some(unit),
+                "not found in source",
+            );
+        }
+    }
+
+    detail!("  Copied {}/{} unit files", copied, ESSENTIAL_UNITS.len());
+    Ok(())
+}
+
+/// System units shipped and enabled on top of the essential ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnitOverrides {
+    /// Units copied from the donor
+    pub add: Vec<String>,
+    /// Units copied from the donor and enabled through their `[Install]`
+    /// section
+    pub enable: Vec<String>,
+}
+
+/// Copy the extra units and enable the ones asked for.
+pub fn setup_extra_units(ctx: &BuildContext, overrides: &UnitOverrides) -> Result<()> {
+    if overrides.add.is_empty() && overrides.enable.is_empty() {
+        return Ok(());
+    }
+    detail!("Copying extra systemd units...");
+
+    let unit_dir = Path::new("usr/lib/systemd/system");
+    for unit in overrides.add.iter().chain(&overrides.enable) {
+        let path = unit_dir.join(unit);
+        if ctx.target(&path).exists() {
+            continue;
+        }
+        if ctx.package_source.is_file(&path) {
+            copy_donor_file(ctx, &path)?;
+        } else {
+            ctx.report.push(
+                ctx.missing_severity(Severity::Warning),
+                "units",
+                Some(unit),
+                "not found in source",
+            );
         }
     }
 
-    println!("  Copied {}/{} unit files", copied, ESSENTIAL_UNITS.len());
+    // Like systemctl enable: a link in every WantedBy=/RequiredBy= target
+    for unit in &overrides.enable {
+        let path = ctx.target(unit_dir.join(unit));
+        let Ok(contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let mut linked = 0;
+        for (section, key, value) in parse_unit(&contents) {
+            let suffix = match (section.as_str(), key.as_str()) {
+                ("Install", "WantedBy") => "wants",
+                ("Install", "RequiredBy") => "requires",
+                _ => continue,
+            };
+            for target in value.split_whitespace() {
+                let dir = ctx
+                    .staging
+                    .join("etc/systemd/system")
+                    .join(format!("{}.{}", target, suffix));
+                fs::create_dir_all(&dir)?;
+                let link = dir.join(unit);
+                if !link.exists() && !link.is_symlink() {
+                    let original = format!("/{}/{}", unit_dir.display(), unit);
+                    std::os::unix::fs::symlink(original, &link)?;
+                }
+                linked += 1;
+            }
+        }
+        if linked == 0 {
+            ctx.report.warn(
+                "units",
+                Some(unit),
+                "no WantedBy= or RequiredBy=, not enabled",
+            );
+        } else {
+            detail!("  Enabled {}", unit);
+        }
+    }
     Ok(())
 }
 
 /// Copy D-Bus activation symlinks.
 pub fn copy_dbus_symlinks(ctx: &BuildContext) -> Result<()> {
-    println!("Copying D-Bus symlinks...");
+    detail!("Copying D-Bus symlinks...");
 
-    let unit_src = ctx.source.join("usr/lib/systemd/system");
-    let unit_dst = ctx.staging.join("usr/lib/systemd/system");
+    let unit_dir = Path::new("usr/lib/systemd/system");
 
     for symlink in DBUS_SYMLINKS {
-        let src = unit_src.join(symlink);
-        let dst = unit_dst.join(symlink);
-        if src.is_symlink() {
-            let target = fs::read_link(&src)?;
-            if !dst.exists() {
+        let dst = ctx.target(unit_dir.join(symlink));
+        if let Some(target) = ctx.package_source.read_link(&unit_dir.join(symlink)) {
+            if !dst.exists() && !dst.is_symlink() {
                 std::os::unix::fs::symlink(&target, &dst)?;
             }
         }
@@ -149,7 +251,7 @@ pub fn copy_dbus_symlinks(ctx: &BuildContext) -> Result<()> {
 
 /// Set up getty for installed system (no autologin).
 pub fn setup_getty(ctx: &BuildContext) -> Result<()> {
-    println!("Setting up getty...");
+    detail!("Setting up getty...");
 
     // Enable getty on tty1
     let getty_wants = ctx
@@ -158,7 +260,7 @@ pub fn setup_getty(ctx: &BuildContext) -> Result<()> {
     fs::create_dir_all(&getty_wants)?;
 
     let getty_link = getty_wants.join("getty@tty1.service");
-    if !getty_link.exists() {
+    if !getty_link.exists() && !getty_link.is_symlink() {
         std::os::unix::fs::symlink("/usr/lib/systemd/system/getty@.service", &getty_link)?;
     }
 
@@ -169,17 +271,17 @@ pub fn setup_getty(ctx: &BuildContext) -> Result<()> {
     fs::create_dir_all(&multi_user_wants)?;
 
     let getty_target_link = multi_user_wants.join("getty.target");
-    if !getty_target_link.exists() {
+    if !getty_target_link.exists() && !getty_target_link.is_symlink() {
         std::os::unix::fs::symlink("/usr/lib/systemd/system/getty.target", &getty_target_link)?;
     }
 
-    println!("  Enabled getty@tty1.service");
+    detail!("  Enabled getty@tty1.service");
     Ok(())
 }
 
 /// Set up serial console for installed system.
 pub fn setup_serial_console(ctx: &BuildContext) -> Result<()> {
-    println!("Setting up serial console...");
+    detail!("Setting up serial console...");
 
     // Enable serial-getty on ttyS0
     let getty_wants = ctx
@@ -188,42 +290,27 @@ pub fn setup_serial_console(ctx: &BuildContext) -> Result<()> {
     fs::create_dir_all(&getty_wants)?;
 
     let serial_link = getty_wants.join("serial-getty@ttyS0.service");
-    if !serial_link.exists() {
+    if !serial_link.exists() && !serial_link.is_symlink() {
         std::os::unix::fs::symlink(
             "/usr/lib/systemd/system/serial-getty@.service",
             &serial_link,
         )?;
     }
 
-    println!("  Enabled serial-getty@ttyS0.service");
+    detail!("  Enabled serial-getty@ttyS0.service");
     Ok(())
 }
 
 /// Set up systemd-networkd for networking.
 pub fn setup_networkd(ctx: &BuildContext) -> Result<()> {
-    println!("Setting up systemd-networkd...");
+    detail!("Setting up systemd-networkd...");
 
     // Create network configuration directory
     let network_dir = ctx.staging.join("etc/systemd/network");
     fs::create_dir_all(&network_dir)?;
 
     // Create default network configuration (DHCP on all interfaces)
-    fs::write(
-        network_dir.join("80-dhcp.network"),
-        r#"[Match]
-Name=en*
-Name=eth*
-
-[Network]
-DHCP=yes
-IPv6AcceptRA=yes
-
-[DHCPv4]
-UseDNS=yes
-UseNTP=yes
-UseHostname=yes
-"#,
-    )?;
+    templates::install(ctx, "etc/systemd/network/80-dhcp.network")?;
 
     // Enable networkd
     let wants_dir = ctx
@@ -232,7 +319,7 @@ UseHostname=yes
     fs::create_dir_all(&wants_dir)?;
 
     let networkd_link = wants_dir.join("systemd-networkd.service");
-    if !networkd_link.exists() {
+    if !networkd_link.exists() && !networkd_link.is_symlink() {
         std::os::unix::fs::symlink(
             "/usr/lib/systemd/system/systemd-networkd.service",
             &networkd_link,
@@ -241,20 +328,20 @@ UseHostname=yes
 
     // Enable resolved
     let resolved_link = wants_dir.join("systemd-resolved.service");
-    if !resolved_link.exists() {
+    if !resolved_link.exists() && !resolved_link.is_symlink() {
         std::os::unix::fs::symlink(
             "/usr/lib/systemd/system/systemd-resolved.service",
             &resolved_link,
         )?;
     }
 
-    println!("  Enabled systemd-networkd and resolved");
+    detail!("  Enabled systemd-networkd and resolved");
     Ok(())
 }
 
 /// Set default.target to multi-user.target.
 pub fn set_default_target(ctx: &BuildContext) -> Result<()> {
-    println!("Setting default target...");
+    detail!("Setting default target...");
 
     let default_link = ctx.staging.join("etc/systemd/system/default.target");
     if default_link.exists() || default_link.is_symlink() {
@@ -262,38 +349,31 @@ pub fn set_default_target(ctx: &BuildContext) -> Result<()> {
     }
     std::os::unix::fs::symlink("/usr/lib/systemd/system/multi-user.target", &default_link)?;
 
-    println!("  Set default.target -> multi-user.target");
+    detail!("  Set default.target -> multi-user.target");
     Ok(())
 }
 
 /// Copy D-Bus configuration.
 pub fn setup_dbus(ctx: &BuildContext) -> Result<()> {
-    println!("Setting up D-Bus...");
+    detail!("Setting up D-Bus...");
 
     // Copy D-Bus system configuration
-    let dbus_src = ctx.source.join("usr/share/dbus-1/system.d");
-    let dbus_dst = ctx.staging.join("usr/share/dbus-1/system.d");
-
-    if dbus_src.exists() {
-        fs::create_dir_all(&dbus_dst)?;
-        for entry in fs::read_dir(&dbus_src)? {
-            let entry = entry?;
-            let dst = dbus_dst.join(entry.file_name());
-            fs::copy(entry.path(), &dst)?;
+    let dbus_dir = Path::new("usr/share/dbus-1/system.d");
+    if let Some(names) = ctx.package_source.read_dir(dbus_dir) {
+        fs::create_dir_all(ctx.target(dbus_dir))?;
+        for name in names {
+            copy_donor_file(ctx, dbus_dir.join(name))?;
         }
     }
 
     // Copy D-Bus system services
-    let services_src = ctx.source.join("usr/share/dbus-1/system-services");
-    let services_dst = ctx.staging.join("usr/share/dbus-1/system-services");
-
-    if services_src.exists() {
-        fs::create_dir_all(&services_dst)?;
-        for entry in fs::read_dir(&services_src)? {
-            let entry = entry?;
-            let dst = services_dst.join(entry.file_name());
-            if entry.path().is_file() {
-                fs::copy(entry.path(), &dst)?;
+    let services_dir = Path::new("usr/share/dbus-1/system-services");
+    if let Some(names) = ctx.package_source.read_dir(services_dir) {
+        fs::create_dir_all(ctx.target(services_dir))?;
+        for name in names {
+            let path = services_dir.join(name);
+            if ctx.package_source.is_file(&path) {
+                copy_donor_file(ctx, path)?;
             }
         }
     }
@@ -305,29 +385,123 @@ pub fn setup_dbus(ctx: &BuildContext) -> Result<()> {
     fs::create_dir_all(&sockets_wants)?;
 
     let dbus_socket_link = sockets_wants.join("dbus.socket");
-    if !dbus_socket_link.exists() {
+    if !dbus_socket_link.exists() && !dbus_socket_link.is_symlink() {
         std::os::unix::fs::symlink("/usr/lib/systemd/system/dbus.socket", &dbus_socket_link)?;
     }
 
-    println!("  Set up D-Bus");
+    detail!("  Set up D-Bus");
+    Ok(())
+}
+
+/// What to ship at /var/lib/systemd/random-seed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RandomSeedPolicy {
+    /// Ship no seed; systemd-random-seed creates one on first boot
+    #[default]
+    None,
+    /// Ship a seed generated at build time, unique to this tarball
+    PerBuild,
+}
+
+impl FromStr for RandomSeedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "per-build" => Ok(Self::PerBuild),
+            other => Err(format!(
+                "unknown random seed policy {:?} (expected none or per-build)",
+                other
+            )),
+        }
+    }
+}
+
+/// Path of the persisted seed inside the rootfs.
+const RANDOM_SEED_PATH: &str = "var/lib/systemd/random-seed";
+
+/// Size of the seed systemd-random-seed itself writes.
+const RANDOM_SEED_SIZE: usize = 512;
+
+/// Unit consuming a `random-seed` credential passed in by the hypervisor.
+const SEED_CREDENTIAL_UNIT: &str = "levitate-random-seed-credential.service";
+
+/// Apply the random seed policy and wire up seeding at boot.
+pub fn setup_random_seed(ctx: &BuildContext) -> Result<()> {
+    detail!("Setting up random seed...");
+
+    let sysinit_wants = ctx.staging.join("etc/systemd/system/sysinit.target.wants");
+    fs::create_dir_all(&sysinit_wants)?;
+
+    // Load the seed early and save it at shutdown
+    let seed_link = sysinit_wants.join("systemd-random-seed.service");
+    if !seed_link.is_symlink() {
+        std::os::unix::fs::symlink(
+            "/usr/lib/systemd/system/systemd-random-seed.service",
+            &seed_link,
+        )?;
+    }
+
+    // Credential-based seeding for VMs, ordered before systemd-random-seed.
+    // VMs pass one with `systemd.set_credential=` or
+    // `-smbios type=11,value=io.systemd.credential:random-seed=...` so
+    // clones booted from the same image start with distinct entropy.
+    templates::install(
+        ctx,
+        &format!("usr/lib/systemd/system/{}", SEED_CREDENTIAL_UNIT),
+    )?;
+    let credential_link = sysinit_wants.join(SEED_CREDENTIAL_UNIT);
+    if !credential_link.is_symlink() {
+        std::os::unix::fs::symlink(
+            format!("/usr/lib/systemd/system/{}", SEED_CREDENTIAL_UNIT),
+            &credential_link,
+        )?;
+    }
+
+    // Never carry over a seed that leaked in from the donor
+    let seed_path = ctx.staging.join(RANDOM_SEED_PATH);
+    if seed_path.exists() {
+        fs::remove_file(&seed_path)?;
+    }
+
+    match ctx.random_seed {
+        RandomSeedPolicy::None => {
+            detail!("  No seed shipped, generated on first boot");
+        }
+        RandomSeedPolicy::PerBuild => {
+            let mut seed = vec![0u8; RANDOM_SEED_SIZE];
+            fs::File::open("/dev/urandom")
+                .and_then(|mut f| f.read_exact(&mut seed))
+                .context("Failed to read /dev/urandom")?;
+
+            fs::create_dir_all(seed_path.parent().unwrap())?;
+            fs::write(&seed_path, &seed)?;
+            fs::set_permissions(&seed_path, fs::Permissions::from_mode(0o600))?;
+            ctx.metadata.chmod(RANDOM_SEED_PATH, 0o600);
+
+            detail!("  Shipped per-build seed at /{}", RANDOM_SEED_PATH);
+            detail!(
+                "  Warning: every system installed from this tarball starts from the same seed"
+            );
+        }
+    }
+
+    detail!("  Enabled systemd-random-seed and {}", SEED_CREDENTIAL_UNIT);
     Ok(())
 }
 
 /// Copy udev rules.
 pub fn copy_udev_rules(ctx: &BuildContext) -> Result<()> {
-    println!("Copying udev rules...");
-
-    let rules_src = ctx.source.join("usr/lib/udev/rules.d");
-    let rules_dst = ctx.staging.join("usr/lib/udev/rules.d");
+    detail!("Copying udev rules...");
 
-    if rules_src.exists() {
-        fs::create_dir_all(&rules_dst)?;
-        for entry in fs::read_dir(&rules_src)? {
-            let entry = entry?;
-            let dst = rules_dst.join(entry.file_name());
-            fs::copy(entry.path(), &dst)?;
+    let rules_dir = Path::new("usr/lib/udev/rules.d");
+    if let Some(names) = ctx.package_source.read_dir(rules_dir) {
+        fs::create_dir_all(ctx.target(rules_dir))?;
+        for name in names {
+            copy_donor_file(ctx, rules_dir.join(name))?;
         }
-        println!("  Copied udev rules");
+        detail!("  Copied udev rules");
     }
 
     Ok(())
@@ -335,21 +509,18 @@ pub fn copy_udev_rules(ctx: &BuildContext) -> Result<()> {
 
 /// Copy tmpfiles.d configuration.
 pub fn copy_tmpfiles(ctx: &BuildContext) -> Result<()> {
-    println!("Copying tmpfiles.d...");
-
-    let tmpfiles_src = ctx.source.join("usr/lib/tmpfiles.d");
-    let tmpfiles_dst = ctx.staging.join("usr/lib/tmpfiles.d");
-
-    if tmpfiles_src.exists() {
-        fs::create_dir_all(&tmpfiles_dst)?;
-        for entry in fs::read_dir(&tmpfiles_src)? {
-            let entry = entry?;
-            let dst = tmpfiles_dst.join(entry.file_name());
-            if entry.path().is_file() {
-                fs::copy(entry.path(), &dst)?;
+    detail!("Copying tmpfiles.d...");
+
+    let tmpfiles_dir = Path::new("usr/lib/tmpfiles.d");
+    if let Some(names) = ctx.package_source.read_dir(tmpfiles_dir) {
+        fs::create_dir_all(ctx.target(tmpfiles_dir))?;
+        for name in names {
+            let path = tmpfiles_dir.join(name);
+            if ctx.package_source.is_file(&path) {
+                copy_donor_file(ctx, path)?;
             }
         }
-        println!("  Copied tmpfiles.d");
+        detail!("  Copied tmpfiles.d");
     }
 
     Ok(())
@@ -357,21 +528,18 @@ pub fn copy_tmpfiles(ctx: &BuildContext) -> Result<()> {
 
 /// Copy sysctl.d configuration.
 pub fn copy_sysctl(ctx: &BuildContext) -> Result<()> {
-    println!("Copying sysctl.d...");
-
-    let sysctl_src = ctx.source.join("usr/lib/sysctl.d");
-    let sysctl_dst = ctx.staging.join("usr/lib/sysctl.d");
-
-    if sysctl_src.exists() {
-        fs::create_dir_all(&sysctl_dst)?;
-        for entry in fs::read_dir(&sysctl_src)? {
-            let entry = entry?;
-            let dst = sysctl_dst.join(entry.file_name());
-            if entry.path().is_file() {
-                fs::copy(entry.path(), &dst)?;
+    detail!("Copying sysctl.d...");
+
+    let sysctl_dir = Path::new("usr/lib/sysctl.d");
+    if let Some(names) = ctx.package_source.read_dir(sysctl_dir) {
+        fs::create_dir_all(ctx.target(sysctl_dir))?;
+        for name in names {
+            let path = sysctl_dir.join(name);
+            if ctx.package_source.is_file(&path) {
+                copy_donor_file(ctx, path)?;
             }
         }
-        println!("  Copied sysctl.d");
+        detail!("  Copied sysctl.d");
     }
 
     Ok(())
@@ -0,0 +1,201 @@
+//! Default-enabled user services.
+//!
+//! Services declared with `--user-service` (e.g. `pipewire.socket`,
+//! `ssh-agent.service`) are listed in a user preset file and enabled
+//! globally in /etc/systemd/user, the way `systemctl --global enable` does.
+//! Every user's manager then starts them, including users created after
+//! installation, without anything in /etc/skel. The preset keeps
+//! `systemctl --global preset-all` from undoing it.
+//!
+//! The units come from the donor's /usr/lib/systemd/user along with the
+//! targets and slices the user manager needs; the programs they run have
+//! to be shipped separately.
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::context::BuildContext;
+use crate::detail;
+use crate::error::Result;
+use crate::remap::copy_donor_file;
+use crate::validate::units::parse_unit;
+
+/// User unit directory, in the donor and the rootfs.
+pub const USER_UNIT_DIR: &str = "usr/lib/systemd/user";
+
+/// Preset listing the declared services.
+pub const USER_PRESET: &str = "usr/lib/systemd/user-preset/80-levitate.preset";
+
+/// Where global enablement links go.
+const GLOBAL_USER_DIR: &str = "etc/systemd/user";
+
+/// Units of the user manager itself, copied when present.
+const USER_MANAGER_UNITS: &[&str] = &[
+    "default.target",
+    "basic.target",
+    "paths.target",
+    "sockets.target",
+    "timers.target",
+    "shutdown.target",
+    "exit.target",
+    "systemd-exit.service",
+    "app.slice",
+    "background.slice",
+    "session.slice",
+];
+
+/// Unit suffixes a user service can enable.
+const UNIT_SUFFIXES: &[&str] = &[".service", ".socket", ".timer", ".path", ".target"];
+
+/// A user unit enabled for every user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct UserService(String);
+
+impl UserService {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for UserService {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let stem = UNIT_SUFFIXES
+            .iter()
+            .find_map(|suffix| s.strip_suffix(suffix))
+            .ok_or_else(|| {
+                format!(
+                    "{:?} is not a unit name (expected e.g. pipewire.socket or ssh-agent.service)",
+                    s
+                )
+            })?;
+        if stem.ends_with('@') {
+            return Err(format!("{:?} is a template; give an instance", s));
+        }
+        let valid = !stem.is_empty()
+            && stem
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@' | ':'));
+        if !valid {
+            return Err(format!("invalid unit name {:?}", s));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// Ship the declared user services enabled for every user.
+pub fn setup_user_services(ctx: &BuildContext, services: &[UserService]) -> Result<()> {
+    detail!("Setting up user services...");
+
+    let unit_dir = Path::new(USER_UNIT_DIR);
+    for unit in USER_MANAGER_UNITS {
+        copy_user_unit(ctx, unit)?;
+    }
+
+    // Enable like systemctl: WantedBy=/RequiredBy= links, Also= units too
+    let mut enabled = BTreeSet::new();
+    let mut missing = BTreeSet::new();
+    let mut pending: Vec<String> = services.iter().map(|s| s.name().to_string()).collect();
+    while let Some(unit) = pending.pop() {
+        if !enabled.insert(unit.clone()) {
+            continue;
+        }
+        if !copy_user_unit(ctx, &unit)? {
+            ctx.report.warn(
+                "user-services",
+                Some(&unit),
+                "not found in the donor, not enabled",
+            );
+            missing.insert(unit);
+            continue;
+        }
+
+        // Sockets, timers and paths need the service they activate
+        if let Some((stem, kind)) = unit.rsplit_once('.') {
+            if matches!(kind, "socket" | "timer" | "path") {
+                copy_user_unit(ctx, &format!("{}.service", stem))?;
+                copy_user_unit(ctx, &format!("{}@.service", stem))?;
+            }
+        }
+
+        // A unit named unit@instance.service is installed from its template
+        let file = template_of(&unit).unwrap_or(unit.clone());
+        let contents = fs::read_to_string(ctx.target(unit_dir.join(&file)))?;
+        let mut wanted = 0;
+        for (section, key, value) in parse_unit(&contents) {
+            if section != "Install" {
+                continue;
+            }
+            match key.as_str() {
+                "WantedBy" | "RequiredBy" => {
+                    let suffix = if key == "WantedBy" {
+                        "wants"
+                    } else {
+                        "requires"
+                    };
+                    for target in value.split_whitespace() {
+                        enable(ctx, target, suffix, &unit, &file)?;
+                        wanted += 1;
+                    }
+                }
+                "Also" => pending.extend(value.split_whitespace().map(str::to_string)),
+                _ => {}
+            }
+        }
+        if wanted == 0 {
+            detail!("  {} has no WantedBy=, only listed in the preset", unit);
+        }
+    }
+
+    let preset = ctx.staging.join(USER_PRESET);
+    fs::create_dir_all(preset.parent().unwrap())?;
+    let mut contents = String::from("# Enabled for every user by the stage3 build\n");
+    for service in services.iter().filter(|s| !missing.contains(s.name())) {
+        contents.push_str(&format!("enable {}\n", service.name()));
+    }
+    fs::write(&preset, contents)?;
+
+    detail!("  Enabled {} user units", enabled.len() - missing.len());
+    Ok(())
+}
+
+/// Copy a user unit (or its template) from the donor; false if it has none.
+fn copy_user_unit(ctx: &BuildContext, unit: &str) -> Result<bool> {
+    let file = template_of(unit).unwrap_or(unit.to_string());
+    let path = Path::new(USER_UNIT_DIR).join(&file);
+    if ctx.target(&path).exists() {
+        return Ok(true);
+    }
+    if !ctx.package_source.is_file(&path) {
+        return Ok(false);
+    }
+    copy_donor_file(ctx, &path)?;
+    Ok(true)
+}
+
+/// `foo@.service` for `foo@bar.service`.
+fn template_of(unit: &str) -> Option<String> {
+    let (prefix, rest) = unit.split_once('@')?;
+    let (_, suffix) = rest.rsplit_once('.')?;
+    Some(format!("{}@.{}", prefix, suffix))
+}
+
+/// Link `unit` into `target.<suffix>` under /etc/systemd/user.
+fn enable(ctx: &BuildContext, target: &str, suffix: &str, unit: &str, file: &str) -> Result<()> {
+    let dir = ctx
+        .staging
+        .join(GLOBAL_USER_DIR)
+        .join(format!("{}.{}", target, suffix));
+    fs::create_dir_all(&dir)?;
+    let link = dir.join(unit);
+    if !link.is_symlink() {
+        std::os::unix::fs::symlink(format!("/{}/{}", USER_UNIT_DIR, file), &link)?;
+        detail!("  Enabled {} for {}", unit, target);
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Network isolation for spawned helpers.
+//!
+//! With offline mode enabled, every helper a build spawns (ldd,
+//! systemd-analyze, minisign, ...) runs in a fresh, empty network namespace
+//! via `unshare --net --map-root-user`. The builder itself never opens a
+//! socket, so this makes the whole build verifiably offline.
+//!
+//! The mode belongs to a build: it travels as a [`Sandbox`] on the
+//! [`BuildContext`](crate::context::BuildContext), and everything a build
+//! runs spawns its helpers through that. Helpers of commands outside a
+//! build (extract, boot-test, verify, ...) go through [`command`], which
+//! doesn't isolate them.
+
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// How one build spawns its helpers; online by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    offline: bool,
+}
+
+impl Sandbox {
+    /// A sandbox isolating helpers from the network if `offline`.
+    pub fn new(offline: bool) -> Self {
+        Self { offline }
+    }
+
+    /// Whether helpers are isolated from the network.
+    pub fn is_offline(self) -> bool {
+        self.offline
+    }
+
+    /// Check that helpers can be isolated from the network on this host,
+    /// if they have to be.
+    pub fn preflight(self) -> Result<()> {
+        if !self.offline {
+            return Ok(());
+        }
+        let status = Command::new("unshare")
+            .args(["--net", "--map-root-user", "true"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to run unshare (util-linux is required for --offline)")?;
+
+        if !status.success() {
+            bail!(
+                "Cannot create a network namespace for --offline (are user namespaces disabled?)"
+            );
+        }
+        Ok(())
+    }
+
+    /// Build a command for a helper, isolated from the network when offline.
+    pub fn command(self, program: &str) -> Command {
+        if self.offline {
+            let mut cmd = Command::new("unshare");
+            cmd.args(["--net", "--map-root-user", "--"]).arg(program);
+            cmd
+        } else {
+            Command::new(program)
+        }
+    }
+}
+
+/// Build a command for a helper spawned outside a build.
+pub fn command(program: &str) -> Command {
+    Sandbox::default().command(program)
+}
+
+/// Look up a helper on `PATH`.
+///
+/// Spawn errors can't distinguish a missing program once it runs under
+/// unshare, so callers with optional helpers check up front.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_offline_sandboxes_wrap_helpers_in_unshare() {
+        let online = Sandbox::default().command("ldd");
+        assert_eq!(online.get_program(), "ldd");
+        assert_eq!(online.get_args().count(), 0);
+
+        let offline = Sandbox::new(true).command("ldd");
+        assert_eq!(offline.get_program(), "unshare");
+        let args: Vec<_> = offline.get_args().collect();
+        assert_eq!(args, ["--net", "--map-root-user", "--", "ldd"]);
+    }
+}
@@ -0,0 +1,159 @@
+//! Host-specific secrets injected into customer builds.
+//!
+//! Secrets (VPN keys, enrollment tokens, ...) are written into the rootfs
+//! with mode 0600 right before archiving. Their contents are never printed,
+//! they are left out of the per-file manifest (which only names their
+//! paths, so verification can tell them from stray entries), and they are
+//! scrubbed from staging as soon as the tarball has been written.
+
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::context::BuildContext;
+use crate::detail;
+
+/// Where a secret's contents come from.
+#[derive(Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// Read from a file on the build host
+    File(PathBuf),
+    /// Read from an environment variable
+    Env(String),
+}
+
+/// A secret to inject at a declared rootfs path.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret {
+    /// Destination inside the rootfs (e.g. `etc/wireguard/wg0.key`)
+    pub dest: PathBuf,
+    pub source: SecretSource,
+}
+
+impl FromStr for Secret {
+    type Err = String;
+
+    /// Parse `DEST=file:PATH` or `DEST=env:VAR`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (dest, source) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected DEST=file:PATH or DEST=env:VAR, got {:?}", s))?;
+
+        let dest = Path::new(dest);
+        let dest = dest.strip_prefix("/").unwrap_or(dest).to_path_buf();
+        if dest.as_os_str().is_empty()
+            || !dest.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("invalid secret destination {:?}", dest));
+        }
+
+        let source = match source.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => SecretSource::File(PathBuf::from(path)),
+            Some(("env", var)) if !var.is_empty() => SecretSource::Env(var.to_string()),
+            _ => {
+                return Err(format!(
+                    "invalid secret source {:?} (expected file:PATH or env:VAR)",
+                    source
+                ))
+            }
+        };
+
+        Ok(Self { dest, source })
+    }
+}
+
+// Only the declaration is ever shown, never the contents
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            SecretSource::File(path) => {
+                write!(f, "/{} (from file {})", self.dest.display(), path.display())
+            }
+            SecretSource::Env(var) => write!(f, "/{} (from ${})", self.dest.display(), var),
+        }
+    }
+}
+
+impl Secret {
+    fn read(&self) -> Result<Vec<u8>> {
+        match &self.source {
+            SecretSource::File(path) => fs::read(path)
+                .with_context(|| format!("Failed to read secret for /{}", self.dest.display())),
+            SecretSource::Env(var) => match std::env::var_os(var) {
+                Some(value) => Ok(value.into_vec()),
+                None => bail!(
+                    "Secret for /{} not available: ${} is not set",
+                    self.dest.display(),
+                    var
+                ),
+            },
+        }
+    }
+}
+
+/// Check every secret source is available before the build starts.
+pub fn preflight(secrets: &[Secret]) -> Result<()> {
+    for secret in secrets {
+        let available = match &secret.source {
+            SecretSource::File(path) => path.is_file(),
+            SecretSource::Env(var) => std::env::var_os(var).is_some(),
+        };
+        if !available {
+            bail!("Secret source for {:?} is not available", secret);
+        }
+    }
+    Ok(())
+}
+
+/// Write secrets into staging with mode 0600, owned by root.
+pub fn inject(ctx: &BuildContext, secrets: &[Secret]) -> Result<()> {
+    if secrets.is_empty() {
+        return Ok(());
+    }
+
+    detail!("Injecting secrets...");
+
+    for secret in secrets {
+        let contents = secret.read()?;
+        let dest = ctx.staging.join(&secret.dest);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if dest.exists() || dest.is_symlink() {
+            fs::remove_file(&dest)?;
+        }
+
+        // Created 0600 up front so the contents are never world-readable
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&dest)
+            .and_then(|mut file| file.write_all(&contents))
+            .with_context(|| format!("Failed to write secret /{}", secret.dest.display()))?;
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o600))?;
+        ctx.metadata.chmod(&secret.dest, 0o600);
+        ctx.metadata.chown(&secret.dest, 0, 0);
+
+        detail!("  Injected /{}", secret.dest.display());
+    }
+
+    Ok(())
+}
+
+/// Remove injected secrets from staging.
+pub fn scrub(staging: &Path, secrets: &[Secret]) {
+    for secret in secrets {
+        fs::remove_file(staging.join(&secret.dest)).ok();
+    }
+}
+
+/// Whether a normalized rootfs path is a declared secret.
+pub fn is_secret(secrets: &[Secret], path: &str) -> bool {
+    secrets.iter().any(|s| s.dest == Path::new(path))
+}
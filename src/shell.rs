@@ -0,0 +1,118 @@
+//! Interactive shells inside a stage3.
+//!
+//! `stage3 shell` chroots into a tarball (extracted to a throwaway
+//! directory) or a staging directory kept with `--keep-staging`, with
+//! /proc, /sys and /dev available. The mounts are made in a private mount
+//! namespace, so they disappear with the shell however it exits and never
+//! show up on the host.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{ExtractOptions, Stage3Archive};
+use crate::sandbox;
+use crate::status;
+
+/// Command run when none is given.
+const DEFAULT_COMMAND: &[&str] = &["/usr/bin/bash", "-l"];
+
+/// Mounts the API filesystems in the new namespace and enters the chroot.
+///
+/// Arguments: the root, then the command to run in it.
+const ENTER_SCRIPT: &str = r#"root=$1; shift
+mount -t proc proc "$root/proc" &&
+mount --rbind /sys "$root/sys" &&
+mount --rbind /dev "$root/dev" || exit 1
+exec chroot "$root" "$@"
+"#;
+
+/// Runs the shell, then removes the extracted rootfs, if any.
+///
+/// Ctrl-C belongs to the shell: this script only traps it so it stays
+/// around to clean up, while the shell gets the default handling back.
+const WRAPPER_SCRIPT: &str = r#"trap : INT QUIT
+work=$1 enter=$2; shift 2
+unshare --mount --propagation private sh -c "$enter" stage3-enter "$@"
+status=$?
+if [ -n "$work" ]; then rm -rf "$work"; fi
+exit $status
+"#;
+
+/// Open a shell (or run `command`) inside a tarball or rootfs directory.
+///
+/// A build output directory is accepted too and resolves to its kept
+/// staging directory. Only returns on error; the process is replaced by
+/// the shell.
+pub fn shell(path: &Path, command: &[String]) -> Result<()> {
+    if fs::metadata("/proc/self")?.uid() != 0 {
+        bail!("shell needs root to chroot and mount /proc, /sys and /dev");
+    }
+    for program in ["unshare", "chroot", "mount"] {
+        if sandbox::find_program(program).is_none() {
+            bail!("{} not found (install util-linux and coreutils)", program);
+        }
+    }
+
+    let (root, work) = if path.is_dir() {
+        (rootfs_dir(path)?, None)
+    } else {
+        let work = tempfile::Builder::new()
+            .prefix("stage3-shell-")
+            .tempdir()
+            .context("Failed to create a directory to extract into")?;
+        status!("Extracting {}...", path.display());
+        Stage3Archive::open(path)
+            .and_then(|archive| archive.extract(work.path(), &ExtractOptions::rootfs()))?;
+        (work.path().to_path_buf(), Some(work))
+    };
+
+    let command: Vec<String> = match command.is_empty() {
+        true => DEFAULT_COMMAND.iter().map(|s| s.to_string()).collect(),
+        false => command.to_vec(),
+    };
+    if command[0].starts_with('/') && !root.join(&command[0][1..]).exists() {
+        bail!(
+            "{} is not in the rootfs; pass another command after --",
+            command[0]
+        );
+    }
+    for dir in ["proc", "sys", "dev"] {
+        fs::create_dir_all(root.join(dir))?;
+    }
+
+    status!("Entering {} (exit the shell to leave)", root.display());
+    // The wrapper removes the extracted rootfs once the shell exits
+    let work = work.map(|work| work.keep());
+    let work_arg = work.as_deref().map(Path::as_os_str).unwrap_or_default();
+    let err = sandbox::command("sh")
+        .arg("-c")
+        .arg(WRAPPER_SCRIPT)
+        .arg("stage3-shell")
+        .arg(work_arg)
+        .arg(ENTER_SCRIPT)
+        .arg(&root)
+        .args(&command)
+        .exec();
+    if let Some(ref work) = work {
+        fs::remove_dir_all(work).ok();
+    }
+    Err(err).context("Failed to run sh")
+}
+
+/// The rootfs to enter for a directory argument.
+fn rootfs_dir(path: &Path) -> Result<PathBuf> {
+    if path.join("usr").is_dir() {
+        return Ok(path.to_path_buf());
+    }
+    let staging = path.join("staging");
+    if staging.join("usr").is_dir() {
+        return Ok(staging);
+    }
+    bail!(
+        "{} is neither a rootfs nor a build output with a kept staging directory (--keep-staging)",
+        path.display()
+    );
+}
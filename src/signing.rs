@@ -0,0 +1,73 @@
+//! Detached minisign signatures for tarballs.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use crate::detail;
+use crate::sandbox::{self, Sandbox};
+
+/// Path of the detached signature for a file.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".minisig");
+    PathBuf::from(name)
+}
+
+/// Sign a file with a minisign secret key, returning the signature path.
+pub fn sign_file(path: &Path, secret_key: &Path, sandbox: Sandbox) -> Result<PathBuf> {
+    detail!("  Signing with {}...", secret_key.display());
+
+    let sig_path = signature_path(path);
+    let status = sandbox
+        .command("minisign")
+        .arg("-S")
+        .arg("-s")
+        .arg(secret_key)
+        .arg("-m")
+        .arg(path)
+        .arg("-x")
+        .arg(&sig_path)
+        .status()
+        .context("Failed to run minisign command")?;
+
+    if !status.success() {
+        anyhow::bail!("minisign command failed with status: {}", status);
+    }
+
+    Ok(sig_path)
+}
+
+/// Check a file's detached signature.
+///
+/// Without `public_key`, minisign's default key location is used.
+pub fn verify_signature(path: &Path, public_key: Option<&Path>) -> Result<()> {
+    let sig_path = signature_path(path);
+    if !sig_path.exists() {
+        anyhow::bail!("Signature not found: {}", sig_path.display());
+    }
+
+    let mut cmd = sandbox::command("minisign");
+    cmd.arg("-V").arg("-q");
+    if let Some(key) = public_key {
+        cmd.arg("-p").arg(key);
+    }
+    let output = cmd
+        .arg("-m")
+        .arg(path)
+        .arg("-x")
+        .arg(&sig_path)
+        .stdout(Stdio::null())
+        .output()
+        .context("Failed to run minisign command")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Signature verification failed for {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Sparse files.
+//!
+//! Some donor files (under /var, say, or seeded databases) are sparse:
+//! their holes take no space on disk and read as zeros. [`copy`] copies
+//! only their data, leaving the same holes in staging, and the tarball
+//! stores them as GNU sparse entries, so neither expands them to their
+//! full size. Holes are found with `SEEK_DATA` and `SEEK_HOLE`.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+/// Offset and length of every run of data in `file`, in order; None if
+/// it has no holes.
+pub fn data_extents(file: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    let meta = file.metadata()?;
+    let len = meta.len();
+    // Only files with fewer blocks than bytes can have holes
+    if !meta.is_file() || meta.blocks() * 512 >= len {
+        return Ok(None);
+    }
+
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let Some(start) = seek(file, offset, libc::SEEK_DATA)? else {
+            break;
+        };
+        let end = seek(file, start, libc::SEEK_HOLE)?.unwrap_or(len).min(len);
+        if end <= start {
+            break;
+        }
+        extents.push((start, end - start));
+        offset = end;
+    }
+    // Filesystems that can't tell report one run of data
+    Ok((extents != [(0, len)]).then_some(extents))
+}
+
+/// Copy `src` to `dest` with its permissions, leaving holes where `src`
+/// has them. Returns None, having copied nothing, if `src` has none.
+pub fn copy(src: &Path, dest: &Path) -> io::Result<Option<u64>> {
+    let from = File::open(src)?;
+    let Some(extents) = data_extents(&from)? else {
+        return Ok(None);
+    };
+    let meta = from.metadata()?;
+    let to = File::create(dest)?;
+    let mut buf = vec![0; 64 * 1024];
+    for &(offset, len) in &extents {
+        let mut done = 0;
+        while done < len {
+            let want = buf.len().min((len - done) as usize);
+            let n = from.read_at(&mut buf[..want], offset + done)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} shrank while copying", src.display()),
+                ));
+            }
+            to.write_all_at(&buf[..n], offset + done)?;
+            done += n as u64;
+        }
+    }
+    // Covers a trailing hole
+    to.set_len(meta.len())?;
+    to.set_permissions(meta.permissions())?;
+    Ok(Some(meta.len()))
+}
+
+/// Reads the data `extents` of a file one after the other.
+pub struct ExtentReader<'a> {
+    file: &'a File,
+    extents: &'a [(u64, u64)],
+    /// Position in the file and bytes left of the current extent
+    position: u64,
+    remaining: u64,
+}
+
+impl<'a> ExtentReader<'a> {
+    pub fn new(file: &'a File, extents: &'a [(u64, u64)]) -> Self {
+        Self {
+            file,
+            extents,
+            position: 0,
+            remaining: 0,
+        }
+    }
+}
+
+impl Read for ExtentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let Some((&(offset, len), rest)) = self.extents.split_first() else {
+                return Ok(0);
+            };
+            self.extents = rest;
+            self.position = offset;
+            self.remaining = len;
+        }
+        let want = buf.len().min(self.remaining as usize);
+        let n = self.file.read_at(&mut buf[..want], self.position)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.position += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// `lseek` to the next data or hole at or after `offset`; None past the
+/// last one.
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    // SAFETY: lseek on a descriptor we own
+    match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+        -1 => match io::Error::last_os_error() {
+            err if err.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            err => Err(err),
+        },
+        found => Ok(Some(found as u64)),
+    }
+}
@@ -0,0 +1,364 @@
+//! Named build steps.
+//!
+//! Each rootfs step runs as soon as the steps it depends on are done,
+//! several at a time (`--step-jobs`): the binary copies, `/etc` and PAM,
+//! say, don't wait for one another. What ends up in staging, the report
+//! and the checkpoint doesn't depend on which finishes first; only the
+//! file counts of the build profile do, as a step's include what steps
+//! running alongside it wrote.
+//!
+//! `--only-step` and `--skip-step` pick a subset to run on the staging
+//! directory an earlier build left (see `--keep-staging`), e.g.
+//! `--only-step pam` to rework just the PAM setup. Steps that don't run
+//! keep what they staged before; what they recorded outside the staging
+//! tree (warnings, ownership, device nodes) is replayed from the staging
+//! directory's checkpoint. Without `tarball`, nothing is archived and the
+//! staging directory stays.
+//!
+//! Downstream projects add rootfs steps of their own, or put one in place
+//! of a built-in step, by implementing [`Step`] and registering it with
+//! [`Stage3Builder::with_step`](crate::builder::Stage3Builder::with_step).
+//! A step named like a built-in one replaces it, running after what the
+//! built-in one would and its own dependencies; any other runs after its
+//! dependencies, or after every built-in rootfs step if it has none.
+//! Custom steps are checkpointed like built-in ones.
+//! `--only-step` and `--skip-step` select a replacement by the built-in
+//! step's name; other custom steps run unless `--only-step` is given.
+
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::context::BuildContext;
+
+/// A step of the build pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStep {
+    Fhs,
+    Symlinks,
+    Shell,
+    Coreutils,
+    Sbin,
+    SystemdBinaries,
+    Units,
+    Etc,
+    Pam,
+    Recipe,
+    UserServices,
+    Accessibility,
+    Healthcheck,
+    Help,
+    Rescue,
+    Lockdown,
+    Sanitize,
+    Validate,
+    Tarball,
+}
+
+impl BuildStep {
+    /// Every step, in build order.
+    pub const ALL: &[BuildStep] = &[
+        BuildStep::Fhs,
+        BuildStep::Symlinks,
+        BuildStep::Shell,
+        BuildStep::Coreutils,
+        BuildStep::Sbin,
+        BuildStep::SystemdBinaries,
+        BuildStep::Units,
+        BuildStep::Etc,
+        BuildStep::Pam,
+        BuildStep::Recipe,
+        BuildStep::UserServices,
+        BuildStep::Accessibility,
+        BuildStep::Healthcheck,
+        BuildStep::Help,
+        BuildStep::Rescue,
+        BuildStep::Lockdown,
+        BuildStep::Sanitize,
+        BuildStep::Validate,
+        BuildStep::Tarball,
+    ];
+
+    /// Name on the command line and in the checkpoint.
+    pub fn name(self) -> &'static str {
+        match self {
+            BuildStep::Fhs => "fhs",
+            BuildStep::Symlinks => "symlinks",
+            BuildStep::Shell => "shell",
+            BuildStep::Coreutils => "coreutils",
+            BuildStep::Sbin => "sbin",
+            BuildStep::SystemdBinaries => "systemd-binaries",
+            BuildStep::Units => "units",
+            BuildStep::Etc => "etc",
+            BuildStep::Pam => "pam",
+            BuildStep::Recipe => "recipe",
+            BuildStep::UserServices => "user-services",
+            BuildStep::Accessibility => "accessibility",
+            BuildStep::Healthcheck => "healthcheck",
+            BuildStep::Help => "help",
+            BuildStep::Rescue => "rescue",
+            BuildStep::Lockdown => "lockdown",
+            BuildStep::Sanitize => "sanitize",
+            BuildStep::Validate => "validate",
+            BuildStep::Tarball => "tarball",
+        }
+    }
+
+    /// Status line title.
+    pub fn title(self) -> &'static str {
+        match self {
+            BuildStep::Fhs => "Filesystem layout",
+            BuildStep::Symlinks => "Symlinks",
+            BuildStep::Shell => "Shell",
+            BuildStep::Coreutils => "Coreutils",
+            BuildStep::Sbin => "sbin and login utilities",
+            BuildStep::SystemdBinaries => "systemd binaries",
+            BuildStep::Units => "systemd",
+            BuildStep::Etc => "/etc, timezones and locales",
+            BuildStep::Pam => "PAM",
+            BuildStep::Recipe => "recipe",
+            BuildStep::UserServices => "User services",
+            BuildStep::Accessibility => "Accessibility",
+            BuildStep::Healthcheck => "Health check",
+            BuildStep::Help => "Offline help",
+            BuildStep::Rescue => "Static rescue busybox",
+            BuildStep::Lockdown => "Appliance lockdown",
+            BuildStep::Sanitize => "Sanitize",
+            BuildStep::Validate => "Validating rootfs",
+            BuildStep::Tarball => "Packaging",
+        }
+    }
+
+    /// Built-in steps that must be done before this one runs.
+    pub fn dependencies(self) -> &'static [BuildStep] {
+        match self {
+            BuildStep::Fhs => &[],
+            BuildStep::Symlinks => &[BuildStep::Fhs],
+            // Binaries go through the /bin -> usr/bin links
+            BuildStep::Shell
+            | BuildStep::Coreutils
+            | BuildStep::Sbin
+            | BuildStep::SystemdBinaries
+            | BuildStep::Etc
+            | BuildStep::Pam
+            | BuildStep::Help => &[BuildStep::Symlinks],
+            BuildStep::Units => &[BuildStep::SystemdBinaries],
+            BuildStep::Recipe
+            | BuildStep::Accessibility
+            | BuildStep::Healthcheck
+            | BuildStep::Rescue => &[BuildStep::Units],
+            BuildStep::UserServices => &[BuildStep::Units, BuildStep::Etc],
+            // Run after every earlier step (see `after_all`)
+            BuildStep::Lockdown
+            | BuildStep::Sanitize
+            | BuildStep::Validate
+            | BuildStep::Tarball => &[],
+        }
+    }
+
+    /// Whether the step changes or checks what every earlier step staged,
+    /// so it runs after all of them, custom ones included.
+    pub fn after_all(self) -> bool {
+        matches!(
+            self,
+            BuildStep::Lockdown | BuildStep::Sanitize | BuildStep::Validate | BuildStep::Tarball
+        )
+    }
+}
+
+impl fmt::Display for BuildStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BuildStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        BuildStep::ALL
+            .iter()
+            .copied()
+            .find(|step| step.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = BuildStep::ALL.iter().map(|step| step.name()).collect();
+                format!(
+                    "unknown step {:?} (expected one of {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Which steps a build runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StepSelection {
+    /// Run only these (all when empty)
+    pub only: Vec<BuildStep>,
+    /// Never run these
+    pub skip: Vec<BuildStep>,
+}
+
+impl StepSelection {
+    /// Whether `step` runs.
+    pub fn runs(&self, step: BuildStep) -> bool {
+        (self.only.is_empty() || self.only.contains(&step)) && !self.skip.contains(&step)
+    }
+
+    /// Whether every step runs.
+    pub fn is_all(&self) -> bool {
+        self.only.is_empty() && self.skip.is_empty()
+    }
+}
+
+/// A rootfs step a downstream project adds to the build.
+pub trait Step: Send + Sync {
+    /// Name in build output and the checkpoint; a built-in step's name to
+    /// replace that step.
+    fn name(&self) -> &str;
+
+    /// Steps, built-in or custom, that must run first.
+    fn dependencies(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Options and files the step depends on besides the build options,
+    /// so a resumed build runs it again when they change.
+    fn inputs(&self) -> Result<String> {
+        Ok(String::new())
+    }
+
+    /// Stage what the step adds.
+    fn run(&self, ctx: &BuildContext) -> Result<()>;
+}
+
+/// What a planned rootfs step runs.
+#[derive(Clone)]
+pub enum StepKind {
+    BuiltIn(BuildStep),
+    /// A custom step, with the built-in step it replaces
+    Custom(Arc<dyn Step>, Option<BuildStep>),
+}
+
+/// A rootfs step in build order.
+#[derive(Clone)]
+pub struct PlannedStep {
+    pub kind: StepKind,
+    /// Earlier steps it runs after, by index in the plan
+    pub dependencies: Vec<usize>,
+}
+
+impl PlannedStep {
+    pub fn name(&self) -> &str {
+        match self.kind {
+            StepKind::BuiltIn(step) => step.name(),
+            StepKind::Custom(ref step, _) => step.name(),
+        }
+    }
+
+    /// The built-in step it is or replaces.
+    pub fn built_in(&self) -> Option<BuildStep> {
+        match self.kind {
+            StepKind::BuiltIn(step) => Some(step),
+            StepKind::Custom(_, replaces) => replaces,
+        }
+    }
+}
+
+/// The rootfs steps in build order, with what each depends on: the
+/// built-in ones with `custom` steps put in place or inserted, in the order
+/// they were registered.
+pub fn plan(custom: &[Arc<dyn Step>]) -> Result<Vec<PlannedStep>> {
+    let mut planned: Vec<StepKind> = BuildStep::ALL
+        .iter()
+        .filter(|step| !matches!(step, BuildStep::Validate | BuildStep::Tarball))
+        .map(|step| StepKind::BuiltIn(*step))
+        .collect();
+    let name = |kind: &StepKind| match kind {
+        StepKind::BuiltIn(step) => step.name().to_string(),
+        StepKind::Custom(step, _) => step.name().to_string(),
+    };
+
+    for step in custom {
+        let position = |wanted: &str| planned.iter().position(|kind| name(kind) == wanted);
+        let mut after = 0;
+        for dependency in step.dependencies() {
+            match position(dependency) {
+                Some(index) => after = after.max(index + 1),
+                None => bail!(
+                    "step {} depends on unknown step {}",
+                    step.name(),
+                    dependency
+                ),
+            }
+        }
+
+        match position(step.name()) {
+            Some(index) => {
+                let StepKind::BuiltIn(replaced) = planned[index] else {
+                    bail!("step {} is registered twice", step.name());
+                };
+                if after > index {
+                    bail!(
+                        "step {} can't replace the built-in one: it depends on a later step",
+                        step.name()
+                    );
+                }
+                planned[index] = StepKind::Custom(step.clone(), Some(replaced));
+            }
+            None if step.name().parse::<BuildStep>().is_ok() => {
+                bail!(
+                    "step {} runs after the rootfs and can't be replaced",
+                    step.name()
+                )
+            }
+            None => {
+                let mut index = match step.dependencies().is_empty() {
+                    true => planned.len(),
+                    false => after,
+                };
+                // After custom steps registered before it
+                while matches!(planned.get(index), Some(StepKind::Custom(_, None))) {
+                    index += 1;
+                }
+                planned.insert(index, StepKind::Custom(step.clone(), None));
+            }
+        }
+    }
+
+    let names: Vec<String> = planned.iter().map(name).collect();
+    let index_of = |wanted: &str| names.iter().position(|name| name == wanted);
+    let mut steps = Vec::new();
+    for (index, kind) in planned.into_iter().enumerate() {
+        let (built_in, custom) = match kind {
+            StepKind::BuiltIn(step) => (Some(step), None),
+            StepKind::Custom(ref step, replaces) => (replaces, Some(step.clone())),
+        };
+        let mut dependencies: Vec<usize> = match (built_in, &custom) {
+            (Some(step), _) if step.after_all() => (0..index).collect(),
+            // Inserted without dependencies: after everything before it
+            (None, Some(step)) if step.dependencies().is_empty() => (0..index).collect(),
+            _ => Vec::new(),
+        };
+        let named = built_in
+            .into_iter()
+            .flat_map(|step| step.dependencies().iter().map(|dep| dep.name()))
+            .chain(
+                custom
+                    .iter()
+                    .flat_map(|step| step.dependencies().iter().copied()),
+            );
+        for dependency in named {
+            // Known, and earlier: checked above, or built-in order
+            let dependency = index_of(dependency).expect("dependencies are planned");
+            if !dependencies.contains(&dependency) {
+                dependencies.push(dependency);
+            }
+        }
+        dependencies.sort_unstable();
+        steps.push(PlannedStep { kind, dependencies });
+    }
+    Ok(steps)
+}
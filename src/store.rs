@@ -0,0 +1,155 @@
+//! Content-addressed store of donor binaries and libraries.
+//!
+//! Builds of several profiles, or the nightly builds of one, copy mostly
+//! the same binaries and libraries out of the donor. With
+//! `--object-store DIR`, each is stored once in `DIR`, named by its
+//! SHA-256 and mode, and hard-linked into every staging directory that
+//! needs it; where a hard link can't be made (the store is on another
+//! filesystem), it is [copied](crate::reflink::copy). Builds
+//! sharing a store may run at the same time: objects are written to a
+//! temporary file and renamed into place.
+//!
+//! A linked file shares its inode with the object, so staged binaries and
+//! libraries must not be changed in place. The steps that change their
+//! mode call [`unshare`] first; nothing writes to them.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::cache;
+use crate::checksum::sha256_file;
+use crate::context::BuildContext;
+use crate::inspect::ByteSize;
+use crate::reflink;
+use crate::status;
+use crate::xattrs;
+
+/// Tells temporary files of one process apart.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// An object store directory, and what one build took from it.
+#[derive(Debug)]
+pub struct ObjectStore {
+    dir: PathBuf,
+    linked: AtomicUsize,
+    added: AtomicUsize,
+    added_bytes: AtomicU64,
+}
+
+impl ObjectStore {
+    /// Use the store in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create object store {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            linked: AtomicUsize::new(0),
+            added: AtomicUsize::new(0),
+            added_bytes: AtomicU64::new(0),
+        })
+    }
+
+    /// Put the donor file `src` at `dest` as a link to its object, adding
+    /// the object first if the store doesn't have it.
+    pub fn link(&self, src: &Path, dest: &Path) -> Result<()> {
+        let meta =
+            fs::metadata(src).with_context(|| format!("Failed to read {}", src.display()))?;
+        let object = self.object_path(&sha256_file(src)?, meta.mode());
+        let object = match object.is_file() {
+            true => object,
+            false => self.add(src)?,
+        };
+
+        // Steps copying over an earlier copy expect it replaced
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(dest)?;
+        }
+        if fs::hard_link(&object, dest).is_err() {
+            reflink::copy(&object, dest)
+                .with_context(|| format!("Failed to copy {}", object.display()))?;
+        }
+        self.linked.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Copy `src` into the store, returning its object path.
+    fn add(&self, src: &Path) -> Result<PathBuf> {
+        let tmp = tmp_path(&self.dir.join("tmp"));
+        let stored = (|| {
+            reflink::copy(src, &tmp)?;
+            // Named after what was copied, in case the donor file changed
+            let meta = fs::metadata(&tmp)?;
+            let object = self.object_path(&sha256_file(&tmp)?, meta.mode());
+            fs::create_dir_all(object.parent().unwrap())?;
+            fs::rename(&tmp, &object)?;
+            self.added.fetch_add(1, Ordering::Relaxed);
+            self.added_bytes.fetch_add(meta.len(), Ordering::Relaxed);
+            anyhow::Ok(object)
+        })();
+        if stored.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        stored.with_context(|| format!("Failed to add {} to the object store", src.display()))
+    }
+
+    /// `DIR/ab/cdef...-755` for contents hashing to `abcdef...`.
+    fn object_path(&self, sha256: &str, mode: u32) -> PathBuf {
+        let (prefix, rest) = sha256.split_at(2);
+        self.dir
+            .join(prefix)
+            .join(format!("{}-{:o}", rest, mode & 0o7777))
+    }
+
+    /// Print how much of the build came from the store.
+    pub fn print_summary(&self) {
+        let linked = self.linked.load(Ordering::Relaxed);
+        let added = self.added.load(Ordering::Relaxed);
+        status!(
+            "Object store: {} files linked from {}, {} of them new ({})",
+            linked,
+            self.dir.display(),
+            added,
+            ByteSize(self.added_bytes.load(Ordering::Relaxed))
+        );
+    }
+}
+
+/// Copy donor binary or library `src` to staged `dest`, through the
+/// object store if the build has one.
+pub fn link_donor(ctx: &BuildContext, src: &Path, dest: &Path) -> Result<()> {
+    match ctx.store {
+        Some(ref store) => {
+            xattrs::record(ctx, src, dest);
+            store.link(src, dest)
+        }
+        None => cache::copy_donor(ctx, src, dest),
+    }
+}
+
+/// Give the staged file at `path` an inode of its own if it is linked to
+/// a store object, so changing it leaves the object alone.
+pub fn unshare(path: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+    if !meta.is_file() || meta.nlink() < 2 {
+        return Ok(());
+    }
+    let tmp = tmp_path(path);
+    reflink::copy(path, &tmp)
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("Failed to unlink {} from the object store", path.display()))
+}
+
+/// A temporary sibling of `path` no other thread or process uses.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
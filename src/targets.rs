@@ -0,0 +1,260 @@
+//! Several targets from one invocation.
+//!
+//! `stage3 build --target x86_64/server --target aarch64/minimal=/srv/rocky-arm`
+//! builds each architecture and profile with the same options, one output
+//! directory per target (`output/x86_64-server/`, ...). A target's donor
+//! is the one after `=`, or `--source`; its architecture has to match.
+//!
+//! Targets from the same donor and architecture share one
+//! [`LibraryCache`], so each library's dependencies are read once for all
+//! of them. With `--object-store`, every target links from the same store.
+//!
+//! Targets build concurrently, up to `--jobs` at a time. Their status lines
+//! are prefixed with the target then, and there is no spinner. A failed
+//! target doesn't stop the others: every outcome is printed at the end and
+//! written to `targets.json` in the output directory.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::builder::Stage3Builder;
+use crate::console::{self, Color};
+use crate::failure::{Failure, FailureKind};
+use crate::linker::LibraryCache;
+use crate::status;
+
+/// Combined report of a multi-target build, in the output directory.
+pub const TARGETS_REPORT_NAME: &str = "targets.json";
+
+/// An architecture and profile to build, optionally from its own donor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildTarget {
+    pub arch: String,
+    pub profile: String,
+    /// Donor rootfs for this target instead of `--source`
+    pub source: Option<PathBuf>,
+}
+
+impl FromStr for BuildTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (target, source) = match s.split_once('=') {
+            Some((target, source)) if !source.is_empty() => (target, Some(PathBuf::from(source))),
+            Some(_) => return Err(format!("missing source directory after '=' in {:?}", s)),
+            None => (s, None),
+        };
+        let (arch, profile) = target.split_once('/').ok_or_else(|| {
+            format!(
+                "expected ARCH/PROFILE[=SOURCE] (e.g. x86_64/server), got {:?}",
+                s
+            )
+        })?;
+        if arch.is_empty() || !arch.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid architecture {:?}", arch));
+        }
+        let valid_profile = !profile.is_empty()
+            && profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid_profile {
+            return Err(format!("invalid profile {:?}", profile));
+        }
+        Ok(Self {
+            arch: arch.to_string(),
+            profile: profile.to_string(),
+            source,
+        })
+    }
+}
+
+impl BuildTarget {
+    /// `arch/profile`.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.arch, self.profile)
+    }
+
+    /// Where the target's tarball and artifacts go under `output`.
+    pub fn output_dir(&self, output: &Path) -> PathBuf {
+        output.join(format!("{}-{}", self.arch, self.profile))
+    }
+
+    /// `base` set up for this target.
+    pub fn builder(&self, base: &Stage3Builder, output: &Path) -> Stage3Builder {
+        let mut builder = base
+            .clone()
+            .with_arch(&self.arch)
+            .with_profile(&self.profile)
+            .with_output(self.output_dir(output));
+        if let Some(ref source) = self.source {
+            builder = builder.with_source(source);
+        }
+        builder
+    }
+}
+
+/// How one target's build went.
+#[derive(Debug, Serialize)]
+pub struct TargetOutcome {
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tarball: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Failure class, for the exit code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<FailureKind>,
+    pub seconds: f64,
+}
+
+impl TargetOutcome {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Build every target from `base`, `jobs` at a time, and write
+/// [`TARGETS_REPORT_NAME`] to `output`.
+pub fn build_targets(
+    base: &Stage3Builder,
+    targets: &[BuildTarget],
+    output: &Path,
+    jobs: usize,
+) -> Result<Vec<TargetOutcome>> {
+    let mut names = BTreeSet::new();
+    for target in targets {
+        if !names.insert(target.name()) {
+            bail!("Target {} given twice", target.name());
+        }
+    }
+
+    let jobs = jobs.clamp(1, targets.len().max(1));
+    let concurrent = jobs > 1;
+    if concurrent {
+        console::set_step_bar(false);
+    }
+    status!("Building {} targets, {} at a time...", targets.len(), jobs);
+
+    let mut libraries: HashMap<(PathBuf, &str), Arc<LibraryCache>> = HashMap::new();
+    let builders: Vec<Stage3Builder> = targets
+        .iter()
+        .map(|target| {
+            let source = target.source.as_deref().unwrap_or(base.source_dir());
+            let cache = libraries
+                .entry((source.to_path_buf(), target.arch.as_str()))
+                .or_default()
+                .clone();
+            target.builder(base, output).with_library_cache(cache)
+        })
+        .collect();
+
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<TargetOutcome>>> =
+        Mutex::new(targets.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(target) = targets.get(index) else {
+                    break;
+                };
+                let outcome = build_target(&builders[index], target, concurrent);
+                outcomes.lock().unwrap()[index] = Some(outcome);
+            });
+        }
+    });
+    console::set_step_bar(true);
+
+    let outcomes: Vec<TargetOutcome> = outcomes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|outcome| outcome.expect("every target was built"))
+        .collect();
+    fs::create_dir_all(output)?;
+    fs::write(
+        output.join(TARGETS_REPORT_NAME),
+        serde_json::to_string_pretty(&outcomes)? + "\n",
+    )?;
+    Ok(outcomes)
+}
+
+fn build_target(builder: &Stage3Builder, target: &BuildTarget, concurrent: bool) -> TargetOutcome {
+    let name = target.name();
+    let started = Instant::now();
+    let result = if concurrent {
+        tracing::info_span!("target", name = %name).in_scope(|| builder.build())
+    } else {
+        console::section(&format!("Target {}", name));
+        builder.build()
+    };
+    let seconds = started.elapsed().as_secs_f64();
+    match result {
+        Ok(tarball) => TargetOutcome {
+            target: name,
+            tarball: Some(tarball),
+            error: None,
+            failure: None,
+            seconds,
+        },
+        Err(err) => {
+            tracing::error!("  {} failed: {:#}", name, err);
+            TargetOutcome {
+                target: name,
+                tarball: None,
+                error: Some(format!("{:#}", err)),
+                failure: err.kind(),
+                seconds,
+            }
+        }
+    }
+}
+
+/// Print one line per target.
+pub fn print_outcomes(outcomes: &[TargetOutcome]) {
+    println!();
+    for outcome in outcomes {
+        let (label, color, detail) = match (&outcome.tarball, &outcome.error) {
+            (Some(tarball), _) => ("ok", Color::Green, tarball.display().to_string()),
+            (None, Some(error)) => ("FAIL", Color::Red, error.clone()),
+            (None, None) => ("FAIL", Color::Red, String::new()),
+        };
+        println!(
+            "  {} {:<24} {:>6.1}s  {}",
+            console::paint(&format!("{:<4}", label), color),
+            outcome.target,
+            outcome.seconds,
+            detail
+        );
+    }
+}
+
+/// Fail if any target failed, with the first failure's class.
+pub fn check_outcomes(outcomes: &[TargetOutcome]) -> Result<()> {
+    let failed: Vec<&TargetOutcome> = outcomes.iter().filter(|o| !o.passed()).collect();
+    let Some(first) = failed.first() else {
+        return Ok(());
+    };
+    let message = format!(
+        "{} of {} targets failed: {}",
+        failed.len(),
+        outcomes.len(),
+        failed
+            .iter()
+            .map(|o| o.target.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    match first.failure {
+        Some(kind) => bail!(Failure::new(kind, message)),
+        None => bail!(message),
+    }
+}
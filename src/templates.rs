@@ -0,0 +1,176 @@
+//! Templates for the configuration files the build generates.
+//!
+//! Every config file written into the rootfs (/etc, PAM, networkd, ...)
+//! is a [minijinja] template under `templates/` in this repository, named
+//! by its path in the rootfs and embedded in the binary. A file at the same
+//! relative path in a template directory (`build --templates DIR`) replaces
+//! the built-in one, so deployments can change any of them without a code
+//! change.
+//!
+//! Templates can use the build's artifact values: `{{ version }}`,
+//! `{{ version_id }}`, `{{ arch }}`, `{{ date }}` and `{{ profile }}`.
+//! Unknown variables are an error rather than an empty string.
+
+use anyhow::{bail, Context, Result};
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::artifact::ArtifactInfo;
+use crate::context::BuildContext;
+
+macro_rules! builtin {
+    ($name:literal) => {
+        ($name, include_str!(concat!("../templates/", $name)))
+    };
+}
+
+/// Built-in templates by rootfs-relative path.
+pub const BUILTIN: &[(&str, &str)] = &[
+    builtin!("boot/loader/entries/levitateos.conf"),
+    builtin!("etc/adjtime"),
+    builtin!("etc/bashrc"),
+    builtin!("etc/crypttab"),
+    builtin!("etc/default/useradd"),
+    builtin!("etc/fstab"),
+    builtin!("etc/group"),
+    builtin!("etc/gshadow"),
+    builtin!("etc/hostname"),
+    builtin!("etc/hosts"),
+    builtin!("etc/levitate/healthcheck.conf"),
+    builtin!("etc/locale.conf"),
+    builtin!("etc/login.defs"),
+    builtin!("etc/nsswitch.conf"),
+    builtin!("etc/os-release"),
+    builtin!("etc/pam.d/chpasswd"),
+    builtin!("etc/pam.d/login"),
+    builtin!("etc/pam.d/other"),
+    builtin!("etc/pam.d/passwd"),
+    builtin!("etc/pam.d/password-auth"),
+    builtin!("etc/pam.d/sshd"),
+    builtin!("etc/pam.d/su"),
+    builtin!("etc/pam.d/sudo"),
+    builtin!("etc/pam.d/system-auth"),
+    builtin!("etc/pam.d/systemd-user"),
+    builtin!("etc/passwd"),
+    builtin!("etc/profile"),
+    builtin!("etc/securetty"),
+    builtin!("etc/security/access.conf"),
+    builtin!("etc/security/limits.conf"),
+    builtin!("etc/security/namespace.conf"),
+    builtin!("etc/security/pam_env.conf"),
+    builtin!("etc/security/pwquality.conf"),
+    builtin!("etc/shadow"),
+    builtin!("etc/shells"),
+    builtin!("etc/skel/.bash_profile"),
+    builtin!("etc/skel/.bashrc"),
+    builtin!("etc/ssh/sshd_config.d/01-levitate-lockdown.conf"),
+    builtin!("etc/sysconfig/chronyd"),
+    builtin!("etc/sysconfig/network"),
+    builtin!("etc/systemd/network/80-dhcp.network"),
+    builtin!("etc/vconsole.conf"),
+    builtin!("root/.bash_profile"),
+    builtin!("root/.bashrc"),
+    builtin!("usr/lib/levitate/healthcheck"),
+    builtin!("usr/lib/systemd/system/levitate-healthcheck.service"),
+    builtin!("usr/lib/systemd/system/levitate-random-seed-credential.service"),
+];
+
+/// Variables available to every template.
+#[derive(Serialize)]
+struct TemplateVars {
+    version: String,
+    version_id: String,
+    arch: String,
+    date: String,
+    profile: String,
+}
+
+/// The templates a build renders, with any user overrides applied.
+pub struct Templates {
+    env: Environment<'static>,
+    vars: TemplateVars,
+}
+
+impl Templates {
+    /// The built-in templates, optionally overridden from `dir`.
+    ///
+    /// Every file in `dir` must replace a built-in template; anything else
+    /// is most likely a misspelled path and is rejected.
+    pub fn new(info: &ArtifactInfo, dir: Option<&Path>) -> Result<Self> {
+        let mut env = Environment::new();
+        env.set_keep_trailing_newline(true);
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        for (name, source) in BUILTIN {
+            env.add_template(name, source)
+                .with_context(|| format!("Invalid built-in template {}", name))?;
+        }
+
+        if let Some(dir) = dir {
+            if !dir.is_dir() {
+                bail!("Template directory not found: {}", dir.display());
+            }
+            for entry in WalkDir::new(dir).sort_by_file_name() {
+                let entry = entry?;
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+                let rel = entry
+                    .path()
+                    .strip_prefix(dir)?
+                    .to_string_lossy()
+                    .to_string();
+                if !BUILTIN.iter().any(|(name, _)| *name == rel) {
+                    bail!(
+                        "{} does not override a built-in template (see templates/ in the stage3 source)",
+                        entry.path().display()
+                    );
+                }
+                let source = fs::read_to_string(entry.path())
+                    .with_context(|| format!("Failed to read {}", entry.path().display()))?;
+                env.add_template_owned(rel.clone(), source)
+                    .with_context(|| format!("Invalid template {}", entry.path().display()))?;
+            }
+        }
+
+        let vars = TemplateVars {
+            version: info.version.clone(),
+            version_id: info
+                .version
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            arch: info.arch.clone(),
+            date: info.date.clone(),
+            profile: info.profile.clone(),
+        };
+        let templates = Self { env, vars };
+
+        // Catch template errors before the build starts
+        for (name, _) in BUILTIN {
+            templates.render(name)?;
+        }
+        Ok(templates)
+    }
+
+    /// Render the template for a rootfs-relative path.
+    pub fn render(&self, name: &str) -> Result<String> {
+        self.env
+            .get_template(name)
+            .and_then(|template| template.render(&self.vars))
+            .with_context(|| format!("Failed to render template {}", name))
+    }
+}
+
+/// Render the template `name` into the same path in the staging tree.
+pub fn install(ctx: &BuildContext, name: &str) -> Result<()> {
+    let dest = ctx.staging.join(name);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&dest, ctx.templates.render(name)?)
+        .with_context(|| format!("Failed to write /{}", name))
+}
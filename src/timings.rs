@@ -0,0 +1,125 @@
+//! Where build time goes.
+//!
+//! Every step run through [`console::step`](crate::console::step) records
+//! its wall-clock time and the regular files it wrote to staging (new or
+//! changed since the step started) with their size. Archiving records how
+//! long compression took and how much it shrank the tar stream. The build
+//! prints the table after packaging and writes it to the manifest, so
+//! regressions show up when comparing the manifests of successive builds.
+//!
+//! Steps replayed from a checkpoint didn't run and aren't listed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+use crate::archive::Compression;
+
+/// Time and output of one build step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepTiming {
+    pub step: String,
+    pub elapsed_ms: u64,
+    /// Regular files the step created or changed in staging
+    pub files: u64,
+    /// Size of those files
+    pub bytes: u64,
+}
+
+/// Time spent compressing the tarball.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionTiming {
+    pub compression: Compression,
+    /// Time in the compressor, without writing the compressed stream out
+    pub elapsed_ms: u64,
+    /// Size of the tar stream
+    pub input_bytes: u64,
+    /// Size of the compressed tarball
+    pub output_bytes: u64,
+}
+
+/// Profile of a build, as written to the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timings {
+    pub steps: Vec<StepTiming>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionTiming>,
+    /// Sum of the step times
+    pub total_ms: u64,
+}
+
+/// Timings collected over a build.
+#[derive(Default)]
+pub struct BuildTimings {
+    steps: Mutex<Vec<StepTiming>>,
+    compression: Mutex<Option<CompressionTiming>>,
+}
+
+impl BuildTimings {
+    /// Record a finished step with what it wrote since `before`.
+    pub fn record(
+        &self,
+        step: &str,
+        elapsed: Duration,
+        before: &StagingSnapshot,
+        after: &StagingSnapshot,
+    ) {
+        let (files, bytes) = after.written_since(before);
+        self.steps.lock().unwrap().push(StepTiming {
+            step: step.to_string(),
+            elapsed_ms: elapsed.as_millis() as u64,
+            files,
+            bytes,
+        });
+    }
+
+    pub fn set_compression(&self, compression: CompressionTiming) {
+        *self.compression.lock().unwrap() = Some(compression);
+    }
+
+    /// Everything recorded so far.
+    pub fn summary(&self) -> Timings {
+        let steps = self.steps.lock().unwrap().clone();
+        Timings {
+            total_ms: steps.iter().map(|s| s.elapsed_ms).sum(),
+            steps,
+            compression: self.compression.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Size and mtime of every regular file in staging.
+#[derive(Debug, Default)]
+pub struct StagingSnapshot {
+    files: HashMap<PathBuf, (u64, SystemTime)>,
+}
+
+impl StagingSnapshot {
+    /// Take a snapshot of `staging`, leaving out what can't be read.
+    pub fn take(staging: &Path) -> Self {
+        let files = WalkDir::new(staging)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let stat = (metadata.len(), metadata.modified().ok()?);
+                Some((entry.into_path(), stat))
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Files new or changed since `before`, and their total size.
+    pub fn written_since(&self, before: &StagingSnapshot) -> (u64, u64) {
+        self.files
+            .iter()
+            .filter(|(path, stat)| before.files.get(*path) != Some(stat))
+            .fold((0, 0), |(files, bytes), (_, (size, _))| {
+                (files + 1, bytes + size)
+            })
+    }
+}
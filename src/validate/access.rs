@@ -0,0 +1,236 @@
+//! Check that the image can still be logged into.
+//!
+//! Every way in can be switched off on its own (`--lockdown` masks the
+//! gettys and rescue shells, a drop-in can disable key logins), so this
+//! looks at what is left: an enabled console getty, the rescue shell, or
+//! sshd with a key-accepting account. An image with none of them can only
+//! be recovered by reinstalling, so that is always an error.
+
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+use super::{config_lines, exists_in_root, read_config};
+use crate::detail;
+use crate::report::{BuildReport, Severity};
+use crate::secrets::Secret;
+
+/// Where systemd looks for enablement links and masks.
+const SYSTEM_DIR: &str = "etc/systemd/system";
+
+/// Getty templates started from getty.target.wants.
+const GETTY_TEMPLATES: &[&str] = &["getty@.service", "serial-getty@.service"];
+
+/// Links that start sshd at boot.
+const SSHD_WANTS: &[&str] = &[
+    "multi-user.target.wants/sshd.service",
+    "sockets.target.wants/sshd.socket",
+];
+
+/// Report an error unless a console, rescue or SSH login remains.
+///
+/// `secrets` count as shipped files since they are injected after
+/// validation, so an authorized_keys secret opens the SSH path.
+pub fn check_access(staging: &Path, report: &BuildReport, secrets: &[Secret]) -> Result<()> {
+    detail!("Checking access paths...");
+
+    let mut closed = Vec::new();
+    let paths = [
+        ("console getty", console_getty(staging)?),
+        ("rescue shell", rescue_shell(staging)),
+        ("SSH", ssh(staging, secrets)?),
+    ];
+    for (name, state) in paths {
+        match state {
+            Ok(how) => {
+                detail!("  {}: {}", name, how);
+                return Ok(());
+            }
+            Err(why) => {
+                detail!("  No {}: {}", name, why);
+                closed.push(format!("{} ({})", name, why));
+            }
+        }
+    }
+
+    report.push(
+        Severity::Error,
+        "access",
+        None,
+        format!(
+            "no way to log in remains: {}; ship a key with --authorized-keys or --secret",
+            closed.join(", ")
+        ),
+    );
+    Ok(())
+}
+
+/// Whether a unit is masked in /etc/systemd/system.
+fn is_masked(staging: &Path, unit: &str) -> bool {
+    fs::read_link(staging.join(SYSTEM_DIR).join(unit))
+        .map(|target| target == Path::new("/dev/null"))
+        .unwrap_or(false)
+}
+
+/// A unit that is shipped and not masked.
+fn is_available(staging: &Path, unit: &str) -> bool {
+    !is_masked(staging, unit)
+        && exists_in_root(staging, &Path::new("usr/lib/systemd/system").join(unit))
+}
+
+/// An enabled getty on a console.
+fn console_getty(staging: &Path) -> Result<Result<String, String>> {
+    let system = Path::new(SYSTEM_DIR);
+    if !exists_in_root(
+        staging,
+        &system.join("multi-user.target.wants/getty.target"),
+    ) || !is_available(staging, "getty.target")
+    {
+        return Ok(Err("getty.target is not enabled".to_string()));
+    }
+
+    let wants = staging.join(SYSTEM_DIR).join("getty.target.wants");
+    if !wants.is_dir() {
+        return Ok(Err("no getty is enabled".to_string()));
+    }
+    let mut enabled: Vec<String> = fs::read_dir(&wants)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|name| {
+            GETTY_TEMPLATES.iter().any(|template| {
+                let prefix = template.trim_end_matches(".service");
+                name.starts_with(prefix) && is_available(staging, template)
+            })
+        })
+        .collect();
+    enabled.sort();
+    Ok(match enabled.first() {
+        Some(getty) => Ok(format!("{} is enabled", getty)),
+        None => Err("no getty is enabled".to_string()),
+    })
+}
+
+/// rescue.target's sulogin shell.
+///
+/// Whether it actually works is `validate::rescue`'s job.
+fn rescue_shell(staging: &Path) -> Result<String, String> {
+    match is_available(staging, "rescue.service") && is_available(staging, "rescue.target") {
+        true => Ok("rescue.target is available".to_string()),
+        false => Err("rescue.target is masked or missing".to_string()),
+    }
+}
+
+/// sshd enabled, accepting keys, and an account with a key.
+fn ssh(staging: &Path, secrets: &[Secret]) -> Result<Result<String, String>> {
+    if !exists_in_root(staging, Path::new("usr/sbin/sshd")) {
+        return Ok(Err("sshd is not shipped".to_string()));
+    }
+    let enabled = SSHD_WANTS.iter().any(|link| {
+        let unit = Path::new(link).file_name().unwrap().to_string_lossy();
+        exists_in_root(staging, &Path::new(SYSTEM_DIR).join(link)) && is_available(staging, &unit)
+    });
+    if !enabled {
+        return Ok(Err("sshd is not enabled".to_string()));
+    }
+
+    let options = SshdOptions::read(staging)?;
+    if options.get("pubkeyauthentication") == Some("no") {
+        return Ok(Err("sshd has PubkeyAuthentication no".to_string()));
+    }
+    let root_allowed = options.get("permitrootlogin") != Some("no");
+
+    let Some(passwd) = read_config(staging, "etc/passwd")? else {
+        return Ok(Err("/etc/passwd is missing".to_string()));
+    };
+    for (_, line) in config_lines(&passwd) {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [name, _, _, _, _, home, shell] = fields[..] else {
+            continue;
+        };
+        if shell.ends_with("nologin") || shell.ends_with("false") || !home.starts_with('/') {
+            continue;
+        }
+        if name == "root" && !root_allowed {
+            continue;
+        }
+        let keys = format!("{}/.ssh/authorized_keys", home.trim_matches('/'));
+        let injected = secrets.iter().any(|s| s.dest == Path::new(&keys));
+        if injected || exists_in_root(staging, Path::new(&keys)) {
+            return Ok(Ok(format!("sshd accepts keys for {} (/{})", name, keys)));
+        }
+    }
+    Ok(Err(
+        "no login account has an authorized_keys file".to_string()
+    ))
+}
+
+/// Global sshd options in effect, drop-ins included.
+///
+/// `Match` blocks are ignored; like sshd, the first value of an option wins.
+struct SshdOptions(Vec<(String, String)>);
+
+impl SshdOptions {
+    fn read(staging: &Path) -> Result<Self> {
+        let mut options = Vec::new();
+        Self::read_file(staging, "etc/ssh/sshd_config", &mut options)?;
+        Ok(Self(options))
+    }
+
+    fn read_file(staging: &Path, file: &str, options: &mut Vec<(String, String)>) -> Result<()> {
+        let Some(contents) = read_config(staging, file)? else {
+            return Ok(());
+        };
+        for (_, line) in config_lines(&contents) {
+            let line = line.trim();
+            let (key, value) = line
+                .split_once(|c: char| c.is_whitespace() || c == '=')
+                .unwrap_or((line, ""));
+            let key = key.to_ascii_lowercase();
+            let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=');
+            match key.as_str() {
+                "match" => break,
+                "include" => {
+                    for pattern in value.split_whitespace() {
+                        for included in expand_include(staging, pattern)? {
+                            Self::read_file(staging, &included, options)?;
+                        }
+                    }
+                }
+                _ => options.push((key, value.trim().to_ascii_lowercase())),
+            }
+        }
+        Ok(())
+    }
+
+    /// First value of a lowercase option name.
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Files matched by an `Include` argument (only `*` in the file name).
+fn expand_include(staging: &Path, pattern: &str) -> Result<Vec<String>> {
+    let pattern = pattern.trim_start_matches('/');
+    let pattern = match pattern.starts_with("etc/") {
+        true => pattern.to_string(),
+        false => format!("etc/ssh/{}", pattern),
+    };
+    let (dir, name) = pattern.rsplit_once('/').unwrap_or(("", &pattern));
+    let Some((prefix, suffix)) = name.split_once('*') else {
+        return Ok(vec![pattern.clone()]);
+    };
+    let Ok(entries) = fs::read_dir(staging.join(dir)) else {
+        return Ok(Vec::new());
+    };
+    let mut files: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|f| f.starts_with(prefix) && f.ends_with(suffix))
+        .map(|f| format!("{}/{}", dir, f))
+        .collect();
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,278 @@
+//! Consistency checks of the account databases.
+//!
+//! passwd, group, shadow and gshadow have to agree with each other and with
+//! the image: a user without a shadow entry or with a shell that isn't
+//! shipped can't log in, and a duplicate UID silently merges two accounts.
+
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::{config_lines, exists_in_root, read_config, Findings};
+use crate::detail;
+use crate::report::BuildReport;
+
+/// Shells that refuse logins; they don't belong in /etc/shells.
+const NOLOGIN_SHELLS: &[&str] = &[
+    "/usr/sbin/nologin",
+    "/sbin/nologin",
+    "/usr/bin/false",
+    "/bin/false",
+];
+
+/// An /etc/passwd entry.
+struct User<'a> {
+    line: usize,
+    name: &'a str,
+    uid: u32,
+    gid: u32,
+    home: &'a str,
+    shell: &'a str,
+}
+
+/// Check passwd, group, shadow and gshadow against each other and the image.
+///
+/// Findings are errors in strict mode and warnings otherwise.
+pub fn check_accounts(staging: &Path, report: &BuildReport, strict: bool) -> Result<()> {
+    detail!("Checking accounts...");
+
+    let mut findings = Findings::new(report, "accounts", strict);
+    let (Some(passwd), Some(group)) = (
+        read_config(staging, "etc/passwd")?,
+        read_config(staging, "etc/group")?,
+    ) else {
+        findings.add("etc/passwd", None, "passwd or group missing".to_string());
+        return Ok(());
+    };
+
+    let groups = parse_group(&group, &mut findings);
+    let users = parse_passwd(&passwd, &mut findings);
+    check_users(staging, &users, &groups, &mut findings)?;
+
+    let user_names: Vec<(&str, usize)> = users.iter().map(|u| (u.name, u.line)).collect();
+    let group_names: Vec<(&str, usize)> = groups.iter().map(|(n, (_, l))| (*n, *l)).collect();
+    check_shadow(
+        staging,
+        "etc/shadow",
+        9,
+        "etc/passwd",
+        &user_names,
+        &mut findings,
+    )?;
+    check_shadow(
+        staging,
+        "etc/gshadow",
+        4,
+        "etc/group",
+        &group_names,
+        &mut findings,
+    )?;
+
+    if findings.count == 0 {
+        detail!(
+            "  {} users and {} groups are consistent",
+            users.len(),
+            groups.len()
+        );
+    }
+    Ok(())
+}
+
+/// Parse /etc/group into name -> (GID, line), reporting malformed lines.
+fn parse_group<'a>(contents: &'a str, findings: &mut Findings) -> BTreeMap<&'a str, (u32, usize)> {
+    let mut groups = BTreeMap::new();
+    let mut gids = BTreeSet::new();
+
+    for (number, line) in config_lines(contents) {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() != 4 {
+            findings.add(
+                "etc/group",
+                Some(number),
+                format!("expected 4 fields, found {}", fields.len()),
+            );
+            continue;
+        }
+        let Ok(gid) = fields[2].parse::<u32>() else {
+            findings.add(
+                "etc/group",
+                Some(number),
+                format!("GID {:?} is not a number", fields[2]),
+            );
+            continue;
+        };
+        if groups.insert(fields[0], (gid, number)).is_some() {
+            findings.add(
+                "etc/group",
+                Some(number),
+                format!("duplicate group {}", fields[0]),
+            );
+        }
+        if !gids.insert(gid) {
+            findings.add("etc/group", Some(number), format!("duplicate GID {}", gid));
+        }
+    }
+    groups
+}
+
+/// Parse /etc/passwd, reporting malformed lines and duplicate names or UIDs.
+fn parse_passwd<'a>(contents: &'a str, findings: &mut Findings) -> Vec<User<'a>> {
+    let mut users: Vec<User> = Vec::new();
+    let mut uids = BTreeSet::new();
+
+    for (number, line) in config_lines(contents) {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() != 7 {
+            findings.add(
+                "etc/passwd",
+                Some(number),
+                format!("expected 7 fields, found {}", fields.len()),
+            );
+            continue;
+        }
+        let (Ok(uid), Ok(gid)) = (fields[2].parse::<u32>(), fields[3].parse::<u32>()) else {
+            findings.add(
+                "etc/passwd",
+                Some(number),
+                format!("UID {:?} or GID {:?} is not a number", fields[2], fields[3]),
+            );
+            continue;
+        };
+        if users.iter().any(|u| u.name == fields[0]) {
+            findings.add(
+                "etc/passwd",
+                Some(number),
+                format!("duplicate user {}", fields[0]),
+            );
+            continue;
+        }
+        if !uids.insert(uid) {
+            findings.add("etc/passwd", Some(number), format!("duplicate UID {}", uid));
+        }
+        users.push(User {
+            line: number,
+            name: fields[0],
+            uid,
+            gid,
+            home: fields[5],
+            shell: fields[6],
+        });
+    }
+    users
+}
+
+/// Check each user's primary group, shell and home directory.
+fn check_users(
+    staging: &Path,
+    users: &[User],
+    groups: &BTreeMap<&str, (u32, usize)>,
+    findings: &mut Findings,
+) -> Result<()> {
+    let gids: BTreeSet<u32> = groups.values().map(|(gid, _)| *gid).collect();
+    let shells: BTreeSet<String> = read_config(staging, "etc/shells")?
+        .map(|contents| {
+            config_lines(&contents)
+                .map(|(_, line)| line.trim().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for user in users {
+        let mut add = |message: String| findings.add("etc/passwd", Some(user.line), message);
+
+        if !gids.contains(&user.gid) {
+            add(format!(
+                "{} has primary GID {} which is not in /etc/group",
+                user.name, user.gid
+            ));
+        }
+
+        let login = !user.shell.is_empty() && !NOLOGIN_SHELLS.contains(&user.shell);
+        if !user.shell.is_empty() && !exists_in_root(staging, Path::new(user.shell)) {
+            add(format!(
+                "{} has shell {} which is not shipped",
+                user.name, user.shell
+            ));
+        } else if login && !shells.contains(user.shell) {
+            add(format!(
+                "{} has shell {} which is not listed in /etc/shells",
+                user.name, user.shell
+            ));
+        }
+
+        if !user.home.starts_with('/') {
+            add(format!(
+                "{} has a relative home directory {:?}",
+                user.name, user.home
+            ));
+        } else if login && !exists_in_root(staging, Path::new(user.home)) {
+            // System accounts get theirs from tmpfiles or StateDirectory=
+            add(format!(
+                "{} (UID {}) can log in but home {} does not exist",
+                user.name, user.uid, user.home
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check a shadow file against the names of its passwd or group file.
+///
+/// `names` are the entries of `database` with their line numbers; each
+/// needs an entry in `file`, and `file` may list nothing else.
+fn check_shadow(
+    staging: &Path,
+    file: &str,
+    fields: usize,
+    database: &str,
+    names: &[(&str, usize)],
+    findings: &mut Findings,
+) -> Result<()> {
+    let Some(contents) = read_config(staging, file)? else {
+        findings.add(file, None, format!("missing, but {} exists", database));
+        return Ok(());
+    };
+
+    let mode = fs::metadata(staging.join(file))?.mode() & 0o777;
+    if mode & 0o077 != 0 {
+        findings.add(
+            file,
+            None,
+            format!("mode {:o} lets other users read password hashes", mode),
+        );
+    }
+
+    let mut listed = BTreeSet::new();
+    for (number, line) in config_lines(&contents) {
+        let parts: Vec<&str> = line.split(':').collect();
+        if parts.len() != fields {
+            findings.add(
+                file,
+                Some(number),
+                format!("expected {} fields, found {}", fields, parts.len()),
+            );
+            continue;
+        }
+        if !names.iter().any(|(name, _)| *name == parts[0]) {
+            findings.add(
+                file,
+                Some(number),
+                format!("{} is not in /{}", parts[0], database),
+            );
+        }
+        listed.insert(parts[0]);
+    }
+
+    for (name, line) in names {
+        if !listed.contains(name) {
+            findings.add(
+                database,
+                Some(*line),
+                format!("{} has no entry in /{}", name, file),
+            );
+        }
+    }
+    Ok(())
+}
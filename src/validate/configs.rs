@@ -0,0 +1,250 @@
+//! Structural checks of generated configuration files.
+//!
+//! The templates are rendered without knowing what the files mean, so a
+//! typo in an override (a misspelled PAM control, a short fstab line)
+//! would otherwise only surface at boot or login. Each finding points at
+//! the file and line. The account databases are checked in `accounts`.
+
+use anyhow::Result;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::{config_lines, read_config, resolve_in_root, Findings};
+use crate::arch;
+use crate::binary::elf_arch;
+use crate::detail;
+use crate::placeholders::{pending, unknown_tokens, PLACEHOLDER_FILES};
+use crate::report::BuildReport;
+use crate::sandbox::{self, Sandbox};
+
+/// PAM management groups.
+const PAM_TYPES: &[&str] = &["auth", "account", "password", "session"];
+
+/// PAM control keywords (the `[value=action ...]` form is checked separately).
+const PAM_CONTROLS: &[&str] = &[
+    "required",
+    "requisite",
+    "sufficient",
+    "optional",
+    "include",
+    "substack",
+];
+
+/// Configs checked with their own tool from the staging tree, when shipped.
+///
+/// (config, tool, arguments)
+const TOOL_CHECKS: &[(&str, &str, &[&str])] = &[
+    (
+        "etc/ssh/sshd_config",
+        "usr/sbin/sshd",
+        &["-t", "-f", "/etc/ssh/sshd_config"],
+    ),
+    (
+        "etc/sudoers",
+        "usr/sbin/visudo",
+        &["-c", "-f", "/etc/sudoers"],
+    ),
+];
+
+/// Validate fstab and PAM files, and configs with their own checker.
+///
+/// Findings are errors in strict mode and warnings otherwise.
+pub fn check_configs(
+    staging: &Path,
+    report: &BuildReport,
+    strict: bool,
+    sandbox: Sandbox,
+) -> Result<()> {
+    detail!("Checking generated configuration...");
+
+    let mut findings = Findings::new(report, "configs", strict);
+    check_fstab(staging, &mut findings)?;
+    check_placeholders(staging, &mut findings)?;
+    check_pam(staging, &mut findings)?;
+    run_tool_checks(staging, &mut findings, sandbox)?;
+
+    if findings.count == 0 {
+        detail!("  Generated configuration is well-formed");
+    }
+    Ok(())
+}
+
+/// Check the placeholder files only use placeholders `finalize` knows.
+fn check_placeholders(staging: &Path, findings: &mut Findings) -> Result<()> {
+    for file in PLACEHOLDER_FILES {
+        let Some(contents) = read_config(staging, file)? else {
+            continue;
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = pending(line).unwrap_or(line);
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let number = index + 1;
+            for token in unknown_tokens(line) {
+                findings.add(
+                    file,
+                    Some(number),
+                    format!(
+                        "unknown placeholder {} (stage3 finalize would reject it)",
+                        token
+                    ),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check every /etc/fstab entry has 4 to 6 fields with numeric dump and pass.
+fn check_fstab(staging: &Path, findings: &mut Findings) -> Result<()> {
+    let Some(contents) = read_config(staging, "etc/fstab")? else {
+        return Ok(());
+    };
+
+    for (number, line) in config_lines(&contents) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if !(4..=6).contains(&fields.len()) {
+            findings.add(
+                "etc/fstab",
+                Some(number),
+                format!("expected 4 to 6 fields, found {}", fields.len()),
+            );
+            continue;
+        }
+        for (field, name) in fields.iter().skip(4).zip(["dump", "pass"]) {
+            if field.parse::<u32>().is_err() {
+                findings.add(
+                    "etc/fstab",
+                    Some(number),
+                    format!("{} field {:?} is not a number", name, field),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the syntax of every file in /etc/pam.d.
+///
+/// Whether the referenced modules exist is `validate::pam`'s job.
+fn check_pam(staging: &Path, findings: &mut Findings) -> Result<()> {
+    let pam_dir = staging.join("etc/pam.d");
+    if !pam_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut files: Vec<_> = fs::read_dir(&pam_dir)?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .collect();
+    files.sort_by_key(|e| e.file_name());
+
+    for file in files {
+        let rel = format!("etc/pam.d/{}", file.file_name().to_string_lossy());
+        let contents = fs::read_to_string(file.path())?;
+        for (number, line) in config_lines(&contents) {
+            if let Some(problem) = pam_syntax_error(line.trim()) {
+                findings.add(&rel, Some(number), problem);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What is wrong with a PAM config line, if anything.
+fn pam_syntax_error(line: &str) -> Option<String> {
+    if let Some(file) = line.strip_prefix("@include") {
+        return match file.trim() {
+            "" => Some("@include without a file".to_string()),
+            _ => None,
+        };
+    }
+
+    let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if !PAM_TYPES.contains(&kind.trim_start_matches('-')) {
+        return Some(format!(
+            "unknown type {:?} (expected {})",
+            kind,
+            PAM_TYPES.join(", ")
+        ));
+    }
+
+    let rest = rest.trim_start();
+    let module = if let Some(bracketed) = rest.strip_prefix('[') {
+        let Some((_, after)) = bracketed.split_once(']') else {
+            return Some("unterminated [...] control".to_string());
+        };
+        after
+    } else {
+        let (control, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if !PAM_CONTROLS.contains(&control) {
+            return Some(format!(
+                "unknown control {:?} (expected {} or [...])",
+                control,
+                PAM_CONTROLS.join(", ")
+            ));
+        }
+        after
+    };
+
+    match module.split_whitespace().next() {
+        None => Some("no module or included file".to_string()),
+        Some(_) => None,
+    }
+}
+
+/// Run each shipped config's own checker in a chroot of the staging tree.
+fn run_tool_checks(staging: &Path, findings: &mut Findings, sandbox: Sandbox) -> Result<()> {
+    let checks: Vec<_> = TOOL_CHECKS
+        .iter()
+        .filter(|(config, tool, _)| staging.join(config).exists() && staging.join(tool).exists())
+        .collect();
+    if checks.is_empty() {
+        return Ok(());
+    }
+
+    let is_root = fs::metadata("/proc/self")?.uid() == 0;
+    if !is_root || sandbox::find_program("chroot").is_none() {
+        for (config, tool, _) in checks {
+            findings.skip(config, &format!("needs root and chroot to run /{}", tool));
+        }
+        return Ok(());
+    }
+
+    for (config, tool, args) in checks {
+        // A checker built for another architecture can't run here
+        let binary = resolve_in_root(staging, Path::new(tool));
+        match binary.as_deref().and_then(elf_arch) {
+            Some(arch) if !arch::is_host(arch) => {
+                findings.skip(config, &format!("/{} is for {}, not this host", tool, arch));
+                continue;
+            }
+            _ => {}
+        }
+        let output = sandbox
+            .command("chroot")
+            .arg(staging)
+            .arg(format!("/{}", tool))
+            .args(*args)
+            .output()?;
+        if output.status.success() {
+            continue;
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut reported = false;
+        for line in stderr.lines().filter(|l| !l.trim().is_empty()) {
+            findings.add(config, None, line.trim().to_string());
+            reported = true;
+        }
+        if !reported {
+            findings.add(
+                config,
+                None,
+                format!("/{} rejected it ({})", tool, output.status),
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Post-staging validation of the rootfs.
+//!
+//! These checks run against the finished staging tree and catch problems
+//! that would otherwise only show up when the installed system boots.
+
+pub mod access;
+pub mod accounts;
+pub mod configs;
+pub mod pam;
+pub mod rescue;
+pub mod symlinks;
+pub mod units;
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::detail;
+use crate::report::{BuildReport, Severity};
+use crate::resolve;
+
+/// Resolve `path` as seen from inside `root`, following symlinks.
+///
+/// Absolute symlink targets are resolved relative to `root` rather than the
+/// build host. Returns the host path of the resolved target, or `None` if it
+/// doesn't exist inside the rootfs.
+pub fn resolve_in_root(root: &Path, path: &Path) -> Option<PathBuf> {
+    resolve::resolve(path, |rel| resolve::host_node(root, rel)).map(|rel| root.join(rel))
+}
+
+/// Check whether `path` exists inside `root`, following symlinks within it.
+pub fn exists_in_root(root: &Path, path: &Path) -> bool {
+    resolve_in_root(root, path).is_some()
+}
+
+/// Findings of a config check, reported with `/file:line` subjects.
+///
+/// Findings are errors in strict mode and warnings otherwise.
+pub struct Findings<'a> {
+    report: &'a BuildReport,
+    check: &'static str,
+    severity: Severity,
+    /// Findings added so far
+    pub count: usize,
+}
+
+impl<'a> Findings<'a> {
+    pub fn new(report: &'a BuildReport, check: &'static str, strict: bool) -> Self {
+        Self {
+            report,
+            check,
+            severity: if strict {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            count: 0,
+        }
+    }
+
+    /// Record a finding in a rootfs-relative `file`, at `line` if known.
+    pub fn add(&mut self, file: &str, line: Option<usize>, message: String) {
+        let subject = match line {
+            Some(line) => format!("/{}:{}", file, line),
+            None => format!("/{}", file),
+        };
+        detail!("  {:?}: {} {}", self.severity, subject, message);
+        self.report
+            .push(self.severity, self.check, Some(&subject), message);
+        self.count += 1;
+    }
+
+    /// Record that `file` could not be checked.
+    pub fn skip(&self, file: &str, message: &str) {
+        detail!("  Skipping /{}: {}", file, message);
+        self.report
+            .skip(self.check, Some(&format!("/{}", file)), message);
+    }
+}
+
+/// Non-comment, non-empty lines with their 1-based line numbers.
+pub fn config_lines(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+}
+
+/// Read a rootfs-relative config file, if it exists.
+pub fn read_config(root: &Path, file: &str) -> Result<Option<String>> {
+    let path = root.join(file);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?))
+}
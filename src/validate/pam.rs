@@ -0,0 +1,128 @@
+//! PAM stack checks.
+
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+use super::exists_in_root;
+use crate::detail;
+use crate::report::{BuildReport, Severity};
+
+/// A module or included stack referenced from a PAM config line.
+enum Reference<'a> {
+    Module(&'a str),
+    Include(&'a str),
+}
+
+/// Parse the module (or included file) a PAM config line refers to.
+fn parse_line(line: &str) -> Option<Reference<'_>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if let Some(file) = line.strip_prefix("@include") {
+        return Some(Reference::Include(file.trim()));
+    }
+
+    let (kind, rest) = line.split_once(char::is_whitespace)?;
+    // `-type` lines are silently skipped by libpam when the module is missing
+    if kind.starts_with('-') {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let (control, rest) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let (_, after) = bracketed.split_once(']')?;
+        ("[...]", after)
+    } else {
+        rest.split_once(char::is_whitespace)?
+    };
+    let target = rest.split_whitespace().next()?;
+
+    match control {
+        "include" | "substack" => Some(Reference::Include(target)),
+        _ => Some(Reference::Module(target)),
+    }
+}
+
+/// Check every module and included stack referenced from /etc/pam.d exists.
+///
+/// Relative module names are looked up in `module_dir`, the rootfs-relative
+/// directory libpam loads them from. Missing references are errors in
+/// strict mode and warnings otherwise.
+pub fn check_pam_modules(
+    staging: &Path,
+    module_dir: &Path,
+    report: &BuildReport,
+    strict: bool,
+) -> Result<()> {
+    detail!("Checking PAM modules...");
+
+    let pam_dir = staging.join("etc/pam.d");
+    if !pam_dir.exists() {
+        detail!("  Warning: /etc/pam.d not found, skipping");
+        report.skip("pam-modules", Some("/etc/pam.d"), "not found");
+        return Ok(());
+    }
+
+    let mut files: Vec<_> = fs::read_dir(&pam_dir)?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .collect();
+    files.sort_by_key(|e| e.file_name());
+
+    let severity = if strict {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    let mut missing = 0;
+
+    for file in &files {
+        let name = file.file_name().to_string_lossy().into_owned();
+        let contents = fs::read_to_string(file.path())?;
+
+        for line in contents.lines() {
+            let (path, what) = match parse_line(line) {
+                Some(Reference::Module(module)) if module.starts_with('/') => {
+                    (module.to_string(), "module")
+                }
+                Some(Reference::Module(module)) => {
+                    (format!("{}/{}", module_dir.display(), module), "module")
+                }
+                Some(Reference::Include(include)) if include.starts_with('/') => {
+                    (include.to_string(), "included stack")
+                }
+                Some(Reference::Include(include)) => {
+                    (format!("etc/pam.d/{}", include), "included stack")
+                }
+                None => continue,
+            };
+
+            if !exists_in_root(staging, Path::new(&path)) {
+                let message = format!(
+                    "references {} /{} which is not shipped",
+                    what,
+                    path.trim_start_matches('/')
+                );
+                let label = if strict { "Error" } else { "Warning" };
+                detail!("  {}: /etc/pam.d/{} {}", label, name, message);
+                report.push(
+                    severity,
+                    "pam",
+                    Some(&format!("/etc/pam.d/{}", name)),
+                    message,
+                );
+                missing += 1;
+            }
+        }
+    }
+
+    if missing == 0 {
+        detail!(
+            "  All modules referenced by {} PAM files present",
+            files.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Rescue and emergency mode checks.
+//!
+//! rescue.target and emergency.target are what a broken install falls back
+//! to, so everything they need has to work without the rest of the system:
+//! the units, systemd-sulogin-shell, sulogin, a loadable root shell and the
+//! password agents that talk to the console.
+
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+use super::{exists_in_root, resolve_in_root};
+use crate::arch::arch_lib_dirs;
+use crate::binary::elf_arch;
+use crate::detail;
+use crate::elf;
+use crate::report::{BuildReport, Severity};
+
+/// Units the fallback boot targets are made of.
+const RESCUE_UNITS: &[&str] = &[
+    "rescue.target",
+    "rescue.service",
+    "emergency.target",
+    "emergency.service",
+];
+
+/// Programs the rescue and emergency services run.
+const RESCUE_PROGRAMS: &[&[&str]] = &[
+    &["usr/lib/systemd/systemd-sulogin-shell"],
+    &["usr/sbin/sulogin", "usr/bin/sulogin"],
+];
+
+/// Password agent units and the unit or program each one needs.
+const ASK_PASSWORD: &[(&str, &str)] = &[
+    (
+        "systemd-ask-password-console.path",
+        "usr/lib/systemd/system/systemd-ask-password-console.service",
+    ),
+    (
+        "systemd-ask-password-wall.path",
+        "usr/lib/systemd/system/systemd-ask-password-wall.service",
+    ),
+    (
+        "systemd-ask-password-console.service",
+        "usr/bin/systemd-tty-ask-password-agent",
+    ),
+    (
+        "systemd-ask-password-wall.service",
+        "usr/bin/systemd-tty-ask-password-agent",
+    ),
+];
+
+/// Library directories searched for the root shell's dependencies.
+const LIB_DIRS: &[&str] = &["lib64", "usr/lib64", "lib", "usr/lib"];
+
+/// Check that rescue.target and emergency.target can give a working shell.
+///
+/// Missing pieces are errors in strict mode and warnings otherwise. A locked
+/// root account is always a warning since the installer normally sets the
+/// password.
+pub fn check_rescue(staging: &Path, report: &BuildReport, strict: bool) -> Result<()> {
+    detail!("Checking rescue and emergency mode...");
+
+    let severity = if strict {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    let label = if strict { "Error" } else { "Warning" };
+    let mut problems = Vec::new();
+
+    for unit in RESCUE_UNITS {
+        let path = format!("usr/lib/systemd/system/{}", unit);
+        if !exists_in_root(staging, Path::new(&path)) {
+            problems.push((format!("/{}", path), "is not shipped".to_string()));
+        }
+    }
+
+    for candidates in RESCUE_PROGRAMS {
+        if !candidates
+            .iter()
+            .any(|p| exists_in_root(staging, Path::new(p)))
+        {
+            problems.push((format!("/{}", candidates[0]), "is not shipped".to_string()));
+        }
+    }
+
+    for (unit, needs) in ASK_PASSWORD {
+        let unit_path = format!("usr/lib/systemd/system/{}", unit);
+        if exists_in_root(staging, Path::new(&unit_path))
+            && !exists_in_root(staging, Path::new(needs))
+        {
+            problems.push((format!("/{}", unit_path), format!("needs /{}", needs)));
+        }
+    }
+
+    let shell = root_shell(staging).unwrap_or_else(|| "/bin/sh".to_string());
+    match resolve_in_root(staging, Path::new(&shell)) {
+        Some(resolved) => {
+            for missing in missing_libraries(staging, &resolved) {
+                problems.push((
+                    shell.clone(),
+                    format!("needs {} which is not shipped", missing),
+                ));
+            }
+        }
+        None => problems.push((shell.clone(), "root shell is not shipped".to_string())),
+    }
+
+    for (subject, message) in &problems {
+        detail!("  {}: {} {}", label, subject, message);
+        report.push(severity, "rescue", Some(subject), message.clone());
+    }
+
+    if root_locked(staging) {
+        let message = "root account is locked, so sulogin will refuse the emergency shell";
+        detail!("  Warning: {}", message);
+        report.warn("rescue", Some("/etc/shadow"), message);
+    }
+
+    if problems.is_empty() {
+        detail!("  Rescue and emergency mode complete");
+    }
+
+    Ok(())
+}
+
+/// Login shell of root from /etc/passwd.
+fn root_shell(staging: &Path) -> Option<String> {
+    let passwd = fs::read_to_string(staging.join("etc/passwd")).ok()?;
+    passwd
+        .lines()
+        .find(|line| line.starts_with("root:"))
+        .and_then(|line| line.split(':').nth(6))
+        .filter(|shell| !shell.is_empty())
+        .map(str::to_string)
+}
+
+/// Whether root's password in /etc/shadow is locked or unset.
+fn root_locked(staging: &Path) -> bool {
+    let Ok(shadow) = fs::read_to_string(staging.join("etc/shadow")) else {
+        return false;
+    };
+    shadow
+        .lines()
+        .find(|line| line.starts_with("root:"))
+        .and_then(|line| line.split(':').nth(1))
+        .is_some_and(|hash| hash.starts_with('!') || hash.starts_with('*'))
+}
+
+/// Interpreter and libraries of an ELF file that are missing from the rootfs.
+///
+/// Static binaries and scripts have nothing to miss.
+fn missing_libraries(staging: &Path, binary: &Path) -> Vec<String> {
+    let Some(info) = fs::read(binary)
+        .ok()
+        .and_then(|bytes| elf::dynamic_info(&bytes))
+    else {
+        return Vec::new();
+    };
+
+    let mut missing = Vec::new();
+    if let Some(interpreter) = info.interpreter {
+        if !exists_in_root(staging, Path::new(&interpreter)) {
+            missing.push(interpreter);
+        }
+    }
+    let multiarch = elf_arch(binary).map(arch_lib_dirs).unwrap_or_default();
+    for needed in info.needed {
+        let found = multiarch
+            .iter()
+            .map(String::as_str)
+            .chain(LIB_DIRS.iter().copied())
+            .any(|dir| exists_in_root(staging, &Path::new(dir).join(&needed)));
+        if !found {
+            missing.push(needed);
+        }
+    }
+    missing
+}
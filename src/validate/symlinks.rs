@@ -0,0 +1,84 @@
+//! Dangling symlink checks.
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+use super::exists_in_root;
+use crate::detail;
+use crate::report::BuildReport;
+
+/// Top-level directories populated at runtime; links into them can't be
+/// checked against the rootfs.
+pub const RUNTIME_DIRS: &[&str] = &["proc", "sys", "dev", "run"];
+
+/// Rootfs path a symlink points at, resolved lexically (one hop).
+pub fn lexical_target(link: &Path, target: &Path) -> PathBuf {
+    let mut parts: Vec<&std::ffi::OsStr> = Vec::new();
+    if target.is_relative() {
+        if let Some(parent) = link.parent() {
+            parts.extend(parent.components().filter_map(|c| match c {
+                Component::Normal(part) => Some(part),
+                _ => None,
+            }));
+        }
+    }
+    for component in target.components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            _ => {}
+        }
+    }
+    parts.iter().collect()
+}
+
+/// Whether a rootfs path lives under a runtime-only directory.
+pub fn is_runtime_path(path: &Path) -> bool {
+    path.components()
+        .next()
+        .is_some_and(|c| RUNTIME_DIRS.iter().any(|dir| c.as_os_str() == *dir))
+}
+
+/// Report symlinks in staging whose targets don't exist inside the rootfs.
+///
+/// Absolute targets are resolved against the staging root, never the host.
+pub fn check_symlinks(staging: &Path, report: &BuildReport) -> Result<()> {
+    detail!("Checking for dangling symlinks...");
+
+    let mut dangling = 0;
+    for entry in WalkDir::new(staging).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.path_is_symlink() {
+            continue;
+        }
+
+        let rel = entry.path().strip_prefix(staging)?;
+        let target = fs::read_link(entry.path())?;
+        if is_runtime_path(&lexical_target(rel, &target)) {
+            continue;
+        }
+
+        if !exists_in_root(staging, rel) {
+            detail!(
+                "  Warning: /{} -> {} is dangling",
+                rel.display(),
+                target.display()
+            );
+            report.warn(
+                "symlinks",
+                Some(&format!("/{}", rel.display())),
+                format!("points at {} which is not shipped", target.display()),
+            );
+            dangling += 1;
+        }
+    }
+
+    if dangling == 0 {
+        detail!("  No dangling symlinks");
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+//! Systemd unit file checks.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use super::exists_in_root;
+use crate::detail;
+use crate::report::{BuildReport, Severity};
+use crate::sandbox::{self, Sandbox};
+
+/// Directories holding unit files and drop-ins.
+const UNIT_DIRS: &[&str] = &["usr/lib/systemd/system", "etc/systemd/system"];
+
+/// Unit settings whose first word is the program to run.
+const EXEC_KEYS: &[&str] = &[
+    "ExecCondition",
+    "ExecStartPre",
+    "ExecStart",
+    "ExecStartPost",
+    "ExecReload",
+    "ExecStop",
+    "ExecStopPost",
+];
+
+/// Directories systemd searches for non-absolute `Exec*=` commands.
+const EXEC_SEARCH_DIRS: &[&str] = &["usr/local/sbin", "usr/local/bin", "usr/sbin", "usr/bin"];
+
+/// A unit file in the staging tree.
+pub struct UnitFile {
+    /// Path inside the rootfs
+    pub path: PathBuf,
+    /// Parsed `Key=value` assignments as (section, key, value)
+    pub entries: Vec<(String, String, String)>,
+}
+
+/// Parse the `Key=value` assignments of a unit file.
+pub fn parse_unit(contents: &str) -> Vec<(String, String, String)> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].to_string();
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            entries.push((
+                section.clone(),
+                key.trim().to_string(),
+                value.trim().to_string(),
+            ));
+        }
+    }
+
+    entries
+}
+
+/// Load every regular unit file and drop-in from the staging tree.
+pub fn load_units(staging: &Path) -> Result<Vec<UnitFile>> {
+    let mut units = Vec::new();
+
+    for dir in UNIT_DIRS {
+        let root = staging.join(dir);
+        if !root.exists() {
+            continue;
+        }
+        for entry in WalkDir::new(&root).sort_by_file_name() {
+            let entry = entry?;
+            // Enablement symlinks point at units we already visit
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let contents = fs::read_to_string(entry.path())?;
+            units.push(UnitFile {
+                path: entry.path().strip_prefix(staging)?.to_path_buf(),
+                entries: parse_unit(&contents),
+            });
+        }
+    }
+
+    Ok(units)
+}
+
+/// Report units referencing `EnvironmentFile=` paths missing from the rootfs.
+pub fn check_environment_files(staging: &Path, report: &BuildReport) -> Result<()> {
+    detail!("Checking unit EnvironmentFile= references...");
+
+    let mut missing = Vec::new();
+    for unit in load_units(staging)? {
+        for (_, key, value) in &unit.entries {
+            if key != "EnvironmentFile" {
+                continue;
+            }
+            // A leading '-' marks the file optional
+            let (optional, path) = match value.strip_prefix('-') {
+                Some(path) => (true, path),
+                None => (false, value.as_str()),
+            };
+            // Specifiers and globs can't be checked statically
+            if path.contains('%') || path.contains('*') {
+                continue;
+            }
+            if !exists_in_root(staging, Path::new(path)) {
+                missing.push((unit.path.clone(), path.to_string(), optional));
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        detail!("  All referenced environment files present");
+    } else {
+        for (unit, path, optional) in &missing {
+            let message = format!(
+                "references {}{} which is not shipped",
+                path,
+                if *optional { " (optional)" } else { "" }
+            );
+            detail!("  Warning: /{} {}", unit.display(), message);
+            report.warn(
+                "environment-files",
+                Some(&format!("/{}", unit.display())),
+                message,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Program named by an `Exec*=` value, without its special prefixes.
+///
+/// Returns `None` for resets (empty values) and commands that can't be
+/// resolved statically because they use specifiers or variables.
+fn exec_program(value: &str) -> Option<&str> {
+    let command = value.trim_start_matches(['@', '-', ':', '+', '!']);
+    let program = command.split_whitespace().next()?;
+    if program.contains('%') || program.contains('$') {
+        return None;
+    }
+    Some(program)
+}
+
+/// Report units whose `Exec*=` programs are missing from the rootfs.
+///
+/// Missing programs are errors in strict mode and warnings otherwise.
+pub fn check_exec_paths(staging: &Path, report: &BuildReport, strict: bool) -> Result<()> {
+    detail!("Checking unit Exec*= programs...");
+
+    let severity = if strict {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    let label = if strict { "Error" } else { "Warning" };
+    let mut missing = 0;
+
+    for unit in load_units(staging)? {
+        for (_, key, value) in &unit.entries {
+            if !EXEC_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let Some(program) = exec_program(value) else {
+                continue;
+            };
+            let found = if program.starts_with('/') {
+                exists_in_root(staging, Path::new(program))
+            } else {
+                EXEC_SEARCH_DIRS
+                    .iter()
+                    .any(|dir| exists_in_root(staging, &Path::new(dir).join(program)))
+            };
+            if found {
+                continue;
+            }
+
+            let message = format!("{}= runs {} which is not shipped", key, program);
+            detail!("  {}: /{} {}", label, unit.path.display(), message);
+            report.push(
+                severity,
+                "exec-paths",
+                Some(&format!("/{}", unit.path.display())),
+                message,
+            );
+            missing += 1;
+        }
+    }
+
+    if missing == 0 {
+        detail!("  All unit programs present");
+    }
+
+    Ok(())
+}
+
+/// Names of the units enabled in the staging tree.
+///
+/// Covers `default.target` and everything linked from a `.wants` or
+/// `.requires` directory under /etc/systemd/system.
+pub fn enabled_units(staging: &Path) -> Result<BTreeSet<String>> {
+    let mut units = BTreeSet::from(["default.target".to_string()]);
+    let root = staging.join("etc/systemd/system");
+    if !root.exists() {
+        return Ok(units);
+    }
+
+    for entry in WalkDir::new(&root).min_depth(2).max_depth(2) {
+        let entry = entry?;
+        let in_deps_dir = entry
+            .path()
+            .parent()
+            .and_then(|p| p.extension())
+            .is_some_and(|ext| ext == "wants" || ext == "requires");
+        if in_deps_dir && entry.path_is_symlink() {
+            units.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    Ok(units)
+}
+
+/// Result of running `systemd-analyze verify` over a rootfs.
+pub struct AnalyzeOutcome {
+    /// Number of enabled units checked
+    pub units: usize,
+    /// Diagnostic lines, empty when everything verified cleanly
+    pub findings: Vec<String>,
+}
+
+/// Run `systemd-analyze verify` over the enabled units of a rootfs.
+///
+/// Generators and man page checks are disabled since neither applies to a
+/// staging tree. Returns `None` if systemd-analyze isn't installed.
+pub fn run_systemd_analyze(root: &Path, sandbox: Sandbox) -> Result<Option<AnalyzeOutcome>> {
+    if sandbox::find_program("systemd-analyze").is_none() {
+        return Ok(None);
+    }
+
+    let units = enabled_units(root)?;
+    let output = sandbox
+        .command("systemd-analyze")
+        .arg("verify")
+        .arg(format!("--root={}", root.display()))
+        .args(["--man=no", "--generators=no", "--recursive-errors=no"])
+        .args(&units)
+        .output()
+        .context("Failed to run systemd-analyze")?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut findings: Vec<String> = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    if findings.is_empty() && !output.status.success() {
+        findings.push(format!(
+            "failed with exit code {} and no output",
+            output.status.code().unwrap_or(-1)
+        ));
+    }
+
+    Ok(Some(AnalyzeOutcome {
+        units: units.len(),
+        findings,
+    }))
+}
+
+/// Run `systemd-analyze verify` over the enabled units.
+///
+/// Findings are recorded as warnings in the build report.
+pub fn verify_with_systemd_analyze(
+    staging: &Path,
+    report: &BuildReport,
+    sandbox: Sandbox,
+) -> Result<()> {
+    detail!("Running systemd-analyze verify...");
+
+    let Some(outcome) = run_systemd_analyze(staging, sandbox)? else {
+        detail!("  Warning: systemd-analyze not found, skipping unit verification");
+        report.skip("systemd-analyze", None, "systemd-analyze not found");
+        return Ok(());
+    };
+
+    if outcome.findings.is_empty() {
+        detail!("  {} enabled units verified cleanly", outcome.units);
+        return Ok(());
+    }
+
+    for line in &outcome.findings {
+        detail!("  Warning: {}", line);
+        // Lines look like `unit.service: message` or `/path:line: message`
+        let (subject, message) = match line.split_once(": ") {
+            Some((subject, message)) => (Some(subject), message),
+            None => (None, line.as_str()),
+        };
+        report.warn("systemd-analyze", subject, message);
+    }
+
+    Ok(())
+}
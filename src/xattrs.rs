@@ -0,0 +1,187 @@
+//! Extended attributes of donor files.
+//!
+//! `ping`, `arping` and a few other binaries get their privileges from a
+//! file capability (the `security.capability` attribute) instead of
+//! setuid, and a copy without it doesn't work for anyone but root.
+//! Setting capabilities in staging would need `CAP_SETFCAP`, so like
+//! ownership they go in the metadata layer: [`record`] reads the
+//! attributes of each donor file the build copies and records them for
+//! its staged path, and the tarball carries them as PAX `SCHILY.xattr`
+//! records, which `tar --xattrs` and `stage3 extract --rootfs` restore.
+//!
+//! SELinux labels (`security.selinux`) are left out unless the build
+//! asks to keep them (`--selinux preserve`), for a target that enforces
+//! the donor's policy. Files the build writes itself have none, and a
+//! target can relabel everything on first boot instead: with
+//! `--selinux relabel`, or when labels were to be kept but no donor file
+//! had one, the rootfs gets a [`/.autorelabel`](AUTORELABEL) marker.
+//! POSIX ACLs (`system.posix_acl_access` and `_default`) are left out too
+//! unless the build asks for them (`--acls`), since not every filesystem
+//! the rootfs is installed to supports them; they are written as GNU tar's
+//! `SCHILY.acl` text records as well.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::context::BuildContext;
+use crate::detail;
+use crate::fakeroot::Xattrs;
+
+/// SELinux label attribute.
+pub const SELINUX: &str = "security.selinux";
+
+/// Marker asking an SELinux-enabled system to relabel on next boot.
+pub const AUTORELABEL: &str = ".autorelabel";
+
+/// Access ACL attribute.
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+
+/// Default ACL attribute, of directories.
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+/// What the build does about SELinux labels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelinuxLabels {
+    /// Leave them out
+    #[default]
+    Drop,
+    /// Carry the donor's labels into the tarball
+    Preserve,
+    /// Leave them out and mark the rootfs for relabeling
+    Relabel,
+}
+
+impl FromStr for SelinuxLabels {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "preserve" => Ok(Self::Preserve),
+            "relabel" => Ok(Self::Relabel),
+            other => Err(format!(
+                "unknown SELinux label handling {:?} (expected drop, preserve or relabel)",
+                other
+            )),
+        }
+    }
+}
+
+/// Copyable extended attributes of `path`, with its ACLs if `acls` is
+/// set and its SELinux label if `selinux` is; those it can't read are
+/// left out.
+pub fn read(path: &Path, acls: bool, selinux: bool) -> Xattrs {
+    let Ok(names) = xattr::list(path) else {
+        return Xattrs::new();
+    };
+    names
+        .filter_map(|name| name.into_string().ok())
+        .filter(|name| selinux || name != SELINUX)
+        .filter(|name| acls || (name != ACL_ACCESS && name != ACL_DEFAULT))
+        .filter_map(|name| match xattr::get(path, &name) {
+            Ok(Some(value)) => Some((name, value)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Record the extended attributes of donor file or directory `src` for its
+/// staged copy `dest`.
+pub fn record(ctx: &BuildContext, src: &Path, dest: &Path) {
+    let Ok(rel) = dest.strip_prefix(&ctx.staging) else {
+        return;
+    };
+    let xattrs = read(src, ctx.acls, ctx.selinux == SelinuxLabels::Preserve);
+    if !xattrs.is_empty() {
+        let names: Vec<&str> = xattrs.keys().map(String::as_str).collect();
+        detail!("  /{}: {}", rel.display(), names.join(", "));
+    }
+    ctx.metadata.set_xattrs(rel, xattrs);
+}
+
+/// Put a `/.autorelabel` marker in staging if the build relabels, or keeps
+/// labels but recorded none.
+pub fn mark_relabel(ctx: &BuildContext) -> Result<()> {
+    let relabel = match ctx.selinux {
+        SelinuxLabels::Drop => false,
+        SelinuxLabels::Relabel => true,
+        SelinuxLabels::Preserve => {
+            let labeled = ctx
+                .metadata
+                .recorded_xattrs()
+                .values()
+                .any(|xattrs| xattrs.contains_key(SELINUX));
+            if !labeled {
+                detail!("  Warning: no donor file has an SELinux label");
+                ctx.report.warn(
+                    "selinux",
+                    None,
+                    "no donor file has an SELinux label; the rootfs is marked for relabeling",
+                );
+            }
+            !labeled
+        }
+    };
+    if relabel {
+        let marker = ctx.staging.join(AUTORELABEL);
+        fs::write(&marker, "").with_context(|| format!("Failed to write {}", marker.display()))?;
+        detail!("  Created /{}", AUTORELABEL);
+    }
+    Ok(())
+}
+
+/// PAX records for extended attributes: one `SCHILY.xattr` record each,
+/// and ACLs also as `SCHILY.acl` text.
+pub fn pax_records(xattrs: &Xattrs) -> Vec<(String, Vec<u8>)> {
+    let mut records: Vec<(String, Vec<u8>)> = xattrs
+        .iter()
+        .map(|(name, value)| (format!("SCHILY.xattr.{}", name), value.clone()))
+        .collect();
+    for (name, key) in [
+        (ACL_ACCESS, "SCHILY.acl.access"),
+        (ACL_DEFAULT, "SCHILY.acl.default"),
+    ] {
+        if let Some(text) = xattrs.get(name).and_then(|value| acl_text(value)) {
+            records.push((key.to_string(), text.into_bytes()));
+        }
+    }
+    records
+}
+
+/// The short text form (`user::rw-,group::r--,other::r--`) of an ACL in
+/// its `system.posix_acl_*` encoding: a version, then tag, permissions and
+/// id of each entry. None if it isn't one.
+fn acl_text(value: &[u8]) -> Option<String> {
+    let (version, entries) = value.split_first_chunk::<4>()?;
+    if u32::from_le_bytes(*version) != 2 || entries.len() % 8 != 0 {
+        return None;
+    }
+    let text: Option<Vec<String>> = entries
+        .chunks_exact(8)
+        .map(|entry| {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let qualifier = match tag {
+                0x01 => "user:".to_string(),
+                0x02 => format!("user:{}", id),
+                0x04 => "group:".to_string(),
+                0x08 => format!("group:{}", id),
+                0x10 => "mask:".to_string(),
+                0x20 => "other:".to_string(),
+                _ => return None,
+            };
+            let flag = |bit: u16, c: char| if perm & bit != 0 { c } else { '-' };
+            Some(format!(
+                "{}:{}{}{}",
+                qualifier,
+                flag(4, 'r'),
+                flag(2, 'w'),
+                flag(1, 'x')
+            ))
+        })
+        .collect();
+    Some(text?.join(","))
+}